@@ -0,0 +1,68 @@
+//! Proving from a deep tree with and without [`SparseMerkleTree::cache_node_hashes`]. Run with
+//! `cargo bench --bench node_hash_cache`.
+use criterion::{criterion_group, criterion_main, Criterion};
+use my_smt::sparse_merkle_tree::SparseMerkleTree;
+use plonky2::{
+    field::types::Sample,
+    plonk::config::{GenericConfig, PoseidonGoldilocksConfig},
+};
+
+type C = PoseidonGoldilocksConfig;
+type F = <C as GenericConfig<2>>::F;
+type H = <C as GenericConfig<2>>::Hasher;
+
+const HEIGHT: usize = 256;
+const LEAVES: u64 = 1000;
+
+fn path(i: u64) -> Vec<bool> {
+    (0..HEIGHT)
+        .map(|bit| bit < 64 && (i.wrapping_mul(0x9e37_79b9_7f4a_7c15) >> bit) & 1 == 1)
+        .collect()
+}
+
+fn tree(cached: bool) -> SparseMerkleTree<F, H> {
+    let mut tree = SparseMerkleTree::new(HEIGHT);
+    for i in 0..LEAVES {
+        tree.update(&path(i), F::rand_vec(4));
+    }
+    if cached {
+        tree.cache_node_hashes();
+    }
+    tree
+}
+
+fn prove(c: &mut Criterion, name: &str, cached: bool) {
+    let tree = tree(cached);
+    let paths: Vec<_> = (0..LEAVES).map(path).collect();
+    let mut i = 0;
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            i = (i + 1) % paths.len();
+            tree.prove(&paths[i])
+        })
+    });
+}
+
+fn update(c: &mut Criterion, name: &str, cached: bool) {
+    let mut tree = tree(cached);
+    // Drawn up front so the RNG isn't part of what's measured.
+    let updates: Vec<_> = (0..LEAVES).map(|i| (path(i), F::rand_vec(4))).collect();
+    let mut i = 0;
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            i = (i + 1) % updates.len();
+            let (path, value) = &updates[i];
+            tree.update(path, value.clone());
+        })
+    });
+}
+
+fn benches(c: &mut Criterion) {
+    prove(c, "prove_uncached", false);
+    prove(c, "prove_cached", true);
+    update(c, "update_uncached", false);
+    update(c, "update_cached", true);
+}
+
+criterion_group!(node_hash_cache, benches);
+criterion_main!(node_hash_cache);
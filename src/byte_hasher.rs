@@ -0,0 +1,48 @@
+use plonky2::{
+    hash::{
+        hash_types::{BytesHash, RichField},
+        keccak::KeccakPermutation,
+    },
+    plonk::config::Hasher,
+};
+use std::{fmt::Debug, marker::PhantomData};
+
+/// A hash function on bytes with 32-byte digests, such as Keccak-256 or Blake3, for trees that
+/// mirror a commitment computed outside of plonky2, e.g. by a contract. Implement it and use
+/// [`Bytes32Hasher`] as the tree's hasher.
+pub trait ByteHasher: Clone + Debug + Eq + Send + Sync {
+    fn hash(input: &[u8]) -> [u8; 32];
+
+    /// Hashes two child digests into their parent, by default the hash of their concatenation.
+    fn two_to_one(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+        Self::hash(&[left.as_slice(), right].concat())
+    }
+}
+
+/// Implements plonky2's [`Hasher`] on top of a [`ByteHasher`], so that the tree, its proofs and
+/// their verification work with it unchanged. Field elements are hashed as their canonical
+/// values in 8 little-endian bytes each, as by plonky2's `KeccakHash`, and leaves of up to four
+/// elements are stored as their bytes without hashing, as for every plonky2 hasher.
+///
+/// Trees over such hashers can't be checked in circuits, which need an `AlgebraicHasher`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bytes32Hasher<B: ByteHasher>(PhantomData<B>);
+
+impl<F: RichField, B: ByteHasher> Hasher<F> for Bytes32Hasher<B> {
+    const HASH_SIZE: usize = 32;
+    type Hash = BytesHash<32>;
+    // Unused by the tree; plonky2 requires one for sponge-based hashers.
+    type Permutation = KeccakPermutation;
+
+    fn hash_no_pad(input: &[F]) -> Self::Hash {
+        let bytes: Vec<u8> = input
+            .iter()
+            .flat_map(|x| x.to_canonical_u64().to_le_bytes())
+            .collect();
+        BytesHash(B::hash(&bytes))
+    }
+
+    fn two_to_one(left: Self::Hash, right: Self::Hash) -> Self::Hash {
+        BytesHash(B::two_to_one(&left.0, &right.0))
+    }
+}
@@ -0,0 +1,640 @@
+use crate::{indexed_tree::ExclusionProof, sparse_merkle_tree::BlockProof, verifier};
+use plonky2::{
+    field::extension::Extendable,
+    hash::{
+        hash_types::{HashOutTarget, RichField},
+        merkle_proofs::{MerkleProof, MerkleProofTarget},
+    },
+    iop::{
+        target::{BoolTarget, Target},
+        witness::WitnessWrite,
+    },
+    plonk::{
+        circuit_builder::CircuitBuilder,
+        circuit_data::VerifierCircuitData,
+        config::{AlgebraicHasher, GenericConfig},
+        proof::{ProofWithPublicInputs, ProofWithPublicInputsTarget},
+    },
+};
+
+/// Adds virtual targets for the siblings of a proof in a binary tree of height `height`.
+pub fn add_virtual_smt_proof<F: RichField + Extendable<D>, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    height: usize,
+) -> MerkleProofTarget {
+    MerkleProofTarget {
+        siblings: builder.add_virtual_hashes(height),
+    }
+}
+
+/// Constrains `proof` to show `leaf` at `path_bits` under `root`, the in-circuit counterpart of
+/// `SparseMerkleTree::verify_borrowed` for binary trees without level tags. The path bits are
+/// ordered like a leaf path, most significant first.
+pub fn verify_smt_proof_circuit<F, H, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    leaf: Vec<Target>,
+    path_bits: &[BoolTarget],
+    root: HashOutTarget,
+    proof: &MerkleProofTarget,
+) where
+    F: RichField + Extendable<D>,
+    H: AlgebraicHasher<F>,
+{
+    assert_eq!(path_bits.len(), proof.siblings.len());
+    // plonky2 takes the leaf index in little-endian bits, i.e. bottom level first.
+    let index_bits: Vec<BoolTarget> = path_bits.iter().rev().copied().collect();
+    builder.verify_merkle_proof::<H>(leaf, &index_bits, root, proof);
+}
+
+/// Constrains the update of the leaf at `path_bits` from `old_leaf` to `new_leaf` to take the
+/// tree from `old_root` to `new_root`. Updating a leaf doesn't change its siblings, so the same
+/// `proof` shows the old leaf under the old root and the new leaf under the new root, as returned
+/// by `SparseMerkleTree::update_with_old_proof`.
+pub fn verify_smt_transition_circuit<F, H, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    old_leaf: Vec<Target>,
+    new_leaf: Vec<Target>,
+    path_bits: &[BoolTarget],
+    old_root: HashOutTarget,
+    new_root: HashOutTarget,
+    proof: &MerkleProofTarget,
+) where
+    F: RichField + Extendable<D>,
+    H: AlgebraicHasher<F>,
+{
+    verify_smt_proof_circuit::<F, H, D>(builder, old_leaf, path_bits, old_root, proof);
+    verify_smt_proof_circuit::<F, H, D>(builder, new_leaf, path_bits, new_root, proof);
+}
+
+/// Constrains `proof` to show `leaf` at `path_bits` under `root`, where the top bits of the path
+/// are `prefix`. Registering only `prefix`, `leaf` and `root` as public inputs and proving with
+/// a zero-knowledge config such as `CircuitConfig::standard_recursion_zk_config` shows the leaf
+/// somewhere under the prefix without revealing the rest of its path or its siblings, which
+/// `SparseMerkleTree::verify_under_prefix` can't.
+pub fn verify_smt_prefix_membership_circuit<F, H, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    prefix: &[BoolTarget],
+    leaf: Vec<Target>,
+    path_bits: &[BoolTarget],
+    root: HashOutTarget,
+    proof: &MerkleProofTarget,
+) where
+    F: RichField + Extendable<D>,
+    H: AlgebraicHasher<F>,
+{
+    assert!(prefix.len() <= path_bits.len());
+    for (prefix_bit, path_bit) in prefix.iter().zip(path_bits) {
+        builder.connect(prefix_bit.target, path_bit.target);
+    }
+    verify_smt_proof_circuit::<F, H, D>(builder, leaf, path_bits, root, proof);
+}
+
+/// Assigns the siblings of `proof`, as returned by `SparseMerkleTree::prove`, to `target`.
+pub fn set_smt_proof_target<F: RichField, H: AlgebraicHasher<F>>(
+    witness: &mut impl WitnessWrite<F>,
+    target: &MerkleProofTarget,
+    proof: &MerkleProof<F, H>,
+) {
+    assert_eq!(target.siblings.len(), proof.siblings.len());
+    for (&sibling, &hash) in target.siblings.iter().zip(&proof.siblings) {
+        witness.set_hash_target(sibling, hash);
+    }
+}
+
+/// Targets for an exclusion proof of `value` from an indexed Merkle tree of height `height`,
+/// see `IndexedMerkleTree::prove_exclusion`.
+#[derive(Clone, Debug)]
+pub struct IndexedExclusionTarget {
+    pub value: Target,
+    /// `[value, next_index, next_value]` of the low leaf.
+    pub low_leaf: [Target; 3],
+    /// The index of the low leaf, most significant bit first like a leaf path.
+    pub low_index_bits: Vec<BoolTarget>,
+    pub proof: MerkleProofTarget,
+}
+
+pub fn add_virtual_indexed_exclusion<F: RichField + Extendable<D>, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    height: usize,
+) -> IndexedExclusionTarget {
+    assert!(
+        (1..=64).contains(&height),
+        "indexed trees are 1 to 64 levels high"
+    );
+    IndexedExclusionTarget {
+        value: builder.add_virtual_target(),
+        low_leaf: [(); 3].map(|_| builder.add_virtual_target()),
+        low_index_bits: (0..height)
+            .map(|_| builder.add_virtual_bool_target_safe())
+            .collect(),
+        proof: add_virtual_smt_proof(builder, height),
+    }
+}
+
+/// Constrains `target` to show its value absent from the indexed Merkle tree with `root`, the
+/// in-circuit counterpart of `IndexedMerkleTree::verify_exclusion`. Since values are below
+/// `2^63`, `a < b` holds exactly when `b - a - 1` fits in 63 bits, so each comparison is a
+/// single range check.
+pub fn verify_indexed_exclusion_circuit<F, H, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    target: &IndexedExclusionTarget,
+    root: HashOutTarget,
+) where
+    F: RichField + Extendable<D>,
+    H: AlgebraicHasher<F>,
+{
+    let [low_value, _, next_value] = target.low_leaf;
+    let one = builder.one();
+    let zero = builder.zero();
+    builder.range_check(target.value, 63);
+
+    let above_low = builder.sub(target.value, low_value);
+    let above_low = builder.sub(above_low, one);
+    builder.range_check(above_low, 63);
+
+    // The last leaf of the list links to nothing and bounds no value from above.
+    let below_next = builder.sub(next_value, target.value);
+    let below_next = builder.sub(below_next, one);
+    let is_last = builder.is_equal(next_value, zero);
+    let below_next = builder.select(is_last, zero, below_next);
+    builder.range_check(below_next, 63);
+
+    // Only the sentinel at index 0 may hold the value 0.
+    let low_is_zero = builder.is_equal(low_value, zero);
+    let index_bits = target.low_index_bits.iter().map(|bit| bit.target);
+    let set_bits = builder.add_many(index_bits);
+    let misplaced_zero = builder.mul(low_is_zero.target, set_bits);
+    builder.assert_zero(misplaced_zero);
+
+    let mut leaf = target.low_leaf.to_vec();
+    leaf.push(one);
+    verify_smt_proof_circuit::<F, H, D>(builder, leaf, &target.low_index_bits, root, &target.proof);
+}
+
+/// Assigns an exclusion proof of `value` to `target`.
+pub fn set_indexed_exclusion_target<F: RichField, H: AlgebraicHasher<F>>(
+    witness: &mut impl WitnessWrite<F>,
+    target: &IndexedExclusionTarget,
+    value: u64,
+    proof: &ExclusionProof<F, H>,
+) {
+    witness.set_target(target.value, F::from_canonical_u64(value));
+    for (&t, x) in target.low_leaf.iter().zip(proof.low_leaf.elements::<F>()) {
+        witness.set_target(t, x);
+    }
+    let height = target.low_index_bits.len();
+    assert!(height <= 64, "indexed trees are 1 to 64 levels high");
+    for (i, &bit) in target.low_index_bits.iter().enumerate() {
+        witness.set_bool_target(bit, (proof.low_index >> (height - 1 - i)) & 1 == 1);
+    }
+    set_smt_proof_target(witness, &target.proof, &proof.proof);
+}
+
+/// Targets for one update of a batch, see [`add_smt_batch_circuit`].
+#[derive(Clone, Debug)]
+pub struct SmtUpdateTarget {
+    pub old_leaf: Vec<Target>,
+    pub new_leaf: Vec<Target>,
+    pub path_bits: Vec<BoolTarget>,
+    pub proof: MerkleProofTarget,
+}
+
+/// Targets for a batch of leaf updates chained from `old_root` to `new_root`.
+#[derive(Clone, Debug)]
+pub struct SmtBatchTarget {
+    pub old_root: HashOutTarget,
+    pub new_root: HashOutTarget,
+    pub updates: Vec<SmtUpdateTarget>,
+    /// The roots left by each update but the last.
+    pub intermediate_roots: Vec<HashOutTarget>,
+}
+
+/// Builds a circuit proving that `batch_size` updates of leaves `leaf_width` elements wide, in a
+/// binary tree of height `height` without level tags, take the tree from one root to another,
+/// each update being proven against the root left by the previous one as in
+/// `SparseMerkleTree::prove_block`. The old and new roots are registered as public inputs in
+/// that order, the layout [`add_smt_aggregation_circuit`] expects.
+pub fn add_smt_batch_circuit<F, H, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    height: usize,
+    leaf_width: usize,
+    batch_size: usize,
+) -> SmtBatchTarget
+where
+    F: RichField + Extendable<D>,
+    H: AlgebraicHasher<F>,
+{
+    let old_root = builder.add_virtual_hash();
+    let new_root = builder.add_virtual_hash();
+    builder.register_public_inputs(&old_root.elements);
+    builder.register_public_inputs(&new_root.elements);
+    let intermediate_roots = builder.add_virtual_hashes(batch_size.saturating_sub(1));
+    let mut roots = vec![old_root];
+    roots.extend(&intermediate_roots);
+    roots.push(new_root);
+    if batch_size == 0 {
+        builder.connect_hashes(old_root, new_root);
+    }
+
+    let updates = roots
+        .windows(2)
+        .map(|roots| {
+            let update = SmtUpdateTarget {
+                old_leaf: builder.add_virtual_targets(leaf_width),
+                new_leaf: builder.add_virtual_targets(leaf_width),
+                path_bits: (0..height)
+                    .map(|_| builder.add_virtual_bool_target_safe())
+                    .collect(),
+                proof: add_virtual_smt_proof(builder, height),
+            };
+            verify_smt_transition_circuit::<F, H, D>(
+                builder,
+                update.old_leaf.clone(),
+                update.new_leaf.clone(),
+                &update.path_bits,
+                roots[0],
+                roots[1],
+                &update.proof,
+            );
+            update
+        })
+        .collect();
+    SmtBatchTarget {
+        old_root,
+        new_root,
+        updates,
+        intermediate_roots,
+    }
+}
+
+/// Assigns `block`, the proof of `updates` from `SparseMerkleTree::prove_block`, to `target`.
+pub fn set_smt_batch_target<F: RichField, H: AlgebraicHasher<F>>(
+    witness: &mut impl WitnessWrite<F>,
+    target: &SmtBatchTarget,
+    block: &BlockProof<F, H>,
+    updates: &[(Vec<bool>, Vec<F>)],
+) {
+    assert_eq!(target.updates.len(), updates.len());
+    assert_eq!(block.steps.len(), updates.len());
+    witness.set_hash_target(target.old_root, block.old_root);
+    witness.set_hash_target(target.new_root, block.new_root);
+    let steps = block.steps.iter().zip(updates);
+    for (i, (t, ((path, old_leaf, proof), (_, new_leaf)))) in
+        target.updates.iter().zip(steps).enumerate()
+    {
+        for (&target, &x) in t.old_leaf.iter().zip(old_leaf) {
+            witness.set_target(target, x);
+        }
+        for (&target, &x) in t.new_leaf.iter().zip(new_leaf) {
+            witness.set_target(target, x);
+        }
+        for (&target, &bit) in t.path_bits.iter().zip(path) {
+            witness.set_bool_target(target, bit);
+        }
+        set_smt_proof_target(witness, &t.proof, proof);
+        if let Some(&root) = target.intermediate_roots.get(i) {
+            let hash = verifier::compute_root_from_proof::<F, H>(new_leaf, path, &proof.siblings)
+                .expect("proof doesn't match its path");
+            witness.set_hash_target(root, hash);
+        }
+    }
+}
+
+/// Targets for the proofs aggregated by [`add_smt_aggregation_circuit`].
+#[derive(Clone, Debug)]
+pub struct SmtAggregationTarget<const D: usize> {
+    pub proofs: Vec<ProofWithPublicInputsTarget<D>>,
+}
+
+/// Builds a circuit that recursively verifies `count` consecutive proofs of the circuit `inner`,
+/// each taking the tree from the root the previous one ended at. `inner` is a batch circuit from
+/// [`add_smt_batch_circuit`] or another aggregation circuit, since both expose the old and new
+/// roots as their only public inputs, and so does the aggregate, covering the whole chain.
+pub fn add_smt_aggregation_circuit<F, C, const D: usize>(
+    builder: &mut CircuitBuilder<F, D>,
+    inner: &VerifierCircuitData<F, C, D>,
+    count: usize,
+) -> SmtAggregationTarget<D>
+where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+    C::Hasher: AlgebraicHasher<F>,
+{
+    assert!(count > 0);
+    assert_eq!(inner.common.num_public_inputs, 8, "expected two roots");
+    let verifier_data = builder.constant_verifier_data(&inner.verifier_only);
+    let proofs: Vec<_> = (0..count)
+        .map(|_| {
+            let proof = builder.add_virtual_proof_with_pis::<C>(&inner.common);
+            builder.verify_proof::<C>(&proof, &verifier_data, &inner.common);
+            proof
+        })
+        .collect();
+    for pair in proofs.windows(2) {
+        for i in 0..4 {
+            builder.connect(pair[0].public_inputs[4 + i], pair[1].public_inputs[i]);
+        }
+    }
+    builder.register_public_inputs(&proofs[0].public_inputs[..4]);
+    builder.register_public_inputs(&proofs[count - 1].public_inputs[4..]);
+    SmtAggregationTarget { proofs }
+}
+
+/// Assigns the consecutive proofs `proofs` to `target`.
+pub fn set_smt_aggregation_target<F, C, const D: usize>(
+    witness: &mut impl WitnessWrite<F>,
+    target: &SmtAggregationTarget<D>,
+    proofs: &[ProofWithPublicInputs<F, C, D>],
+) where
+    F: RichField + Extendable<D>,
+    C: GenericConfig<D, F = F>,
+    C::Hasher: AlgebraicHasher<F>,
+{
+    assert_eq!(target.proofs.len(), proofs.len());
+    for (t, proof) in target.proofs.iter().zip(proofs) {
+        witness.set_proof_with_pis_target(t, proof);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        indexed_tree::{IndexedLeaf, IndexedMerkleTree},
+        sparse_merkle_tree::{tests::usize_to_vec, SparseMerkleTree},
+    };
+    use plonky2::{
+        field::types::Sample,
+        hash::poseidon::PoseidonHash,
+        iop::witness::PartialWitness,
+        plonk::{
+            circuit_data::CircuitConfig,
+            config::{Hasher, PoseidonGoldilocksConfig},
+        },
+    };
+    use rand::Rng;
+
+    const D: usize = 2;
+    type C = PoseidonGoldilocksConfig;
+    type F = <C as GenericConfig<D>>::F;
+    type H = PoseidonHash;
+
+    #[test]
+    fn prefix_membership_circuit_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let path = usize_to_vec(0b1001_0110, height);
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+        tree.update(&usize_to_vec(3, height), F::rand_vec(4));
+
+        // Only the prefix, the leaf and the root are public. A zero-knowledge config would
+        // also hide the rest of the witness, but is much slower to prove in debug builds.
+        let config = CircuitConfig::standard_recursion_config();
+        let mut builder = CircuitBuilder::<F, D>::new(config);
+        let prefix: Vec<_> = (0..2)
+            .map(|_| builder.add_virtual_bool_target_safe())
+            .collect();
+        let leaf = builder.add_virtual_targets(4);
+        let path_bits: Vec<_> = (0..height)
+            .map(|_| builder.add_virtual_bool_target_safe())
+            .collect();
+        let root = builder.add_virtual_hash();
+        let proof = add_virtual_smt_proof(&mut builder, height);
+        for bit in &prefix {
+            builder.register_public_input(bit.target);
+        }
+        builder.register_public_inputs(&leaf);
+        builder.register_public_inputs(&root.elements);
+        verify_smt_prefix_membership_circuit::<F, H, D>(
+            &mut builder,
+            &prefix,
+            leaf.clone(),
+            &path_bits,
+            root,
+            &proof,
+        );
+        let data = builder.build::<C>();
+
+        let prove = |prefix_bits: [bool; 2]| {
+            let mut pw = PartialWitness::new();
+            for (&target, bit) in prefix.iter().zip(prefix_bits) {
+                pw.set_bool_target(target, bit);
+            }
+            for (&target, &x) in leaf.iter().zip(&value) {
+                pw.set_target(target, x);
+            }
+            for (&target, &bit) in path_bits.iter().zip(&path) {
+                pw.set_bool_target(target, bit);
+            }
+            pw.set_hash_target(root, tree.get_root());
+            set_smt_proof_target(&mut pw, &proof, &tree.prove(&path));
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| data.prove(pw)))
+        };
+        data.verify(prove([true, false]).unwrap().unwrap()).unwrap();
+        assert!(!matches!(prove([false, true]), Ok(Ok(_))));
+    }
+
+    #[test]
+    fn membership_circuit_test() {
+        let mut rng = rand::thread_rng();
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..10 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let path = tree.iter_leaves().nth(2).unwrap().0.to_bits();
+
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let leaf = builder.add_virtual_targets(4);
+        let path_bits: Vec<_> = (0..height)
+            .map(|_| builder.add_virtual_bool_target_safe())
+            .collect();
+        let root = builder.add_virtual_hash();
+        let proof = add_virtual_smt_proof(&mut builder, height);
+        verify_smt_proof_circuit::<F, H, D>(&mut builder, leaf.clone(), &path_bits, root, &proof);
+        let data = builder.build::<C>();
+
+        let witness = |value: &[F]| {
+            let mut pw = PartialWitness::new();
+            for (&target, &x) in leaf.iter().zip(value) {
+                pw.set_target(target, x);
+            }
+            for (&target, &bit) in path_bits.iter().zip(&path) {
+                pw.set_bool_target(target, bit);
+            }
+            pw.set_hash_target(root, tree.get_root());
+            set_smt_proof_target(&mut pw, &proof, &tree.prove(&path));
+            pw
+        };
+        let circuit_proof = data.prove(witness(&tree.get_leaf(&path))).unwrap();
+        data.verify(circuit_proof).unwrap();
+
+        let wrong = F::rand_vec(4);
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| data.prove(witness(&wrong))));
+        assert!(!matches!(result, Ok(Ok(_))));
+    }
+
+    #[test]
+    fn transition_circuit_test() {
+        let mut rng = rand::thread_rng();
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..10 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let path = tree.iter_leaves().nth(2).unwrap().0.to_bits();
+        let old_leaf = tree.get_leaf(&path);
+        let new_leaf = F::rand_vec(4);
+        let (merkle_proof, old_root, new_root) =
+            tree.update_with_old_proof(&path, new_leaf.clone());
+
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let old_leaf_t = builder.add_virtual_targets(4);
+        let new_leaf_t = builder.add_virtual_targets(4);
+        let path_bits: Vec<_> = (0..height)
+            .map(|_| builder.add_virtual_bool_target_safe())
+            .collect();
+        let old_root_t = builder.add_virtual_hash();
+        let new_root_t = builder.add_virtual_hash();
+        let proof = add_virtual_smt_proof(&mut builder, height);
+        verify_smt_transition_circuit::<F, H, D>(
+            &mut builder,
+            old_leaf_t.clone(),
+            new_leaf_t.clone(),
+            &path_bits,
+            old_root_t,
+            new_root_t,
+            &proof,
+        );
+        let data = builder.build::<C>();
+
+        let witness = |claimed_root| {
+            let mut pw = PartialWitness::new();
+            for (targets, values) in [(&old_leaf_t, &old_leaf), (&new_leaf_t, &new_leaf)] {
+                for (&target, &x) in targets.iter().zip(values) {
+                    pw.set_target(target, x);
+                }
+            }
+            for (&target, &bit) in path_bits.iter().zip(&path) {
+                pw.set_bool_target(target, bit);
+            }
+            pw.set_hash_target(old_root_t, old_root);
+            pw.set_hash_target(new_root_t, claimed_root);
+            set_smt_proof_target(&mut pw, &proof, &merkle_proof);
+            pw
+        };
+        let circuit_proof = data.prove(witness(new_root)).unwrap();
+        data.verify(circuit_proof).unwrap();
+
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            data.prove(witness(old_root))
+        }));
+        assert!(!matches!(result, Ok(Ok(_))));
+    }
+
+    /// Proves two consecutive batches of three updates, returning the batch circuit, the proofs
+    /// and the roots before and after both.
+    #[allow(clippy::type_complexity)]
+    fn prove_two_batches() -> (
+        plonky2::plonk::circuit_data::CircuitData<F, C, D>,
+        [plonky2::plonk::proof::ProofWithPublicInputs<F, C, D>; 2],
+        <H as Hasher<F>>::Hash,
+        <H as Hasher<F>>::Hash,
+    ) {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        tree.update(usize_to_vec(7, height), F::rand_vec(4));
+        let start = tree.get_root();
+
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let target = add_smt_batch_circuit::<F, H, D>(&mut builder, height, 4, 3);
+        let batch = builder.build::<C>();
+
+        let mut prove_batch = |indices: [usize; 3]| {
+            let updates: Vec<_> = indices
+                .iter()
+                .map(|&i| (usize_to_vec(i, height), F::rand_vec(4)))
+                .collect();
+            let block = tree.prove_block(&updates).unwrap();
+            let mut pw = PartialWitness::new();
+            set_smt_batch_target(&mut pw, &target, &block, &updates);
+            batch.prove(pw).unwrap()
+        };
+        // The second batch updates a leaf of the first again.
+        let proofs = [prove_batch([1, 7, 200]), prove_batch([7, 2, 3])];
+        (batch, proofs, start, tree.get_root())
+    }
+
+    #[test]
+    fn batch_circuit_test() {
+        let (batch, proofs, start, end) = prove_two_batches();
+        assert_eq!(proofs[0].public_inputs[..4], start.elements);
+        assert_eq!(proofs[0].public_inputs[4..], proofs[1].public_inputs[..4]);
+        assert_eq!(proofs[1].public_inputs[4..], end.elements);
+        for proof in proofs {
+            batch.verify(proof).unwrap();
+        }
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, ignore = "takes minutes without optimizations")]
+    fn aggregation_circuit_test() {
+        let (batch, proofs, start, end) = prove_two_batches();
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let target = add_smt_aggregation_circuit(&mut builder, &batch.verifier_data(), 2);
+        let aggregation = builder.build::<C>();
+        let mut pw = PartialWitness::new();
+        set_smt_aggregation_target(&mut pw, &target, &proofs);
+        let aggregate = aggregation.prove(pw).unwrap();
+        assert_eq!(aggregate.public_inputs[..4], start.elements);
+        assert_eq!(aggregate.public_inputs[4..], end.elements);
+        aggregation.verify(aggregate).unwrap();
+
+        // Out of order, the second proof doesn't start where the first ends.
+        let mut pw = PartialWitness::new();
+        set_smt_aggregation_target(&mut pw, &target, &[proofs[1].clone(), proofs[0].clone()]);
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| aggregation.prove(pw)));
+        assert!(!matches!(result, Ok(Ok(_))));
+    }
+
+    #[test]
+    fn indexed_exclusion_circuit_test() {
+        let height = 8;
+        let mut tree = IndexedMerkleTree::<F, H>::new(height);
+        for value in [300, 100, 200] {
+            tree.insert(value).unwrap();
+        }
+
+        let mut builder = CircuitBuilder::<F, D>::new(CircuitConfig::standard_recursion_config());
+        let target = add_virtual_indexed_exclusion(&mut builder, height);
+        let root = builder.add_virtual_hash();
+        verify_indexed_exclusion_circuit::<F, H, D>(&mut builder, &target, root);
+        let data = builder.build::<C>();
+
+        let prove = |value, proof: &ExclusionProof<F, H>| {
+            let mut pw = PartialWitness::new();
+            set_indexed_exclusion_target(&mut pw, &target, value, proof);
+            pw.set_hash_target(root, tree.get_root());
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| data.prove(pw)))
+        };
+        for value in [150, 301] {
+            let proof = tree.prove_exclusion(value).unwrap();
+            data.verify(prove(value, &proof).unwrap().unwrap()).unwrap();
+        }
+        // The low leaf of 150 doesn't cover 200, which is present.
+        let proof = tree.prove_exclusion(150).unwrap();
+        assert!(!matches!(prove(200, &proof), Ok(Ok(_))));
+        // Nor does a zeroed leaf in place of an unused slot.
+        let forged = ExclusionProof {
+            low_index: 9,
+            low_leaf: IndexedLeaf::default(),
+            proof: tree.tree().prove(usize_to_vec(9, height)),
+        };
+        assert!(!matches!(prove(200, &forged), Ok(Ok(_))));
+    }
+}
@@ -0,0 +1,186 @@
+#[cfg(feature = "hash-stats")]
+use crate::sparse_merkle_tree::HashOpStats;
+use crate::sparse_merkle_tree::{HashOps, LeafValue, SparseMerkleTree};
+use plonky2::{
+    hash::{hash_types::RichField, merkle_proofs::MerkleProof},
+    plonk::config::Hasher,
+};
+
+/// A binary sparse Merkle tree for a handful of leaves, stored as a sorted list of
+/// `(index, value)` pairs with no inner nodes at all. Roots and proofs are folded from the leaves
+/// and the hashes of empty subtrees on demand, so each costs `O(leaves * height)` hashes, but
+/// memory is just the leaves themselves.
+///
+/// Roots and proofs are the same as those of a [`SparseMerkleTree`] of the same height without
+/// level tags, so the two can be verified interchangeably. The height is at most 64.
+#[derive(Clone, Debug)]
+pub struct CompactSmt<F: RichField, H: Hasher<F>> {
+    pub height: usize,
+    leaves: Vec<(u64, Vec<F>)>,
+    /// Hashes of empty subtrees, indexed by depth.
+    zero_hashes: Vec<H::Hash>,
+    hash_ops: HashOps,
+}
+
+impl<F: RichField, H: Hasher<F>> CompactSmt<F, H> {
+    pub fn new(height: usize) -> Self {
+        assert!(height <= 64);
+        let hash_ops = HashOps::default();
+        let mut h = hash_ops.hash_or_noop::<F, H>(&<Vec<F> as LeafValue<F>>::empty());
+        let mut zero_hashes = vec![h];
+        for _ in 0..height {
+            h = hash_ops.two_to_one::<F, H>(h, h);
+            zero_hashes.push(h);
+        }
+        zero_hashes.reverse();
+        Self {
+            height,
+            leaves: vec![],
+            zero_hashes,
+            hash_ops,
+        }
+    }
+
+    fn check_index(&self, index: u64) {
+        assert!(self.height == 64 || index >> self.height == 0);
+    }
+
+    pub fn update(&mut self, index: u64, value: Vec<F>) {
+        self.check_index(index);
+        match self.leaves.binary_search_by_key(&index, |(i, _)| *i) {
+            Ok(pos) => self.leaves[pos].1 = value,
+            Err(pos) => self.leaves.insert(pos, (index, value)),
+        }
+    }
+
+    /// Removes the leaf at `index`, returning its value if it was set.
+    pub fn delete(&mut self, index: u64) -> Option<Vec<F>> {
+        let pos = self.leaves.binary_search_by_key(&index, |(i, _)| *i).ok()?;
+        Some(self.leaves.remove(pos).1)
+    }
+
+    pub fn get_leaf(&self, index: u64) -> Option<&Vec<F>> {
+        let pos = self.leaves.binary_search_by_key(&index, |(i, _)| *i).ok()?;
+        Some(&self.leaves[pos].1)
+    }
+
+    /// Number of set leaves.
+    pub fn len(&self) -> usize {
+        self.leaves.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.leaves.is_empty()
+    }
+
+    pub fn get_root(&self) -> H::Hash {
+        self.subtree_hash(&self.leaves, 0)
+    }
+
+    /// Returns the siblings of every node on the path to `index`, from the bottom up, as
+    /// [`SparseMerkleTree::prove`] would.
+    pub fn prove(&self, index: u64) -> MerkleProof<F, H> {
+        self.check_index(index);
+        let siblings = (1..=self.height)
+            .rev()
+            .map(|depth| {
+                let shift = self.height - depth;
+                let sibling = (index >> shift) ^ 1;
+                let start = self.leaves.partition_point(|(i, _)| i >> shift < sibling);
+                let end = self.leaves.partition_point(|(i, _)| i >> shift <= sibling);
+                self.subtree_hash(&self.leaves[start..end], depth)
+            })
+            .collect();
+        MerkleProof { siblings }
+    }
+
+    /// Checks `proof` like [`SparseMerkleTree::verify_borrowed`].
+    pub fn verify(
+        &self,
+        index: u64,
+        value: &[F],
+        root: H::Hash,
+        proof: &MerkleProof<F, H>,
+    ) -> bool {
+        self.check_index(index);
+        let path: Vec<bool> = (0..self.height)
+            .rev()
+            .map(|i| (index >> i) & 1 == 1)
+            .collect();
+        SparseMerkleTree::<F, H>::verify_borrowed(&path, value, &proof.siblings, root)
+    }
+
+    /// The hasher calls made by this tree, as [`SparseMerkleTree::hash_op_stats`] counts them.
+    #[cfg(feature = "hash-stats")]
+    pub fn hash_op_stats(&self) -> HashOpStats {
+        self.hash_ops.stats()
+    }
+
+    #[cfg(feature = "hash-stats")]
+    pub fn reset_hash_op_stats(&self) {
+        self.hash_ops.reset();
+    }
+
+    /// Hashes the subtree at `depth` holding exactly `leaves`.
+    fn subtree_hash(&self, leaves: &[(u64, Vec<F>)], depth: usize) -> H::Hash {
+        if leaves.is_empty() {
+            return self.zero_hashes[depth];
+        }
+        if depth == self.height {
+            return self.hash_ops.hash_or_noop::<F, H>(&leaves[0].1);
+        }
+        let bit = self.height - 1 - depth;
+        let mid = leaves.partition_point(|(i, _)| (i >> bit) & 1 == 0);
+        self.hash_ops.two_to_one::<F, H>(
+            self.subtree_hash(&leaves[..mid], depth + 1),
+            self.subtree_hash(&leaves[mid..], depth + 1),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use plonky2::{
+        field::types::Sample,
+        hash::poseidon::PoseidonHash,
+        plonk::config::{GenericConfig, PoseidonGoldilocksConfig},
+    };
+    use rand::Rng;
+
+    type C = PoseidonGoldilocksConfig;
+    type F = <C as GenericConfig<2>>::F;
+    type H = PoseidonHash;
+
+    #[test]
+    fn compact_smt_test() {
+        let mut rng = rand::thread_rng();
+        let height = 64;
+        let to_path =
+            |index: u64| -> Vec<bool> { (0..64).rev().map(|i| (index >> i) & 1 == 1).collect() };
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let mut compact = CompactSmt::<F, H>::new(height);
+        assert_eq!(compact.get_root(), tree.get_root());
+        let mut indices = vec![0, u64::MAX];
+        indices.extend((0..6).map(|_| rng.gen::<u64>()));
+        for &index in &indices {
+            let value = F::rand_vec(4);
+            tree.update(&to_path(index), value.clone());
+            compact.update(index, value);
+        }
+        assert_eq!(compact.len(), 8);
+        assert_eq!(compact.get_root(), tree.get_root());
+
+        let root = compact.get_root();
+        for index in [indices[3], indices[1], rng.gen::<u64>()] {
+            let proof = compact.prove(index);
+            assert_eq!(proof.siblings, tree.prove(&to_path(index)).siblings);
+            let value = tree.get_leaf(&to_path(index));
+            assert!(compact.verify(index, &value, root, &proof));
+        }
+
+        assert!(compact.delete(indices[2]).is_some());
+        tree.delete(to_path(indices[2]));
+        assert_eq!(compact.get_root(), tree.get_root());
+    }
+}
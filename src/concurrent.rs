@@ -0,0 +1,89 @@
+use crate::{
+    error::SmtResult,
+    node_map::MerkleNodeMap,
+    path::Path,
+    sparse_merkle_tree::{LeafValue, Node, SparseMerkleTree},
+};
+use plonky2::{
+    hash::{hash_types::RichField, merkle_proofs::MerkleProof},
+    plonk::config::Hasher,
+};
+use std::{
+    collections::HashMap,
+    sync::{RwLock, RwLockReadGuard},
+};
+
+/// A [`SparseMerkleTree`] that can be shared between threads, e.g. in an `Arc`. Reads such as
+/// proving take a shared lock and run in parallel, since reading a tree never mutates it; each
+/// write takes the lock exclusively for the duration of the update.
+///
+/// For reads that shouldn't hold up writers at all, [`Self::snapshot`] clones the tree, which
+/// shares its nodes until the next write copies them.
+#[derive(Debug)]
+pub struct ConcurrentSparseMerkleTree<
+    F: RichField,
+    H: Hasher<F>,
+    const A: usize = 2,
+    L: LeafValue<F> = Vec<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>> = HashMap<Path, Node<F, H, A, L>>,
+> {
+    tree: RwLock<SparseMerkleTree<F, H, A, L, M>>,
+}
+
+impl<F, H, const A: usize, L, M> ConcurrentSparseMerkleTree<F, H, A, L, M>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>>,
+{
+    pub fn new(tree: SparseMerkleTree<F, H, A, L, M>) -> Self {
+        Self {
+            tree: RwLock::new(tree),
+        }
+    }
+
+    /// Locks the tree for reading, for several reads that must see the same state.
+    pub fn read(&self) -> RwLockReadGuard<'_, SparseMerkleTree<F, H, A, L, M>> {
+        self.tree.read().unwrap()
+    }
+
+    pub fn get_root(&self) -> H::Hash {
+        self.read().get_root()
+    }
+
+    pub fn get_leaf(&self, path: &Vec<bool>) -> SmtResult<Option<L>> {
+        self.read().try_get_leaf(path)
+    }
+
+    pub fn prove(&self, path: &Vec<bool>) -> SmtResult<MerkleProof<F, H>> {
+        self.read().try_prove(path)
+    }
+
+    /// Proves `path` together with the root the proof is against, read under one lock.
+    pub fn prove_with_root(&self, path: &Vec<bool>) -> SmtResult<(MerkleProof<F, H>, H::Hash)> {
+        let tree = self.read();
+        Ok((tree.try_prove(path)?, tree.get_root()))
+    }
+
+    pub fn update(&self, path: &Vec<bool>, value: L) -> SmtResult<()> {
+        self.tree.write().unwrap().try_update(path, value)
+    }
+
+    pub fn update_batch(&self, entries: &[(Vec<bool>, L)]) -> SmtResult<()> {
+        self.tree.write().unwrap().try_update_batch(entries)
+    }
+
+    pub fn delete(&self, path: &Vec<bool>) -> SmtResult<()> {
+        self.tree.write().unwrap().try_delete(path)
+    }
+
+    /// A copy of the current tree that further writes don't affect.
+    pub fn snapshot(&self) -> SparseMerkleTree<F, H, A, L, M> {
+        self.read().clone()
+    }
+
+    pub fn into_inner(self) -> SparseMerkleTree<F, H, A, L, M> {
+        self.tree.into_inner().unwrap()
+    }
+}
@@ -0,0 +1,140 @@
+use std::fmt;
+
+/// Errors returned by the fallible operations of the sparse Merkle tree.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SmtError {
+    /// A path of length `len` was given where a leaf of a tree of height `height` was expected.
+    InvalidPath { len: usize, height: usize },
+    /// The requested level is deeper than the tree or does not fall on a node boundary.
+    InvalidLevel { level: usize, height: usize },
+    /// A proof for the leaf at `path` doesn't verify against the expected root.
+    InvalidProof { path: Vec<bool> },
+    /// The data supplied to rebuild or check a tree doesn't hash to the expected root.
+    RootMismatch,
+    /// The node stored at `path` is misplaced or doesn't match the hashes of its children.
+    CorruptNode { path: Vec<bool> },
+    /// Serialized data is truncated or holds out-of-range values.
+    InvalidEncoding,
+    /// The leaf at `path` was expected to be empty but is set.
+    LeafNotEmpty { path: Vec<bool> },
+    /// A supplied zero-hash table has the wrong length or its entry for `level` doesn't hash
+    /// from the level below.
+    InvalidZeroHashes { level: usize },
+    /// The leaf validator of the tree rejected the value given for the leaf at `path`.
+    InvalidLeaf { path: Vec<bool> },
+    /// A cap of `2^cap_height` node hashes was requested with `cap_height` above
+    /// [`crate::sparse_merkle_tree::SparseMerkleTree::MAX_CAP_HEIGHT`].
+    CapTooLarge { cap_height: usize },
+    /// The leaf at `path` has been frozen and can no longer be updated or deleted.
+    LeafFrozen { path: Vec<bool> },
+    /// A proof needs the hash of the node at `path`, which lies under a subtree a partial tree
+    /// only knows by its digest.
+    MissingSibling { path: Vec<bool> },
+    /// A key read as a big-endian integer needs more than the `height` bits of a leaf path.
+    KeyOutOfRange { height: usize },
+    /// The value of the leaf at `path` is needed, but the leaf was set with
+    /// [`crate::sparse_merkle_tree::SparseMerkleTree::update_hash`] and only its hash is known.
+    LeafHashOnly { path: Vec<bool> },
+}
+
+impl fmt::Display for SmtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SmtError::InvalidPath { len, height } => {
+                write!(f, "path of length {len} for a tree of height {height}")
+            }
+            SmtError::InvalidLevel { level, height } => {
+                write!(f, "invalid level {level} for a tree of height {height}")
+            }
+            SmtError::InvalidProof { path } => {
+                write!(f, "invalid proof for path {}", bit_string(path))
+            }
+            SmtError::RootMismatch => write!(f, "root mismatch"),
+            SmtError::CorruptNode { path } => {
+                write!(f, "corrupt node at path {}", bit_string(path))
+            }
+            SmtError::InvalidEncoding => write!(f, "invalid encoding"),
+            SmtError::LeafNotEmpty { path } => write!(f, "leaf {} is set", bit_string(path)),
+            SmtError::InvalidZeroHashes { level } => {
+                write!(f, "invalid zero hash table at level {level}")
+            }
+            SmtError::InvalidLeaf { path } => {
+                write!(f, "invalid value for leaf {}", bit_string(path))
+            }
+            SmtError::CapTooLarge { cap_height } => {
+                write!(f, "cap height {cap_height} is too large")
+            }
+            SmtError::LeafFrozen { path } => write!(f, "leaf {} is frozen", bit_string(path)),
+            SmtError::MissingSibling { path } => {
+                write!(f, "hash of node {} is unknown", bit_string(path))
+            }
+            SmtError::KeyOutOfRange { height } => {
+                write!(f, "key doesn't fit in a path of height {height}")
+            }
+            SmtError::LeafHashOnly { path } => {
+                write!(f, "leaf {} is only known by its hash", bit_string(path))
+            }
+        }
+    }
+}
+
+fn bit_string(path: &[bool]) -> String {
+    path.iter().map(|&b| if b { '1' } else { '0' }).collect()
+}
+
+impl std::error::Error for SmtError {}
+
+impl SmtError {
+    /// Emits a warning for errors caused by misuse that the tree recovers from, when the `log`
+    /// feature is enabled.
+    pub(crate) fn report(self) -> Self {
+        #[cfg(feature = "log")]
+        log::warn!("{self}");
+        self
+    }
+}
+
+pub type SmtResult<T> = Result<T, SmtError>;
+
+/// A problem found by [`crate::sparse_merkle_tree::SparseMerkleTree::check_integrity`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum IntegrityError {
+    /// The node at `path` is a leaf above the bottom of the tree, an inner or filled node at the
+    /// bottom, or a node below a filled one.
+    MisplacedNode { path: Vec<bool> },
+    /// The inner node at `path` holds a hash for its child `child` that isn't the hash of the
+    /// node stored there, or the zero hash if none is.
+    ChildHashMismatch { path: Vec<bool>, child: usize },
+    /// The filled node at `path` holds hashes that aren't those of a subtree full of its value.
+    FilledHashMismatch { path: Vec<bool> },
+}
+
+impl IntegrityError {
+    pub fn path(&self) -> &[bool] {
+        match self {
+            IntegrityError::MisplacedNode { path } => path,
+            IntegrityError::ChildHashMismatch { path, .. } => path,
+            IntegrityError::FilledHashMismatch { path } => path,
+        }
+    }
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::MisplacedNode { path } => {
+                write!(f, "misplaced node at path {}", bit_string(path))
+            }
+            IntegrityError::ChildHashMismatch { path, child } => write!(
+                f,
+                "stale hash of child {child} of node {}",
+                bit_string(path)
+            ),
+            IntegrityError::FilledHashMismatch { path } => {
+                write!(f, "stale hashes of filled node {}", bit_string(path))
+            }
+        }
+    }
+}
+
+impl std::error::Error for IntegrityError {}
@@ -0,0 +1,295 @@
+use crate::{
+    error::{SmtError, SmtResult},
+    sparse_merkle_tree::SparseMerkleTree,
+};
+use plonky2::{
+    hash::{hash_types::RichField, merkle_proofs::MerkleProof},
+    plonk::config::Hasher,
+};
+use std::collections::BTreeMap;
+
+/// A leaf of an [`IndexedMerkleTree`]: a value and a link to the leaf holding the next larger
+/// value, or zeros if there is none. It is hashed as the four elements
+/// `[value, next_index, next_value, 1]`, where the trailing 1 keeps every appended leaf, the
+/// sentinel included, from hashing like an empty slot.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct IndexedLeaf {
+    pub value: u64,
+    pub next_index: u64,
+    pub next_value: u64,
+}
+
+impl IndexedLeaf {
+    pub fn elements<F: RichField>(&self) -> Vec<F> {
+        [self.value, self.next_index, self.next_value, 1]
+            .into_iter()
+            .map(F::from_canonical_u64)
+            .collect()
+    }
+
+    /// Whether `value` lies strictly between this leaf's value and the next one, i.e. would be
+    /// linked in right after this leaf.
+    pub fn covers(&self, value: u64) -> bool {
+        self.value < value && (self.next_value == 0 || value < self.next_value)
+    }
+}
+
+/// Shows that a value isn't in an [`IndexedMerkleTree`] with a single membership proof: the leaf
+/// with the largest smaller value links past it. See [`IndexedMerkleTree::prove_exclusion`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ExclusionProof<F: RichField, H: Hasher<F>> {
+    pub low_index: u64,
+    pub low_leaf: IndexedLeaf,
+    pub proof: MerkleProof<F, H>,
+}
+
+/// A set of values stored as a sorted linked list in the leaves of a binary tree, the "indexed"
+/// Merkle tree of Aztec-style rollups. Leaves are appended in insertion order and each one links
+/// to the leaf with the next larger value, so absence of a value is shown by proving the leaf
+/// that links past it, instead of an empty leaf at the bottom of a tree as deep as the values
+/// are wide.
+///
+/// Leaf 0 is a sentinel holding 0, so values range over `1..=MAX_VALUE`. Keeping them below
+/// `2^63` lets circuits compare them with a single range check, see
+/// `circuit::verify_indexed_exclusion_circuit`.
+#[derive(Clone, Debug)]
+pub struct IndexedMerkleTree<F: RichField, H: Hasher<F>> {
+    tree: SparseMerkleTree<F, H>,
+    /// The index of the leaf holding each value, the sentinel included.
+    indices: BTreeMap<u64, u64>,
+}
+
+impl<F: RichField, H: Hasher<F>> IndexedMerkleTree<F, H> {
+    pub const MAX_VALUE: u64 = (1 << 63) - 1;
+
+    /// Creates a tree with room for `2^height` leaves, the sentinel included.
+    pub fn new(height: usize) -> Self {
+        assert!((1..=64).contains(&height));
+        let mut tree = SparseMerkleTree::new(height);
+        tree.update(leaf_path(0, height), IndexedLeaf::default().elements());
+        Self {
+            tree,
+            indices: BTreeMap::from([(0, 0)]),
+        }
+    }
+
+    pub fn tree(&self) -> &SparseMerkleTree<F, H> {
+        &self.tree
+    }
+
+    pub fn get_root(&self) -> H::Hash {
+        self.tree.get_root()
+    }
+
+    /// Number of inserted values, not counting the sentinel.
+    pub fn len(&self) -> usize {
+        self.indices.len() - 1
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn contains(&self, value: u64) -> bool {
+        value != 0 && self.indices.contains_key(&value)
+    }
+
+    /// The leaf at `index`, which must have been appended.
+    pub fn leaf(&self, index: u64) -> IndexedLeaf {
+        let elements = self.tree.get_leaf(leaf_path(index, self.tree.height));
+        let [value, next_index, next_value] = [0, 1, 2].map(|i| elements[i].to_canonical_u64());
+        IndexedLeaf {
+            value,
+            next_index,
+            next_value,
+        }
+    }
+
+    /// Appends a leaf for `value` and links it in after the leaf with the next smaller value,
+    /// returning its index. Both leaves change in a single version of the underlying tree.
+    ///
+    /// Fails with [`SmtError::KeyOutOfRange`] if `value` is 0 or above [`Self::MAX_VALUE`], or
+    /// if the tree is full, and with [`SmtError::LeafNotEmpty`] if `value` is present.
+    pub fn insert(&mut self, value: u64) -> SmtResult<u64> {
+        let height = self.tree.height;
+        if value == 0 || value > Self::MAX_VALUE {
+            return Err(SmtError::KeyOutOfRange { height: 63 });
+        }
+        if let Some(&index) = self.indices.get(&value) {
+            return Err(SmtError::LeafNotEmpty {
+                path: leaf_path(index, height),
+            });
+        }
+        let index = self.indices.len() as u64;
+        if height < 64 && index >> height != 0 {
+            return Err(SmtError::KeyOutOfRange { height });
+        }
+        let (_, &low_index) = self.indices.range(..value).next_back().unwrap();
+        let mut low = self.leaf(low_index);
+        let leaf = IndexedLeaf {
+            value,
+            next_index: low.next_index,
+            next_value: low.next_value,
+        };
+        low.next_index = index;
+        low.next_value = value;
+        self.tree.try_update_batch(&[
+            (leaf_path(low_index, height), low.elements()),
+            (leaf_path(index, height), leaf.elements()),
+        ])?;
+        self.indices.insert(value, index);
+        Ok(index)
+    }
+
+    /// Returns the index of the leaf holding `value` and its proof, or `None` if it is absent.
+    pub fn prove_membership(&self, value: u64) -> Option<(u64, MerkleProof<F, H>)> {
+        if value == 0 {
+            return None;
+        }
+        let &index = self.indices.get(&value)?;
+        Some((index, self.tree.prove(leaf_path(index, self.tree.height))))
+    }
+
+    /// Proves that `value` is absent. Fails like [`Self::insert`] if `value` is out of range or
+    /// present.
+    pub fn prove_exclusion(&self, value: u64) -> SmtResult<ExclusionProof<F, H>> {
+        if value == 0 || value > Self::MAX_VALUE {
+            return Err(SmtError::KeyOutOfRange { height: 63 });
+        }
+        if let Some(&index) = self.indices.get(&value) {
+            return Err(SmtError::LeafNotEmpty {
+                path: leaf_path(index, self.tree.height),
+            });
+        }
+        let (_, &low_index) = self.indices.range(..value).next_back().unwrap();
+        Ok(ExclusionProof {
+            low_index,
+            low_leaf: self.leaf(low_index),
+            proof: self.tree.prove(leaf_path(low_index, self.tree.height)),
+        })
+    }
+
+    /// Checks that the leaf at `index` holding `leaf` is under `root`. Proofs with more than 64
+    /// siblings are rejected, since indices are `u64`s.
+    pub fn verify_membership(
+        root: H::Hash,
+        index: u64,
+        leaf: &IndexedLeaf,
+        proof: &MerkleProof<F, H>,
+    ) -> bool {
+        let height = proof.siblings.len();
+        height <= 64
+            && (height == 64 || index >> height == 0)
+            && SparseMerkleTree::<F, H>::verify_borrowed(
+                &leaf_path(index, height),
+                &leaf.elements::<F>(),
+                &proof.siblings,
+                root,
+            )
+    }
+
+    /// Checks that `proof` shows `value` absent from the tree with `root`. Only the sentinel at
+    /// index 0 may hold the value 0.
+    pub fn verify_exclusion(root: H::Hash, value: u64, proof: &ExclusionProof<F, H>) -> bool {
+        value <= Self::MAX_VALUE
+            && (proof.low_leaf.value != 0 || proof.low_index == 0)
+            && proof.low_leaf.covers(value)
+            && Self::verify_membership(root, proof.low_index, &proof.low_leaf, &proof.proof)
+    }
+}
+
+/// The path of the leaf at `index`, most significant bit first. `height` is at most 64.
+fn leaf_path(index: u64, height: usize) -> Vec<bool> {
+    debug_assert!(height <= 64);
+    (0..height).rev().map(|i| (index >> i) & 1 == 1).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_merkle_tree::tests::usize_to_vec;
+    use plonky2::{
+        hash::poseidon::PoseidonHash,
+        plonk::config::{GenericConfig, PoseidonGoldilocksConfig},
+    };
+
+    type C = PoseidonGoldilocksConfig;
+    type F = <C as GenericConfig<2>>::F;
+    type H = PoseidonHash;
+
+    #[test]
+    fn indexed_tree_test() {
+        type T = IndexedMerkleTree<F, H>;
+
+        let mut tree = T::new(4);
+        for value in [30, 10, 20, 50] {
+            tree.insert(value).unwrap();
+        }
+        assert_eq!(tree.len(), 4);
+        assert_eq!(tree.leaf(3).next_value, 30);
+        assert_eq!(tree.leaf(0).next_value, 10);
+        assert_eq!(tree.leaf(4).next_value, 0);
+        let root = tree.get_root();
+
+        for (value, low_value) in [(5, 0), (25, 20), (99, 50)] {
+            let proof = tree.prove_exclusion(value).unwrap();
+            assert_eq!(proof.low_leaf.value, low_value);
+            assert!(T::verify_exclusion(root, value, &proof));
+            assert!(!T::verify_exclusion(root, low_value, &proof));
+        }
+        let (index, proof) = tree.prove_membership(20).unwrap();
+        assert!(T::verify_membership(root, index, &tree.leaf(index), &proof));
+        assert!(matches!(
+            tree.prove_exclusion(20),
+            Err(SmtError::LeafNotEmpty { .. })
+        ));
+        assert!(matches!(
+            tree.insert(30),
+            Err(SmtError::LeafNotEmpty { .. })
+        ));
+        assert_eq!(tree.insert(0), Err(SmtError::KeyOutOfRange { height: 63 }));
+        assert_eq!(
+            tree.insert(1 << 63),
+            Err(SmtError::KeyOutOfRange { height: 63 })
+        );
+        assert_eq!(tree.get_root(), root);
+
+        // A low leaf that doesn't cover the value is rejected even with a valid proof.
+        let mut forged = tree.prove_exclusion(25).unwrap();
+        forged.low_leaf.next_value = 40;
+        assert!(!T::verify_exclusion(root, 35, &forged));
+
+        // An unused slot hashes like an empty leaf, which a zeroed low leaf must not pass for.
+        let forged = ExclusionProof {
+            low_index: 7,
+            low_leaf: IndexedLeaf::default(),
+            proof: tree.tree().prove(&usize_to_vec(7, 4)),
+        };
+        assert!(!T::verify_exclusion(root, 20, &forged));
+        let (_, proof) = tree.prove_membership(10).unwrap();
+        let forged = ExclusionProof {
+            low_index: 0,
+            low_leaf: IndexedLeaf::default(),
+            proof,
+        };
+        assert!(!T::verify_exclusion(root, 20, &forged));
+
+        // Proofs deeper than a `u64` index can address are rejected without shifting out of range.
+        let (index, mut proof) = tree.prove_membership(20).unwrap();
+        proof.siblings.resize(65, proof.siblings[0]);
+        assert!(!T::verify_membership(
+            root,
+            index,
+            &tree.leaf(index),
+            &proof
+        ));
+        let mut forged = tree.prove_exclusion(25).unwrap();
+        forged.proof.siblings.resize(70, forged.proof.siblings[0]);
+        assert!(!T::verify_exclusion(root, 25, &forged));
+
+        for value in 100..111 {
+            tree.insert(value).unwrap();
+        }
+        assert_eq!(tree.insert(200), Err(SmtError::KeyOutOfRange { height: 4 }));
+    }
+}
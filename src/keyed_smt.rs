@@ -0,0 +1,408 @@
+use crate::{
+    error::{SmtError, SmtResult},
+    path::Path,
+    sparse_merkle_tree::SparseMerkleTree,
+};
+use plonky2::{
+    hash::{hash_types::RichField, merkle_proofs::MerkleProof},
+    plonk::config::{GenericHashOut, Hasher},
+};
+use std::{collections::HashMap, iter};
+
+/// A binary sparse Merkle tree addressed by keys instead of bit paths. The encoding of each key
+/// from [`KeyBytes`] is hashed with `H`, and the top `height` bits of the digest become the
+/// key's path, so paths are the same on every platform and Rust version.
+///
+/// Two keys can land on the same path when `height` is small. The tree remembers which key owns
+/// each path and [`Self::insert`] fails with [`SmtError::LeafNotEmpty`] rather than letting the
+/// second key overwrite the first.
+#[derive(Clone, Debug)]
+pub struct KeyedSmt<K, F: RichField, H: Hasher<F>> {
+    tree: SparseMerkleTree<F, H>,
+    keys: HashMap<Vec<bool>, K>,
+}
+
+impl<K: KeyBytes + Eq + Clone, F: RichField, H: Hasher<F>> KeyedSmt<K, F, H> {
+    pub fn new(height: usize) -> Self {
+        assert!(height <= 8 * H::HASH_SIZE);
+        Self {
+            tree: SparseMerkleTree::new(height),
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn tree(&self) -> &SparseMerkleTree<F, H> {
+        &self.tree
+    }
+
+    /// The leaf path of `key`, whether or not it is set.
+    pub fn path_of(&self, key: &K) -> Vec<bool> {
+        let bytes = key.key_bytes();
+        // Seven bytes per element stay below the field order; the length keeps trailing zero
+        // bytes from being dropped.
+        let mut inputs = vec![F::from_canonical_usize(bytes.len())];
+        inputs.extend(bytes.chunks(7).map(|chunk| {
+            F::from_canonical_u64(chunk.iter().rev().fold(0, |acc, &b| (acc << 8) | b as u64))
+        }));
+        self.tree
+            .hash_ops
+            .hash_no_pad::<F, H>(&inputs)
+            .to_bytes()
+            .into_iter()
+            .flat_map(|byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+            .take(self.tree.height)
+            .collect()
+    }
+
+    /// Sets the value of `key`. Fails if another key already holds the same path.
+    pub fn insert(&mut self, key: K, value: Vec<F>) -> SmtResult<()> {
+        let path = self.path_of(&key);
+        if let Some(owner) = self.keys.get(&path) {
+            if *owner != key {
+                return Err(SmtError::LeafNotEmpty { path });
+            }
+        }
+        self.tree.try_update(&path, value)?;
+        self.keys.insert(path, key);
+        Ok(())
+    }
+
+    /// Removes `key`, returning its value if it was set.
+    pub fn remove(&mut self, key: &K) -> Option<Vec<F>> {
+        let path = self.path_of(key);
+        if self.keys.get(&path) != Some(key) {
+            return None;
+        }
+        let value = self.tree.get_leaf(&path);
+        self.tree.delete(&path);
+        self.keys.remove(&path);
+        Some(value)
+    }
+
+    pub fn get(&self, key: &K) -> Option<Vec<F>> {
+        let path = self.path_of(key);
+        (self.keys.get(&path) == Some(key)).then(|| self.tree.get_leaf(&path))
+    }
+
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+
+    pub fn get_root(&self) -> H::Hash {
+        self.tree.get_root()
+    }
+
+    /// Proves the leaf at the path of `key`. For an unset key this shows that the path holds the
+    /// empty value.
+    pub fn prove(&self, key: &K) -> MerkleProof<F, H> {
+        self.tree.prove(self.path_of(key))
+    }
+
+    /// Checks that `proof` shows `value` under `root` at the path of `key`.
+    pub fn verify(&self, key: &K, value: &[F], root: H::Hash, proof: &MerkleProof<F, H>) -> bool {
+        SparseMerkleTree::<F, H>::verify_borrowed(&self.path_of(key), value, &proof.siblings, root)
+    }
+}
+
+/// A key that is used as a leaf index directly, read as a big-endian integer.
+pub trait SmtKey {
+    /// The bits of the key, most significant first.
+    fn to_bits(&self) -> Vec<bool>;
+
+    /// The leaf path of height `height` whose index is the key. Fails with
+    /// [`SmtError::KeyOutOfRange`] if a set bit of the key doesn't fit.
+    fn to_path(&self, height: usize) -> SmtResult<Vec<bool>> {
+        let bits = self.to_bits();
+        let excess = bits.len().saturating_sub(height);
+        if bits[..excess].iter().any(|&b| b) {
+            return Err(SmtError::KeyOutOfRange { height });
+        }
+        let padding = height.saturating_sub(bits.len());
+        Ok(iter::repeat(false)
+            .take(padding)
+            .chain(bits[excess..].iter().copied())
+            .collect())
+    }
+}
+
+impl SmtKey for u64 {
+    fn to_bits(&self) -> Vec<bool> {
+        (0..64).rev().map(|i| (self >> i) & 1 == 1).collect()
+    }
+}
+
+impl SmtKey for u128 {
+    fn to_bits(&self) -> Vec<bool> {
+        (0..128).rev().map(|i| (self >> i) & 1 == 1).collect()
+    }
+}
+
+impl SmtKey for Path {
+    fn to_bits(&self) -> Vec<bool> {
+        Path::to_bits(self)
+    }
+}
+
+/// Bytes are read as a big-endian index, so a key shorter than the path leaves the leading bits
+/// clear.
+impl<const N: usize> SmtKey for [u8; N] {
+    fn to_bits(&self) -> Vec<bool> {
+        self.as_slice().to_bits()
+    }
+}
+
+impl SmtKey for Vec<u8> {
+    fn to_bits(&self) -> Vec<bool> {
+        self.as_slice().to_bits()
+    }
+}
+
+impl SmtKey for &[u8] {
+    fn to_bits(&self) -> Vec<bool> {
+        self.iter()
+            .flat_map(|&byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+            .collect()
+    }
+}
+
+/// A field element used as a key through its canonical value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FieldKey<F: RichField>(pub F);
+
+impl<F: RichField> SmtKey for FieldKey<F> {
+    fn to_bits(&self) -> Vec<bool> {
+        self.0.to_canonical_u64().to_bits()
+    }
+}
+
+/// A binary sparse Merkle tree addressed by [`SmtKey`]s. Unlike [`KeyedSmt`], keys aren't
+/// hashed: each key is the index of its leaf, so distinct keys never share a path and keys that
+/// don't fit in the tree are rejected.
+#[derive(Clone, Debug)]
+pub struct SmtKV<F: RichField, H: Hasher<F>> {
+    tree: SparseMerkleTree<F, H>,
+}
+
+impl<F: RichField, H: Hasher<F>> SmtKV<F, H> {
+    pub fn new(height: usize) -> Self {
+        Self {
+            tree: SparseMerkleTree::new(height),
+        }
+    }
+
+    pub fn tree(&self) -> &SparseMerkleTree<F, H> {
+        &self.tree
+    }
+
+    pub fn path_of(&self, key: &impl SmtKey) -> SmtResult<Vec<bool>> {
+        key.to_path(self.tree.height)
+    }
+
+    pub fn insert(&mut self, key: &impl SmtKey, value: Vec<F>) -> SmtResult<()> {
+        self.tree.try_update(self.path_of(key)?, value)
+    }
+
+    /// Removes `key`, returning its value if it was set.
+    pub fn remove(&mut self, key: &impl SmtKey) -> SmtResult<Option<Vec<F>>> {
+        let path = self.path_of(key)?;
+        let value = self.tree.try_get_leaf(&path)?;
+        if value.is_some() {
+            self.tree.try_delete(&path)?;
+        }
+        Ok(value)
+    }
+
+    pub fn get(&self, key: &impl SmtKey) -> SmtResult<Option<Vec<F>>> {
+        self.tree.try_get_leaf(self.path_of(key)?)
+    }
+
+    pub fn get_root(&self) -> H::Hash {
+        self.tree.get_root()
+    }
+
+    /// Proves the leaf of `key`, which for an unset key holds the empty value.
+    pub fn prove(&self, key: &impl SmtKey) -> SmtResult<MerkleProof<F, H>> {
+        self.tree.try_prove(self.path_of(key)?)
+    }
+
+    /// Checks that `proof` shows `value` under `root` at the leaf of `key`. A key that doesn't
+    /// fit in the tree has no leaf and never verifies.
+    pub fn verify(
+        &self,
+        key: &impl SmtKey,
+        value: &[F],
+        root: H::Hash,
+        proof: &MerkleProof<F, H>,
+    ) -> bool {
+        self.path_of(key).map_or(false, |path| {
+            SparseMerkleTree::<F, H>::verify_borrowed(&path, value, &proof.siblings, root)
+        })
+    }
+}
+
+/// A key of a [`KeyedSmt`], hashed to its path through an explicit byte encoding.
+pub trait KeyBytes {
+    /// The encoding of the key. Distinct keys of a type must encode differently.
+    fn key_bytes(&self) -> Vec<u8>;
+}
+
+impl KeyBytes for str {
+    fn key_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl KeyBytes for String {
+    fn key_bytes(&self) -> Vec<u8> {
+        self.as_bytes().to_vec()
+    }
+}
+
+impl KeyBytes for [u8] {
+    fn key_bytes(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+}
+
+impl KeyBytes for Vec<u8> {
+    fn key_bytes(&self) -> Vec<u8> {
+        self.clone()
+    }
+}
+
+impl<const N: usize> KeyBytes for [u8; N] {
+    fn key_bytes(&self) -> Vec<u8> {
+        self.to_vec()
+    }
+}
+
+impl<T: KeyBytes + ?Sized> KeyBytes for &T {
+    fn key_bytes(&self) -> Vec<u8> {
+        (**self).key_bytes()
+    }
+}
+
+/// Integers encode as their big-endian bytes.
+macro_rules! impl_key_bytes_for_int {
+    ($($t:ty),*) => {
+        $(impl KeyBytes for $t {
+            fn key_bytes(&self) -> Vec<u8> {
+                self.to_be_bytes().to_vec()
+            }
+        })*
+    };
+}
+
+impl_key_bytes_for_int!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sparse_merkle_tree::tests::usize_to_vec;
+    use plonky2::{
+        field::types::{Field, Sample},
+        hash::poseidon::PoseidonHash,
+        plonk::config::{GenericConfig, PoseidonGoldilocksConfig},
+    };
+
+    type C = PoseidonGoldilocksConfig;
+    type F = <C as GenericConfig<2>>::F;
+    type H = PoseidonHash;
+
+    #[test]
+    fn keyed_smt_test() {
+        let mut smt = KeyedSmt::<&str, F, H>::new(32);
+        let keys = ["alice", "bob", "carol"];
+        for key in keys {
+            smt.insert(key, F::rand_vec(4)).unwrap();
+        }
+        let value = F::rand_vec(4);
+        smt.insert("bob", value.clone()).unwrap();
+        assert_eq!(smt.len(), 3);
+        assert_eq!(smt.get(&"bob"), Some(value.clone()));
+        assert_eq!(smt.get(&"dave"), None);
+
+        let root = smt.get_root();
+        for key in keys {
+            let proof = smt.prove(&key);
+            assert!(smt.verify(&key, &smt.get(&key).unwrap(), root, &proof));
+        }
+        assert!(!smt.verify(&"alice", &value, root, &smt.prove(&"alice")));
+        assert_eq!(smt.remove(&"bob"), Some(value));
+        assert_eq!(smt.get(&"bob"), None);
+
+        // Paths come from the keys' byte encodings, which don't depend on the platform.
+        assert_eq!("alice".key_bytes(), b"alice");
+        assert_eq!(7u32.key_bytes(), [0, 0, 0, 7]);
+        let owned = KeyedSmt::<String, F, H>::new(32);
+        assert_eq!(owned.path_of(&"alice".to_string()), smt.path_of(&"alice"));
+
+        // With two leaves, some pair of three keys must collide; the second one is rejected and
+        // the first keeps its value.
+        let mut small = KeyedSmt::<u32, F, H>::new(1);
+        let mut rejected = 0;
+        for key in 0..3 {
+            match small.insert(key, vec![F::from_canonical_u32(key)]) {
+                Ok(()) => {}
+                Err(SmtError::LeafNotEmpty { path }) => {
+                    let owner = small.tree().get_leaf(&path);
+                    assert_ne!(owner, vec![F::from_canonical_u32(key)]);
+                    rejected += 1;
+                }
+                Err(err) => panic!("{err}"),
+            }
+        }
+        assert_eq!(small.len() + rejected, 3);
+        assert!(rejected >= 1);
+    }
+
+    #[test]
+    fn smt_kv_test() {
+        let height = 40;
+        let mut kv = SmtKV::<F, H>::new(height);
+        let value = F::rand_vec(4);
+        kv.insert(&5u64, value.clone()).unwrap();
+        assert_eq!(kv.get(&5u64), Ok(Some(value.clone())));
+        assert_eq!(
+            kv.get(&FieldKey(F::from_canonical_u64(5))),
+            Ok(Some(value.clone()))
+        );
+        let mut bytes = [0u8; 32];
+        bytes[31] = 5;
+        assert_eq!(kv.get(&bytes), Ok(Some(value.clone())));
+        assert_eq!(kv.path_of(&5u64).unwrap(), usize_to_vec(5, height));
+        assert_eq!(kv.get(&vec![0u8, 5]), Ok(Some(value.clone())));
+        assert_eq!(kv.get(&[5u8]), Ok(Some(value.clone())));
+
+        let root = kv.get_root();
+        let proof = kv.prove(&5u64).unwrap();
+        assert!(kv.verify(&5u64, &value, root, &proof));
+        assert!(!kv.verify(&6u64, &value, root, &proof));
+        assert_eq!(
+            kv.prove(&6u64).unwrap(),
+            kv.tree().prove(usize_to_vec(6, height))
+        );
+
+        let too_big = 1u64 << height;
+        assert_eq!(
+            kv.insert(&too_big, F::rand_vec(4)),
+            Err(SmtError::KeyOutOfRange { height })
+        );
+        bytes[0] = 1;
+        assert_eq!(kv.get(&bytes), Err(SmtError::KeyOutOfRange { height }));
+        assert!(!kv.verify(&too_big, &value, root, &proof));
+        assert_eq!(kv.get_root(), root);
+
+        assert_eq!(kv.remove(&5u64), Ok(Some(value)));
+        assert_eq!(kv.remove(&5u64), Ok(None));
+        assert_eq!(
+            kv.get_root(),
+            SparseMerkleTree::<F, H>::new(height).get_root()
+        );
+        assert_eq!(7u64.to_path(70).unwrap().len(), 70);
+    }
+}
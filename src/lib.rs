@@ -1 +1,13 @@
+pub mod byte_hasher;
+pub mod circuit;
+pub mod compact_smt;
+pub mod concurrent;
+pub mod error;
+pub mod indexed_tree;
+pub mod keyed_smt;
+pub mod node_map;
+pub mod path;
+#[cfg(feature = "serde")]
+pub mod serialization;
 pub mod sparse_merkle_tree;
+pub mod verifier;
@@ -0,0 +1,176 @@
+use crate::{
+    path::Path,
+    sparse_merkle_tree::{LeafValue, Node},
+};
+use plonky2::{hash::hash_types::RichField, plonk::config::Hasher};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::Arc,
+};
+
+/// The map from node paths to nodes that backs a sparse Merkle tree.
+///
+/// Implemented for `HashMap` (the default), `BTreeMap`, whose iteration order is deterministic,
+/// and [`OverlayNodeMap`], which layers writes over a shared map. Other maps, e.g.
+/// insertion-ordered or caching ones, can be plugged in by implementing this trait. Nodes are
+/// keyed by packed [`Path`]s, which are stored inline. Maps are cloned when a tree sharing them
+/// is first written to.
+pub trait MerkleNodeMap<V>: Clone + Default {
+    fn get(&self, path: &Path) -> Option<&V>;
+
+    fn insert(&mut self, path: Path, node: V) -> Option<V>;
+
+    fn remove(&mut self, path: &Path) -> Option<V>;
+
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Iterates over the stored nodes in no particular order.
+    fn iter(&self) -> Box<dyn Iterator<Item = (Path, &V)> + '_>;
+}
+
+/// A node store for a tree over `F` and `H` with arity `A` and leaves `L`, i.e. any
+/// [`MerkleNodeMap`] of its nodes. This is the bound to name when writing code generic over the
+/// storage backend.
+///
+/// Stores hand out nodes by reference, so a store backed by a database needs to keep the nodes
+/// it returns in memory, e.g. as a cache in front of the database that writes through on
+/// `insert` and `remove`. An existing store is reopened with `SparseMerkleTree::with_store`.
+pub trait NodeStore<F: RichField, H: Hasher<F>, const A: usize = 2, L: LeafValue<F> = Vec<F>>:
+    MerkleNodeMap<Node<F, H, A, L>>
+{
+}
+
+impl<F, H, const A: usize, L, M> NodeStore<F, H, A, L> for M
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>>,
+{
+}
+
+impl<V: Clone> MerkleNodeMap<V> for HashMap<Path, V> {
+    fn get(&self, path: &Path) -> Option<&V> {
+        HashMap::get(self, path)
+    }
+
+    fn insert(&mut self, path: Path, node: V) -> Option<V> {
+        HashMap::insert(self, path, node)
+    }
+
+    fn remove(&mut self, path: &Path) -> Option<V> {
+        HashMap::remove(self, path)
+    }
+
+    fn len(&self) -> usize {
+        HashMap::len(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Path, &V)> + '_> {
+        Box::new(HashMap::iter(self).map(|(path, node)| (*path, node)))
+    }
+}
+
+impl<V: Clone> MerkleNodeMap<V> for BTreeMap<Path, V> {
+    fn get(&self, path: &Path) -> Option<&V> {
+        BTreeMap::get(self, path)
+    }
+
+    fn insert(&mut self, path: Path, node: V) -> Option<V> {
+        BTreeMap::insert(self, path, node)
+    }
+
+    fn remove(&mut self, path: &Path) -> Option<V> {
+        BTreeMap::remove(self, path)
+    }
+
+    fn len(&self) -> usize {
+        BTreeMap::len(self)
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Path, &V)> + '_> {
+        Box::new(BTreeMap::iter(self).map(|(path, node)| (*path, node)))
+    }
+}
+
+/// A node map that keeps its writes apart from a base map it shares, so that a copy of a large
+/// tree can be written to without copying the base. Removed nodes are remembered as `None`.
+/// Backs the trees returned by `SparseMerkleTree::fork`.
+#[derive(Clone, Debug)]
+pub struct OverlayNodeMap<V, M> {
+    pub(crate) base: Arc<M>,
+    pub(crate) changes: HashMap<Path, Option<V>>,
+}
+
+impl<V, M> OverlayNodeMap<V, M> {
+    pub fn new(base: Arc<M>) -> Self {
+        Self {
+            base,
+            changes: HashMap::new(),
+        }
+    }
+
+    /// Number of nodes written or removed on top of the base.
+    pub fn num_changes(&self) -> usize {
+        self.changes.len()
+    }
+}
+
+impl<V, M: Default> Default for OverlayNodeMap<V, M> {
+    fn default() -> Self {
+        Self::new(Arc::new(M::default()))
+    }
+}
+
+impl<V: Clone, M: MerkleNodeMap<V>> MerkleNodeMap<V> for OverlayNodeMap<V, M> {
+    fn get(&self, path: &Path) -> Option<&V> {
+        match self.changes.get(path) {
+            Some(node) => node.as_ref(),
+            None => self.base.get(path),
+        }
+    }
+
+    fn insert(&mut self, path: Path, node: V) -> Option<V> {
+        let previous = self.get(&path).cloned();
+        self.changes.insert(path, Some(node));
+        previous
+    }
+
+    fn remove(&mut self, path: &Path) -> Option<V> {
+        let previous = self.get(path).cloned();
+        if previous.is_some() {
+            self.changes.insert(*path, None);
+        }
+        previous
+    }
+
+    fn len(&self) -> usize {
+        let (added, removed) =
+            self.changes
+                .iter()
+                .fold((0, 0), |(added, removed), (path, node)| {
+                    match (self.base.get(path).is_some(), node.is_some()) {
+                        (false, true) => (added + 1, removed),
+                        (true, false) => (added, removed + 1),
+                        _ => (added, removed),
+                    }
+                });
+        self.base.len() + added - removed
+    }
+
+    fn iter(&self) -> Box<dyn Iterator<Item = (Path, &V)> + '_> {
+        let written = self
+            .changes
+            .iter()
+            .filter_map(|(path, node)| Some((*path, node.as_ref()?)));
+        let kept = self
+            .base
+            .iter()
+            .filter(|(path, _)| !self.changes.contains_key(path));
+        Box::new(written.chain(kept))
+    }
+}
@@ -0,0 +1,238 @@
+use crate::error::{SmtError, SmtResult};
+use std::{fmt, ops::Index};
+
+/// A path of up to [`Path::MAX_LEN`] bits packed into machine words, most significant bit first.
+/// Unlike a `Vec<bool>` it is stored inline in 40 bytes whatever its length, and it orders the
+/// same way, so a path sorts right before its extensions. Trees key their nodes by it, and take
+/// anything convertible into it, such as a `Vec<bool>`, wherever a path is expected.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Path {
+    /// The bits from the top of the first word down, with the bits past `len` cleared.
+    words: [u64; 4],
+    len: u16,
+}
+
+impl Path {
+    pub const MAX_LEN: usize = 256;
+
+    /// The empty path, which leads to the root.
+    pub const fn new() -> Self {
+        Self {
+            words: [0; 4],
+            len: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn bit(&self, i: usize) -> bool {
+        assert!(i < self.len());
+        (self.words[i / 64] >> (63 - i % 64)) & 1 == 1
+    }
+
+    pub fn set(&mut self, i: usize, bit: bool) {
+        assert!(i < self.len());
+        let mask = 1 << (63 - i % 64);
+        if bit {
+            self.words[i / 64] |= mask;
+        } else {
+            self.words[i / 64] &= !mask;
+        }
+    }
+
+    /// Appends `bit`, panicking if the path is already [`Self::MAX_LEN`] bits long.
+    pub fn push(&mut self, bit: bool) {
+        let i = self.len();
+        assert!(
+            i < Self::MAX_LEN,
+            "paths are limited to {} bits",
+            Self::MAX_LEN
+        );
+        self.words[i / 64] |= (bit as u64) << (63 - i % 64);
+        self.len += 1;
+    }
+
+    pub fn pop(&mut self) -> Option<bool> {
+        let bit = self.last()?;
+        self.truncate(self.len() - 1);
+        Some(bit)
+    }
+
+    pub fn last(&self) -> Option<bool> {
+        (!self.is_empty()).then(|| self.bit(self.len() - 1))
+    }
+
+    /// Drops the bits from `len` on, if the path is longer.
+    pub fn truncate(&mut self, len: usize) {
+        if len >= self.len() {
+            return;
+        }
+        for (i, word) in self.words.iter_mut().enumerate() {
+            let kept = len.saturating_sub(64 * i);
+            if kept == 0 {
+                *word = 0;
+            } else if kept < 64 {
+                *word &= !(u64::MAX >> kept);
+            }
+        }
+        self.len = len as u16;
+    }
+
+    /// Truncates the path to `len` bits or pads it with `bit` up to them.
+    pub fn resize(&mut self, len: usize, bit: bool) {
+        self.truncate(len);
+        while self.len() < len {
+            self.push(bit);
+        }
+    }
+
+    /// The first `len` bits of the path.
+    pub fn prefix(&self, len: usize) -> Path {
+        assert!(len <= self.len());
+        let mut prefix = *self;
+        prefix.truncate(len);
+        prefix
+    }
+
+    /// The bits of the path from `start` on.
+    pub fn suffix(&self, start: usize) -> Path {
+        (start..self.len()).map(|i| self.bit(i)).collect()
+    }
+
+    pub fn starts_with(&self, prefix: &Path) -> bool {
+        prefix.len() <= self.len() && self.prefix(prefix.len()) == *prefix
+    }
+
+    /// Reads the `len` bits from `start` on as a big-endian number, e.g. the index of a child
+    /// within its parent.
+    pub fn index_at(&self, start: usize, len: usize) -> usize {
+        (start..start + len).fold(0, |acc, i| (acc << 1) | self.bit(i) as usize)
+    }
+
+    /// The path followed by the bits of `other`.
+    pub fn join(&self, other: &Path) -> Path {
+        let mut path = *self;
+        path.extend(other.bits());
+        path
+    }
+
+    pub fn bits(&self) -> impl DoubleEndedIterator<Item = bool> + ExactSizeIterator + '_ {
+        (0..self.len()).map(|i| self.bit(i))
+    }
+
+    pub fn to_bits(&self) -> Vec<bool> {
+        self.bits().collect()
+    }
+}
+
+impl Index<usize> for Path {
+    type Output = bool;
+
+    fn index(&self, i: usize) -> &bool {
+        if self.bit(i) {
+            &true
+        } else {
+            &false
+        }
+    }
+}
+
+impl Extend<bool> for Path {
+    fn extend<I: IntoIterator<Item = bool>>(&mut self, bits: I) {
+        for bit in bits {
+            self.push(bit);
+        }
+    }
+}
+
+impl FromIterator<bool> for Path {
+    fn from_iter<I: IntoIterator<Item = bool>>(bits: I) -> Self {
+        let mut path = Path::new();
+        path.extend(bits);
+        path
+    }
+}
+
+impl fmt::Debug for Path {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bits: String = self.bits().map(|b| if b { '1' } else { '0' }).collect();
+        write!(f, "Path({bits})")
+    }
+}
+
+/// Panics on more than [`Path::MAX_LEN`] bits, like the other conversions from bits.
+impl From<&[bool]> for Path {
+    fn from(bits: &[bool]) -> Self {
+        bits.iter().copied().collect()
+    }
+}
+
+impl<const N: usize> From<&[bool; N]> for Path {
+    fn from(bits: &[bool; N]) -> Self {
+        Path::from(bits.as_slice())
+    }
+}
+
+impl From<&Vec<bool>> for Path {
+    fn from(bits: &Vec<bool>) -> Self {
+        Path::from(bits.as_slice())
+    }
+}
+
+impl From<Vec<bool>> for Path {
+    fn from(bits: Vec<bool>) -> Self {
+        Path::from(bits.as_slice())
+    }
+}
+
+impl From<&Path> for Path {
+    fn from(path: &Path) -> Self {
+        *path
+    }
+}
+
+impl From<Path> for Vec<bool> {
+    fn from(path: Path) -> Self {
+        path.to_bits()
+    }
+}
+
+/// Packs `path` into bytes, most significant bit first, so a path of `8 * n` bits is the
+/// big-endian encoding of its leaf index in `n` bytes. A path whose length isn't a multiple of 8
+/// is padded with cleared low bits in its last byte.
+pub fn path_to_bytes(path: &[bool]) -> Vec<u8> {
+    path.chunks(8)
+        .map(|chunk| {
+            let byte = chunk.iter().fold(0u8, |acc, &b| (acc << 1) | b as u8);
+            byte << (8 - chunk.len())
+        })
+        .collect()
+}
+
+/// Unpacks a path of `len` bits from [`path_to_bytes`]. Fails with [`SmtError::InvalidEncoding`]
+/// unless `bytes` is exactly `ceil(len / 8)` bytes long with cleared padding, so that each path
+/// has a single encoding.
+pub fn path_from_bytes(bytes: &[u8], len: usize) -> SmtResult<Vec<bool>> {
+    if bytes.len() != (len + 7) / 8 {
+        return Err(SmtError::InvalidEncoding);
+    }
+    let padding = bytes.len() * 8 - len;
+    if bytes
+        .last()
+        .map_or(0, |&byte| byte & ((1u16 << padding) - 1) as u8)
+        != 0
+    {
+        return Err(SmtError::InvalidEncoding);
+    }
+    Ok(bytes
+        .iter()
+        .flat_map(|&byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+        .take(len)
+        .collect())
+}
@@ -0,0 +1,184 @@
+use crate::{
+    error::{SmtError, SmtResult},
+    node_map::MerkleNodeMap,
+    path::{path_from_bytes, path_to_bytes, Path},
+    sparse_merkle_tree::{LeafValue, Node, SparseMerkleTree},
+};
+use plonky2::{hash::hash_types::RichField, plonk::config::Hasher};
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+/// A path packed into bytes by [`path_to_bytes`], along with its length in bits. Paths are encoded this
+/// way wherever serde sees them, since a `Vec<bool>` would take a byte per bit in most formats.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CompactPath {
+    len: usize,
+    bytes: Vec<u8>,
+}
+
+impl CompactPath {
+    pub fn new(path: &[bool]) -> Self {
+        Self {
+            len: path.len(),
+            bytes: path_to_bytes(path),
+        }
+    }
+
+    /// Checks that `bytes` packs a path of `len` bits, with the unused low bits of the last byte
+    /// cleared so that each path has a single encoding. Fails with
+    /// [`SmtError::InvalidEncoding`] otherwise.
+    pub fn from_bytes(len: usize, bytes: Vec<u8>) -> SmtResult<Self> {
+        path_from_bytes(&bytes, len)?;
+        Ok(Self { len, bytes })
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn to_path(&self) -> Vec<bool> {
+        self.bytes
+            .iter()
+            .flat_map(|&byte| (0..8).rev().map(move |i| (byte >> i) & 1 == 1))
+            .take(self.len)
+            .collect()
+    }
+}
+
+impl Serialize for CompactPath {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        (self.len as u64, &self.bytes).serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for CompactPath {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let (len, bytes) = <(u64, Vec<u8>)>::deserialize(deserializer)?;
+        let len = usize::try_from(len).map_err(D::Error::custom)?;
+        Self::from_bytes(len, bytes).map_err(D::Error::custom)
+    }
+}
+
+/// Serializes a path as a [`CompactPath`], for use with `#[serde(with = ...)]`.
+pub mod compact_path {
+    use super::CompactPath;
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(path: &[bool], serializer: S) -> Result<S::Ok, S::Error> {
+        CompactPath::new(path).serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<bool>, D::Error> {
+        Ok(CompactPath::deserialize(deserializer)?.to_path())
+    }
+}
+
+/// Serializes a list of paths as [`CompactPath`]s, for use with `#[serde(with = ...)]`.
+pub mod compact_paths {
+    use super::CompactPath;
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(paths: &[Vec<bool>], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(paths.iter().map(|path| CompactPath::new(path)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Vec<Vec<bool>>, D::Error> {
+        let paths = Vec::<CompactPath>::deserialize(deserializer)?;
+        Ok(paths.iter().map(CompactPath::to_path).collect())
+    }
+}
+
+/// Serializes the children of an inner node as a sequence, since serde only implements its
+/// traits for arrays of fixed small lengths.
+pub(crate) mod hash_array {
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer, T: Serialize, const N: usize>(
+        array: &[T; N],
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(array)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>, T: Deserialize<'de>, const N: usize>(
+        deserializer: D,
+    ) -> Result<[T; N], D::Error> {
+        let items = Vec::<T>::deserialize(deserializer)?;
+        let len = items.len();
+        items
+            .try_into()
+            .map_err(|_| D::Error::invalid_length(len, &"one hash per child"))
+    }
+}
+
+/// The serialized form of a [`SparseMerkleTree`]: its height, whether it is level-tagged, its
+/// default leaf, its stored nodes sorted by path and its frozen leaves. Version history, the
+/// dirty set and other settings of the tree aren't kept. Deserializing checks the nodes with
+/// [`SparseMerkleTree::verify_integrity`].
+#[derive(Serialize, Deserialize)]
+#[serde(bound(serialize = "L: Serialize", deserialize = "L: Deserialize<'de>"))]
+pub struct TreeRepr<F: RichField, H: Hasher<F>, const A: usize, L: LeafValue<F>> {
+    height: u64,
+    level_tags: bool,
+    default_leaf: L,
+    nodes: Vec<(CompactPath, Node<F, H, A, L>)>,
+    #[serde(with = "compact_paths")]
+    frozen: Vec<Vec<bool>>,
+}
+
+impl<F, H, const A: usize, L, M> From<SparseMerkleTree<F, H, A, L, M>> for TreeRepr<F, H, A, L>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>>,
+{
+    fn from(tree: SparseMerkleTree<F, H, A, L, M>) -> Self {
+        let mut nodes: Vec<_> = tree.nodes.iter().collect();
+        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        Self {
+            height: tree.height as u64,
+            level_tags: tree.is_level_tagged(),
+            default_leaf: tree.default_leaf().clone(),
+            nodes: nodes
+                .into_iter()
+                .map(|(path, node)| (CompactPath::new(&path.to_bits()), node.clone()))
+                .collect(),
+            frozen: tree.frozen_leaves().iter().map(Path::to_bits).collect(),
+        }
+    }
+}
+
+impl<F, H, const A: usize, L, M> TryFrom<TreeRepr<F, H, A, L>> for SparseMerkleTree<F, H, A, L, M>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>>,
+{
+    type Error = SmtError;
+
+    fn try_from(repr: TreeRepr<F, H, A, L>) -> SmtResult<Self> {
+        let height = usize::try_from(repr.height).map_err(|_| SmtError::InvalidEncoding)?;
+        if height > Path::MAX_LEN || height % A.trailing_zeros() as usize != 0 {
+            return Err(SmtError::InvalidEncoding);
+        }
+        let mut tree = Self::build_empty(height, repr.level_tags, repr.default_leaf);
+        for path in &repr.frozen {
+            if path.len() != height {
+                return Err(SmtError::InvalidEncoding);
+            }
+            tree.freeze_leaf(path);
+        }
+        let nodes = repr
+            .nodes
+            .into_iter()
+            .map(|(path, node)| (path.to_path(), node));
+        Self::load_nodes(tree, nodes)
+    }
+}
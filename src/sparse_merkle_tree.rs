@@ -1,181 +1,8455 @@
+use crate::{
+    error::{IntegrityError, SmtError, SmtResult},
+    node_map::{MerkleNodeMap, OverlayNodeMap},
+    path::{path_from_bytes, path_to_bytes, Path},
+};
 use plonky2::{
-    hash::{hash_types::RichField, merkle_proofs::MerkleProof},
-    plonk::config::Hasher,
+    hash::{
+        hash_types::RichField,
+        merkle_proofs::MerkleProof,
+        merkle_tree::{MerkleCap, MerkleTree},
+    },
+    plonk::config::{GenericHashOut, Hasher},
 };
 
-use std::collections::HashMap;
+#[cfg(feature = "zeroize")]
+use zeroize::{Zeroize, ZeroizeOnDrop};
 
-#[derive(Debug)]
-pub struct SparseMerkleTree<F: RichField, H: Hasher<F>> {
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fmt::Debug,
+    marker::PhantomData,
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+};
+
+#[cfg(feature = "hash-stats")]
+use std::sync::atomic::{AtomicU64, Ordering};
+
+/// A sparse Merkle tree whose inner nodes have `A` children.
+///
+/// `A` must be a power of two; each level of the tree consumes `log2(A)` bits of a leaf path, so
+/// `height` (the number of path bits) must be a multiple of `log2(A)`. The default arity of 2 is
+/// the plain binary tree, whose proofs are compatible with plonky2's `verify_merkle_proof`.
+/// That function takes the leaf index as a `usize`, so for heights above 64, e.g. a 256-bit
+/// keyspace of hashed keys, verify with [`crate::verifier`] instead, which takes the path bits.
+/// Wide indices convert to paths through [`SmtKey`](crate::keyed_smt::SmtKey).
+/// Leaves are stored as `L`, see [`LeafValue`], and nodes are kept in the map `M`, keyed by
+/// their [`Path`], which bounds the height by [`Path::MAX_LEN`].
+///
+/// The node map is shared between clones and only copied on the first write to either of them,
+/// so cloning a tree to take a snapshot is cheap.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(
+        into = "crate::serialization::TreeRepr<F, H, A, L>",
+        try_from = "crate::serialization::TreeRepr<F, H, A, L>",
+        bound(
+            serialize = "L: serde::Serialize",
+            deserialize = "L: serde::Deserialize<'de>"
+        )
+    )
+)]
+pub struct SparseMerkleTree<
+    F: RichField,
+    H: Hasher<F>,
+    const A: usize = 2,
+    L: LeafValue<F> = Vec<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>> = HashMap<Path, Node<F, H, A, L>>,
+> {
     pub height: usize,
-    pub nodes: HashMap<Vec<bool>, Node<F, H>>,
+    pub nodes: Arc<M>,
     zero_hashes: Vec<H::Hash>,
+    /// The value of unset leaves, see [`Self::new_with_default_leaf`].
+    default_leaf: L,
+    /// Paths of the nodes written since the last `take_dirty` or `mark_clean`.
+    dirty: HashSet<Path>,
+    /// Number of updates applied so far.
+    version: u64,
+    /// For each of the last `history_limit` updates, oldest first, the hashes that the nodes it
+    /// overwrote had before the update.
+    history: VecDeque<HashMap<Path, H::Hash>>,
+    history_limit: usize,
+    /// Whether inner nodes hash their depth along with their children.
+    level_tags: bool,
+    /// Leaf paths that may no longer be updated or deleted.
+    frozen: HashSet<Path>,
+    /// The hashes of the stored nodes, kept up to date by [`Self::cache_node_hashes`].
+    hash_cache: Option<Arc<HashMap<Path, H::Hash>>>,
+    /// The cap height and node hashes kept up to date by [`Self::cache_cap`].
+    cap: Option<(usize, Vec<H::Hash>)>,
+    /// Checks values before they are written, see [`Self::set_leaf_validator`].
+    validator: Option<LeafValidator<F>>,
+    /// The writes made since recording was started, see [`Self::start_recording`].
+    log: Option<Vec<Op<F, H, L>>>,
+    /// The leaf writes made since the update log was started, see [`Self::start_update_log`].
+    update_log: Option<UpdateLog<F, H, L>>,
+    /// Whether every write is checked by proving the written leaf against the new root.
+    strict: bool,
+    /// Callbacks run when a write changes the root, see [`Self::on_root_change`]. Clones start
+    /// without any.
+    observers: RootObservers<H::Hash>,
+    /// What the writes since [`Self::with_rollback`] started overwrote, while it runs.
+    undo: Option<UndoJournal<F, H, A, L>>,
+    /// Hashes for the tree and counts the calls, see [`Self::hash_op_stats`].
+    pub(crate) hash_ops: HashOps,
+    /// Makes inner nodes record a wrong child hash, to exercise the strict mode.
+    #[cfg(test)]
+    corrupt_children: bool,
+    _leaf: PhantomData<L>,
 }
 
-impl<F: RichField, H: Hasher<F>> SparseMerkleTree<F, H> {
+/// A tree whose leaves are `W` field elements wide, stored inline without a heap allocation.
+pub type FixedLeafSparseMerkleTree<F, H, const W: usize, const A: usize = 2> =
+    SparseMerkleTree<F, H, A, [F; W]>;
+
+/// A tree branched off another by [`SparseMerkleTree::fork`], whose writes are kept apart from
+/// the nodes it shares with it.
+pub type ForkedSparseMerkleTree<F, H, const A: usize = 2, L = Vec<F>, M = NodeMap<F, H, A, L>> =
+    SparseMerkleTree<F, H, A, L, OverlayNodeMap<Node<F, H, A, L>, M>>;
+
+type NodeMap<F, H, const A: usize, L> = HashMap<Path, Node<F, H, A, L>>;
+
+impl<F, H, const A: usize, L, M> SparseMerkleTree<F, H, A, L, M>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>>,
+{
+    /// Number of path bits consumed by a single level of the tree.
+    const LEVEL_BITS: usize = A.trailing_zeros() as usize;
+
+    /// The most path bits the best-effort [`Self::verify_under_prefix`] searches through per
+    /// candidate proof.
+    pub const MAX_HIDDEN_BITS: usize = 16;
+
+    /// The largest cap height accepted by [`SparseMerkleTree::get_cap`] and
+    /// [`Self::cache_cap`], whose caps hold `2^cap_height` hashes.
+    pub const MAX_CAP_HEIGHT: usize = 20;
+
     pub fn new(height: usize) -> Self {
-        // zero_hashes = reverse([H(zero_leaf), H(H(zero_leaf), H(zero_leaf)), ...])
+        Self::with_level_tags(height, false)
+    }
+
+    /// Opens a tree without level tags over the nodes already held by `store`, e.g. one that
+    /// persists them. The nodes are trusted; call [`Self::verify_integrity`] to check them.
+    pub fn with_store(height: usize, store: M) -> Self {
+        let mut tree = Self::new(height);
+        tree.nodes = Arc::new(store);
+        tree
+    }
+
+    /// Creates a tree whose inner nodes are domain-separated by depth: each one hashes its
+    /// depth in levels below the root ahead of its children. Such trees have different roots
+    /// than untagged ones and their proofs only verify with [`Self::verify`] on a tagged tree.
+    pub fn new_level_tagged(height: usize) -> Self {
+        Self::with_level_tags(height, true)
+    }
+
+    /// Whether the tree was created by [`Self::new_level_tagged`].
+    pub fn is_level_tagged(&self) -> bool {
+        self.level_tags
+    }
+
+    /// Creates a tree whose unset leaves hold `default_leaf` rather than [`LeafValue::empty`],
+    /// e.g. an application's encoding of an empty account. Unset leaves read as it and the zero
+    /// hashes are those of subtrees full of it, so the root is as if every leaf were set to it.
+    ///
+    /// The associated verifiers that take no tree, such as [`Self::verify_non_membership`],
+    /// assume the standard empty leaf and don't apply to such trees.
+    pub fn new_with_default_leaf(height: usize, default_leaf: impl Into<L>) -> Self {
+        Self::build_empty(height, false, default_leaf.into())
+    }
+
+    fn with_level_tags(height: usize, level_tags: bool) -> Self {
+        Self::build_empty(height, level_tags, L::empty())
+    }
+
+    /// The value of unset leaves, [`LeafValue::empty`] unless set by
+    /// [`Self::new_with_default_leaf`].
+    pub fn default_leaf(&self) -> &L {
+        &self.default_leaf
+    }
+
+    pub(crate) fn build_empty(height: usize, level_tags: bool, default_leaf: L) -> Self {
+        assert!(
+            A >= 2 && A.is_power_of_two(),
+            "arity must be a power of two"
+        );
+        assert_eq!(height % Self::LEVEL_BITS, 0);
+        assert!(
+            height <= Path::MAX_LEN,
+            "heights are limited to {} bits",
+            Path::MAX_LEN
+        );
+        let levels = height / Self::LEVEL_BITS;
+        let tag = |depth: usize| level_tags.then_some(depth);
+        // zero_hashes = reverse([H(zero_leaf), H(H(zero_leaf), ..., H(zero_leaf)), ...])
+        let hash_ops = HashOps::default();
         let mut zero_hashes = vec![];
-        let node = Node::Leaf::<F, H> {
-            value: vec![F::ZERO; 4],
-        };
-        let mut h = node.hash();
+        let mut h = hash_ops.hash_or_noop::<F, H>(default_leaf.elements());
         zero_hashes.push(h);
-        for _ in 0..height {
-            let node = Node::InnerNode::<F, H> { left: h, right: h };
-            h = node.hash();
+        for depth in (0..levels).rev() {
+            let node = Node::InnerNode::<F, H, A, L> { children: [h; A] };
+            h = node.hash(&hash_ops, tag(depth));
             zero_hashes.push(h);
         }
         zero_hashes.reverse();
+        let mut tree = Self::from_zero_hashes(height, zero_hashes, level_tags);
+        tree.default_leaf = default_leaf;
+        tree.hash_ops = hash_ops;
+        tree
+    }
+
+    /// Creates an empty tree without level tags from a precomputed table of the hashes of
+    /// empty subtrees, indexed by level from the root down to the leaves, so that trees of the
+    /// same shape can share one table. The table is checked level by level; an entry that
+    /// doesn't hash from the one below, or a table of the wrong length, is rejected with
+    /// [`SmtError::InvalidZeroHashes`].
+    pub fn with_zero_hashes(height: usize, zero_hashes: Vec<H::Hash>) -> SmtResult<Self> {
+        assert_eq!(height % Self::LEVEL_BITS, 0);
+        let levels = height / Self::LEVEL_BITS;
+        if zero_hashes.len() != levels + 1 {
+            return Err(SmtError::InvalidZeroHashes {
+                level: zero_hashes.len().min(levels + 1),
+            });
+        }
+        if zero_hashes[levels] != H::hash_or_noop(L::empty().elements()) {
+            return Err(SmtError::InvalidZeroHashes { level: levels });
+        }
+        for level in 0..levels {
+            if zero_hashes[level] != hash_children::<F, H, A>(&[zero_hashes[level + 1]; A], None) {
+                return Err(SmtError::InvalidZeroHashes { level });
+            }
+        }
+        Ok(Self::from_zero_hashes(height, zero_hashes, false))
+    }
 
-        let nodes: HashMap<Vec<bool>, Node<F, H>> = HashMap::new();
+    /// The hashes of empty subtrees by level, as accepted by [`Self::with_zero_hashes`].
+    pub fn zero_hashes(&self) -> &[H::Hash] {
+        &self.zero_hashes
+    }
 
+    fn from_zero_hashes(height: usize, zero_hashes: Vec<H::Hash>, level_tags: bool) -> Self {
         Self {
             height,
-            nodes,
+            nodes: Arc::new(M::default()),
             zero_hashes,
+            default_leaf: L::empty(),
+            dirty: HashSet::new(),
+            version: 0,
+            history: VecDeque::new(),
+            history_limit: 0,
+            level_tags,
+            frozen: HashSet::new(),
+            hash_cache: None,
+            cap: None,
+            validator: None,
+            log: None,
+            update_log: None,
+            strict: false,
+            observers: RootObservers::default(),
+            undo: None,
+            hash_ops: HashOps::default(),
+            #[cfg(test)]
+            corrupt_children: false,
+            _leaf: PhantomData,
         }
     }
 
-    pub fn get_leaf(&self, path: &Vec<bool>) -> Vec<F> {
-        assert_eq!(path.len(), self.height);
-        match self.nodes.get(path) {
-            Some(Node::Leaf { value }) => value.clone(),
-            _ => vec![F::ZERO; 4],
+    /// Builds a partial tree from proofs of some of its leaves against `root`. The proven leaves
+    /// are stored along with their siblings, which are kept as [`Node::Digest`]s, so the tree can
+    /// be queried and updated at those leaves. Fails if any proof doesn't verify against `root`.
+    pub fn from_proofs(
+        height: usize,
+        root: H::Hash,
+        items: &[(Vec<bool>, L, MerkleProof<F, H>)],
+    ) -> SmtResult<Self> {
+        let mut tree = Self::new(height);
+        let nodes = Arc::make_mut(&mut tree.nodes);
+        if items.is_empty() && root != tree.zero_hashes[0] {
+            nodes.insert(Path::new(), Node::Digest { hash: root });
+        }
+        for (path, value, proof) in items {
+            if path.len() != height
+                || !Self::verify_borrowed(path, value.elements(), &proof.siblings, root)
+            {
+                return Err(SmtError::InvalidProof { path: path.clone() });
+            }
+            let mut path = Path::from(path);
+            nodes.insert(
+                path,
+                Node::Leaf {
+                    value: value.clone(),
+                },
+            );
+            let mut siblings = proof.siblings.iter();
+            while !path.is_empty() {
+                let parent_len = path.len() - Self::LEVEL_BITS;
+                let index = path.index_at(parent_len, Self::LEVEL_BITS);
+                for i in (0..A).filter(|&i| i != index) {
+                    path.truncate(parent_len);
+                    path.extend(index_to_bits(i, Self::LEVEL_BITS));
+                    let hash = *siblings.next().unwrap();
+                    if nodes.get(&path).is_none() {
+                        nodes.insert(path, Node::Digest { hash });
+                    }
+                }
+                path.truncate(parent_len);
+            }
         }
+        tree.rehash_ancestors(items.iter().map(|(path, _, _)| Path::from(path)));
+        Ok(tree)
     }
 
-    pub fn get_node_hash(&self, path: &Vec<bool>) -> H::Hash {
-        assert!(path.len() <= self.height);
-        match self.nodes.get(path) {
-            Some(node) => node.hash(),
-            None => self.zero_hashes[path.len()],
+    /// Inserts `count` pseudo-random leaves into an empty tree and records the index and value of
+    /// each along with the root after inserting it, for checking other implementations
+    /// against. Indices and values come from SplitMix64 seeded with `seed`: each index takes one
+    /// output reduced to `height` bits and each value the next four outputs reduced into `F`, so
+    /// the vectors depend only on `seed`. Indices may repeat, which overwrites the leaf.
+    pub fn test_vectors(height: usize, seed: u64, count: usize) -> Vec<(u64, Vec<F>, H::Hash)> {
+        assert!(height <= 64);
+        let mut state = seed;
+        let mut next = || {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^ (z >> 31)
+        };
+        let mut tree = Self::new(height);
+        (0..count)
+            .map(|_| {
+                let index = next().checked_shr(64 - height as u32).unwrap_or(0);
+                let value: Vec<F> = (0..4).map(|_| F::from_noncanonical_u64(next())).collect();
+                let path: Path = (0..height).rev().map(|i| (index >> i) & 1 == 1).collect();
+                tree.update(path, L::from_elements(&value));
+                (index, value, tree.get_root())
+            })
+            .collect()
+    }
+
+    /// Builds a tree without level tags from `leaves` bottom-up, hashing each inner node once
+    /// instead of once per leaf below it as repeated [`Self::update`]s would. Later entries for
+    /// the same path take precedence. The tree starts at version 0 with nothing marked dirty.
+    pub fn from_leaves(height: usize, leaves: impl IntoIterator<Item = (Vec<bool>, L)>) -> Self {
+        Self::build_from_leaves(height, leaves, |tree, groups| {
+            groups.iter().map(|group| tree.hash_group(group)).collect()
+        })
+    }
+
+    /// Stores `leaves` in a new tree, then stores the inner nodes above them level by level. Each
+    /// level is grouped by parent and `hash_level` turns every group into its parent's path,
+    /// children and hash.
+    #[allow(clippy::type_complexity)]
+    fn build_from_leaves(
+        height: usize,
+        leaves: impl IntoIterator<Item = (Vec<bool>, L)>,
+        hash_level: impl Fn(&Self, &[&[(Path, H::Hash)]]) -> Vec<(Path, [H::Hash; A], H::Hash)>,
+    ) -> Self {
+        let mut tree = Self::new(height);
+        let leaves: BTreeMap<Path, L> = leaves
+            .into_iter()
+            .inspect(|(path, _)| assert_eq!(path.len(), height))
+            .map(|(path, value)| (Path::from(path), value))
+            .collect();
+        let mut level: Vec<(Path, H::Hash)> = leaves
+            .iter()
+            .map(|(path, value)| (*path, tree.hash_ops.hash_or_noop::<F, H>(value.elements())))
+            .collect();
+        let nodes = Arc::make_mut(&mut tree.nodes);
+        for (path, value) in leaves {
+            nodes.insert(path, Node::Leaf { value });
+        }
+        while level.first().map_or(false, |(path, _)| !path.is_empty()) {
+            let parent_len = level[0].0.len() - Self::LEVEL_BITS;
+            let mut groups = vec![];
+            let mut rest = &level[..];
+            while let Some((first, _)) = rest.first() {
+                let end = rest.partition_point(|(path, _)| {
+                    path.prefix(parent_len) == first.prefix(parent_len)
+                });
+                groups.push(&rest[..end]);
+                rest = &rest[end..];
+            }
+            let parents = hash_level(&tree, &groups);
+            let nodes = Arc::make_mut(&mut tree.nodes);
+            level = parents
+                .into_iter()
+                .map(|(path, children, hash)| {
+                    nodes.insert(path, Node::InnerNode { children });
+                    (path, hash)
+                })
+                .collect();
         }
+        tree
     }
 
-    pub fn get_root(&self) -> H::Hash {
-        self.get_node_hash(&vec![])
+    /// The parent of `group`, a run of nodes sharing it, along with its children and hash.
+    fn hash_group(&self, group: &[(Path, H::Hash)]) -> (Path, [H::Hash; A], H::Hash) {
+        let parent_len = group[0].0.len() - Self::LEVEL_BITS;
+        let mut children = [self.zero_hashes[parent_len / Self::LEVEL_BITS + 1]; A];
+        for (path, hash) in group {
+            children[path.index_at(parent_len, Self::LEVEL_BITS)] = *hash;
+        }
+        let hash = self
+            .hash_ops
+            .hash_children::<F, H, A>(&children, self.level_tag(parent_len));
+        (group[0].0.prefix(parent_len), children, hash)
     }
 
-    pub fn get_sibling_hash(&self, path: &Vec<bool>) -> H::Hash {
-        assert!(path.len() > 0);
-        // TODO maybe more elegant code exists
-        let mut path = path.clone();
-        let last = path.len() - 1;
-        path[last] = !path[last];
-        self.get_node_hash(&path)
+    /// Recomputes the inner nodes above each of `paths` from their children, bypassing the dirty
+    /// set and version history. Used when assembling a tree from trusted parts.
+    fn rehash_ancestors(&mut self, paths: impl Iterator<Item = Path>) {
+        for mut path in paths {
+            while !path.is_empty() {
+                path.truncate(path.len() - Self::LEVEL_BITS);
+                let children = self.get_children_hashes(&path);
+                Arc::make_mut(&mut self.nodes).insert(path, Node::InnerNode { children });
+                self.refresh_caches(&path);
+            }
+        }
     }
 
-    pub fn update(&mut self, path: &Vec<bool>, value: Vec<F>) {
-        assert_eq!(path.len(), self.height);
-        let mut path = path.clone();
-
-        self.nodes.insert(path.clone(), Node::Leaf { value });
-
-        loop {
-            let hash = self.get_node_hash(&path);
-            let parent_path = path[0..path.len() - 1].to_vec();
-            self.nodes.insert(
-                parent_path,
-                if path[path.len() - 1] {
-                    Node::InnerNode {
-                        left: self.get_sibling_hash(&path),
-                        right: hash,
+    /// Returns the inner nodes whose hashes must be recomputed after writing the leaves at `paths`.
+    fn dirty_ancestors(&self, paths: impl Iterator<Item = Path>) -> HashSet<Path> {
+        let mut ancestors = HashSet::new();
+        for path in paths {
+            assert_eq!(path.len(), self.height);
+            for len in (0..self.height).step_by(Self::LEVEL_BITS) {
+                ancestors.insert(path.prefix(len));
+            }
+        }
+        ancestors
+    }
+
+    /// Computes the root the tree would have after applying `updates`, along with the number of
+    /// inner nodes that would be recomputed, without modifying the tree.
+    pub fn simulate_batch(&self, updates: &[(Vec<bool>, L)]) -> (H::Hash, usize) {
+        if updates.is_empty() {
+            return (self.get_root(), 0);
+        }
+        let mut ancestors: Vec<_> = self
+            .dirty_ancestors(updates.iter().map(|(path, _)| Path::from(path)))
+            .into_iter()
+            .collect();
+        ancestors.sort_by_key(|path| std::cmp::Reverse(path.len()));
+
+        let mut overlay: HashMap<Path, H::Hash> = updates
+            .iter()
+            .map(|(path, value)| {
+                (
+                    Path::from(path),
+                    self.hash_ops.hash_or_noop::<F, H>(value.elements()),
+                )
+            })
+            .collect();
+        for path in &ancestors {
+            let mut child = *path;
+            let children = core::array::from_fn(|i| {
+                child.truncate(path.len());
+                child.extend(index_to_bits(i, Self::LEVEL_BITS));
+                match overlay.get(&child) {
+                    Some(hash) => *hash,
+                    None => self.get_node_hash(child),
+                }
+            });
+            let hash = self
+                .hash_ops
+                .hash_children::<F, H, A>(&children, self.level_tag(path.len()));
+            overlay.insert(*path, hash);
+        }
+        (overlay[&Path::new()], ancestors.len())
+    }
+
+    /// Encodes the height, the default leaf and the set leaves, leaving out inner nodes since
+    /// they can be recomputed. Each leaf is its path packed into bytes, most significant bit
+    /// first, then a byte that is 0 if its value follows and 1 if only its hash follows, as
+    /// for leaves set with [`Self::update_hash`]; values are encoded as their length and
+    /// elements, and all integers are little-endian. Digests above the leaves aren't encoded,
+    /// so a partial tree from [`Self::from_proofs`] doesn't round-trip.
+    pub fn leaves_to_bytes(&self) -> Vec<u8> {
+        let mut leaves: Vec<_> = self.leaf_nodes().collect();
+        leaves.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut bytes = vec![];
+        bytes.extend((self.height as u64).to_le_bytes());
+        bytes.push(self.level_tags as u8);
+        let write_value = |bytes: &mut Vec<u8>, value: &L| {
+            bytes.extend((value.elements().len() as u32).to_le_bytes());
+            for x in value.elements() {
+                bytes.extend(x.to_canonical_u64().to_le_bytes());
+            }
+        };
+        write_value(&mut bytes, &self.default_leaf);
+        bytes.extend((leaves.len() as u64).to_le_bytes());
+        for (path, node) in &leaves {
+            bytes.extend(path_to_bytes(&path.to_bits()));
+            match node {
+                Node::Leaf { value } | Node::Filled { value, .. } => {
+                    bytes.push(0);
+                    write_value(&mut bytes, value);
+                }
+                Node::Digest { hash } => {
+                    bytes.push(1);
+                    bytes.extend(hash.to_bytes());
+                }
+                Node::InnerNode { .. } => unreachable!(),
+            }
+        }
+        bytes
+    }
+
+    /// Rebuilds a tree from the output of [`Self::leaves_to_bytes`]. Fails with
+    /// [`SmtError::InvalidEncoding`] on a height above [`Path::MAX_LEN`], a path with set
+    /// padding bits, or a leaf of the wrong width for the leaf type, see
+    /// [`LeafValue::try_from_elements`].
+    pub fn from_leaves_bytes(bytes: &[u8]) -> SmtResult<Self> {
+        fn take<'a>(rest: &mut &'a [u8], n: usize) -> SmtResult<&'a [u8]> {
+            if rest.len() < n {
+                return Err(SmtError::InvalidEncoding);
+            }
+            let (head, tail) = rest.split_at(n);
+            *rest = tail;
+            Ok(head)
+        }
+        fn read_u64(rest: &mut &[u8]) -> SmtResult<u64> {
+            Ok(u64::from_le_bytes(take(rest, 8)?.try_into().unwrap()))
+        }
+        fn read_value<F: RichField, L: LeafValue<F>>(rest: &mut &[u8]) -> SmtResult<L> {
+            let len = u32::from_le_bytes(take(rest, 4)?.try_into().unwrap()) as usize;
+            let elements = take(rest, len.checked_mul(8).ok_or(SmtError::InvalidEncoding)?)?
+                .chunks(8)
+                .map(|b| match u64::from_le_bytes(b.try_into().unwrap()) {
+                    x if x < F::ORDER => Ok(F::from_canonical_u64(x)),
+                    _ => Err(SmtError::InvalidEncoding),
+                })
+                .collect::<SmtResult<Vec<F>>>()?;
+            L::try_from_elements(&elements).ok_or(SmtError::InvalidEncoding)
+        }
+
+        let mut rest = bytes;
+        let height =
+            usize::try_from(read_u64(&mut rest)?).map_err(|_| SmtError::InvalidEncoding)?;
+        if height > Path::MAX_LEN || height % Self::LEVEL_BITS != 0 {
+            return Err(SmtError::InvalidEncoding);
+        }
+        let level_tags = match take(&mut rest, 1)?[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(SmtError::InvalidEncoding),
+        };
+        let default_leaf = read_value(&mut rest)?;
+        let mut tree = Self::build_empty(height, level_tags, default_leaf);
+        let count = read_u64(&mut rest)?;
+        let mut paths = vec![];
+        for _ in 0..count {
+            let path = Path::from(path_from_bytes(take(&mut rest, (height + 7) / 8)?, height)?);
+            let node = match take(&mut rest, 1)?[0] {
+                0 => Node::Leaf {
+                    value: read_value(&mut rest)?,
+                },
+                1 => Node::Digest {
+                    hash: H::Hash::from_bytes(take(&mut rest, H::HASH_SIZE)?),
+                },
+                _ => return Err(SmtError::InvalidEncoding),
+            };
+            Arc::make_mut(&mut tree.nodes).insert(path, node);
+            paths.push(path);
+        }
+        if !rest.is_empty() {
+            return Err(SmtError::InvalidEncoding);
+        }
+        tree.rehash_ancestors(paths.into_iter());
+        Ok(tree)
+    }
+
+    /// Copies the subtree under `prefix` into a tree of its own, of height
+    /// `height - prefix.len()`, whose root is `get_node_hash(prefix)`. Only for trees without
+    /// level tags, since tags depend on the depth in the outer tree.
+    pub fn extract_subtree(&self, prefix: impl Into<Path>) -> Self {
+        let prefix: &Path = &prefix.into();
+        assert!(
+            !self.level_tags,
+            "subtrees of level-tagged trees aren't supported"
+        );
+        assert!(prefix.len() <= self.height);
+        assert_eq!(prefix.len() % Self::LEVEL_BITS, 0);
+        let height = self.height - prefix.len();
+        let mut subtree = Self::build_empty(height, false, self.default_leaf.clone());
+        let nodes = Arc::make_mut(&mut subtree.nodes);
+        for (path, node) in self.nodes.iter() {
+            if path.starts_with(prefix) {
+                nodes.insert(path.suffix(prefix.len()), node.clone());
+            }
+        }
+        if let Some(Cow::Owned(root)) = self.node(prefix) {
+            nodes.insert(Path::new(), root);
+        }
+        subtree
+    }
+
+    /// Replaces the subtree under `prefix` with `subtree`, e.g. one obtained from
+    /// [`Self::extract_subtree`] and since modified, and recomputes the ancestors of `prefix`.
+    pub fn reinsert_subtree(&mut self, prefix: impl Into<Path>, subtree: &Self) {
+        let prefix: &Path = &prefix.into();
+        assert!(
+            !self.level_tags,
+            "subtrees of level-tagged trees aren't supported"
+        );
+        assert_eq!(prefix.len() + subtree.height, self.height);
+        assert_eq!(prefix.len() % Self::LEVEL_BITS, 0);
+        let logged = self.update_log.is_some().then(|| {
+            let old = self
+                .leaf_nodes()
+                .map(|(path, _)| path)
+                .filter(|path| path.starts_with(prefix));
+            let new = subtree.leaf_nodes().map(|(path, _)| prefix.join(&path));
+            self.logged_writes(old.chain(new), |path| {
+                subtree.logged_leaf(&path.suffix(prefix.len()))
+            })
+        });
+        let mut changes = HashMap::new();
+        self.unfill(prefix, &mut changes);
+        let stale: Vec<Path> = self
+            .nodes
+            .iter()
+            .map(|(path, _)| path)
+            .filter(|path| path.starts_with(prefix))
+            .collect();
+        for path in &stale {
+            self.remove_node(path, &mut changes);
+        }
+        for (path, node) in subtree.nodes.iter() {
+            self.write_node(&prefix.join(&path), node.clone(), &mut changes);
+        }
+
+        let mut path = *prefix;
+        while !path.is_empty() {
+            path.truncate(path.len() - Self::LEVEL_BITS);
+            let children = self.get_children_hashes(&path);
+            self.write_node(&path, Node::InnerNode { children }, &mut changes);
+        }
+        self.commit_version(changes);
+        self.record_updates(logged.into_iter().flatten());
+    }
+
+    /// Like [`Self::extract_subtree`], but fails with [`SmtError::InvalidLevel`] instead of
+    /// panicking if `prefix` doesn't end on a node of the tree. Still panics for level-tagged
+    /// trees.
+    pub fn get_subtree(&self, prefix: impl Into<Path>) -> SmtResult<Self> {
+        let prefix: &Path = &prefix.into();
+        self.check_prefix(prefix)?;
+        Ok(self.extract_subtree(prefix))
+    }
+
+    /// Grafts `subtree` under `prefix`, e.g. to recombine shards of a tree split with
+    /// [`Self::get_subtree`] and updated by different workers. Only nodes above `prefix` are
+    /// rehashed. Fails without changing the tree if `prefix` isn't where a tree of the height of
+    /// `subtree` fits ([`SmtError::InvalidLevel`]), if `subtree` doesn't share the zero hashes
+    /// of that part of the tree, e.g. because its default leaf differs
+    /// ([`SmtError::InvalidZeroHashes`]), or if a leaf under `prefix` is frozen.
+    pub fn insert_subtree(&mut self, prefix: impl Into<Path>, subtree: &Self) -> SmtResult<()> {
+        let prefix: &Path = &prefix.into();
+        self.check_prefix(prefix)?;
+        if prefix.len() + subtree.height != self.height {
+            return Err(SmtError::InvalidLevel {
+                level: prefix.len(),
+                height: self.height,
+            });
+        }
+        let depth = prefix.len() / Self::LEVEL_BITS;
+        if let Some(level) = (0..subtree.zero_hashes.len())
+            .find(|&level| subtree.zero_hashes[level] != self.zero_hashes[depth + level])
+        {
+            return Err(SmtError::InvalidZeroHashes { level });
+        }
+        if let Some(path) = self.frozen.iter().find(|path| path.starts_with(prefix)) {
+            return Err(SmtError::LeafFrozen {
+                path: path.to_bits(),
+            });
+        }
+        self.reinsert_subtree(prefix, subtree);
+        Ok(())
+    }
+
+    /// Checks that a cap of `2^cap_height` node hashes can be taken from the tree.
+    fn check_cap_height(&self, cap_height: usize) -> SmtResult<()> {
+        if cap_height > self.height || cap_height % Self::LEVEL_BITS != 0 {
+            return Err(SmtError::InvalidLevel {
+                level: cap_height,
+                height: self.height,
+            });
+        }
+        if cap_height > Self::MAX_CAP_HEIGHT {
+            return Err(SmtError::CapTooLarge { cap_height });
+        }
+        Ok(())
+    }
+
+    fn check_prefix(&self, prefix: &Path) -> SmtResult<()> {
+        if prefix.len() > self.height || prefix.len() % Self::LEVEL_BITS != 0 {
+            return Err(SmtError::InvalidLevel {
+                level: prefix.len(),
+                height: self.height,
+            });
+        }
+        Ok(())
+    }
+
+    /// Stores `node` at `path` as-is, without recomputing its ancestors or recording history.
+    ///
+    /// This is meant for reloading nodes from a trusted store and leaves the tree inconsistent
+    /// until every node has been inserted: a node that doesn't match its children yields wrong
+    /// roots and proofs. Call [`Self::verify_integrity`] once loading is done.
+    pub fn insert_raw_node(&mut self, path: impl Into<Path>, node: Node<F, H, A, L>) {
+        let path: &Path = &path.into();
+        assert!(path.len() <= self.height);
+        assert_eq!(path.len() % Self::LEVEL_BITS, 0);
+        Arc::make_mut(&mut self.nodes).insert(*path, node);
+        self.refresh_caches(path);
+    }
+
+    /// Builds a tree from its nodes as they are read, e.g. off a checkpoint on disk, without
+    /// collecting them first. Nodes are trusted while loading, as with [`Self::insert_raw_node`],
+    /// and the result is checked with [`Self::verify_integrity`] once the stream ends. A path
+    /// that can't hold a node, or a [`Node::Filled`] without a hash for each level from its path
+    /// down, is reported as [`SmtError::CorruptNode`].
+    pub fn from_node_stream(
+        height: usize,
+        nodes: impl IntoIterator<Item = (Vec<bool>, Node<F, H, A, L>)>,
+    ) -> SmtResult<Self> {
+        Self::load_nodes(Self::new(height), nodes)
+    }
+
+    /// Inserts `nodes` into the empty `tree` as [`Self::from_node_stream`] does.
+    pub(crate) fn load_nodes(
+        mut tree: Self,
+        nodes: impl IntoIterator<Item = (Vec<bool>, Node<F, H, A, L>)>,
+    ) -> SmtResult<Self> {
+        let height = tree.height;
+        for (path, node) in nodes {
+            if path.len() > height || path.len() % Self::LEVEL_BITS != 0 {
+                return Err(SmtError::CorruptNode { path });
+            }
+            if let Node::Filled { hashes, .. } = &node {
+                if hashes.len() != (height - path.len()) / Self::LEVEL_BITS + 1 {
+                    return Err(SmtError::CorruptNode { path });
+                }
+            }
+            tree.insert_raw_node(&path, node);
+        }
+        tree.verify_integrity()?;
+        Ok(tree)
+    }
+
+    /// Checks that every stored leaf sits at the bottom of the tree, every stored inner or filled
+    /// node above it with nothing stored below a filled one, that each inner node holds the
+    /// current hashes of its children and each filled node those of its value. Digests are
+    /// trusted as they are. Fails with [`SmtError::CorruptNode`] at the first offending path; see
+    /// [`Self::check_integrity`] for every problem found.
+    pub fn verify_integrity(&self) -> SmtResult<()> {
+        self.check_integrity()
+            .map_err(|errors| SmtError::CorruptNode {
+                path: errors[0].path().to_vec(),
+            })
+    }
+
+    /// Runs the checks of [`Self::verify_integrity`] over all stored nodes and returns every
+    /// problem found, ordered by path, e.g. to judge the damage to a tree read from untrusted
+    /// storage. Stale hashes can be repaired with [`Self::rebuild_internal_nodes`].
+    pub fn check_integrity(&self) -> Result<(), Vec<IntegrityError>> {
+        let mut paths: Vec<Path> = self.nodes.iter().map(|(path, _)| path).collect();
+        paths.sort();
+        let mut errors = vec![];
+        for path in paths {
+            match self.nodes.get(&path) {
+                Some(Node::Leaf { .. }) if path.len() != self.height => {
+                    errors.push(IntegrityError::MisplacedNode {
+                        path: path.to_bits(),
+                    });
+                }
+                Some(Node::InnerNode { .. })
+                    if path.len() >= self.height || path.len() % Self::LEVEL_BITS != 0 =>
+                {
+                    errors.push(IntegrityError::MisplacedNode {
+                        path: path.to_bits(),
+                    });
+                }
+                Some(Node::InnerNode { .. } | Node::Filled { .. })
+                    if path.len() >= self.height || path.len() % Self::LEVEL_BITS != 0 =>
+                {
+                    errors.push(IntegrityError::MisplacedNode {
+                        path: path.to_bits(),
+                    });
+                }
+                _ if self.below_filled(&path) => {
+                    errors.push(IntegrityError::MisplacedNode {
+                        path: path.to_bits(),
+                    });
+                }
+                Some(Node::InnerNode { children }) => {
+                    let actual = self.get_children_hashes(&path);
+                    for child in (0..A).filter(|&i| children[i] != actual[i]) {
+                        errors.push(IntegrityError::ChildHashMismatch {
+                            path: path.to_bits(),
+                            child,
+                        });
                     }
-                } else {
-                    Node::InnerNode {
-                        left: hash,
-                        right: self.get_sibling_hash(&path),
+                }
+                Some(Node::Filled { value, hashes }) => {
+                    if hashes[..] != self.uniform_hashes(value)[path.len() / Self::LEVEL_BITS..] {
+                        errors.push(IntegrityError::FilledHashMismatch {
+                            path: path.to_bits(),
+                        });
                     }
+                }
+                _ => {}
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Recomputes every inner node from the stored leaves, digests and filled nodes, dropping the
+    /// inner nodes and misplaced leaves that were stored before, so that
+    /// [`Self::check_integrity`] passes afterwards. Nodes under a digest or a filled node are
+    /// dropped as well, since those stand for the whole subtree. Like [`Self::insert_raw_node`] this bypasses the dirty set and version
+    /// history.
+    pub fn rebuild_internal_nodes(&mut self) {
+        let mut kept = vec![];
+        let mut dropped = vec![];
+        for (path, node) in self.nodes.iter() {
+            match node {
+                Node::Leaf { .. } if path.len() == self.height => kept.push(path),
+                Node::Filled { .. } if path.len() < self.height => kept.push(path),
+                Node::Digest { .. } => kept.push(path),
+                _ => dropped.push(path),
+            }
+        }
+        let digests: HashSet<Path> = kept
+            .iter()
+            .filter(|path| {
+                matches!(
+                    self.nodes.get(path),
+                    Some(Node::Digest { .. } | Node::Filled { .. })
+                )
+            })
+            .cloned()
+            .collect();
+        for path in &kept {
+            let covered = (0..path.len())
+                .step_by(Self::LEVEL_BITS)
+                .any(|len| digests.contains(&path.prefix(len)));
+            if covered {
+                dropped.push(*path);
+            }
+        }
+        for path in &dropped {
+            Arc::make_mut(&mut self.nodes).remove(path);
+            self.refresh_caches(path);
+        }
+
+        let mut ancestors: Vec<Path> = self
+            .nodes
+            .iter()
+            .flat_map(|(path, _)| {
+                (0..path.len())
+                    .step_by(Self::LEVEL_BITS)
+                    .map(move |len| path.prefix(len))
+            })
+            .collect::<HashSet<_>>()
+            .into_iter()
+            .collect();
+        ancestors.sort_by_key(|path| std::cmp::Reverse(path.len()));
+        for path in ancestors {
+            let children = self.get_children_hashes(&path);
+            Arc::make_mut(&mut self.nodes).insert(path, Node::InnerNode { children });
+            self.refresh_caches(&path);
+        }
+    }
+
+    /// Describes the effect of `updates` for a receiver that only knows the current root: the
+    /// old and new values of the touched leaves plus the non-empty siblings of their paths that
+    /// aren't themselves recomputed. Later updates to the same path take precedence. Fails with
+    /// [`SmtError::LeafHashOnly`] if a touched leaf is only known by its hash.
+    pub fn update_delta(&self, updates: &[(Vec<bool>, L)]) -> SmtResult<StateDelta<F, H, L>> {
+        let mut new_values: HashMap<Path, &L> = HashMap::new();
+        for (path, value) in updates {
+            new_values.insert(path.into(), value);
+        }
+        let on_path = self.dirty_ancestors(new_values.keys().copied());
+
+        let mut leaves = new_values
+            .into_iter()
+            .map(|(path, value)| Ok((path, self.known_leaf(&path)?, value.clone())))
+            .collect::<SmtResult<Vec<_>>>()?;
+        leaves.sort_by(|a, b| a.0.cmp(&b.0));
+
+        let mut siblings = vec![];
+        for parent in &on_path {
+            let mut child = *parent;
+            for i in 0..A {
+                child.truncate(parent.len());
+                child.extend(index_to_bits(i, Self::LEVEL_BITS));
+                let is_leaf = child.len() == self.height;
+                let recomputed = if is_leaf {
+                    leaves.binary_search_by(|l| l.0.cmp(&child)).is_ok()
+                } else {
+                    on_path.contains(&child)
+                };
+                if !recomputed && self.has_node(&child) {
+                    siblings.push((child.to_bits(), self.get_node_hash(child)));
+                }
+            }
+        }
+        siblings.sort_by(|a, b| a.0.cmp(&b.0));
+
+        Ok(StateDelta {
+            height: self.height,
+            leaves: leaves
+                .into_iter()
+                .map(|(path, old, new)| (path.to_bits(), old, new))
+                .collect(),
+            siblings,
+        })
+    }
+
+    /// Applies `updates` in order and records, for each, the leaf's old value and its proof
+    /// against the root just before that update, so that siblings changed by earlier updates in
+    /// the block are accounted for. See [`Self::verify_block`]. Fails with
+    /// [`SmtError::LeafHashOnly`], before applying any update, if a leaf is only known by its
+    /// hash when the block reaches it. Panics with level tags.
+    pub fn prove_block(&mut self, updates: &[(Vec<bool>, L)]) -> SmtResult<BlockProof<F, H, L>> {
+        assert!(!self.level_tags);
+        let mut written = HashSet::new();
+        for (path, _) in updates {
+            if written.insert(path) {
+                self.known_leaf(&path.into())?;
+            }
+        }
+        let old_root = self.get_root();
+        let steps = updates
+            .iter()
+            .map(|(path, value)| {
+                let old_value = self.get_leaf(path);
+                let (proof, _, _) = self.update_with_old_proof(path, value.clone());
+                (path.clone(), old_value, proof)
+            })
+            .collect();
+        Ok(BlockProof {
+            old_root,
+            new_root: self.get_root(),
+            steps,
+        })
+    }
+
+    /// Replays `updates` from `block.old_root` using the proofs in `block` and returns the
+    /// resulting root. Fails with [`SmtError::InvalidProof`] at the first update whose path
+    /// doesn't match the block or whose proof doesn't verify against the intermediate root,
+    /// and with [`SmtError::RootMismatch`] if the block doesn't end at `block.new_root`.
+    pub fn verify_block(
+        block: &BlockProof<F, H, L>,
+        updates: &[(Vec<bool>, L)],
+    ) -> SmtResult<H::Hash> {
+        if block.steps.len() != updates.len() {
+            return Err(SmtError::RootMismatch);
+        }
+        let mut root = block.old_root;
+        for ((path, old_value, proof), (update_path, new_value)) in block.steps.iter().zip(updates)
+        {
+            if path != update_path
+                || !Self::verify_borrowed(path, old_value.elements(), &proof.siblings, root)
+            {
+                return Err(SmtError::InvalidProof {
+                    path: update_path.clone(),
+                });
+            }
+            root = Self::fold_proof(path, new_value.elements(), &proof.siblings, false);
+        }
+        if root != block.new_root {
+            return Err(SmtError::RootMismatch);
+        }
+        Ok(root)
+    }
+
+    /// Checks `delta` against `old_root` and returns the root after applying it, using only
+    /// the data carried by the delta.
+    pub fn apply_delta(old_root: H::Hash, delta: &StateDelta<F, H, L>) -> SmtResult<H::Hash> {
+        let mut tree = Self::new(delta.height);
+        let nodes = Arc::make_mut(&mut tree.nodes);
+        for (path, hash) in &delta.siblings {
+            nodes.insert(path.into(), Node::Digest { hash: *hash });
+        }
+        for (path, old_value, _) in &delta.leaves {
+            nodes.insert(
+                path.into(),
+                Node::Leaf {
+                    value: old_value.clone(),
                 },
             );
-            if path.len() == 1 {
-                break;
-            } else {
-                path.pop();
-            }
         }
+        tree.rehash_ancestors(delta.leaves.iter().map(|(path, _, _)| Path::from(path)));
+        if tree.get_root() != old_root {
+            return Err(SmtError::RootMismatch);
+        }
+        for (path, _, new_value) in &delta.leaves {
+            tree.update(path, new_value.clone());
+        }
+        Ok(tree.get_root())
     }
 
-    pub fn prove(&self, path: &Vec<bool>) -> MerkleProof<F, H> {
+    /// Returns the value of the leaf at `path`, or [`Self::default_leaf`] if the leaf is unset.
+    /// Panics if only the leaf's hash is known, as after [`Self::update_hash`]; use
+    /// [`Self::try_get_leaf`] to get `None` for such a leaf instead.
+    pub fn get_leaf(&self, path: impl Into<Path>) -> L {
+        let path: &Path = &path.into();
+        self.get_leaf_or(path, self.default_leaf.clone())
+    }
+
+    /// Returns the value of the leaf at `path`, or `default` if the leaf is unset. Panics like
+    /// [`Self::get_leaf`] if only the leaf's hash is known.
+    pub fn get_leaf_or(&self, path: impl Into<Path>, default: L) -> L {
+        let path: &Path = &path.into();
         assert_eq!(path.len(), self.height);
-        let mut path = path.clone();
-        let mut siblings = vec![];
-        loop {
-            siblings.push(self.get_sibling_hash(&path));
-            if path.len() == 1 {
-                break;
-            } else {
-                path.pop();
+        match self.node(path).as_deref() {
+            Some(Node::Leaf { value }) => value.clone(),
+            Some(Node::Digest { .. }) => panic!("leaf {path:?} is only known by its hash"),
+            _ => default,
+        }
+    }
+
+    /// Returns the value of the leaf at `path` for an operation that needs it, failing with
+    /// [`SmtError::LeafHashOnly`] if only the leaf's hash is known.
+    fn known_leaf(&self, path: &Path) -> SmtResult<L> {
+        match self.nodes.get(path) {
+            Some(Node::Digest { .. }) => Err(SmtError::LeafHashOnly {
+                path: path.to_bits(),
+            }),
+            _ => Ok(self.get_leaf(path)),
+        }
+    }
+
+    /// Returns the value of the leaf at `path`, or [`Self::default_leaf`] if the leaf is unset.
+    pub fn get_leaf_or_empty(&self, path: impl Into<Path>) -> L {
+        let path: &Path = &path.into();
+        self.get_leaf_or(path, self.default_leaf.clone())
+    }
+
+    /// Returns the value of the leaf at `path`, or `None` if the leaf is unset or only its hash
+    /// is known, as after [`Self::update_hash`]. Unlike
+    /// [`Self::get_leaf`], a path of the wrong length is reported as [`SmtError::InvalidPath`]
+    /// instead of panicking, so paths from untrusted input can be passed as they are.
+    pub fn try_get_leaf(&self, path: impl Into<Path>) -> SmtResult<Option<L>> {
+        let path: &Path = &path.into();
+        self.check_leaf_path(path)?;
+        match self.node(path).as_deref() {
+            Some(Node::Leaf { value }) => Ok(Some(value.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    /// Returns the values of the leaves at `paths` in input order, with `None` for unset leaves.
+    pub fn get_leaves(&self, paths: &[Vec<bool>]) -> Vec<Option<L>> {
+        paths
+            .iter()
+            .map(|path| {
+                assert_eq!(path.len(), self.height);
+                match self.node(&path.into()).as_deref() {
+                    Some(Node::Leaf { value }) => Some(value.clone()),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    pub fn get_node_hash(&self, path: impl Into<Path>) -> H::Hash {
+        let path: &Path = &path.into();
+        assert!(path.len() <= self.height);
+        assert_eq!(path.len() % Self::LEVEL_BITS, 0);
+        if let Some(hashes) = &self.hash_cache {
+            if let Some(&hash) = hashes.get(path) {
+                return hash;
             }
+        } else if let Some(node) = self.nodes.get(path) {
+            return node.hash(&self.hash_ops, self.level_tag(path.len()));
+        }
+        match self.filled_ancestor(path) {
+            Some((_, _, hashes)) => hashes[0],
+            None => self.zero_hashes[path.len() / Self::LEVEL_BITS],
         }
-        MerkleProof { siblings }
     }
-}
 
-#[derive(Debug)]
-pub enum Node<F: RichField, H: Hasher<F>> {
-    InnerNode { left: H::Hash, right: H::Hash },
-    Leaf { value: Vec<F> },
-}
+    /// Like [`Self::get_node_hash`], but returns `None` instead of a zero hash when the node at
+    /// `path` is neither stored nor implied by a [`Node::Filled`] ancestor.
+    pub fn stored_node_hash(&self, path: impl Into<Path>) -> Option<H::Hash> {
+        let path: &Path = &path.into();
+        assert!(path.len() <= self.height);
+        match self.nodes.get(path) {
+            Some(node) => Some(node.hash(&self.hash_ops, self.level_tag(path.len()))),
+            None => self.filled_ancestor(path).map(|(_, _, hashes)| hashes[0]),
+        }
+    }
 
-impl<F: RichField, H: Hasher<F>> Node<F, H> {
-    fn hash(&self) -> H::Hash {
-        match self {
-            Node::InnerNode { left, right } => H::two_to_one(left.clone(), right.clone()),
-            Node::Leaf { value } => H::hash_or_noop(&value),
+    /// The nearest stored ancestor of `path` with its value and hashes from the level of `path`
+    /// down, if `path` isn't stored and that ancestor is a [`Node::Filled`] standing for it.
+    fn filled_ancestor(&self, path: &Path) -> Option<(Path, &L, &[H::Hash])> {
+        if self.nodes.get(path).is_some() {
+            return None;
+        }
+        let mut len = path.len();
+        while len > 0 {
+            len -= Self::LEVEL_BITS;
+            let ancestor = path.prefix(len);
+            match self.nodes.get(&ancestor) {
+                Some(Node::Filled { value, hashes }) => {
+                    let depth = (path.len() - len) / Self::LEVEL_BITS;
+                    return Some((ancestor, value, &hashes[depth..]));
+                }
+                Some(_) => return None,
+                None => {}
+            }
         }
+        None
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use plonky2::{
-        field::types::Sample,
-        hash::{merkle_proofs::verify_merkle_proof, poseidon::PoseidonHash},
-        plonk::config::{GenericConfig, PoseidonGoldilocksConfig},
-    };
-    use rand::Rng;
+    /// The node at `path`, whether stored or implied by a [`Node::Filled`] ancestor.
+    fn node(&self, path: &Path) -> Option<Cow<'_, Node<F, H, A, L>>> {
+        if let Some(node) = self.nodes.get(path) {
+            return Some(Cow::Borrowed(node));
+        }
+        let (_, value, hashes) = self.filled_ancestor(path)?;
+        let value = value.clone();
+        Some(Cow::Owned(if path.len() == self.height {
+            Node::Leaf { value }
+        } else {
+            Node::Filled {
+                value,
+                hashes: hashes.to_vec(),
+            }
+        }))
+    }
 
-    const D: usize = 2;
-    type C = PoseidonGoldilocksConfig;
-    type F = <C as GenericConfig<D>>::F;
-    type H = PoseidonHash;
+    /// Whether a [`Node::Filled`] is stored above `path`.
+    fn below_filled(&self, path: &Path) -> bool {
+        (0..path.len())
+            .step_by(Self::LEVEL_BITS)
+            .any(|len| matches!(self.nodes.get(&path.prefix(len)), Some(Node::Filled { .. })))
+    }
 
-    fn usize_to_vec(x: usize, length: usize) -> Vec<bool> {
-        let mut x = x;
-        let mut v = vec![];
-        for _ in 0..length {
-            v.push((x & 1) == 1);
-            x >>= 1;
+    /// Whether the node at `path` is stored or implied by a [`Node::Filled`] ancestor.
+    fn has_node(&self, path: &Path) -> bool {
+        self.nodes.get(path).is_some() || self.filled_ancestor(path).is_some()
+    }
+
+    /// The paths of length `len` below `path` that start with `prefix`, e.g. the leaves a
+    /// [`Node::Filled`] at `path` stands for.
+    fn paths_below(path: &Path, prefix: &Path, len: usize) -> impl Iterator<Item = Path> {
+        let base = if prefix.starts_with(path) {
+            Some(*prefix)
+        } else if path.starts_with(prefix) {
+            Some(*path)
+        } else {
+            None
+        };
+        let span = base.map_or(0, |base| len.saturating_sub(base.len()));
+        assert!(span < 128, "filled subtree too large to enumerate");
+        base.into_iter().flat_map(move |base| {
+            (0..1u128 << span).map(move |i| {
+                let mut leaf = base;
+                leaf.extend((0..span).rev().map(|j| (i >> j) & 1 == 1));
+                leaf
+            })
+        })
+    }
+
+    /// The stored nodes at the leaf level and the leaves implied by [`Node::Filled`] subtrees,
+    /// each paired with the node holding it, in the order of the node map.
+    fn leaf_nodes(&self) -> impl Iterator<Item = (Path, &Node<F, H, A, L>)> {
+        self.nodes.iter().flat_map(move |(path, node)| {
+            let (leaf, filled) = match node {
+                Node::Filled { .. } => {
+                    let leaves = Self::paths_below(&path, &Path::new(), self.height);
+                    (None, Some(leaves))
+                }
+                Node::InnerNode { .. } => (None, None),
+                _ => ((path.len() == self.height).then_some(path), None),
+            };
+            leaf.into_iter()
+                .chain(filled.into_iter().flatten())
+                .map(move |leaf| (leaf, node))
+        })
+    }
+
+    /// Splits the [`Node::Filled`] standing for `path`, if any, into the nodes it implies down
+    /// to `path`, so that `path` is stored and can be overwritten or removed on its own.
+    fn unfill(&mut self, path: &Path, changes: &mut HashMap<Path, H::Hash>) {
+        let (mut parent, value) = match self.filled_ancestor(path) {
+            Some((ancestor, value, _)) => (ancestor, value.clone()),
+            None => return,
+        };
+        while parent.len() < path.len() {
+            let hashes = match self.nodes.get(&parent) {
+                Some(Node::Filled { hashes, .. }) => hashes[1..].to_vec(),
+                _ => unreachable!(),
+            };
+            let children = [hashes[0]; A];
+            self.write_node(&parent, Node::InnerNode { children }, changes);
+            let mut child = parent;
+            for i in 0..A {
+                child.truncate(parent.len());
+                child.extend(index_to_bits(i, Self::LEVEL_BITS));
+                let value = value.clone();
+                let node = if child.len() == self.height {
+                    Node::Leaf { value }
+                } else {
+                    Node::Filled {
+                        value,
+                        hashes: hashes.clone(),
+                    }
+                };
+                self.write_node(&child, node, changes);
+            }
+            parent = path.prefix(parent.len() + Self::LEVEL_BITS);
         }
-        v.reverse();
-        v
     }
 
-    #[test]
-    fn tree_test() {
-        let mut rng = rand::thread_rng();
-        let height = 100;
-        let mut tree = SparseMerkleTree::<F, H>::new(height);
+    pub fn get_root(&self) -> H::Hash {
+        self.get_node_hash(Path::new())
+    }
 
-        for _ in 0..100 {
-            let index = rng.gen_range(0..1 << height);
-            let path = usize_to_vec(index, height);
-            let new_leaf = F::rand_vec(4);
-            tree.update(&path, new_leaf.clone());
-            let proof = tree.prove(&path);
-            assert_eq!(tree.get_leaf(&path), new_leaf.clone());
-            verify_merkle_proof(new_leaf, index, tree.get_root(), &proof).unwrap();
+    /// A 32-byte identifier of the root for systems that expect fixed-size roots. The root's field
+    /// elements are encoded as canonical little-endian `u64`s, then zero-padded to 32 bytes, or
+    /// XOR-folded in 32-byte chunks if longer. For `PoseidonHash` this is exactly the four
+    /// elements of the root.
+    pub fn root_digest(&self) -> [u8; 32] {
+        let mut digest = [0; 32];
+        let bytes = self
+            .get_root()
+            .to_vec()
+            .into_iter()
+            .flat_map(|x| x.to_canonical_u64().to_le_bytes());
+        for (i, byte) in bytes.enumerate() {
+            digest[i % 32] ^= byte;
         }
+        digest
+    }
 
-        for _ in 0..100 {
-            let index = rng.gen_range(0..1 << height);
-            let path = usize_to_vec(index, height);
-            let leaf = tree.get_leaf(&path);
-            let proof = tree.prove(&path);
-            verify_merkle_proof(leaf, index, tree.get_root(), &proof).unwrap();
+    /// Returns the path and hash of every non-empty subtree whose root lies `level` bits below the
+    /// root, sorted by path. Level 0 is the root itself and level `height` yields the set leaves.
+    pub fn level_nodes(&self, level: usize) -> SmtResult<Vec<(Path, H::Hash)>> {
+        if level > self.height || level % Self::LEVEL_BITS != 0 {
+            return Err(SmtError::InvalidLevel {
+                level,
+                height: self.height,
+            });
         }
+        let mut nodes: Vec<(Path, H::Hash)> = self
+            .nodes
+            .iter()
+            .flat_map(|(path, node)| match node {
+                Node::Filled { hashes, .. } if path.len() < level => {
+                    let hash = hashes[(level - path.len()) / Self::LEVEL_BITS];
+                    Self::paths_below(&path, &Path::new(), level)
+                        .map(|below| (below, hash))
+                        .collect()
+                }
+                _ if path.len() == level => {
+                    vec![(path, node.hash(&self.hash_ops, self.level_tag(level)))]
+                }
+                _ => vec![],
+            })
+            .collect();
+        nodes.sort_by(|a, b| a.0.cmp(&b.0));
+        Ok(nodes)
+    }
+
+    /// The domain tag of inner nodes `path_len` bits below the root, if the tree uses them.
+    fn level_tag(&self, path_len: usize) -> Option<usize> {
+        self.level_tags.then_some(path_len / Self::LEVEL_BITS)
+    }
+
+    /// Returns the hashes of the `A` children of the inner node at `path`, ordered by child index.
+    fn get_children_hashes(&self, path: &Path) -> [H::Hash; A] {
+        assert!(path.len() < self.height);
+        let mut child = *path;
+        child.resize(path.len() + Self::LEVEL_BITS, false);
+        core::array::from_fn(|i| {
+            child.truncate(path.len());
+            child.extend(index_to_bits(i, Self::LEVEL_BITS));
+            #[cfg(test)]
+            if self.corrupt_children {
+                return self.hash_ops.hash_or_noop::<F, H>(&[F::ONE]);
+            }
+            self.get_node_hash(child)
+        })
+    }
+
+    /// Returns the hashes of the `A - 1` siblings of the node at `path`, ordered by child index.
+    pub fn get_sibling_hashes(&self, path: impl Into<Path>) -> Vec<H::Hash> {
+        let path: &Path = &path.into();
+        assert!(!path.is_empty());
+        let parent_len = path.len() - Self::LEVEL_BITS;
+        let index = path.index_at(parent_len, Self::LEVEL_BITS);
+        let children = self.get_children_hashes(&path.prefix(parent_len));
+        children
+            .into_iter()
+            .enumerate()
+            .filter(|&(i, _)| i != index)
+            .map(|(_, h)| h)
+            .collect()
+    }
+
+    /// Sets the leaf at `path`. Accepts anything convertible into the leaf type, so a `Vec<F>`
+    /// tree can be fed arrays or `&[F]` as well as vectors.
+    ///
+    /// Panics if the leaf is frozen, see [`Self::try_update`].
+    pub fn update(&mut self, path: impl Into<Path>, value: impl Into<L>) {
+        let path: &Path = &path.into();
+        if let Err(err) = self.try_update(path, value) {
+            panic!("{err}");
+        }
+    }
+
+    /// Like [`Self::update`], but fails instead of panicking if `path` isn't a leaf path or the
+    /// leaf has been frozen. With the `log` feature, such failures are also logged as warnings.
+    pub fn try_update(&mut self, path: impl Into<Path>, value: impl Into<L>) -> SmtResult<()> {
+        let path: &Path = &path.into();
+        self.check_writable(path)?;
+        let value = value.into();
+        if let Some(validator) = &self.validator {
+            if !(validator.0)(value.elements()) {
+                return Err(SmtError::InvalidLeaf {
+                    path: path.to_bits(),
+                }
+                .report());
+            }
+        }
+        if let Some(log) = &mut self.log {
+            log.push(Op::Update {
+                path: path.to_bits(),
+                value: value.clone(),
+            });
+        }
+        let logged = self.update_log.is_some().then(|| {
+            let new = LoggedLeaf::Value(value.clone());
+            (path.to_bits(), self.logged_leaf(path), new)
+        });
+        self.write_leaf(path, Node::Leaf { value });
+        self.record_updates(logged);
+        Ok(())
+    }
+
+    /// Sets the leaf at `path` to a leaf with hash `leaf_hash` without storing its value, which
+    /// saves memory when only roots and proofs are needed. The leaf is kept as a
+    /// [`Node::Digest`], so [`Self::try_get_leaf`] and [`Self::get_leaves`] report it as `None`
+    /// and [`Self::get_leaf`] panics, but roots and proofs are as if the value were stored.
+    ///
+    /// Panics if the leaf is frozen, like [`Self::update`].
+    pub fn update_hash(&mut self, path: impl Into<Path>, leaf_hash: H::Hash) {
+        let path: &Path = &path.into();
+        if let Err(err) = self.check_writable(path) {
+            panic!("{err}");
+        }
+        if let Some(log) = &mut self.log {
+            log.push(Op::UpdateHash {
+                path: path.to_bits(),
+                hash: leaf_hash,
+            });
+        }
+        let logged = self.update_log.is_some().then(|| {
+            let new = LoggedLeaf::Hash(leaf_hash);
+            (path.to_bits(), self.logged_leaf(path), new)
+        });
+        self.write_leaf(path, Node::Digest { hash: leaf_hash });
+        self.record_updates(logged);
+    }
+
+    /// Sets the leaves in `entries` as a single version. All leaves are written first and each
+    /// inner node above them is then rehashed once, deepest first, instead of once per leaf as
+    /// with repeated [`Self::update`]s, so leaves sharing long prefixes save most of the hashing.
+    /// Later entries for the same path take precedence.
+    ///
+    /// Panics like [`Self::update`] if an entry is rejected, see [`Self::try_update_batch`].
+    pub fn update_batch(&mut self, entries: &[(Vec<bool>, L)]) {
+        if let Err(err) = self.try_update_batch(entries) {
+            panic!("{err}");
+        }
+    }
+
+    /// Like [`Self::update_batch`], but fails instead of panicking. All entries are checked as
+    /// in [`Self::try_update`] before any is written, so a failed batch leaves the tree as is.
+    pub fn try_update_batch(&mut self, entries: &[(Vec<bool>, L)]) -> SmtResult<()> {
+        for (path, value) in entries {
+            self.check_writable(&path.into())?;
+            if let Some(validator) = &self.validator {
+                if !(validator.0)(value.elements()) {
+                    return Err(SmtError::InvalidLeaf { path: path.clone() }.report());
+                }
+            }
+        }
+        if entries.is_empty() {
+            return Ok(());
+        }
+        if let Some(log) = &mut self.log {
+            log.extend(entries.iter().map(|(path, value)| Op::Update {
+                path: path.clone(),
+                value: value.clone(),
+            }));
+        }
+        let logged = self.update_log.is_some().then(|| {
+            let mut current = HashMap::new();
+            entries
+                .iter()
+                .map(|(path, value)| {
+                    let old = match current.insert(path, value.clone()) {
+                        Some(previous) => LoggedLeaf::Value(previous),
+                        None => self.logged_leaf(&path.into()),
+                    };
+                    (path.clone(), old, LoggedLeaf::Value(value.clone()))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let leaves: BTreeMap<Path, &L> = entries.iter().map(|(p, v)| (p.into(), v)).collect();
+        let mut changes = HashMap::new();
+        for (path, &value) in &leaves {
+            let node = Node::Leaf {
+                value: value.clone(),
+            };
+            self.write_node(path, node, &mut changes);
+        }
+        let mut ancestors: Vec<_> = self
+            .dirty_ancestors(leaves.keys().copied())
+            .into_iter()
+            .collect();
+        ancestors.sort_by_key(|path| std::cmp::Reverse(path.len()));
+        for path in &ancestors {
+            let children = self.get_children_hashes(path);
+            self.write_node(path, Node::InnerNode { children }, &mut changes);
+        }
+        self.commit_version(changes);
+        for path in leaves.keys() {
+            self.shadow_check(path);
+        }
+        self.record_updates(logged.into_iter().flatten());
+        Ok(())
+    }
+
+    /// Sets every leaf with an index in `start..end` to `value` as a single version. All leaves
+    /// of a subtree inside the range are the same, so its hashes form one chain computed up
+    /// front like the zero hashes, and the subtree is stored as a single [`Node::Filled`] in
+    /// place of whatever was stored under it. Only nodes on the edges of the range are hashed
+    /// from their children, so a fill stores `O(A * height)` nodes however long the range is. A
+    /// later write into a filled subtree splits it along the written path.
+    ///
+    /// Recording, the update log and the strict mode still go through every leaf of the range.
+    /// The nodes under a filled subtree aren't kept in the version history one by one, so
+    /// [`Self::prove_at_version`] returns `None` below it for versions before the fill.
+    ///
+    /// Panics like [`Self::update`] if a leaf in the range is frozen or `value` is rejected.
+    pub fn fill_range(&mut self, start: u64, end: u64, value: impl Into<L>) {
+        assert!(self.height <= 64);
+        assert!(start <= end && end as u128 <= 1u128 << self.height);
+        let index = |path: &Path| path.index_at(0, path.len()) as u64;
+        if let Some(path) = self
+            .frozen
+            .iter()
+            .find(|p| (start..end).contains(&index(p)))
+        {
+            let path = path.to_bits();
+            panic!("{}", SmtError::LeafFrozen { path }.report());
+        }
+        let value = value.into();
+        if let Some(validator) = &self.validator {
+            if start < end && !(validator.0)(value.elements()) {
+                let path = index_to_bits(start as usize, self.height).collect();
+                panic!("{}", SmtError::InvalidLeaf { path }.report());
+            }
+        }
+        let height = self.height;
+        let leaf_path = |i: u64| -> Path { (0..height).rev().map(|j| (i >> j) & 1 == 1).collect() };
+        if let Some(log) = &mut self.log {
+            log.extend((start..end).map(|i| Op::Update {
+                path: leaf_path(i).to_bits(),
+                value: value.clone(),
+            }));
+        }
+        let logged = self.update_log.is_some().then(|| {
+            (start..end)
+                .map(|i| {
+                    let path = leaf_path(i);
+                    let old = self.logged_leaf(&path);
+                    (path.to_bits(), old, LoggedLeaf::Value(value.clone()))
+                })
+                .collect::<Vec<_>>()
+        });
+
+        let filled = self.uniform_hashes(&value);
+        let mut changes = HashMap::new();
+        if start < end {
+            let range = (start as u128, end as u128);
+            self.fill_subtree(&mut Path::new(), range, &value, &filled, &mut changes);
+        }
+        self.commit_version(changes);
+        if self.strict {
+            for i in start..end {
+                self.shadow_check(&leaf_path(i));
+            }
+        }
+        self.record_updates(logged.into_iter().flatten());
+    }
+
+    /// The hashes of subtrees all of whose leaves are `value`, indexed by level.
+    fn uniform_hashes(&self, value: &L) -> Vec<H::Hash> {
+        let levels = self.height / Self::LEVEL_BITS;
+        let mut hashes = vec![self.hash_ops.hash_or_noop::<F, H>(value.elements())];
+        for level in (0..levels).rev() {
+            let child = hashes[hashes.len() - 1];
+            let tag = self.level_tag(level * Self::LEVEL_BITS);
+            hashes.push(self.hash_ops.hash_children::<F, H, A>(&[child; A], tag));
+        }
+        hashes.reverse();
+        hashes
+    }
+
+    /// Writes the leaves of `range` below `path` for [`Self::fill_range`] and rehashes `path`.
+    /// A subtree inside the range becomes a [`Node::Filled`] with the hashes of `filled` from its
+    /// level down.
+    fn fill_subtree(
+        &mut self,
+        path: &mut Path,
+        (start, end): (u128, u128),
+        value: &L,
+        filled: &[H::Hash],
+        changes: &mut HashMap<Path, H::Hash>,
+    ) {
+        let span = self.height - path.len();
+        let first = (path.index_at(0, path.len()) as u128) << span;
+        let last = first + (1u128 << span);
+        if last <= start || end <= first {
+            return;
+        }
+        let value = value.clone();
+        if span == 0 {
+            self.write_node(path, Node::Leaf { value }, changes);
+            return;
+        }
+        let len = path.len();
+        if start <= first && last <= end {
+            self.remove_below(path, changes);
+            let hashes = filled[len / Self::LEVEL_BITS..].to_vec();
+            self.write_node(path, Node::Filled { value, hashes }, changes);
+            return;
+        }
+        for i in 0..A {
+            path.truncate(len);
+            path.extend(index_to_bits(i, Self::LEVEL_BITS));
+            self.fill_subtree(path, (start, end), &value, filled, changes);
+        }
+        path.truncate(len);
+        let children = self.get_children_hashes(path);
+        self.write_node(path, Node::InnerNode { children }, changes);
+    }
+
+    /// Removes every node stored below `path`, but not the one at `path`.
+    fn remove_below(&mut self, path: &mut Path, changes: &mut HashMap<Path, H::Hash>) {
+        if let Some(Node::InnerNode { .. }) = self.nodes.get(path) {
+            let len = path.len();
+            for i in 0..A {
+                path.truncate(len);
+                path.extend(index_to_bits(i, Self::LEVEL_BITS));
+                self.remove_below(path, changes);
+                self.remove_node(path, changes);
+            }
+            path.truncate(len);
+        }
+    }
+
+    /// Stores `node` at the leaf path `leaf` and rehashes its ancestors as a single version.
+    fn write_leaf(&mut self, leaf: &Path, node: Node<F, H, A, L>) {
+        let mut path = *leaf;
+        let mut changes = HashMap::new();
+        self.write_node(&path, node, &mut changes);
+
+        while !path.is_empty() {
+            path.truncate(path.len() - Self::LEVEL_BITS);
+            let children = self.get_children_hashes(&path);
+            self.write_node(&path, Node::InnerNode { children }, &mut changes);
+        }
+        self.commit_version(changes);
+        self.shadow_check(leaf);
+    }
+
+    /// Like [`Self::update`], but also returns the proof of the leaf taken before the update
+    /// along with the roots before and after it. Siblings aren't affected by the update, so
+    /// the proof shows the old value under the old root and the new value under the new root.
+    pub fn update_with_old_proof(
+        &mut self,
+        path: impl Into<Path>,
+        value: impl Into<L>,
+    ) -> (MerkleProof<F, H>, H::Hash, H::Hash) {
+        let path: &Path = &path.into();
+        let proof = self.prove(path);
+        let old_root = self.get_root();
+        self.update(path, value);
+        (proof, old_root, self.get_root())
+    }
+
+    /// Like [`Self::update`], but returns the roots before and after the update along with the
+    /// paths of the nodes it rewrote, from the leaf up to the root.
+    ///
+    /// Panics like [`Self::update`], see [`Self::try_update_tracked`].
+    pub fn update_tracked(
+        &mut self,
+        path: impl Into<Path>,
+        value: impl Into<L>,
+    ) -> UpdateResult<F, H> {
+        let path: &Path = &path.into();
+        match self.try_update_tracked(path, value) {
+            Ok(result) => result,
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    /// Like [`Self::update_tracked`], but fails instead of panicking, as [`Self::try_update`].
+    pub fn try_update_tracked(
+        &mut self,
+        path: impl Into<Path>,
+        value: impl Into<L>,
+    ) -> SmtResult<UpdateResult<F, H>> {
+        let path: &Path = &path.into();
+        let old_root = self.get_root();
+        self.try_update(path, value)?;
+        let dirtied_nodes = (0..=path.len() / Self::LEVEL_BITS)
+            .rev()
+            .map(|level| path.prefix(level * Self::LEVEL_BITS).to_bits())
+            .collect();
+        Ok(UpdateResult {
+            old_root,
+            new_root: self.get_root(),
+            dirtied_nodes,
+        })
+    }
+
+    /// Sets the leaf at `path` to a copy of `value`.
+    pub fn update_slice(&mut self, path: impl Into<Path>, value: &[F]) {
+        let path: &Path = &path.into();
+        self.update(path, L::from_elements(value));
+    }
+
+    /// Stores the root of a nested tree as the leaf at `path`.
+    pub fn update_subtree_root(&mut self, path: impl Into<Path>, sub_root: H::Hash) {
+        let path: &Path = &path.into();
+        self.update_slice(path, &hash_to_elements::<F, H>(&sub_root));
+    }
+
+    /// Reads back a nested tree root stored with `update_subtree_root`, or `None` if the leaf at
+    /// `path` is unset or doesn't hold a hash.
+    pub fn get_subtree_root(&self, path: impl Into<Path>) -> Option<H::Hash> {
+        let path: &Path = &path.into();
+        assert_eq!(path.len(), self.height);
+        match self.node(path).as_deref() {
+            Some(Node::Leaf { value }) => elements_to_hash::<F, H>(value.elements()),
+            _ => None,
+        }
+    }
+
+    /// Resets the leaf at `path` to the empty value, removing it and every ancestor whose subtree
+    /// becomes empty from the node map.
+    ///
+    /// Panics if the leaf is frozen, see [`Self::try_delete`].
+    pub fn delete(&mut self, path: impl Into<Path>) {
+        let path: &Path = &path.into();
+        if let Err(err) = self.try_delete(path) {
+            panic!("{err}");
+        }
+    }
+
+    /// Like [`Self::delete`], but fails instead of panicking if `path` isn't a leaf path or the
+    /// leaf has been frozen.
+    pub fn try_delete(&mut self, path: impl Into<Path>) -> SmtResult<()> {
+        let path: &Path = &path.into();
+        self.check_writable(path)?;
+        let leaf = path;
+        let mut path = *path;
+        let mut changes = HashMap::new();
+        if let Some(log) = &mut self.log {
+            log.push(Op::Delete {
+                path: path.to_bits(),
+            });
+        }
+        let logged = self.update_log.is_some().then(|| {
+            (
+                path.to_bits(),
+                self.logged_leaf(&path),
+                LoggedLeaf::Value(self.default_leaf.clone()),
+            )
+        });
+
+        self.remove_node(&path, &mut changes);
+
+        while !path.is_empty() {
+            path.truncate(path.len() - Self::LEVEL_BITS);
+            let children = self.get_children_hashes(&path);
+            let zero = self.zero_hashes[(path.len() + Self::LEVEL_BITS) / Self::LEVEL_BITS];
+            if children.iter().all(|&h| h == zero) {
+                self.remove_zero_subtree(&mut path, &mut changes);
+            } else {
+                self.write_node(&path, Node::InnerNode { children }, &mut changes);
+            }
+        }
+        self.commit_version(changes);
+        self.shadow_check(leaf);
+        self.record_updates(logged);
+        Ok(())
+    }
+
+    /// Makes [`Self::try_update`] reject values for which `validator` returns `false` with
+    /// [`SmtError::InvalidLeaf`], and [`Self::update`] panic on them. Leaves written with
+    /// [`Self::update_hash`] aren't checked since their values are unknown.
+    pub fn set_leaf_validator(&mut self, validator: impl Fn(&[F]) -> bool + Send + Sync + 'static) {
+        self.validator = Some(LeafValidator(Arc::new(validator)));
+    }
+
+    /// Restricts values to `width` elements on top of the current validator, e.g. to keep a
+    /// `Vec<F>` tree from mixing widths. The empty leaf keeps the width of [`LeafValue::empty`];
+    /// a fixed-width leaf type such as `[F; W]` enforces its width without a validator.
+    pub fn require_leaf_width(&mut self, width: usize) {
+        let previous = self.validator.take();
+        self.set_leaf_validator(move |value| {
+            value.len() == width && previous.as_ref().map_or(true, |p| (p.0)(value))
+        });
+    }
+
+    /// Calls `callback` with the new root after each write that changes it, e.g. to publish
+    /// commitments. A batch or [`Self::transaction`] counts as one write, and writes that are
+    /// rolled back aren't reported. Clones of the tree don't inherit callbacks.
+    pub fn on_root_change(&mut self, callback: impl FnMut(H::Hash) + Send + Sync + 'static) {
+        if self.observers.callbacks.is_empty() {
+            self.observers.last_root = Some(self.get_root());
+        }
+        self.observers.callbacks.push(Box::new(callback));
+    }
+
+    /// Like [`Self::on_root_change`], but sends the new roots down a channel, for consumers on
+    /// other threads. Dropping the receiver just discards later roots.
+    pub fn subscribe(&mut self) -> Receiver<H::Hash>
+    where
+        H::Hash: 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let sender = Mutex::new(sender);
+        self.on_root_change(move |root| {
+            let _ = sender.lock().unwrap().send(root);
+        });
+        receiver
+    }
+
+    /// Starts recording every successful update and delete as an [`Op`], discarding any log
+    /// recorded so far.
+    pub fn start_recording(&mut self) {
+        self.log = Some(vec![]);
+    }
+
+    /// Stops recording and returns the operations recorded since [`Self::start_recording`].
+    pub fn take_log(&mut self) -> Vec<Op<F, H, L>> {
+        self.log.take().unwrap_or_default()
+    }
+
+    /// Replays `ops` in order, stopping at the first one that fails.
+    pub fn apply_log(&mut self, ops: &[Op<F, H, L>]) -> SmtResult<()> {
+        for op in ops {
+            match op {
+                Op::Update { path, value } => self.try_update(path, value.clone())?,
+                Op::UpdateHash { path, hash } => {
+                    self.check_writable(&path.into())?;
+                    self.update_hash(path, *hash);
+                }
+                Op::Delete { path } => self.try_delete(path)?,
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts recording every successful leaf write, from [`Self::try_update`],
+    /// [`Self::try_update_batch`], [`Self::try_delete`], [`Self::update_hash`] and
+    /// [`Self::fill_range`], in an [`UpdateLog`] along with the leaf's old state and the root
+    /// after the write, discarding any log recorded so far. [`Self::reinsert_subtree`],
+    /// [`Self::insert_subtree`] and [`Self::commit_fork`] record the leaves they change as a
+    /// single write. Nodes stored directly, e.g. with [`Self::insert_raw_node`], aren't leaf
+    /// writes and aren't recorded.
+    pub fn start_update_log(&mut self) {
+        self.update_log = Some(UpdateLog {
+            start_root: self.get_root(),
+            entries: vec![],
+        });
+    }
+
+    pub fn update_log(&self) -> Option<&UpdateLog<F, H, L>> {
+        self.update_log.as_ref()
+    }
+
+    /// Stops recording and returns the log started by [`Self::start_update_log`], if any.
+    pub fn take_update_log(&mut self) -> Option<UpdateLog<F, H, L>> {
+        self.update_log.take()
+    }
+
+    /// The state of the leaf at `path` as the update log records it.
+    fn logged_leaf(&self, path: &Path) -> LoggedLeaf<F, H, L> {
+        match self.node(path).as_deref() {
+            Some(Node::Digest { hash }) => LoggedLeaf::Hash(*hash),
+            Some(Node::Leaf { value }) => LoggedLeaf::Value(value.clone()),
+            _ => LoggedLeaf::Value(self.default_leaf.clone()),
+        }
+    }
+
+    /// Writes `leaf` at `path` for [`Self::replay`] and [`Self::revert_to`].
+    fn write_logged(&mut self, path: &Path, leaf: &LoggedLeaf<F, H, L>) -> SmtResult<()> {
+        match leaf {
+            LoggedLeaf::Value(value) => self.try_update(path, value.clone()),
+            LoggedLeaf::Hash(hash) => {
+                self.check_writable(path)?;
+                self.update_hash(path, *hash);
+                Ok(())
+            }
+        }
+    }
+
+    /// The update log entries for setting each leaf in `paths` to `new(path)` in one write,
+    /// sorted by path and leaving out the leaves whose hash doesn't change.
+    #[allow(clippy::type_complexity)]
+    fn logged_writes(
+        &self,
+        paths: impl IntoIterator<Item = Path>,
+        new: impl Fn(&Path) -> LoggedLeaf<F, H, L>,
+    ) -> Vec<(Vec<bool>, LoggedLeaf<F, H, L>, LoggedLeaf<F, H, L>)> {
+        let paths: BTreeSet<Path> = paths.into_iter().collect();
+        paths
+            .into_iter()
+            .filter_map(|path| {
+                let (old, new) = (self.logged_leaf(&path), new(&path));
+                (old.hash() != new.hash()).then(|| (path.to_bits(), old, new))
+            })
+            .collect()
+    }
+
+    /// Appends the writes in `logged`, as `(path, old state, new state)`, to the update log.
+    #[allow(clippy::type_complexity)]
+    fn record_updates(
+        &mut self,
+        logged: impl IntoIterator<Item = (Vec<bool>, LoggedLeaf<F, H, L>, LoggedLeaf<F, H, L>)>,
+    ) {
+        let root = self.get_root();
+        if let Some(log) = &mut self.update_log {
+            log.entries
+                .extend(logged.into_iter().map(|(path, old, new)| UpdateLogEntry {
+                    path,
+                    old,
+                    new,
+                    root,
+                }));
+        }
+    }
+
+    /// Applies the writes in `log` to this tree, which must be at [`UpdateLog::start_root`],
+    /// e.g. to rebuild a tree after a crash from a fresh [`Self::new`] and the log of a tree
+    /// that started empty. Each entry's old state is checked against the hash of the leaf it
+    /// overwrites, and the root against the recorded one after each write. Fails with
+    /// [`SmtError::RootMismatch`] at the first discrepancy, or like [`Self::try_update`],
+    /// leaving the tree as it was.
+    pub fn replay(&mut self, log: &UpdateLog<F, H, L>) -> SmtResult<()> {
+        self.with_rollback(|tree| {
+            if tree.get_root() != log.start_root {
+                return Err(SmtError::RootMismatch);
+            }
+            for (i, entry) in log.entries.iter().enumerate() {
+                if tree.logged_leaf(&(&entry.path).into()).hash() != entry.old.hash() {
+                    return Err(SmtError::RootMismatch);
+                }
+                tree.write_logged(&(&entry.path).into(), &entry.new)?;
+                let ends_write = log
+                    .entries
+                    .get(i + 1)
+                    .map_or(true, |e| e.root != entry.root);
+                if ends_write && tree.get_root() != entry.root {
+                    return Err(SmtError::RootMismatch);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    /// Undoes the most recent writes in the update log until the tree is back at `root`,
+    /// dropping their entries, and returns how many leaf writes were undone, e.g. to follow a
+    /// chain reorganization back to the common ancestor. Each undone write is a new version and
+    /// isn't itself recorded.
+    ///
+    /// Fails with [`SmtError::RootMismatch`], leaving the tree and the log as they were, if no
+    /// log is kept, `root` isn't one the log went through, or undoing doesn't lead back to the
+    /// recorded roots, e.g. because of an unrecorded write in between.
+    pub fn revert_to(&mut self, root: H::Hash) -> SmtResult<usize> {
+        let Some(log) = &self.update_log else {
+            return Err(SmtError::RootMismatch);
+        };
+        if log.start_root != root && log.entries.iter().all(|entry| entry.root != root) {
+            return Err(SmtError::RootMismatch);
+        }
+        let mut reverted = 0;
+        self.with_rollback(|tree| {
+            // The log is put aside so that the undoing writes aren't recorded.
+            let mut log = tree.update_log.take().unwrap();
+            let kept = tree.undo_logged(&log, root);
+            if let Ok(kept) = kept {
+                reverted = log.entries.len() - kept;
+                log.entries.truncate(kept);
+            }
+            tree.update_log = Some(log);
+            kept.map(|_| ())
+        })?;
+        Ok(reverted)
+    }
+
+    /// Undoes the entries of `log` from the last one until the tree is at `root` for
+    /// [`Self::revert_to`], returning the number of entries left.
+    fn undo_logged(&mut self, log: &UpdateLog<F, H, L>, root: H::Hash) -> SmtResult<usize> {
+        let mut kept = log.entries.len();
+        while self.get_root() != root {
+            if kept == 0 {
+                return Err(SmtError::RootMismatch);
+            }
+            kept -= 1;
+            let entry = &log.entries[kept];
+            self.write_logged(&(&entry.path).into(), &entry.old)?;
+            let previous = log.entries[..kept]
+                .last()
+                .map_or(log.start_root, |e| e.root);
+            if previous != entry.root && self.get_root() != previous {
+                return Err(SmtError::RootMismatch);
+            }
+        }
+        Ok(kept)
+    }
+
+    /// Enables or disables strict mode, in which every update and delete proves the written
+    /// leaf against the new root and panics if the proof doesn't verify. This catches
+    /// inconsistencies between hashing and proving as soon as they happen, at the cost of a
+    /// proof per write, so it's off by default.
+    pub fn set_strict(&mut self, strict: bool) {
+        self.strict = strict;
+    }
+
+    fn shadow_check(&self, path: &Path) {
+        if !self.strict {
+            return;
+        }
+        let leaf_hash = match self.node(path).as_deref() {
+            Some(Node::Leaf { value }) => self.hash_ops.hash_or_noop::<F, H>(value.elements()),
+            _ => self.get_node_hash(path),
+        };
+        let proof = self.prove(path);
+        assert!(
+            Self::fold_hash(&path.to_bits(), leaf_hash, &proof.siblings, self.level_tags)
+                == self.get_root(),
+            "strict mode: proof of the leaf just written doesn't verify"
+        );
+    }
+
+    /// Checks that `path` addresses a leaf that may be written.
+    fn check_leaf_path(&self, path: &Path) -> SmtResult<()> {
+        if path.len() != self.height {
+            return Err(SmtError::InvalidPath {
+                len: path.len(),
+                height: self.height,
+            }
+            .report());
+        }
+        Ok(())
+    }
+
+    fn check_writable(&self, path: &Path) -> SmtResult<()> {
+        self.check_leaf_path(path)?;
+        if self.frozen.contains(path) {
+            return Err(SmtError::LeafFrozen {
+                path: path.to_bits(),
+            }
+            .report());
+        }
+        Ok(())
+    }
+
+    /// Makes the leaf at `path` immutable: later updates and deletes of it fail with
+    /// [`SmtError::LeafFrozen`]. Freezing is permanent and doesn't change the root.
+    pub fn freeze_leaf(&mut self, path: impl Into<Path>) {
+        let path: &Path = &path.into();
+        assert_eq!(path.len(), self.height);
+        self.frozen.insert(*path);
+    }
+
+    pub fn is_frozen(&self, path: impl Into<Path>) -> bool {
+        let path: &Path = &path.into();
+        self.frozen.contains(path)
+    }
+
+    /// The paths of the frozen leaves, sorted.
+    pub fn frozen_leaves(&self) -> Vec<Path> {
+        let mut paths: Vec<_> = self.frozen.iter().copied().collect();
+        paths.sort();
+        paths
+    }
+
+    /// Deletes the stored leaves whose value is the default value, which [`Self::update`] stores
+    /// like any other, pruning their ancestors as [`Self::delete`] does. Their hash is the empty
+    /// leaf hash, so the root doesn't change. Frozen leaves are kept.
+    pub fn compact_empty_leaves(&mut self) {
+        let empty = self.default_leaf.clone();
+        let paths: Vec<Path> = self
+            .leaf_nodes()
+            .filter(|(path, node)| {
+                matches!(
+                    node,
+                    Node::Leaf { value } | Node::Filled { value, .. }
+                        if value.elements() == empty.elements()
+                ) && !self.frozen.contains(path)
+            })
+            .map(|(path, _)| path)
+            .collect();
+        for path in paths {
+            self.delete(path);
+        }
+    }
+
+    /// Sets the leaf at `path` to `value`, or deletes it if `value` is `None`.
+    pub fn set(&mut self, path: impl Into<Path>, value: Option<L>) {
+        let path: &Path = &path.into();
+        match value {
+            Some(value) => self.update(path, value),
+            None => self.delete(path),
+        }
+    }
+
+    /// Applies `updates` in order with [`Self::try_update`] and then runs `validate` on the
+    /// result. If an update is rejected or validation fails, the tree is restored to its prior
+    /// state, including its dirty set and version history, and the error is returned. The tree
+    /// isn't copied: the updates record the nodes they overwrite, so undoing them costs about as
+    /// much as the updates themselves.
+    pub fn transaction<E: From<SmtError>>(
+        &mut self,
+        updates: &[(Vec<bool>, L)],
+        validate: impl Fn(&Self) -> Result<(), E>,
+    ) -> Result<(), E> {
+        self.with_rollback(|tree| {
+            for (path, value) in updates {
+                tree.try_update(path, value.clone())?;
+            }
+            validate(tree)
+        })
+    }
+
+    /// Applies `updates` like [`Self::transaction`] and commits them only if the resulting root
+    /// is `expected_root`, e.g. the root claimed by whoever sent the updates. Otherwise, or if
+    /// an update is rejected by [`Self::try_update`], the tree is left as it was and the error
+    /// is returned, [`SmtError::RootMismatch`] for a wrong root.
+    pub fn apply_and_verify(
+        &mut self,
+        updates: &[(Vec<bool>, L)],
+        expected_root: H::Hash,
+    ) -> SmtResult<()> {
+        self.with_rollback(|tree| {
+            for (path, value) in updates {
+                tree.try_update(path, value.clone())?;
+            }
+            if tree.get_root() == expected_root {
+                Ok(())
+            } else {
+                Err(SmtError::RootMismatch)
+            }
+        })
+    }
+
+    /// Branches off a tree for speculative updates. The fork reads through to the nodes of this
+    /// tree, which it shares, and keeps its own writes in an [`OverlayNodeMap`], so unlike
+    /// writing to a clone, writing to the fork never copies the whole node map. Commit it with
+    /// [`Self::commit_fork`] or drop it to discard its updates. Writing to this tree while a fork
+    /// is alive copies the nodes, as for a clone.
+    ///
+    /// The fork keeps the shape, leaf validator and frozen leaves of this tree, but no history,
+    /// observers or caches.
+    pub fn fork(&self) -> ForkedSparseMerkleTree<F, H, A, L, M> {
+        let mut fork = SparseMerkleTree::from_zero_hashes(
+            self.height,
+            self.zero_hashes.clone(),
+            self.level_tags,
+        );
+        fork.nodes = Arc::new(OverlayNodeMap::new(self.nodes.clone()));
+        fork.default_leaf = self.default_leaf.clone();
+        fork.version = self.version;
+        fork.frozen = self.frozen.clone();
+        fork.validator = self.validator.clone();
+        fork.strict = self.strict;
+        fork
+    }
+
+    /// Applies the updates made to `fork` since [`Self::fork`] as a single version, rewriting
+    /// only the nodes they touched, and keeps the leaves frozen on `fork` frozen. Fails with
+    /// [`SmtError::RootMismatch`] if `fork` wasn't forked from this tree in its current state,
+    /// e.g. because this tree was written to since.
+    pub fn commit_fork(&mut self, fork: ForkedSparseMerkleTree<F, H, A, L, M>) -> SmtResult<()> {
+        if !Arc::ptr_eq(&fork.nodes.base, &self.nodes) {
+            return Err(SmtError::RootMismatch);
+        }
+        let logged = self.update_log.is_some().then(|| {
+            // A filled node stands for all the leaves below it, on either side.
+            let leaves = fork.nodes.changes.iter().flat_map(|(path, node)| {
+                let filled =
+                    |node: Option<&Node<F, H, A, L>>| matches!(node, Some(Node::Filled { .. }));
+                if filled(node.as_ref()) || filled(self.nodes.get(path)) {
+                    Self::paths_below(path, &Path::new(), self.height).collect()
+                } else if path.len() == self.height {
+                    vec![*path]
+                } else {
+                    vec![]
+                }
+            });
+            self.logged_writes(leaves, |path| fork.logged_leaf(path))
+        });
+        let overlay = Arc::try_unwrap(fork.nodes).unwrap_or_else(|nodes| (*nodes).clone());
+        // Let go of the base first so that writing to this tree doesn't copy it.
+        let OverlayNodeMap { base, changes } = overlay;
+        drop(base);
+        let mut versioned = HashMap::new();
+        for (path, node) in changes {
+            match node {
+                Some(node) => self.write_node(&path, node, &mut versioned),
+                None => self.remove_node(&path, &mut versioned),
+            }
+        }
+        self.commit_version(versioned);
+        self.frozen.extend(fork.frozen);
+        self.record_updates(logged.into_iter().flatten());
+        Ok(())
+    }
+
+    /// Stores `node` at `path`, recording the hash it replaces in `changes` when history is kept.
+    fn write_node(
+        &mut self,
+        path: &Path,
+        node: Node<F, H, A, L>,
+        changes: &mut HashMap<Path, H::Hash>,
+    ) {
+        self.unfill(path, changes);
+        if self.history_limit > 0 && !changes.contains_key(path) {
+            changes.insert(*path, self.get_node_hash(path));
+        }
+        self.journal_write(path);
+        Arc::make_mut(&mut self.nodes).insert(*path, node);
+        self.dirty.insert(*path);
+        self.refresh_caches(path);
+    }
+
+    /// Removes the node at `path`, recording the hash it had like [`Self::write_node`].
+    fn remove_node(&mut self, path: &Path, changes: &mut HashMap<Path, H::Hash>) {
+        self.unfill(path, changes);
+        let filled = match self.nodes.get(path) {
+            Some(node) => matches!(node, Node::Filled { .. }),
+            None => return,
+        };
+        if self.history_limit > 0 && !changes.contains_key(path) {
+            changes.insert(*path, self.get_node_hash(path));
+        }
+        self.journal_write(path);
+        Arc::make_mut(&mut self.nodes).remove(path);
+        self.dirty.insert(*path);
+        self.refresh_caches(path);
+        if filled {
+            self.refresh_cap_below(path);
+        }
+    }
+
+    /// The hasher calls made by this tree, on any thread, since it was created or cloned or the
+    /// last [`Self::reset_hash_op_stats`]. Static verifiers such as [`Self::verify_borrowed`]
+    /// belong to no tree and aren't counted. Counting is only compiled in with the `hash-stats`
+    /// feature.
+    #[cfg(feature = "hash-stats")]
+    pub fn hash_op_stats(&self) -> HashOpStats {
+        self.hash_ops.stats()
+    }
+
+    #[cfg(feature = "hash-stats")]
+    pub fn reset_hash_op_stats(&self) {
+        self.hash_ops.reset();
+    }
+
+    /// Borrows the tree as a [`ReadOnlySmt`].
+    pub fn read_only(&self) -> ReadOnlySmt<'_, F, H, A, L, M> {
+        ReadOnlySmt { tree: self }
+    }
+
+    /// Removes the node at `path` and every node stored below it, which must all hash like empty
+    /// subtrees. Besides the path of a deleted leaf, this catches siblings that were explicitly
+    /// set to the empty value and would otherwise be left behind without a parent.
+    fn remove_zero_subtree(&mut self, path: &mut Path, changes: &mut HashMap<Path, H::Hash>) {
+        if let Some(Node::InnerNode { .. }) = self.nodes.get(path) {
+            let len = path.len();
+            for i in 0..A {
+                path.truncate(len);
+                path.extend(index_to_bits(i, Self::LEVEL_BITS));
+                self.remove_zero_subtree(path, changes);
+            }
+            path.truncate(len);
+        }
+        self.remove_node(path, changes);
+    }
+
+    /// Keeps the hash of every stored node cached next to the node map, updating the entry of a
+    /// node whenever it is written or removed. Reading a node hash, and so each sibling of a
+    /// proof, is then a lookup instead of a hasher call, at the cost of a hash and a path per
+    /// stored node and of slightly slower writes; `benches/node_hash_cache.rs` compares both.
+    /// Like the node map, the cache is shared by clones until either is written.
+    ///
+    /// Nodes written to `self.nodes` directly bypass the cache; call this again afterwards.
+    pub fn cache_node_hashes(&mut self) {
+        let hashes = self
+            .nodes
+            .iter()
+            .map(|(path, node)| {
+                let hash = node.hash(&self.hash_ops, self.level_tag(path.len()));
+                (path, hash)
+            })
+            .collect();
+        self.hash_cache = Some(Arc::new(hashes));
+    }
+
+    /// Stops caching node hashes, freeing the cache built by [`Self::cache_node_hashes`].
+    pub fn clear_node_hash_cache(&mut self) {
+        self.hash_cache = None;
+    }
+
+    /// Keeps the hashes of the nodes `cap_height` bits below the root cached, updating the
+    /// affected entry whenever one of those nodes is written, so that [`Self::cached_cap`] and
+    /// `get_cap(cap_height)` don't rehash anything. Fails like `get_cap` if `cap_height` isn't a
+    /// level of the tree or is above [`Self::MAX_CAP_HEIGHT`], leaving the cached cap as it was.
+    pub fn cache_cap(&mut self, cap_height: usize) -> SmtResult<()> {
+        self.check_cap_height(cap_height)?;
+        let cap = (0..1usize << cap_height)
+            .map(|i| self.get_node_hash(index_to_bits(i, cap_height).collect::<Path>()))
+            .collect();
+        self.cap = Some((cap_height, cap));
+        Ok(())
+    }
+
+    /// The cap maintained since [`Self::cache_cap`], ordered by node index.
+    pub fn cached_cap(&self) -> Option<&[H::Hash]> {
+        self.cap.as_ref().map(|(_, cap)| cap.as_slice())
+    }
+
+    /// Updates the cached hashes after the node at `path` was written or removed.
+    fn refresh_caches(&mut self, path: &Path) {
+        let tag = self.level_tag(path.len());
+        if let Some(hashes) = &mut self.hash_cache {
+            let hashes = Arc::make_mut(hashes);
+            match self.nodes.get(path) {
+                Some(node) => match hashes.get_mut(path) {
+                    Some(hash) => *hash = node.hash(&self.hash_ops, tag),
+                    None => {
+                        hashes.insert(*path, node.hash(&self.hash_ops, tag));
+                    }
+                },
+                None => {
+                    hashes.remove(path);
+                }
+            }
+        }
+        if matches!(self.cap, Some((cap_height, _)) if cap_height == path.len()) {
+            let hash = self.get_node_hash(path);
+            self.cap.as_mut().unwrap().1[path.index_at(0, path.len())] = hash;
+        }
+        if matches!(self.nodes.get(path), Some(Node::Filled { .. })) {
+            self.refresh_cap_below(path);
+        }
+    }
+
+    /// Rereads the cached cap entries below `path` after a [`Node::Filled`] there was written or
+    /// removed, since the nodes it stands for aren't written one by one.
+    fn refresh_cap_below(&mut self, path: &Path) {
+        let cap_height = match self.cap {
+            Some((cap_height, _)) if cap_height > path.len() => cap_height,
+            _ => return,
+        };
+        let span = cap_height - path.len();
+        for i in 0..1usize << span {
+            let mut node = *path;
+            node.extend(index_to_bits(i, span));
+            let hash = self.get_node_hash(node);
+            self.cap.as_mut().unwrap().1[node.index_at(0, cap_height)] = hash;
+        }
+    }
+
+    /// Finishes an update whose overwritten hashes are `changes`, starting a new version.
+    fn commit_version(&mut self, changes: HashMap<Path, H::Hash>) {
+        self.version += 1;
+        if self.history_limit > 0 {
+            self.history.push_back(changes);
+            self.set_history_limit(self.history_limit);
+        }
+        self.notify_root_change();
+    }
+
+    /// Runs the observers if the root differs from the one they last saw.
+    fn notify_root_change(&mut self) {
+        if self.observers.callbacks.is_empty() {
+            return;
+        }
+        let root = self.get_root();
+        if self.observers.last_root != Some(root) {
+            self.observers.last_root = Some(root);
+            for callback in &mut self.observers.callbacks {
+                callback(root);
+            }
+        }
+    }
+
+    /// Runs `f`, restoring the tree to its prior state if it fails or panics. Observers are held
+    /// back until `f` returns, so they only see the root that is kept.
+    ///
+    /// Rather than snapshotting the tree, which would copy the whole node map on the first write
+    /// while the snapshot shares it, the writes of `f` journal the nodes they overwrite, so a
+    /// rollback costs about as much as the writes it undoes.
+    fn with_rollback<E>(&mut self, f: impl FnOnce(&mut Self) -> Result<(), E>) -> Result<(), E> {
+        assert!(self.undo.is_none(), "rollbacks don't nest");
+        let observers = std::mem::take(&mut self.observers);
+        self.undo = Some(UndoJournal {
+            nodes: HashMap::new(),
+            dirtied: vec![],
+            version: self.version,
+            history_kept: self.history.len(),
+            trimmed: vec![],
+            log_len: self.log.as_ref().map(Vec::len),
+            update_log_len: self.update_log.as_ref().map(UpdateLog::len),
+        });
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| f(self)));
+        let undo = self.undo.take().unwrap();
+        if !matches!(result, Ok(Ok(()))) {
+            self.roll_back(undo);
+        }
+        self.observers = observers;
+        match result {
+            Ok(result) => {
+                self.notify_root_change();
+                result
+            }
+            Err(panic) => std::panic::resume_unwind(panic),
+        }
+    }
+
+    /// Remembers what the node at `path` held before its first write under [`Self::with_rollback`].
+    fn journal_write(&mut self, path: &Path) {
+        if let Some(undo) = &mut self.undo {
+            if !undo.nodes.contains_key(path) {
+                undo.nodes.insert(*path, self.nodes.get(path).cloned());
+                if !self.dirty.contains(path) {
+                    undo.dirtied.push(*path);
+                }
+            }
+        }
+    }
+
+    /// Undoes the writes recorded in `undo`.
+    fn roll_back(&mut self, undo: UndoJournal<F, H, A, L>) {
+        for (path, node) in undo.nodes {
+            let nodes = Arc::make_mut(&mut self.nodes);
+            match node {
+                Some(node) => nodes.insert(path, node),
+                None => nodes.remove(&path),
+            };
+            self.refresh_caches(&path);
+        }
+        for path in &undo.dirtied {
+            self.dirty.remove(path);
+        }
+        self.version = undo.version;
+        self.history.truncate(undo.history_kept);
+        for changes in undo.trimmed.into_iter().rev() {
+            self.history.push_front(changes);
+        }
+        if let (Some(log), Some(len)) = (&mut self.log, undo.log_len) {
+            log.truncate(len);
+        }
+        if let (Some(log), Some(len)) = (&mut self.update_log, undo.update_log_len) {
+            log.entries.truncate(len);
+        }
+    }
+
+    /// The number of updates applied to the tree.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Keeps enough history to prove against the roots of the last `limit` versions. The default
+    /// of 0 keeps no history, so only the current version can be proven.
+    pub fn set_history_limit(&mut self, limit: usize) {
+        self.history_limit = limit;
+        while self.history.len() > limit {
+            let changes = self.history.pop_front().unwrap();
+            if let Some(undo) = &mut self.undo {
+                if undo.history_kept > 0 {
+                    undo.history_kept -= 1;
+                    undo.trimmed.push(changes);
+                }
+            }
+        }
+    }
+
+    /// Returns the hash the node at `path` had at `version`, or `None` if that version is not
+    /// retained.
+    fn get_node_hash_at_version(&self, path: &Path, version: u64) -> Option<H::Hash> {
+        let oldest = self.version - self.history.len() as u64;
+        if version < oldest || version > self.version {
+            return None;
+        }
+        // The first update after `version` that touched the node remembers its old hash.
+        let mut later = self.history.iter().skip((version - oldest) as usize);
+        if let Some(hash) = later.clone().find_map(|changes| changes.get(path).copied()) {
+            return Some(hash);
+        }
+        // Filling a subtree only records the hash of its root, so what was below it before is
+        // unknown.
+        if let Some((filled, _, _)) = self.filled_ancestor(path) {
+            if later.any(|changes| changes.contains_key(&filled)) {
+                return None;
+            }
+        }
+        Some(self.get_node_hash(path))
+    }
+
+    pub fn get_root_at_version(&self, version: u64) -> Option<H::Hash> {
+        self.get_node_hash_at_version(&Path::new(), version)
+    }
+
+    /// Like [`Self::prove`], but against the root the tree had at `version`. Returns `None` if
+    /// that version is in the future or older than the retained history, or if a sibling lies
+    /// below a subtree filled since, see [`Self::fill_range`].
+    pub fn prove_at_version(
+        &self,
+        path: impl Into<Path>,
+        version: u64,
+    ) -> Option<MerkleProof<F, H>> {
+        let path: &Path = &path.into();
+        assert_eq!(path.len(), self.height);
+        let siblings = Self::sibling_paths(path)
+            .iter()
+            .map(|sibling| self.get_node_hash_at_version(sibling, version))
+            .collect::<Option<_>>()?;
+        Some(MerkleProof { siblings })
+    }
+
+    /// Records the current version and root, to be proven against later with
+    /// [`Self::prove_at_snapshot`] for as long as the history limit retains that version.
+    pub fn snapshot(&self) -> TreeSnapshot<F, H> {
+        TreeSnapshot {
+            version: self.version,
+            root: self.get_root(),
+        }
+    }
+
+    /// Like [`Self::prove_at_version`] for the version of `snapshot`. Also returns `None` if the
+    /// tree had a different root at that version, e.g. if the snapshot is of another tree or an
+    /// update since was rolled back and replaced.
+    pub fn prove_at_snapshot(
+        &self,
+        snapshot: &TreeSnapshot<F, H>,
+        path: impl Into<Path>,
+    ) -> Option<MerkleProof<F, H>> {
+        let path: &Path = &path.into();
+        if self.get_root_at_version(snapshot.version)? != snapshot.root {
+            return None;
+        }
+        self.prove_at_version(path, snapshot.version)
+    }
+
+    /// Returns the sorted paths of all nodes written since the last call (or `mark_clean`) and
+    /// clears the dirty set, so that a persistence layer only has to flush those nodes.
+    pub fn take_dirty(&mut self) -> Vec<Path> {
+        let mut dirty: Vec<Path> = self.dirty.drain().collect();
+        dirty.sort();
+        dirty
+    }
+
+    /// Forgets all pending dirty paths without returning them.
+    pub fn mark_clean(&mut self) {
+        self.dirty.clear();
+    }
+
+    /// Returns the siblings of every node on `path`, from the bottommost level up. Each level
+    /// contributes `A - 1` siblings ordered by child index.
+    ///
+    /// Panics if `path` isn't a leaf path or the tree is partial and lacks a sibling, see
+    /// [`Self::try_prove`].
+    pub fn prove(&self, path: impl Into<Path>) -> MerkleProof<F, H> {
+        let path: &Path = &path.into();
+        match self.try_prove(path) {
+            Ok(proof) => proof,
+            Err(err) => panic!("{err}"),
+        }
+    }
+
+    /// Like [`Self::prove`], but fails instead of panicking if `path` isn't a leaf path, or with
+    /// [`SmtError::MissingSibling`] if a sibling lies under a [`Node::Digest`] of a partial tree.
+    /// Unstored nodes below a digest aren't known to be empty, so their zero hash can't be used.
+    pub fn try_prove(&self, path: impl Into<Path>) -> SmtResult<MerkleProof<F, H>> {
+        let path: &Path = &path.into();
+        self.check_leaf_path(path)?;
+        // Whether the children of each inner node on `path` are hidden behind a digest.
+        let mut hidden = vec![];
+        let mut below_digest = false;
+        let mut prefix = Path::new();
+        while prefix.len() < self.height {
+            below_digest = match self.nodes.get(&prefix) {
+                Some(Node::Digest { hash }) => {
+                    *hash != self.zero_hashes[prefix.len() / Self::LEVEL_BITS]
+                }
+                Some(_) => false,
+                None => below_digest,
+            };
+            hidden.push(below_digest);
+            prefix = path.prefix(prefix.len() + Self::LEVEL_BITS);
+        }
+        let mut siblings = vec![];
+        let mut sibling = *path;
+        for (level, hidden) in hidden.into_iter().enumerate().rev() {
+            let parent_len = level * Self::LEVEL_BITS;
+            prefix.truncate(parent_len + Self::LEVEL_BITS);
+            let index = path.index_at(parent_len, Self::LEVEL_BITS);
+            for i in (0..A).filter(|&i| i != index) {
+                sibling.truncate(parent_len);
+                sibling.extend(index_to_bits(i, Self::LEVEL_BITS));
+                if hidden && self.nodes.get(&sibling).is_none() {
+                    return Err(SmtError::MissingSibling {
+                        path: sibling.to_bits(),
+                    });
+                }
+            }
+            siblings.extend(self.get_sibling_hashes(prefix));
+        }
+        Ok(MerkleProof { siblings })
+    }
+
+    /// Returns the smallest index of an unset leaf, or `None` if every leaf is set. A leaf counts
+    /// as set when it is stored, even if its value is the empty value; a subtree is full when all
+    /// of its leaves are set. Only subtrees containing stored nodes are searched, so the cost is
+    /// bounded by the number of stored nodes rather than `2^height`.
+    pub fn first_empty(&self) -> Option<u64> {
+        assert!(self.height <= 64);
+        let mut path = Path::new();
+        if self.find_empty(&mut path) {
+            Some(path.index_at(0, path.len()) as u64)
+        } else {
+            None
+        }
+    }
+
+    /// Extends `path` to the leftmost unset leaf below it, returning whether there is one.
+    fn find_empty(&self, path: &mut Path) -> bool {
+        match self.nodes.get(path) {
+            None => {
+                path.resize(self.height, false);
+                true
+            }
+            Some(Node::InnerNode { .. }) => {
+                let len = path.len();
+                for i in 0..A {
+                    path.truncate(len);
+                    path.extend(index_to_bits(i, Self::LEVEL_BITS));
+                    if self.find_empty(path) {
+                        return true;
+                    }
+                }
+                path.truncate(len);
+                false
+            }
+            // The contents of a digest are unknown, so they're never handed out.
+            Some(Node::Leaf { .. } | Node::Digest { .. } | Node::Filled { .. }) => false,
+        }
+    }
+
+    /// Returns up to `limit` indices of unset leaves under `prefix`, in ascending order, with the
+    /// same notion of set as [`Self::first_empty`]. Empty subtrees are handed out without being
+    /// walked, so the cost is bounded by the stored nodes under `prefix` plus `limit`.
+    pub fn empty_slots_under(&self, prefix: impl Into<Path>, limit: usize) -> Vec<u64> {
+        let prefix: &Path = &prefix.into();
+        assert!(self.height <= 64);
+        assert!(prefix.len() <= self.height);
+        assert_eq!(prefix.len() % Self::LEVEL_BITS, 0);
+        let mut slots = vec![];
+        let covered = (0..prefix.len()).step_by(Self::LEVEL_BITS).any(|len| {
+            matches!(
+                self.nodes.get(&prefix.prefix(len)),
+                Some(Node::Digest { .. } | Node::Filled { .. })
+            )
+        });
+        if !covered {
+            self.collect_empty(&mut prefix.clone(), limit, &mut slots);
+        }
+        slots
+    }
+
+    /// Appends the unset leaves below `path` to `slots` until it holds `limit` of them.
+    fn collect_empty(&self, path: &mut Path, limit: usize, slots: &mut Vec<u64>) {
+        match self.nodes.get(path) {
+            None => {
+                let depth = self.height - path.len();
+                let start = (path.index_at(0, path.len()) as u128) << depth;
+                let count = (1u128 << depth).min((limit - slots.len()) as u128);
+                slots.extend((start..start + count).map(|i| i as u64));
+            }
+            Some(Node::InnerNode { .. }) => {
+                let len = path.len();
+                for i in 0..A {
+                    if slots.len() == limit {
+                        break;
+                    }
+                    path.truncate(len);
+                    path.extend(index_to_bits(i, Self::LEVEL_BITS));
+                    self.collect_empty(path, limit, slots);
+                }
+                path.truncate(len);
+            }
+            Some(Node::Leaf { .. } | Node::Digest { .. } | Node::Filled { .. }) => {}
+        }
+    }
+
+    /// Returns the nearest set leaves strictly below and above `index`, with their values and
+    /// proofs, as used to argue that `index` lies in a gap of an ordered tree. A leaf counts as
+    /// set when it is stored, as in [`Self::first_empty`], and fails with
+    /// [`SmtError::LeafHashOnly`] if a neighbor is only known by its hash.
+    #[allow(clippy::type_complexity)]
+    pub fn prove_neighbors(
+        &self,
+        index: u64,
+    ) -> SmtResult<(Option<NeighborLeaf<F, H, L>>, Option<NeighborLeaf<F, H, L>>)> {
+        assert!(self.height <= 64);
+        let mut paths: Vec<_> = self.leaf_nodes().map(|(path, _)| path).collect();
+        paths.sort();
+        let target: Path = (0..self.height)
+            .rev()
+            .map(|i| (index >> i) & 1 == 1)
+            .collect();
+        let below = paths.partition_point(|path| *path < target);
+        let above = paths.partition_point(|path| *path <= target);
+        let neighbor = |path: &Path| {
+            Ok(NeighborLeaf {
+                index: path.index_at(0, path.len()) as u64,
+                value: self.known_leaf(path)?,
+                proof: self.prove(path),
+            })
+        };
+        Ok((
+            below
+                .checked_sub(1)
+                .map(|i| neighbor(&paths[i]))
+                .transpose()?,
+            paths.get(above).map(neighbor).transpose()?,
+        ))
+    }
+
+    /// The number of leaves that are still unset, counting a leaf as set when it is stored as in
+    /// [`Self::first_empty`]. Returns `None` from height 128 on, where `2^height` doesn't fit in
+    /// a `u128`, even though only a few leaves may be set.
+    pub fn remaining_capacity(&self) -> Option<u128> {
+        let capacity = 1u128.checked_shl(self.height.try_into().ok()?)?;
+        let set: u128 = self
+            .nodes
+            .iter()
+            .map(|(path, node)| match node {
+                Node::Filled { .. } => 1 << (self.height - path.len()),
+                _ => (path.len() == self.height) as u128,
+            })
+            .sum();
+        Some(capacity - set)
+    }
+
+    /// Counts the set leaves whose path starts with `prefix`, descending only into stored
+    /// subtrees that agree with it. Leaves hidden behind a [`Node::Digest`] aren't counted.
+    pub fn count_under(&self, prefix: impl Into<Path>) -> usize {
+        let prefix: &Path = &prefix.into();
+        assert!(prefix.len() <= self.height);
+        self.count_leaves(&mut Path::new(), prefix)
+    }
+
+    fn count_leaves(&self, path: &mut Path, prefix: &Path) -> usize {
+        match self.nodes.get(path) {
+            Some(Node::Leaf { .. }) => 1,
+            Some(Node::Filled { .. }) => {
+                let span = self.height - path.len().max(prefix.len());
+                1 << span
+            }
+            Some(Node::InnerNode { .. }) => {
+                let len = path.len();
+                let mut count = 0;
+                for i in 0..A {
+                    path.truncate(len);
+                    path.extend(index_to_bits(i, Self::LEVEL_BITS));
+                    let shared = path.len().min(prefix.len());
+                    if path.prefix(shared) == prefix.prefix(shared) {
+                        count += self.count_leaves(path, prefix);
+                    }
+                }
+                path.truncate(len);
+                count
+            }
+            Some(Node::Digest { .. }) | None => 0,
+        }
+    }
+
+    /// Returns the leaves under `prefix` whose value isn't the default, ordered by path, e.g. the
+    /// accounts of one shard when paths start with a shard id. Only stored subtrees that agree
+    /// with `prefix` and don't hash like empty ones are visited, so the cost follows the number
+    /// of such leaves rather than the size of the subtree. Leaves hidden behind a
+    /// [`Node::Digest`] aren't returned.
+    pub fn leaves_with_prefix(&self, prefix: impl Into<Path>) -> Vec<(Path, &L)> {
+        let prefix: &Path = &prefix.into();
+        let mut leaves = vec![];
+        self.visit_nonzero_leaves(prefix, |path, value| leaves.push((*path, value)));
+        leaves
+    }
+
+    /// Counts the leaves [`Self::leaves_with_prefix`] returns without collecting them. Unlike
+    /// [`Self::count_under`], leaves explicitly set to the default value aren't counted.
+    pub fn count_nonzero_under(&self, prefix: impl Into<Path>) -> usize {
+        let prefix: &Path = &prefix.into();
+        let mut count = 0;
+        self.visit_nonzero_leaves(prefix, |_, _| count += 1);
+        count
+    }
+
+    fn visit_nonzero_leaves<'a>(&'a self, prefix: &Path, mut visit: impl FnMut(&Path, &'a L)) {
+        assert!(prefix.len() <= self.height);
+        if self.get_root() != self.zero_hashes[0] {
+            self.visit_nonzero_below(&mut Path::new(), prefix, &mut visit);
+        }
+    }
+
+    /// Visits the leaves below `path`, whose hash is known not to be a zero hash, for
+    /// [`Self::visit_nonzero_leaves`]. Children with a zero hash are skipped.
+    fn visit_nonzero_below<'a>(
+        &'a self,
+        path: &mut Path,
+        prefix: &Path,
+        visit: &mut impl FnMut(&Path, &'a L),
+    ) {
+        match self.nodes.get(path) {
+            Some(Node::Leaf { value }) => visit(path, value),
+            Some(Node::Filled { value, .. }) => {
+                for leaf in Self::paths_below(path, prefix, self.height) {
+                    visit(&leaf, value);
+                }
+            }
+            Some(Node::InnerNode { children }) => {
+                let len = path.len();
+                let zero = self.zero_hashes[len / Self::LEVEL_BITS + 1];
+                for (i, child) in children.iter().enumerate() {
+                    path.truncate(len);
+                    path.extend(index_to_bits(i, Self::LEVEL_BITS));
+                    let shared = path.len().min(prefix.len());
+                    if *child != zero && path.prefix(shared) == prefix.prefix(shared) {
+                        self.visit_nonzero_below(path, prefix, visit);
+                    }
+                }
+                path.truncate(len);
+            }
+            Some(Node::Digest { .. }) | None => {}
+        }
+    }
+
+    /// Lists the pairs of set leaves whose values differ but hash the same, ordered by path.
+    /// `hash_or_noop` zero-pads values shorter than a hash, so e.g. `[x]` and `[x, 0]` collide,
+    /// which usually points to an encoding bug.
+    pub fn check_leaf_hash_collisions(&self) -> Vec<(Path, Path)> {
+        let mut by_hash: HashMap<Vec<u8>, Vec<Path>> = HashMap::new();
+        for path in self.leaf_paths() {
+            let hash = self.get_node_hash(path);
+            by_hash.entry(hash.to_bytes()).or_default().push(path);
+        }
+        let mut collisions = vec![];
+        for paths in by_hash.values() {
+            for (i, a) in paths.iter().enumerate() {
+                for b in &paths[i + 1..] {
+                    if self.get_leaf(a).elements() != self.get_leaf(b).elements() {
+                        collisions.push((*a, *b));
+                    }
+                }
+            }
+        }
+        collisions.sort();
+        collisions
+    }
+
+    /// Describes the stored nodes as a Graphviz digraph, labelled with their paths and the first
+    /// bytes of their hashes. Children of an inner node that aren't stored point to a "zero"
+    /// placeholder instead.
+    pub fn to_dot(&self) -> String {
+        let bits =
+            |path: &Path| -> String { path.bits().map(|b| if b { '1' } else { '0' }).collect() };
+        let id = |path: &Path| format!("n{}", bits(path));
+        let short_hash = |hash: H::Hash| -> String {
+            hash.to_bytes()[..4]
+                .iter()
+                .map(|b| format!("{b:02x}"))
+                .collect()
+        };
+
+        let mut paths: Vec<Path> = self.nodes.iter().map(|(path, _)| path).collect();
+        paths.sort();
+        let mut dot = String::from("digraph smt {\n");
+        for path in &paths {
+            let node = self.nodes.get(path).unwrap();
+            let kind = match node {
+                Node::InnerNode { .. } => "",
+                Node::Leaf { .. } => ", shape=box",
+                Node::Digest { .. } => ", style=dashed",
+                Node::Filled { .. } => ", style=filled",
+            };
+            let hash = short_hash(self.get_node_hash(path));
+            let label = if path.is_empty() {
+                "root".to_string()
+            } else {
+                bits(path)
+            };
+            dot += &format!("  {} [label=\"{label}\\n{hash}\"{kind}];\n", id(path));
+            if let Node::InnerNode { .. } = node {
+                let mut child = *path;
+                for i in 0..A {
+                    child.truncate(path.len());
+                    child.extend(index_to_bits(i, Self::LEVEL_BITS));
+                    if self.nodes.get(&child).is_some() {
+                        dot += &format!("  {} -> {};\n", id(path), id(&child));
+                    } else {
+                        let zero = format!("z{}", bits(&child));
+                        dot += &format!("  {zero} [label=\"zero\", shape=plaintext];\n");
+                        dot += &format!("  {} -> {zero};\n", id(path));
+                    }
+                }
+            }
+        }
+        dot += "}\n";
+        dot
+    }
+
+    /// Computes the root the tree would have if every leaf outside of `[start, end)` were empty.
+    /// Only subtrees straddling a bound of the window are rehashed; panics if one of them is a
+    /// [`Node::Digest`], whose leaves are unknown.
+    pub fn windowed_root(&self, start: u64, end: u64) -> H::Hash {
+        assert!(self.height <= 64);
+        self.window_hash(&mut Path::new(), start as u128, end as u128)
+    }
+
+    fn window_hash(&self, path: &mut Path, start: u128, end: u128) -> H::Hash {
+        let span = self.height - path.len();
+        let first = (path.index_at(0, path.len()) as u128) << span;
+        let last = first + (1u128 << span);
+        if start <= first && last <= end {
+            return self.get_node_hash(*path);
+        }
+        let zero = self.zero_hashes[path.len() / Self::LEVEL_BITS];
+        if last <= start || end <= first {
+            return zero;
+        }
+        match self.node(path).as_deref() {
+            None => zero,
+            Some(Node::InnerNode { .. } | Node::Filled { .. }) => {
+                let len = path.len();
+                let children = core::array::from_fn(|i| {
+                    path.truncate(len);
+                    path.extend(index_to_bits(i, Self::LEVEL_BITS));
+                    self.window_hash(path, start, end)
+                });
+                path.truncate(len);
+                self.hash_ops
+                    .hash_children::<F, H, A>(&children, self.level_tag(len))
+            }
+            // A leaf spans a single index, so it is always either inside or outside.
+            Some(Node::Leaf { .. }) => unreachable!(),
+            Some(Node::Digest { .. }) => panic!("window bound inside a digest"),
+        }
+    }
+
+    /// Whether the current root is `expected`.
+    pub fn matches_root(&self, expected: H::Hash) -> bool {
+        self.get_root() == expected
+    }
+
+    /// Returns `None` if `other` has the same root, and otherwise the path at which the two
+    /// trees diverge: starting from the root, the search follows the only differing child for as
+    /// long as there is exactly one, so a single differing leaf is found exactly. It stops early
+    /// at a node with several differing children or a [`Node::Digest`] on either side.
+    pub fn first_divergence(&self, other: &Self) -> Option<Path> {
+        assert_eq!(self.height, other.height);
+        let mut path = Path::new();
+        if self.get_node_hash(path) == other.get_node_hash(path) {
+            return None;
+        }
+        while path.len() < self.height {
+            let is_digest =
+                |tree: &Self| matches!(tree.nodes.get(&path), Some(Node::Digest { .. }));
+            if is_digest(self) || is_digest(other) {
+                break;
+            }
+            let (ours, theirs) = (
+                self.get_children_hashes(&path),
+                other.get_children_hashes(&path),
+            );
+            let mut differing = (0..A).filter(|&i| ours[i] != theirs[i]);
+            match (differing.next(), differing.next()) {
+                (Some(i), None) => path.extend(index_to_bits(i, Self::LEVEL_BITS)),
+                _ => break,
+            }
+        }
+        Some(path)
+    }
+
+    /// Returns the leaves stored in `self` but not in `other`, and those stored in `other` but not
+    /// in `self`, where a leaf set to different values in both appears on both sides. Subtrees
+    /// with equal hashes are skipped, so for mostly identical replicas only the nodes above the
+    /// differences are visited. Digests can't be descended into, so trees whose differences lie
+    /// under a [`Node::Digest`] make this panic.
+    #[allow(clippy::type_complexity)]
+    pub fn symmetric_difference(&self, other: &Self) -> (Vec<(Path, L)>, Vec<(Path, L)>) {
+        let mut differences = (vec![], vec![]);
+        for (path, ours, theirs) in self.diff(other) {
+            if let Some(value) = ours {
+                differences.0.push((path, value));
+            }
+            if let Some(value) = theirs {
+                differences.1.push((path, value));
+            }
+        }
+        differences
+    }
+
+    /// Returns `(path, value in self, value in other)` for each leaf that differs between the
+    /// trees, in path order, with `None` for an unset leaf. Like [`Self::symmetric_difference`],
+    /// subtrees with equal hashes are skipped and differences under a digest panic.
+    /// [`Self::apply_diff`] turns `self` into `other` with the result.
+    #[allow(clippy::type_complexity)]
+    pub fn diff(&self, other: &Self) -> Vec<(Path, Option<L>, Option<L>)> {
+        assert_eq!(self.height, other.height);
+        let mut differences = vec![];
+        self.diff_subtrees(other, &mut Path::new(), &mut differences);
+        differences
+    }
+
+    /// Applies a diff from [`Self::diff`] as a replica would, setting each leaf to the value of
+    /// the other tree or deleting it. Each leaf must still hold the value the diff was taken
+    /// from, otherwise the replica has diverged and [`SmtError::RootMismatch`] is returned. On
+    /// any error the tree is left unchanged.
+    #[allow(clippy::type_complexity)]
+    pub fn apply_diff(&mut self, diff: &[(Path, Option<L>, Option<L>)]) -> SmtResult<()> {
+        self.with_rollback(|tree| {
+            diff.iter().try_for_each(|(path, old, new)| {
+                let current = tree.try_get_leaf(path)?;
+                if current.as_ref().map(L::elements) != old.as_ref().map(L::elements) {
+                    return Err(SmtError::RootMismatch);
+                }
+                match new {
+                    Some(value) => tree.try_update(path, value.clone()),
+                    None => tree.try_delete(path),
+                }
+            })
+        })
+    }
+
+    /// Adds the differing leaves below `path` to `differences`, returning the number of nodes
+    /// compared.
+    #[allow(clippy::type_complexity)]
+    fn diff_subtrees(
+        &self,
+        other: &Self,
+        path: &mut Path,
+        differences: &mut Vec<(Path, Option<L>, Option<L>)>,
+    ) -> usize {
+        if self.get_node_hash(*path) == other.get_node_hash(*path) {
+            return 1;
+        }
+        if path.len() == self.height {
+            let value = |tree: &Self| match tree.node(path).as_deref() {
+                Some(Node::Leaf { value }) => Some(value.clone()),
+                _ => None,
+            };
+            differences.push((*path, value(self), value(other)));
+            return 1;
+        }
+        let is_digest = |tree: &Self| matches!(tree.nodes.get(path), Some(Node::Digest { .. }));
+        assert!(
+            !is_digest(self) && !is_digest(other),
+            "trees differ under a digest"
+        );
+        let len = path.len();
+        let mut visited = 1;
+        for i in 0..A {
+            path.truncate(len);
+            path.extend(index_to_bits(i, Self::LEVEL_BITS));
+            visited += self.diff_subtrees(other, path, differences);
+        }
+        path.truncate(len);
+        visited
+    }
+
+    /// Sorted paths of all set leaves.
+    fn leaf_paths(&self) -> Vec<Path> {
+        self.iter_leaves_sorted().map(|(path, _)| path).collect()
+    }
+
+    /// Iterates over the stored leaves in the order of the node map, listing the leaves of a
+    /// [`Node::Filled`] one by one. Leaves only known by their hash, see [`Self::update_hash`],
+    /// are skipped.
+    pub fn iter_leaves(&self) -> impl Iterator<Item = (Path, &L)> {
+        self.leaf_nodes().filter_map(|(path, node)| match node {
+            Node::Leaf { value } | Node::Filled { value, .. } => Some((path, value)),
+            _ => None,
+        })
+    }
+
+    /// Like [`Self::iter_leaves`], but ordered by path, which sorts the leaves up front.
+    pub fn iter_leaves_sorted(&self) -> impl Iterator<Item = (Path, &L)> {
+        let mut leaves: Vec<_> = self.iter_leaves().collect();
+        leaves.sort_by(|a, b| a.0.cmp(&b.0));
+        leaves.into_iter()
+    }
+
+    /// Commits to the multiset of non-empty leaf values, independently of where they are stored:
+    /// the leaf hashes are sorted and hashed together. Two trees get the same commitment exactly
+    /// when they hold the same values the same number of times, assuming `H` is collision
+    /// resistant. The commitment binds neither positions nor the height, and it isn't hiding:
+    /// anyone who can guess the values can recompute it.
+    pub fn leaf_set_commitment(&self) -> H::Hash {
+        let empty = self.zero_hashes[self.zero_hashes.len() - 1];
+        let mut hashes: Vec<H::Hash> = self
+            .leaf_nodes()
+            .filter_map(|(_, node)| match node {
+                Node::Leaf { .. } => Some(node.hash(&self.hash_ops, None)),
+                Node::Filled { hashes, .. } => Some(hashes[hashes.len() - 1]),
+                _ => None,
+            })
+            .filter(|&h| h != empty)
+            .collect();
+        hashes.sort_by_cached_key(|h| h.to_bytes());
+        let inputs: Vec<F> = hashes.iter().flat_map(|h| h.to_vec()).collect();
+        self.hash_ops.hash_no_pad::<F, H>(&inputs)
+    }
+
+    /// Proves every set leaf at once. All sibling hashes are stored once in the returned arena,
+    /// sharing both stored siblings and zero hashes between proofs, and each [`ProofRef`] lists
+    /// the arena indices of its siblings in the order [`Self::prove`] would return them.
+    pub fn prove_all_arena(&self) -> (Vec<H::Hash>, Vec<ProofRef>) {
+        let mut arena = vec![];
+        // Stored siblings are keyed by their path, empty ones by their depth.
+        let mut indices: HashMap<Result<Path, usize>, usize> = HashMap::new();
+        let mut proofs = vec![];
+        for leaf_path in self.leaf_paths() {
+            let mut siblings = vec![];
+            for path in Self::sibling_paths(&leaf_path) {
+                let hash = self.get_node_hash(path);
+                let key = if self.has_node(&path) {
+                    Ok(path)
+                } else {
+                    Err(path.len())
+                };
+                let next = arena.len();
+                let arena_index = *indices.entry(key).or_insert(next);
+                if arena_index == next {
+                    arena.push(hash);
+                }
+                siblings.push(arena_index);
+            }
+            proofs.push(ProofRef {
+                path: leaf_path.to_bits(),
+                siblings,
+            });
+        }
+        (arena, proofs)
+    }
+
+    /// Proves that the leaf at `path` is empty, i.e. that it hashes like the empty leaf. Fails
+    /// with [`SmtError::LeafNotEmpty`] if it is set, see [`Self::verify_non_membership`].
+    pub fn prove_non_membership(&self, path: impl Into<Path>) -> SmtResult<MerkleProof<F, H>> {
+        let path: &Path = &path.into();
+        let empty = self.zero_hashes[self.zero_hashes.len() - 1];
+        let proof = self.try_prove(path)?;
+        if self.get_node_hash(path) != empty {
+            return Err(SmtError::LeafNotEmpty {
+                path: path.to_bits(),
+            });
+        }
+        Ok(proof)
+    }
+
+    /// Checks a proof from [`Self::prove_non_membership`], i.e. that `siblings` show the empty
+    /// value at `path` under `root`.
+    pub fn verify_non_membership(path: &[bool], siblings: &[H::Hash], root: H::Hash) -> bool {
+        Self::verify_borrowed(path, L::empty().elements(), siblings, root)
+    }
+
+    /// Proves that none of the leaves at `paths` is set, sharing the siblings between the paths:
+    /// a sibling that is itself on the way from a queried leaf to the root is left out, since
+    /// the verifier recomputes it. Fails with [`SmtError::LeafNotEmpty`] if a leaf is set.
+    pub fn prove_all_absent(&self, paths: &[Vec<bool>]) -> SmtResult<MultiNonInclusionProof<F, H>> {
+        let empty = self.zero_hashes[self.zero_hashes.len() - 1];
+        for path in paths {
+            assert_eq!(path.len(), self.height);
+            if self.get_node_hash(path) != empty {
+                return Err(SmtError::LeafNotEmpty { path: path.clone() });
+            }
+        }
+        let (paths, siblings) = self.shared_siblings(paths);
+        Ok(MultiNonInclusionProof { paths, siblings })
+    }
+
+    /// Proves the leaves at `paths` together, sharing the siblings between them as in
+    /// [`Self::prove_all_absent`]. See [`Self::verify_multi`].
+    pub fn prove_multi(&self, paths: &[Vec<bool>]) -> SparseMerkleMultiProof<F, H> {
+        for path in paths {
+            assert_eq!(path.len(), self.height);
+        }
+        let (paths, siblings) = self.shared_siblings(paths);
+        SparseMerkleMultiProof { paths, siblings }
+    }
+
+    /// The sorted, deduplicated `paths` along with the siblings needed to recompute the root
+    /// from them, level by level from the bottom and ordered by path within a level.
+    fn shared_siblings(&self, paths: &[Vec<bool>]) -> (Vec<Vec<bool>>, Vec<H::Hash>) {
+        let mut level: BTreeSet<Vec<bool>> = paths.iter().cloned().collect();
+        let queried = level.iter().cloned().collect();
+        let mut siblings = vec![];
+        while level.first().map_or(false, |path| !path.is_empty()) {
+            let parents = Self::parents(&level);
+            for parent in &parents {
+                for child in Self::child_paths(parent) {
+                    if !level.contains(&child) {
+                        siblings.push(self.get_node_hash(&child));
+                    }
+                }
+            }
+            level = parents;
+        }
+        (queried, siblings)
+    }
+
+    /// Checks a [`MultiNonInclusionProof`] against `root`. Only for trees without level tags.
+    pub fn verify_all_absent(proof: &MultiNonInclusionProof<F, H>, root: H::Hash) -> bool {
+        let empty = H::hash_or_noop(L::empty().elements());
+        let leaves = proof
+            .paths
+            .iter()
+            .map(|path| (path.clone(), empty))
+            .collect();
+        Self::verify_shared(leaves, &proof.siblings, root)
+    }
+
+    /// Checks `proof` from [`Self::prove_multi`] for the leaves holding `values`, given in the
+    /// order of `proof.paths`, against `root`. Like [`Self::verify_borrowed`], it assumes a tree
+    /// without level tags.
+    pub fn verify_multi(proof: &SparseMerkleMultiProof<F, H>, values: &[L], root: H::Hash) -> bool {
+        if values.len() != proof.paths.len() || proof.paths.windows(2).any(|w| w[0] >= w[1]) {
+            return false;
+        }
+        let leaves = proof
+            .paths
+            .iter()
+            .zip(values)
+            .map(|(path, value)| (path.clone(), H::hash_or_noop(value.elements())))
+            .collect();
+        Self::verify_shared(leaves, &proof.siblings, root)
+    }
+
+    /// Folds leaf hashes and the siblings from [`Self::shared_siblings`] up to the root and
+    /// compares it with `root`. With no leaves there is nothing to prove and any root is accepted.
+    fn verify_shared(
+        mut level: BTreeMap<Vec<bool>, H::Hash>,
+        siblings: &[H::Hash],
+        root: H::Hash,
+    ) -> bool {
+        let Some(height) = level.keys().next().map(Vec::len) else {
+            return true;
+        };
+        if height % Self::LEVEL_BITS != 0 || level.keys().any(|p| p.len() != height) {
+            return false;
+        }
+        let mut siblings = siblings.iter();
+        for _ in 0..height / Self::LEVEL_BITS {
+            let parents = Self::parents(&level.keys().cloned().collect());
+            let mut next = BTreeMap::new();
+            for parent in parents {
+                let mut children = [root; A];
+                for (i, child) in Self::child_paths(&parent).iter().enumerate() {
+                    children[i] = match level.get(child).or_else(|| siblings.next()) {
+                        Some(&hash) => hash,
+                        None => return false,
+                    };
+                }
+                next.insert(parent, hash_children::<F, H, A>(&children, None));
+            }
+            level = next;
+        }
+        siblings.next().is_none() && level.get(&vec![]) == Some(&root)
+    }
+
+    /// The distinct parents of the nodes at `paths`, which all have the same length.
+    fn parents(paths: &BTreeSet<Vec<bool>>) -> BTreeSet<Vec<bool>> {
+        paths
+            .iter()
+            .map(|path| path[..path.len() - Self::LEVEL_BITS].to_vec())
+            .collect()
+    }
+
+    /// The paths of the `A` children of the node at `path`, ordered by child index.
+    fn child_paths(path: &[bool]) -> Vec<Vec<bool>> {
+        (0..A)
+            .map(|i| {
+                let mut child = path.to_vec();
+                child.extend(index_to_bits(i, Self::LEVEL_BITS));
+                child
+            })
+            .collect()
+    }
+
+    /// Paths of the siblings of every node on `path`, in the order of [`Self::prove`].
+    fn sibling_paths(path: &Path) -> Vec<Path> {
+        let mut path = *path;
+        let mut siblings = vec![];
+        while !path.is_empty() {
+            let parent_len = path.len() - Self::LEVEL_BITS;
+            let index = path.index_at(parent_len, Self::LEVEL_BITS);
+            for i in (0..A).filter(|&i| i != index) {
+                let mut sibling = path.prefix(parent_len);
+                sibling.extend(index_to_bits(i, Self::LEVEL_BITS));
+                siblings.push(sibling);
+            }
+            path.truncate(parent_len);
+        }
+        siblings
+    }
+
+    /// Like [`Self::prove`], but pairs each sibling with whether it is an implied zero hash
+    /// rather than a stored node, so that a compressor can drop the zero ones.
+    pub fn prove_annotated(&self, path: impl Into<Path>) -> Vec<(H::Hash, bool)> {
+        let path: &Path = &path.into();
+        assert_eq!(path.len(), self.height);
+        Self::sibling_paths(path)
+            .iter()
+            .map(|sibling| match self.stored_node_hash(sibling) {
+                Some(hash) => (hash, false),
+                None => (self.get_node_hash(sibling), true),
+            })
+            .collect()
+    }
+
+    /// Describes every node on `path` from the leaf up to just below the root: its position
+    /// within its level, its hash and its siblings, for tools that render where a leaf sits.
+    pub fn full_path_info(&self, path: impl Into<Path>) -> PathInfo<F, H> {
+        let path: &Path = &path.into();
+        assert_eq!(path.len(), self.height);
+        let mut path = *path;
+        let mut levels = vec![];
+        while !path.is_empty() {
+            levels.push(PathLevel {
+                index: path.index_at(0, path.len()),
+                hash: self.get_node_hash(path),
+                siblings: self.get_sibling_hashes(path),
+            });
+            path.truncate(path.len() - Self::LEVEL_BITS);
+        }
+        PathInfo {
+            levels,
+            root: self.get_root(),
+        }
+    }
+
+    /// Returns the hash of every node on `path`, from the leaf up to the root, for tracking down
+    /// where a computed root diverges from an expected one.
+    pub fn leaf_path_hashes(&self, path: impl Into<Path>) -> Vec<H::Hash> {
+        let path: &Path = &path.into();
+        assert_eq!(path.len(), self.height);
+        (0..=self.height)
+            .rev()
+            .step_by(Self::LEVEL_BITS)
+            .map(|len| self.get_node_hash(path.prefix(len)))
+            .collect()
+    }
+
+    /// The hash of the leaf at `path`, which is what proofs fold up from.
+    pub fn get_leaf_hash(&self, path: impl Into<Path>) -> H::Hash {
+        let path: &Path = &path.into();
+        assert_eq!(path.len(), self.height);
+        self.get_node_hash(path)
+    }
+
+    /// Like [`Self::prove`], but returns the leaf hash in place of the leaf value, see
+    /// [`Self::verify_hash_only`].
+    pub fn prove_hash_only(&self, path: impl Into<Path>) -> (H::Hash, Vec<H::Hash>) {
+        let path: &Path = &path.into();
+        (self.get_leaf_hash(path), self.prove(path).siblings)
+    }
+
+    /// The size in bytes of the siblings of a full proof for `path`, at `H::HASH_SIZE` each.
+    pub fn proof_size_bytes(&self, path: impl Into<Path>) -> usize {
+        let path: &Path = &path.into();
+        assert_eq!(path.len(), self.height);
+        Self::sibling_paths(path).len() * H::HASH_SIZE
+    }
+
+    /// The size in bytes of a proof for `path` that omits the implied zero siblings, as flagged
+    /// by [`Self::prove_annotated`]: a bitmap with one bit per sibling followed by the
+    /// non-zero siblings.
+    pub fn compressed_proof_size_bytes(&self, path: impl Into<Path>) -> usize {
+        let path: &Path = &path.into();
+        let siblings = self.prove_annotated(path);
+        let stored = siblings.iter().filter(|(_, is_zero)| !is_zero).count();
+        (siblings.len() + 7) / 8 + stored * H::HASH_SIZE
+    }
+
+    /// Returns the hash of the node `depth` bits down `path` along with the siblings that fold
+    /// it up to the root, bottom-up as in [`Self::prove`]. The counterpart of proving against
+    /// a cap with [`Self::prove_in_subtree`] for verifiers that only check the top levels.
+    pub fn prove_to_depth(&self, path: impl Into<Path>, depth: usize) -> (H::Hash, Vec<H::Hash>) {
+        let path: &Path = &path.into();
+        assert!(depth <= path.len());
+        assert_eq!(depth % Self::LEVEL_BITS, 0);
+        let mut node = path.prefix(depth);
+        let hash = self.get_node_hash(node);
+        let mut siblings = vec![];
+        while !node.is_empty() {
+            siblings.extend(self.get_sibling_hashes(node));
+            node.truncate(node.len() - Self::LEVEL_BITS);
+        }
+        (hash, siblings)
+    }
+
+    /// Computes the root the tree would have if every leaf under `prefix` were deleted, by
+    /// folding the empty subtree hash up with the siblings of `prefix`.
+    pub fn root_if_subtree_zeroed(&self, prefix: impl Into<Path>) -> H::Hash {
+        let prefix: &Path = &prefix.into();
+        assert!(prefix.len() <= self.height);
+        let (_, siblings) = self.prove_to_depth(prefix, prefix.len());
+        let zero = self.zero_hashes[prefix.len() / Self::LEVEL_BITS];
+        Self::fold_hash(&prefix.to_bits(), zero, &siblings, self.level_tags)
+    }
+
+    /// Proves the leaf at `path` and encodes the siblings directly as their concatenated
+    /// `to_bytes` encodings, `H::HASH_SIZE` bytes each, bottom-up.
+    pub fn prove_wire(&self, path: impl Into<Path>) -> Vec<u8> {
+        let path: &Path = &path.into();
+        assert_eq!(path.len(), self.height);
+        let siblings = Self::sibling_paths(path);
+        let mut bytes = Vec::with_capacity(siblings.len() * H::HASH_SIZE);
+        for sibling in &siblings {
+            bytes.extend(self.get_node_hash(sibling).to_bytes());
+        }
+        bytes
+    }
+
+    /// Checks a proof encoded by [`Self::prove_wire`]. Only for trees without level tags.
+    pub fn verify_wire(bytes: &[u8], path: &[bool], value: &[F], root: H::Hash) -> bool {
+        if bytes.len() % H::HASH_SIZE != 0 {
+            return false;
+        }
+        let siblings: Vec<H::Hash> = bytes
+            .chunks(H::HASH_SIZE)
+            .map(H::Hash::from_bytes)
+            .collect();
+        Self::verify_borrowed(path, value, &siblings, root)
+    }
+
+    /// Proves `paths` with the siblings above their deepest common ancestor factored out. Returns
+    /// those upper siblings once, followed by the siblings below the common ancestor for each
+    /// path, both bottom-up. Appending the upper siblings to a path's lower siblings gives its
+    /// proof from [`Self::prove`].
+    pub fn prove_with_shared_suffix(
+        &self,
+        paths: &[Vec<bool>],
+    ) -> (Vec<H::Hash>, Vec<Vec<H::Hash>>) {
+        let Some(first) = paths.first() else {
+            return (vec![], vec![]);
+        };
+        let common = paths.iter().fold(self.height, |common, path| {
+            assert_eq!(path.len(), self.height);
+            common.min(first.iter().zip(path).take_while(|(a, b)| a == b).count())
+        });
+        let depth = common - common % Self::LEVEL_BITS;
+        let (_, shared) = self.prove_to_depth(first, depth);
+        let lower = paths
+            .iter()
+            .map(|path| {
+                let mut node = path.clone();
+                let mut siblings = vec![];
+                while node.len() > depth {
+                    siblings.extend(self.get_sibling_hashes(&node));
+                    node.truncate(node.len() - Self::LEVEL_BITS);
+                }
+                siblings
+            })
+            .collect();
+        (shared, lower)
+    }
+
+    /// Proves each of the leaves at `paths`, in order.
+    pub fn prove_batch(&self, paths: &[Vec<bool>]) -> Vec<MerkleProof<F, H>> {
+        self.proofs_iter(paths).collect()
+    }
+
+    /// Like [`Self::prove_batch`], but computes each proof only when the iterator reaches it,
+    /// so exporting many proofs needs memory for just one at a time.
+    pub fn proofs_iter<'a>(
+        &'a self,
+        paths: &'a [Vec<bool>],
+    ) -> impl Iterator<Item = MerkleProof<F, H>> + 'a {
+        paths.iter().map(|path| self.prove(path))
+    }
+
+    /// Like [`Self::prove`], but tags the proof with the tree's height.
+    pub fn prove_with_height(&self, path: impl Into<Path>) -> MerkleProofWithHeight<F, H> {
+        let path: &Path = &path.into();
+        MerkleProofWithHeight {
+            height: self.height,
+            proof: self.prove(path),
+        }
+    }
+
+    /// Checks that `proof` shows `value` stored at `path` in a tree of this shape with `root`,
+    /// telling a proof shaped for a different tree apart from one for a different value.
+    pub fn verify(
+        &self,
+        path: impl Into<Path>,
+        value: &[F],
+        root: H::Hash,
+        proof: &MerkleProof<F, H>,
+    ) -> VerifyResult {
+        let path: &Path = &path.into();
+        let levels = self.height / Self::LEVEL_BITS;
+        if path.len() != self.height || proof.siblings.len() != levels * (A - 1) {
+            VerifyResult::WrongLength
+        } else if Self::fold_proof(&path.to_bits(), value, &proof.siblings, self.level_tags) == root
+        {
+            VerifyResult::Valid
+        } else {
+            VerifyResult::WrongValue
+        }
+    }
+
+    /// Like [`Self::verify`], but borrows the siblings, e.g. from an arena shared by many proofs,
+    /// and takes the height from `path`. Only for trees without level tags.
+    pub fn verify_borrowed(
+        path: &[bool],
+        value: &[F],
+        siblings: &[H::Hash],
+        root: H::Hash,
+    ) -> bool {
+        let levels = path.len() / Self::LEVEL_BITS;
+        if path.len() % Self::LEVEL_BITS != 0 || siblings.len() != levels * (A - 1) {
+            return false;
+        }
+        Self::fold_proof(path, value, siblings, false) == root
+    }
+
+    /// Like [`Self::verify_borrowed`], but also rejects values that hash like the empty leaf, so
+    /// that a proof of absence can't be passed off as membership. The leaf hashes are compared
+    /// rather than the values because short values are padded: `[0]` hashes like `[0, 0, 0, 0]`.
+    pub fn verify_membership(
+        path: &[bool],
+        value: &[F],
+        siblings: &[H::Hash],
+        root: H::Hash,
+    ) -> bool {
+        H::hash_or_noop(value) != H::hash_or_noop(L::empty().elements())
+            && Self::verify_borrowed(path, value, siblings, root)
+    }
+
+    /// Checks that `value` is a leaf somewhere under `prefix`, given proofs that carry siblings
+    /// but not the leaf's remaining path bits. Accepts if any candidate verifies like
+    /// [`Self::verify_borrowed`] for some choice of the hidden bits, so a prover may mix the real
+    /// proof in with decoys. The height is taken from the length of each proof.
+    ///
+    /// This is a best-effort check for a few hidden bits, not a hiding proof. Siblings can't be
+    /// folded without knowing which side the path takes, so every choice of the hidden bits is
+    /// tried: the cost doubles with each hidden bit, candidates hiding more than
+    /// [`Self::MAX_HIDDEN_BITS`] are rejected, and the choice that matches tells the verifier the
+    /// bits the proofs left out. To keep them private, or to hide more of them, prove membership
+    /// with [`crate::circuit::verify_smt_prefix_membership_circuit`] instead, which takes the
+    /// whole path as a private witness.
+    pub fn verify_under_prefix(
+        prefix: &[bool],
+        value: &[F],
+        candidate_proofs: &[MerkleProof<F, H>],
+        root: H::Hash,
+    ) -> bool {
+        candidate_proofs.iter().any(|proof| {
+            let height = proof.siblings.len() / (A - 1) * Self::LEVEL_BITS;
+            if prefix.len() > height {
+                return false;
+            }
+            let hidden = height - prefix.len();
+            if hidden > Self::MAX_HIDDEN_BITS {
+                return false;
+            }
+            let mut path = prefix.to_vec();
+            (0..1u64 << hidden).any(|suffix| {
+                path.truncate(prefix.len());
+                path.extend((0..hidden).rev().map(|i| (suffix >> i) & 1 == 1));
+                Self::verify_borrowed(&path, value, &proof.siblings, root)
+            })
+        })
+    }
+
+    /// Like [`Self::verify_borrowed`], but starts from the hash of the leaf rather than its
+    /// value, for verifiers that computed the leaf hash themselves.
+    pub fn verify_hash_only(
+        leaf_hash: H::Hash,
+        path: &[bool],
+        siblings: &[H::Hash],
+        root: H::Hash,
+    ) -> bool {
+        let levels = path.len() / Self::LEVEL_BITS;
+        if path.len() % Self::LEVEL_BITS != 0 || siblings.len() != levels * (A - 1) {
+            return false;
+        }
+        Self::fold_hash(path, leaf_hash, siblings, false) == root
+    }
+
+    /// Folds `value` at `path` with `siblings` up to the root. The shapes must already match.
+    fn fold_proof(path: &[bool], value: &[F], siblings: &[H::Hash], level_tags: bool) -> H::Hash {
+        Self::fold_hash(path, H::hash_or_noop(value), siblings, level_tags)
+    }
+
+    /// Like [`Self::fold_proof`], starting from the leaf hash `h`.
+    fn fold_hash(path: &[bool], mut h: H::Hash, siblings: &[H::Hash], level_tags: bool) -> H::Hash {
+        let levels = path.len() / Self::LEVEL_BITS;
+        let level_paths = path.chunks(Self::LEVEL_BITS).rev();
+        for (depth, (bits, siblings)) in (0..levels)
+            .rev()
+            .zip(level_paths.zip(siblings.chunks(A - 1)))
+        {
+            let index = bits_to_index(bits);
+            let mut siblings = siblings.iter();
+            let children = core::array::from_fn(|i| {
+                if i == index {
+                    h
+                } else {
+                    *siblings.next().unwrap()
+                }
+            });
+            h = hash_children::<F, H, A>(&children, level_tags.then_some(depth));
+        }
+        h
+    }
+}
+
+/// Trees are equal when they have the same height and root, regardless of which nodes happen
+/// to be stored, their dirty sets or their history.
+impl<F, H, const A: usize, L, M> PartialEq for SparseMerkleTree<F, H, A, L, M>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>>,
+{
+    fn eq(&self, other: &Self) -> bool {
+        self.height == other.height && self.get_root() == other.get_root()
+    }
+}
+
+impl<F, H, const A: usize, L, M> Eq for SparseMerkleTree<F, H, A, L, M>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>>,
+{
+}
+
+/// Hashes the height and root only, consistently with `PartialEq`, so the layout of the node
+/// map is intentionally ignored.
+impl<F, H, const A: usize, L, M> std::hash::Hash for SparseMerkleTree<F, H, A, L, M>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>>,
+{
+    fn hash<S: std::hash::Hasher>(&self, state: &mut S) {
+        self.height.hash(state);
+        self.get_root().to_bytes().hash(state);
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl<F, H, const A: usize, L, M> SparseMerkleTree<F, H, A, L, M>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F> + Send + Sync,
+    M: MerkleNodeMap<Node<F, H, A, L>> + Send + Sync,
+{
+    /// Like [`Self::from_leaves`], but hashes the nodes of each level on the rayon thread pool.
+    pub fn from_leaves_par(
+        height: usize,
+        leaves: impl IntoIterator<Item = (Vec<bool>, L)>,
+    ) -> Self {
+        use rayon::prelude::*;
+
+        Self::build_from_leaves(height, leaves, |tree, groups| {
+            groups
+                .par_iter()
+                .map(|group| tree.hash_group(group))
+                .collect()
+        })
+    }
+
+    /// Checks on the rayon thread pool that every `(path, value, proof)` in `items` verifies
+    /// against the current root.
+    pub fn verify_batch_par(&self, items: &[(Vec<bool>, L, MerkleProof<F, H>)]) -> bool {
+        use rayon::prelude::*;
+
+        let root = self.get_root();
+        items
+            .par_iter()
+            .all(|(path, value, proof)| self.verify(path, value.elements(), root, proof).is_valid())
+    }
+}
+
+impl<F, H, L, M> SparseMerkleTree<F, H, 2, L, M>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, 2, L>>,
+{
+    /// Like [`Self::verify_borrowed`] with the height fixed at compile time, so the path and
+    /// siblings are folded from stack arrays without allocating. Only for trees without level
+    /// tags.
+    pub fn verify_const<const H_LEN: usize>(
+        path: &[bool; H_LEN],
+        value: &[F],
+        siblings: &[H::Hash; H_LEN],
+        root: H::Hash,
+    ) -> bool {
+        let mut h = H::hash_or_noop(value);
+        for (&bit, &sibling) in path.iter().rev().zip(siblings) {
+            h = if bit {
+                H::two_to_one(sibling, h)
+            } else {
+                H::two_to_one(h, sibling)
+            };
+        }
+        h == root
+    }
+
+    pub fn get_sibling_hash(&self, path: impl Into<Path>) -> H::Hash {
+        let mut path: Path = path.into();
+        assert!(!path.is_empty());
+        let last = path.len() - 1;
+        path.set(last, !path[last]);
+        self.get_node_hash(path)
+    }
+
+    /// Returns the `2^cap_height` node hashes `cap_height` levels below the root, ordered by
+    /// index, in plonky2's `MerkleCap` convention. Fails with [`SmtError::InvalidLevel`] if
+    /// `cap_height` exceeds the height, or with [`SmtError::CapTooLarge`] above
+    /// [`Self::MAX_CAP_HEIGHT`].
+    pub fn get_cap(&self, cap_height: usize) -> SmtResult<MerkleCap<F, H>> {
+        self.check_cap_height(cap_height)?;
+        if let Some((cached_height, cap)) = &self.cap {
+            if *cached_height == cap_height {
+                return Ok(MerkleCap(cap.clone()));
+            }
+        }
+        Ok(MerkleCap(
+            (0..1usize << cap_height)
+                .map(|i| self.get_node_hash(index_to_bits(i, cap_height).collect::<Path>()))
+                .collect(),
+        ))
+    }
+
+    /// Proves the leaf at `path` against the cap at `cap_height` rather than the root. Returns
+    /// the index of the leaf's subtree in the cap along with the siblings from the leaf up to the
+    /// cap, so the pair can be checked with plonky2's `verify_merkle_proof_to_cap`.
+    pub fn prove_in_subtree(
+        &self,
+        cap_height: usize,
+        path: impl Into<Path>,
+    ) -> (usize, MerkleProof<F, H>) {
+        let path: &Path = &path.into();
+        assert!(cap_height <= self.height);
+        let mut proof = self.prove(path);
+        proof.siblings.truncate(self.height - cap_height);
+        (path.index_at(0, cap_height), proof)
+    }
+
+    /// Expands the tree into a dense plonky2 `MerkleTree` with the given cap height, so it can
+    /// back plonky2's commitments such as FRI openings. Leaf `i` of the result is the leaf whose
+    /// path is `i` in big-endian bits, which makes its cap equal to [`Self::get_cap`] and its
+    /// proofs equal to those of [`Self::prove_in_subtree`].
+    ///
+    /// All `2^height` leaves are materialised, so this is only viable for small heights. Panics
+    /// if the tree uses level tags or holds leaves known only by their hash.
+    pub fn to_plonky2_merkle_tree(&self, cap_height: usize) -> MerkleTree<F, H> {
+        assert!(!self.level_tags);
+        assert!(self.height < usize::BITS as usize);
+        let mut leaves = vec![self.default_leaf.elements().to_vec(); 1 << self.height];
+        for (path, node) in self.leaf_nodes() {
+            match node {
+                Node::Leaf { value } | Node::Filled { value, .. } => {
+                    leaves[path.index_at(0, path.len())] = value.elements().to_vec()
+                }
+                Node::Digest { .. } => {
+                    panic!("leaf {path:?} is only known by its hash")
+                }
+                _ => {}
+            }
+        }
+        MerkleTree::new(leaves, cap_height)
+    }
+
+    /// Proves the leaf at `path` as a [`SparseMerkleProof`] carrying the path, the value and the
+    /// current root along with the siblings. Fails with [`SmtError::LeafHashOnly`] if only the
+    /// leaf's hash is known. Only for trees without level tags.
+    pub fn prove_leaf(&self, path: impl Into<Path>) -> SmtResult<SparseMerkleProof<F, H>> {
+        let path: &Path = &path.into();
+        Ok(SparseMerkleProof {
+            path: path.to_bits(),
+            value: self.known_leaf(path)?.elements().to_vec(),
+            siblings: self.prove(path).siblings,
+            root: Some(self.get_root()),
+        })
+    }
+
+    /// Returns the `(left, right)` child hashes of the inner node at `path`, whether stored or
+    /// implied by empty subtrees.
+    pub fn children(&self, path: impl Into<Path>) -> (H::Hash, H::Hash) {
+        let path: &Path = &path.into();
+        let [left, right] = self.get_children_hashes(path);
+        (left, right)
+    }
+}
+
+/// A Merkle proof that carries the height of the tree it was generated from, so that a verifier
+/// working with paths of a different length rejects it instead of folding to a wrong root.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MerkleProofWithHeight<F: RichField, H: Hasher<F>> {
+    pub height: usize,
+    pub proof: MerkleProof<F, H>,
+}
+
+impl<F: RichField, H: Hasher<F>> MerkleProofWithHeight<F, H> {
+    /// Verifies a proof from a binary tree, checking `path` and the siblings against the
+    /// embedded height first.
+    pub fn verify(&self, path: &[bool], value: &[F], root: H::Hash) -> VerifyResult {
+        if path.len() != self.height || self.proof.siblings.len() != self.height {
+            VerifyResult::WrongLength
+        } else if SparseMerkleTree::<F, H>::verify_borrowed(path, value, &self.proof.siblings, root)
+        {
+            VerifyResult::Valid
+        } else {
+            VerifyResult::WrongValue
+        }
+    }
+}
+
+/// A proof from a binary tree that carries what it proves: the leaf path and value, and
+/// optionally the root it was generated against. See [`SparseMerkleTree::prove_leaf`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound = "")
+)]
+pub struct SparseMerkleProof<F: RichField, H: Hasher<F>> {
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::compact_path"))]
+    pub path: Vec<bool>,
+    pub value: Vec<F>,
+    /// Siblings from the bottom level up, as in [`MerkleProof`].
+    pub siblings: Vec<H::Hash>,
+    pub root: Option<H::Hash>,
+}
+
+impl<F: RichField, H: Hasher<F>> SparseMerkleProof<F, H> {
+    /// Checks that the proof shows its value at its path under `root`. A proof that carries a
+    /// different root is rejected, even if its siblings happen to fold to `root`.
+    pub fn verify(&self, root: H::Hash) -> bool {
+        self.root.map_or(true, |own| own == root)
+            && SparseMerkleTree::<F, H>::verify_borrowed(
+                &self.path,
+                &self.value,
+                &self.siblings,
+                root,
+            )
+    }
+
+    /// The siblings as a plonky2 proof, e.g. for `verify_merkle_proof` or the circuit gadgets.
+    pub fn to_plonky2_proof(&self) -> MerkleProof<F, H> {
+        MerkleProof {
+            siblings: self.siblings.clone(),
+        }
+    }
+
+    /// Encodes the proof for verifiers outside of Rust. The format is stable, with every integer
+    /// big-endian:
+    ///
+    /// - the path length in bits as a `u32`, then the path packed by [`path_to_bytes`];
+    /// - the number of value elements as a `u32`, then each element as its canonical `u64`;
+    /// - the number of siblings as a `u32`, then each sibling bottom-up as `H::HASH_SIZE` bytes
+    ///   from `GenericHashOut::to_bytes`, i.e. four little-endian `u64`s for a `HashOut`;
+    /// - a byte that is 1 if a root follows, encoded like a sibling, and 0 otherwise.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        bytes.extend((self.path.len() as u32).to_be_bytes());
+        bytes.extend(path_to_bytes(&self.path));
+        bytes.extend((self.value.len() as u32).to_be_bytes());
+        for x in &self.value {
+            bytes.extend(x.to_canonical_u64().to_be_bytes());
+        }
+        bytes.extend((self.siblings.len() as u32).to_be_bytes());
+        for sibling in &self.siblings {
+            bytes.extend(sibling.to_bytes());
+        }
+        match self.root {
+            Some(root) => {
+                bytes.push(1);
+                bytes.extend(root.to_bytes());
+            }
+            None => bytes.push(0),
+        }
+        bytes
+    }
+
+    /// Decodes a proof from [`Self::to_bytes`]. Fails with [`SmtError::InvalidEncoding`] if the
+    /// input is truncated, has trailing bytes or holds a non-canonical path or element.
+    pub fn from_bytes(bytes: &[u8]) -> SmtResult<Self> {
+        let mut rest = bytes;
+        let mut take = |n: usize| -> SmtResult<&[u8]> {
+            if rest.len() < n {
+                return Err(SmtError::InvalidEncoding);
+            }
+            let (head, tail) = rest.split_at(n);
+            rest = tail;
+            Ok(head)
+        };
+        let read_u32 = |b: &[u8]| u32::from_be_bytes(b.try_into().unwrap()) as usize;
+
+        let len = read_u32(take(4)?);
+        let path = path_from_bytes(take((len + 7) / 8)?, len)?;
+        let width = read_u32(take(4)?);
+        let value = (0..width)
+            .map(|_| match u64::from_be_bytes(take(8)?.try_into().unwrap()) {
+                x if x < F::ORDER => Ok(F::from_canonical_u64(x)),
+                _ => Err(SmtError::InvalidEncoding),
+            })
+            .collect::<SmtResult<Vec<F>>>()?;
+        let count = read_u32(take(4)?);
+        let siblings = (0..count)
+            .map(|_| Ok(H::Hash::from_bytes(take(H::HASH_SIZE)?)))
+            .collect::<SmtResult<Vec<_>>>()?;
+        let root = match take(1)?[0] {
+            0 => None,
+            1 => Some(H::Hash::from_bytes(take(H::HASH_SIZE)?)),
+            _ => return Err(SmtError::InvalidEncoding),
+        };
+        if !rest.is_empty() {
+            return Err(SmtError::InvalidEncoding);
+        }
+        Ok(Self {
+            path,
+            value,
+            siblings,
+            root,
+        })
+    }
+}
+
+/// A batch of leaf updates together with the sibling hashes needed to recompute the root, see
+/// [`SparseMerkleTree::update_delta`].
+#[derive(Clone, Debug)]
+pub struct StateDelta<F: RichField, H: Hasher<F>, L: LeafValue<F> = Vec<F>> {
+    pub height: usize,
+    /// `(path, old value, new value)` for each touched leaf, sorted by path.
+    pub leaves: Vec<(Vec<bool>, L, L)>,
+    /// Hashes of the non-empty subtrees adjacent to the touched paths, sorted by path. Siblings
+    /// that are omitted are empty.
+    pub siblings: Vec<(Vec<bool>, H::Hash)>,
+}
+
+/// The proofs of a block of leaf updates, see [`SparseMerkleTree::prove_block`].
+#[derive(Clone, Debug)]
+pub struct BlockProof<F: RichField, H: Hasher<F>, L: LeafValue<F> = Vec<F>> {
+    pub old_root: H::Hash,
+    pub new_root: H::Hash,
+    /// `(path, old value, proof)` for each update in order, where the proof is taken against the
+    /// root left by the previous update.
+    pub steps: Vec<(Vec<bool>, L, MerkleProof<F, H>)>,
+}
+
+/// Folds a proof one sibling at a time, bottom-up, so that the siblings can be consumed from a
+/// stream instead of being held in memory. Expects the sibling order of
+/// [`SparseMerkleTree::prove`] and a tree without level tags.
+#[derive(Clone, Debug)]
+pub struct ProofFolder<F: RichField, H: Hasher<F>, const A: usize = 2> {
+    path: Vec<bool>,
+    /// Hash of the node at the current level on the path.
+    current: H::Hash,
+    /// Siblings received for the level above the current node.
+    pending: Vec<H::Hash>,
+}
+
+impl<F: RichField, H: Hasher<F>, const A: usize> ProofFolder<F, H, A> {
+    const LEVEL_BITS: usize = A.trailing_zeros() as usize;
+
+    pub fn new(path: &[bool], value: &[F]) -> Self {
+        assert_eq!(path.len() % Self::LEVEL_BITS, 0);
+        Self {
+            path: path.to_vec(),
+            current: H::hash_or_noop(value),
+            pending: Vec::with_capacity(A - 1),
+        }
+    }
+
+    /// Consumes the next sibling, moving up a level once all `A - 1` siblings of the current
+    /// node have been seen.
+    pub fn push_sibling(&mut self, hash: H::Hash) {
+        assert!(
+            !self.path.is_empty(),
+            "all siblings have already been pushed"
+        );
+        self.pending.push(hash);
+        if self.pending.len() < A - 1 {
+            return;
+        }
+        let parent_len = self.path.len() - Self::LEVEL_BITS;
+        let index = bits_to_index(&self.path[parent_len..]);
+        let mut siblings = self.pending.drain(..);
+        let children = core::array::from_fn(|i| {
+            if i == index {
+                self.current
+            } else {
+                siblings.next().unwrap()
+            }
+        });
+        self.current = hash_children::<F, H, A>(&children, None);
+        self.path.truncate(parent_len);
+    }
+
+    /// Returns the root the proof folds to. Panics if siblings are still missing.
+    pub fn finish(self) -> H::Hash {
+        assert!(self.path.is_empty() && self.pending.is_empty());
+        self.current
+    }
+}
+
+/// A proof folded once into the root it implies, so it can be checked against roots that are only
+/// known later, or against several candidate roots, without refolding. Like
+/// [`SparseMerkleTree::verify_borrowed`], it assumes a tree without level tags.
+#[derive(Clone, Debug)]
+pub struct PreparedVerification<F: RichField, H: Hasher<F>, const A: usize = 2> {
+    /// `None` if the proof is malformed and matches no root.
+    root: Option<H::Hash>,
+}
+
+impl<F: RichField, H: Hasher<F>, const A: usize> PreparedVerification<F, H, A> {
+    pub fn new(path: &[bool], value: &[F], siblings: &[H::Hash]) -> Self {
+        let level_bits = A.trailing_zeros() as usize;
+        let root = (path.len() % level_bits == 0
+            && siblings.len() == path.len() / level_bits * (A - 1))
+            .then(|| SparseMerkleTree::<F, H, A>::fold_proof(path, value, siblings, false));
+        Self { root }
+    }
+
+    /// The root the proof folds to, or `None` if the proof is malformed.
+    pub fn root(&self) -> Option<H::Hash> {
+        self.root
+    }
+
+    pub fn matches(&self, root: H::Hash) -> bool {
+        self.root == Some(root)
+    }
+}
+
+/// A shared borrow of a tree that only exposes queries, for handing the tree to code that must
+/// not modify it. Unlike `&SparseMerkleTree`, it doesn't give access to the public fields either,
+/// and there is no way back to the tree from it.
+///
+/// ```compile_fail
+/// use my_smt::sparse_merkle_tree::SparseMerkleTree;
+/// use plonky2::{field::goldilocks_field::GoldilocksField, hash::poseidon::PoseidonHash};
+///
+/// let tree = SparseMerkleTree::<GoldilocksField, PoseidonHash>::new(4);
+/// let view = tree.read_only();
+/// view.update(&vec![false; 4], vec![]);
+/// ```
+pub struct ReadOnlySmt<
+    'a,
+    F: RichField,
+    H: Hasher<F>,
+    const A: usize = 2,
+    L: LeafValue<F> = Vec<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>> = HashMap<Path, Node<F, H, A, L>>,
+> {
+    tree: &'a SparseMerkleTree<F, H, A, L, M>,
+}
+
+impl<'a, F, H, const A: usize, L, M> Clone for ReadOnlySmt<'a, F, H, A, L, M>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>>,
+{
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<'a, F, H, const A: usize, L, M> Copy for ReadOnlySmt<'a, F, H, A, L, M>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>>,
+{
+}
+
+impl<'a, F, H, const A: usize, L, M> ReadOnlySmt<'a, F, H, A, L, M>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>>,
+{
+    pub fn height(&self) -> usize {
+        self.tree.height
+    }
+
+    pub fn get_leaf(&self, path: impl Into<Path>) -> L {
+        let path: &Path = &path.into();
+        self.tree.get_leaf(path)
+    }
+
+    pub fn try_get_leaf(&self, path: impl Into<Path>) -> SmtResult<Option<L>> {
+        let path: &Path = &path.into();
+        self.tree.try_get_leaf(path)
+    }
+
+    pub fn get_leaves(&self, paths: &[Vec<bool>]) -> Vec<Option<L>> {
+        self.tree.get_leaves(paths)
+    }
+
+    pub fn get_node_hash(&self, path: impl Into<Path>) -> H::Hash {
+        let path: &Path = &path.into();
+        self.tree.get_node_hash(path)
+    }
+
+    pub fn get_root(&self) -> H::Hash {
+        self.tree.get_root()
+    }
+
+    pub fn prove(&self, path: impl Into<Path>) -> MerkleProof<F, H> {
+        let path: &Path = &path.into();
+        self.tree.prove(path)
+    }
+
+    pub fn try_prove(&self, path: impl Into<Path>) -> SmtResult<MerkleProof<F, H>> {
+        let path: &Path = &path.into();
+        self.tree.try_prove(path)
+    }
+
+    pub fn verify(
+        &self,
+        path: impl Into<Path>,
+        value: &[F],
+        root: H::Hash,
+        proof: &MerkleProof<F, H>,
+    ) -> VerifyResult {
+        let path: &Path = &path.into();
+        self.tree.verify(path, value, root, proof)
+    }
+
+    pub fn count_under(&self, prefix: impl Into<Path>) -> usize {
+        let prefix: &Path = &prefix.into();
+        self.tree.count_under(prefix)
+    }
+
+    pub fn first_empty(&self) -> Option<u64> {
+        self.tree.first_empty()
+    }
+}
+
+/// Proves consecutive leaves of a tree one after the other. Moving to the next index only
+/// changes the siblings of the levels below the highest flipped bit, so on average just the
+/// bottom level's siblings are recomputed per step.
+#[derive(Debug)]
+pub struct WindowProver<
+    'a,
+    F: RichField,
+    H: Hasher<F>,
+    const A: usize = 2,
+    L: LeafValue<F> = Vec<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>> = HashMap<Path, Node<F, H, A, L>>,
+> {
+    tree: &'a SparseMerkleTree<F, H, A, L, M>,
+    index: u64,
+    path: Path,
+    siblings: Vec<H::Hash>,
+}
+
+impl<'a, F, H, const A: usize, L, M> WindowProver<'a, F, H, A, L, M>
+where
+    F: RichField,
+    H: Hasher<F>,
+    L: LeafValue<F>,
+    M: MerkleNodeMap<Node<F, H, A, L>>,
+{
+    const LEVEL_BITS: usize = A.trailing_zeros() as usize;
+
+    pub fn new(tree: &'a SparseMerkleTree<F, H, A, L, M>, index: u64) -> Self {
+        assert!(tree.height <= 64);
+        assert!(tree.height == 64 || index >> tree.height == 0);
+        let path: Path = (0..tree.height)
+            .rev()
+            .map(|i| (index >> i) & 1 == 1)
+            .collect();
+        let siblings = tree.prove(path).siblings;
+        Self {
+            tree,
+            index,
+            path,
+            siblings,
+        }
+    }
+
+    pub fn index(&self) -> u64 {
+        self.index
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The proof of the leaf at the current index.
+    pub fn proof(&self) -> MerkleProof<F, H> {
+        MerkleProof {
+            siblings: self.siblings.clone(),
+        }
+    }
+
+    /// Moves to the next index, recomputing only the siblings that change. Panics when moving
+    /// past the last leaf.
+    pub fn advance(&mut self) {
+        let height = self.tree.height;
+        let next = self.index + 1;
+        assert!(
+            height == 64 || next >> height == 0,
+            "advanced past the last leaf"
+        );
+        let highest_flipped = 63 - (self.index ^ next).leading_zeros() as usize;
+        for bit in 0..=highest_flipped {
+            self.path.set(height - 1 - bit, (next >> bit) & 1 == 1);
+        }
+        for level in 0..=highest_flipped / Self::LEVEL_BITS {
+            let node = self.path.prefix(height - level * Self::LEVEL_BITS);
+            let siblings = self.tree.get_sibling_hashes(node);
+            self.siblings[level * (A - 1)..(level + 1) * (A - 1)].copy_from_slice(&siblings);
+        }
+        self.index = next;
+    }
+}
+
+/// A proof whose siblings live in an arena shared with other proofs, see
+/// [`SparseMerkleTree::prove_all_arena`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProofRef {
+    pub path: Vec<bool>,
+    /// Indices of the siblings in the arena, from the bottommost level up.
+    pub siblings: Vec<usize>,
+}
+
+impl ProofRef {
+    /// Copies the siblings out of `arena` into a standalone proof.
+    pub fn to_proof<F: RichField, H: Hasher<F>>(&self, arena: &[H::Hash]) -> MerkleProof<F, H> {
+        MerkleProof {
+            siblings: self.siblings.iter().map(|&i| arena[i]).collect(),
+        }
+    }
+}
+
+/// The nodes on the path to a leaf, see [`SparseMerkleTree::full_path_info`].
+#[derive(Clone, Debug)]
+pub struct PathInfo<F: RichField, H: Hasher<F>> {
+    /// One entry per level, from the leaf up to the children of the root.
+    pub levels: Vec<PathLevel<F, H>>,
+    pub root: H::Hash,
+}
+
+/// A node on the path to a leaf.
+#[derive(Clone, Debug)]
+pub struct PathLevel<F: RichField, H: Hasher<F>> {
+    /// Position of the node among all nodes of its level, counting from the left.
+    pub index: usize,
+    pub hash: H::Hash,
+    /// The `A - 1` siblings of the node, ordered by child index.
+    pub siblings: Vec<H::Hash>,
+}
+
+/// Proof that several leaves are all empty, see [`SparseMerkleTree::prove_all_absent`].
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound = "")
+)]
+pub struct MultiNonInclusionProof<F: RichField, H: Hasher<F>> {
+    /// The queried leaf paths, sorted and without duplicates.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::compact_paths"))]
+    pub paths: Vec<Vec<bool>>,
+    /// The siblings that can't be recomputed from the queried leaves, level by level from the
+    /// bottom, and within a level ordered by path.
+    pub siblings: Vec<H::Hash>,
+}
+
+/// A version of a tree and the root it had then, see [`SparseMerkleTree::snapshot`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound = "")
+)]
+pub struct TreeSnapshot<F: RichField, H: Hasher<F>> {
+    pub version: u64,
+    pub root: H::Hash,
+}
+
+/// A proof for several leaves at once from [`SparseMerkleTree::prove_multi`]. A sibling shared by
+/// the paths of two leaves, or one that is itself on another path, is only included once.
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound = "")
+)]
+pub struct SparseMerkleMultiProof<F: RichField, H: Hasher<F>> {
+    /// The proven leaf paths, sorted and without duplicates.
+    #[cfg_attr(feature = "serde", serde(with = "crate::serialization::compact_paths"))]
+    pub paths: Vec<Vec<bool>>,
+    /// Siblings from the bottom level up, and by path within a level.
+    pub siblings: Vec<H::Hash>,
+}
+
+/// A set leaf next to a queried index, see [`SparseMerkleTree::prove_neighbors`].
+#[derive(Clone, Debug)]
+pub struct NeighborLeaf<F: RichField, H: Hasher<F>, L: LeafValue<F> = Vec<F>> {
+    pub index: u64,
+    pub value: L,
+    pub proof: MerkleProof<F, H>,
+}
+
+/// A predicate on leaf values, see [`SparseMerkleTree::set_leaf_validator`].
+#[derive(Clone)]
+pub struct LeafValidator<F>(Arc<ValidatorFn<F>>);
+
+type ValidatorFn<F> = dyn Fn(&[F]) -> bool + Send + Sync;
+
+impl<F> Debug for LeafValidator<F> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("LeafValidator")
+    }
+}
+
+/// What an update changed, see [`SparseMerkleTree::update_tracked`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UpdateResult<F: RichField, H: Hasher<F>> {
+    pub old_root: H::Hash,
+    pub new_root: H::Hash,
+    /// Paths of the rewritten nodes, from the leaf up to the root.
+    pub dirtied_nodes: Vec<Vec<bool>>,
+}
+
+/// The state a rollback restores, see [`SparseMerkleTree::with_rollback`].
+#[derive(Clone, Debug)]
+struct UndoJournal<F: RichField, H: Hasher<F>, const A: usize, L: LeafValue<F>> {
+    /// The node each written path held before its first write, `None` if it was empty.
+    nodes: HashMap<Path, Option<Node<F, H, A, L>>>,
+    /// Written paths that weren't dirty before.
+    dirtied: Vec<Path>,
+    version: u64,
+    /// How many of the versions in the history at the start are still there, at its front.
+    history_kept: usize,
+    /// Versions from the start that the history limit has since dropped, oldest first.
+    trimmed: Vec<HashMap<Path, H::Hash>>,
+    log_len: Option<usize>,
+    update_log_len: Option<usize>,
+}
+
+/// The callbacks registered with [`SparseMerkleTree::on_root_change`], along with the root they
+/// were last run with.
+struct RootObservers<T> {
+    last_root: Option<T>,
+    callbacks: Vec<Box<dyn FnMut(T) + Send + Sync>>,
+}
+
+impl<T> Default for RootObservers<T> {
+    fn default() -> Self {
+        Self {
+            last_root: None,
+            callbacks: vec![],
+        }
+    }
+}
+
+impl<T> Clone for RootObservers<T> {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl<T> Debug for RootObservers<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "RootObservers({})", self.callbacks.len())
+    }
+}
+
+/// A write to a tree, as recorded by [`SparseMerkleTree::start_recording`] and replayed by
+/// [`SparseMerkleTree::apply_log`].
+#[derive(Clone, Debug)]
+pub enum Op<F: RichField, H: Hasher<F>, L: LeafValue<F> = Vec<F>> {
+    Update {
+        path: Vec<bool>,
+        value: L,
+    },
+    /// A write made with [`SparseMerkleTree::update_hash`].
+    UpdateHash {
+        path: Vec<bool>,
+        hash: H::Hash,
+    },
+    Delete {
+        path: Vec<bool>,
+    },
+}
+
+/// The state of a leaf before or after a write recorded in an [`UpdateLog`].
+#[derive(Clone, Debug)]
+pub enum LoggedLeaf<F: RichField, H: Hasher<F>, L: LeafValue<F> = Vec<F>> {
+    /// A stored value, the default leaf for an empty leaf.
+    Value(L),
+    /// Only the hash of the leaf, as written by [`SparseMerkleTree::update_hash`].
+    Hash(H::Hash),
+}
+
+impl<F: RichField, H: Hasher<F>, L: LeafValue<F>> LoggedLeaf<F, H, L> {
+    /// The hash of the leaf as it enters the tree.
+    pub fn hash(&self) -> H::Hash {
+        match self {
+            LoggedLeaf::Value(value) => H::hash_or_noop(value.elements()),
+            LoggedLeaf::Hash(hash) => *hash,
+        }
+    }
+}
+
+/// The values kept in an [`UpdateLog`] are copies of leaves, so with the `zeroize` feature they
+/// are wiped on drop like the leaves stored in the tree.
+#[cfg(feature = "zeroize")]
+impl<F: RichField, H: Hasher<F>, L: LeafValue<F>> Zeroize for LoggedLeaf<F, H, L> {
+    fn zeroize(&mut self) {
+        if let LoggedLeaf::Value(value) = self {
+            value.wipe();
+        }
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl<F: RichField, H: Hasher<F>, L: LeafValue<F>> Drop for LoggedLeaf<F, H, L> {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl<F: RichField, H: Hasher<F>, L: LeafValue<F>> ZeroizeOnDrop for LoggedLeaf<F, H, L> {}
+
+/// A leaf write recorded in an [`UpdateLog`].
+#[derive(Clone, Debug)]
+pub struct UpdateLogEntry<F: RichField, H: Hasher<F>, L: LeafValue<F> = Vec<F>> {
+    pub path: Vec<bool>,
+    /// The leaf before the write.
+    pub old: LoggedLeaf<F, H, L>,
+    /// The leaf after the write, the default leaf for a delete.
+    pub new: LoggedLeaf<F, H, L>,
+    /// The root after the write. Entries written by one [`SparseMerkleTree::try_update_batch`],
+    /// [`SparseMerkleTree::fill_range`], [`SparseMerkleTree::reinsert_subtree`] or
+    /// [`SparseMerkleTree::commit_fork`] share the root the write ended at.
+    pub root: H::Hash,
+}
+
+/// The leaf writes made to a tree since [`SparseMerkleTree::start_update_log`], in order. It is
+/// only appended to by the tree's writes and shortened by [`SparseMerkleTree::revert_to`], and
+/// can be replayed with [`SparseMerkleTree::replay`].
+#[derive(Clone, Debug)]
+pub struct UpdateLog<F: RichField, H: Hasher<F>, L: LeafValue<F> = Vec<F>> {
+    start_root: H::Hash,
+    entries: Vec<UpdateLogEntry<F, H, L>>,
+}
+
+impl<F: RichField, H: Hasher<F>, L: LeafValue<F>> UpdateLog<F, H, L> {
+    /// The root of the tree when the log was started.
+    pub fn start_root(&self) -> H::Hash {
+        self.start_root
+    }
+
+    pub fn entries(&self) -> &[UpdateLogEntry<F, H, L>] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+/// The outcome of [`SparseMerkleTree::verify`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum VerifyResult {
+    Valid,
+    /// The proof has the right shape but doesn't fold to the root with the given value.
+    WrongValue,
+    /// The path or the number of siblings doesn't match the tree's height and arity.
+    WrongLength,
+}
+
+impl VerifyResult {
+    pub fn is_valid(self) -> bool {
+        self == VerifyResult::Valid
+    }
+}
+
+#[derive(Clone, Debug)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(bound(
+        serialize = "L: serde::Serialize",
+        deserialize = "L: serde::Deserialize<'de>"
+    ))
+)]
+pub enum Node<F: RichField, H: Hasher<F>, const A: usize = 2, L: LeafValue<F> = Vec<F>> {
+    InnerNode {
+        #[cfg_attr(feature = "serde", serde(with = "crate::serialization::hash_array"))]
+        children: [H::Hash; A],
+    },
+    Leaf {
+        value: L,
+    },
+    /// A subtree known only by its hash, e.g. a sibling taken from a proof.
+    Digest {
+        hash: H::Hash,
+    },
+    /// A subtree above the leaves all of whose leaves hold `value`, as written by
+    /// [`SparseMerkleTree::fill_range`]. `hashes` holds the hash of such a subtree at each level
+    /// from this node down to the leaves, so nothing is stored below it.
+    Filled {
+        value: L,
+        hashes: Vec<H::Hash>,
+    },
+}
+
+impl<F: RichField, H: Hasher<F>, const A: usize, L: LeafValue<F>> Node<F, H, A, L> {
+    /// Hashes the node, prefixing inner nodes with `tag` if given.
+    fn hash(&self, ops: &HashOps, tag: Option<usize>) -> H::Hash {
+        match self {
+            Node::InnerNode { children } => ops.hash_children::<F, H, A>(children, tag),
+            Node::Leaf { value } => ops.hash_or_noop::<F, H>(value.elements()),
+            Node::Digest { hash } => *hash,
+            Node::Filled { hashes, .. } => hashes[0],
+        }
+    }
+}
+
+/// Wipes the value of a leaf or filled node; inner nodes and digests only hold hashes and are
+/// left as they are.
+#[cfg(feature = "zeroize")]
+impl<F: RichField, H: Hasher<F>, const A: usize, L: LeafValue<F>> Zeroize for Node<F, H, A, L> {
+    fn zeroize(&mut self) {
+        if let Node::Leaf { value } | Node::Filled { value, .. } = self {
+            value.wipe();
+        }
+    }
+}
+
+/// With the `zeroize` feature, leaf values are wiped when their node is dropped, which covers
+/// leaves that are overwritten or deleted as well as dropping the whole tree.
+#[cfg(feature = "zeroize")]
+impl<F: RichField, H: Hasher<F>, const A: usize, L: LeafValue<F>> Drop for Node<F, H, A, L> {
+    fn drop(&mut self) {
+        self.zeroize();
+    }
+}
+
+#[cfg(feature = "zeroize")]
+impl<F: RichField, H: Hasher<F>, const A: usize, L: LeafValue<F>> ZeroizeOnDrop
+    for Node<F, H, A, L>
+{
+}
+
+/// Overwrites `elements` with zeros through [`Zeroize`], so the writes aren't elided as dead
+/// stores.
+#[cfg(feature = "zeroize")]
+fn wipe<F: RichField>(elements: &mut [F]) {
+    // SAFETY: field elements are plain integers without padding, so their storage is valid as
+    // bytes, and the all-zero pattern is the element zero.
+    let bytes = unsafe {
+        std::slice::from_raw_parts_mut(
+            elements.as_mut_ptr().cast::<u8>(),
+            std::mem::size_of_val(elements),
+        )
+    };
+    bytes.zeroize();
+    debug_assert!(elements.iter().all(|x| x.is_zero()));
+}
+
+/// The stored value of a leaf. `Vec<F>` accepts values of any width, while `[F; W]` fixes the
+/// width in the type and keeps the value inline.
+///
+/// Structured leaves implement this trait over a fixed-width array of their fields, e.g. an
+/// account as `struct Account([F; 3])` with accessors for its nonce, balance and storage root.
+/// A `SparseMerkleTree<F, H, A, Account>` then stores, hashes and proves accounts directly.
+pub trait LeafValue<F: RichField>: Clone + Debug {
+    /// The value of a leaf that has never been set.
+    fn empty() -> Self;
+
+    fn elements(&self) -> &[F];
+
+    fn elements_mut(&mut self) -> &mut [F];
+
+    /// Copies `elements` into a leaf, panicking if the leaf type can't hold that many.
+    fn from_elements(elements: &[F]) -> Self;
+
+    /// Like [`Self::from_elements`], but returns `None` if the leaf type can't hold that many
+    /// elements, for decoding untrusted input. Leaf types of a fixed width should override the
+    /// default, which defers to [`Self::from_elements`].
+    fn try_from_elements(elements: &[F]) -> Option<Self> {
+        Some(Self::from_elements(elements))
+    }
+
+    /// Overwrites the value with zeros. The tree wipes the leaves it stores, but values it hands
+    /// out, e.g. from [`SparseMerkleTree::get_leaf`], are copies the caller has to wipe.
+    #[cfg(feature = "zeroize")]
+    fn wipe(&mut self) {
+        wipe(self.elements_mut());
+    }
+}
+
+impl<F: RichField> LeafValue<F> for Vec<F> {
+    fn empty() -> Self {
+        vec![F::ZERO; 4]
+    }
+
+    fn from_elements(elements: &[F]) -> Self {
+        elements.to_vec()
+    }
+
+    fn elements(&self) -> &[F] {
+        self
+    }
+
+    fn elements_mut(&mut self) -> &mut [F] {
+        self
+    }
+}
+
+impl<F: RichField, const W: usize> LeafValue<F> for [F; W] {
+    fn empty() -> Self {
+        [F::ZERO; W]
+    }
+
+    fn from_elements(elements: &[F]) -> Self {
+        assert_eq!(elements.len(), W);
+        core::array::from_fn(|i| elements[i])
+    }
+
+    fn try_from_elements(elements: &[F]) -> Option<Self> {
+        elements.try_into().ok()
+    }
+
+    fn elements(&self) -> &[F] {
+        self
+    }
+
+    fn elements_mut(&mut self) -> &mut [F] {
+        self
+    }
+}
+
+/// Computes the root of a binary tree without level tags after the leaf at `path` changes from
+/// `old_value` to `new_value`, given the leaf's proof against `old_root`. Fails with
+/// [`SmtError::InvalidProof`] if the proof doesn't show `old_value` under `old_root`.
+pub fn apply_update_to_root<F: RichField, H: Hasher<F>>(
+    old_root: H::Hash,
+    path: &[bool],
+    old_value: &[F],
+    new_value: &[F],
+    siblings: &[H::Hash],
+) -> SmtResult<H::Hash> {
+    if !SparseMerkleTree::<F, H>::verify_borrowed(path, old_value, siblings, old_root) {
+        return Err(SmtError::InvalidProof {
+            path: path.to_vec(),
+        });
+    }
+    Ok(SparseMerkleTree::<F, H>::fold_proof(
+        path, new_value, siblings, false,
+    ))
+}
+
+/// Flattens a hash into its field elements so it can be stored as a leaf value, e.g. the root of a
+/// nested tree. For `PoseidonHash` this is the four elements of the `HashOut`.
+pub fn hash_to_elements<F: RichField, H: Hasher<F>>(hash: &H::Hash) -> Vec<F> {
+    hash.to_vec()
+}
+
+/// Rebuilds a hash from the elements produced by `hash_to_elements`. Only meaningful for hashers
+/// whose output is a sequence of field elements, such as `PoseidonHash`. Returns `None` if
+/// `elements` isn't as wide as a hash, e.g. for an ordinary leaf value.
+pub fn elements_to_hash<F: RichField, H: Hasher<F>>(elements: &[F]) -> Option<H::Hash> {
+    if elements.len() * 8 != H::HASH_SIZE {
+        return None;
+    }
+    let bytes: Vec<u8> = elements
+        .iter()
+        .flat_map(|x| x.to_canonical_u64().to_le_bytes())
+        .collect();
+    Some(H::Hash::from_bytes(&bytes))
+}
+
+/// Decodes a hex key, with or without a `0x` prefix, and returns its top `height` bits as a path,
+/// most significant bit first. Fails with [`SmtError::InvalidEncoding`] on odd-length or non-hex
+/// input and with [`SmtError::InvalidPath`] if the key has fewer than `height` bits.
+pub fn path_from_hex(hex: &str, height: usize) -> SmtResult<Vec<bool>> {
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    if hex.len() % 2 != 0 {
+        return Err(SmtError::InvalidEncoding);
+    }
+    let nibbles = hex
+        .chars()
+        .map(|c| c.to_digit(16).ok_or(SmtError::InvalidEncoding))
+        .collect::<SmtResult<Vec<u32>>>()?;
+    if nibbles.len() * 4 < height {
+        return Err(SmtError::InvalidPath {
+            len: nibbles.len() * 4,
+            height,
+        });
+    }
+    Ok(nibbles
+        .into_iter()
+        .flat_map(|n| (0..4).rev().map(move |i| (n >> i) & 1 == 1))
+        .take(height)
+        .collect())
+}
+
+/// Counts of the hasher calls made by a tree, see [`SparseMerkleTree::hash_op_stats`].
+#[cfg(feature = "hash-stats")]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HashOpStats {
+    /// Binary inner nodes without level tags.
+    pub two_to_one: u64,
+    /// Tagged or wider inner nodes.
+    pub hash_no_pad: u64,
+    /// Leaves.
+    pub hash_or_noop: u64,
+}
+
+/// The hasher of a tree, which counts its calls with the `hash-stats` feature. The counters are
+/// atomic, so hashes computed on rayon worker threads are counted too. Clones start from zero.
+#[derive(Debug, Default)]
+pub(crate) struct HashOps {
+    #[cfg(feature = "hash-stats")]
+    two_to_one: AtomicU64,
+    #[cfg(feature = "hash-stats")]
+    hash_no_pad: AtomicU64,
+    #[cfg(feature = "hash-stats")]
+    hash_or_noop: AtomicU64,
+}
+
+impl Clone for HashOps {
+    fn clone(&self) -> Self {
+        Self::default()
+    }
+}
+
+impl HashOps {
+    pub(crate) fn two_to_one<F: RichField, H: Hasher<F>>(
+        &self,
+        left: H::Hash,
+        right: H::Hash,
+    ) -> H::Hash {
+        #[cfg(feature = "hash-stats")]
+        self.two_to_one.fetch_add(1, Ordering::Relaxed);
+        H::two_to_one(left, right)
+    }
+
+    pub(crate) fn hash_no_pad<F: RichField, H: Hasher<F>>(&self, inputs: &[F]) -> H::Hash {
+        #[cfg(feature = "hash-stats")]
+        self.hash_no_pad.fetch_add(1, Ordering::Relaxed);
+        H::hash_no_pad(inputs)
+    }
+
+    pub(crate) fn hash_or_noop<F: RichField, H: Hasher<F>>(&self, inputs: &[F]) -> H::Hash {
+        #[cfg(feature = "hash-stats")]
+        self.hash_or_noop.fetch_add(1, Ordering::Relaxed);
+        H::hash_or_noop(inputs)
+    }
+
+    /// Hashes an inner node as [`hash_children`] does.
+    fn hash_children<F: RichField, H: Hasher<F>, const A: usize>(
+        &self,
+        children: &[H::Hash; A],
+        tag: Option<usize>,
+    ) -> H::Hash {
+        if A == 2 && tag.is_none() {
+            return self.two_to_one::<F, H>(children[0], children[1]);
+        }
+        self.hash_no_pad::<F, H>(&children_inputs::<F, H, A>(children, tag))
+    }
+
+    #[cfg(feature = "hash-stats")]
+    pub(crate) fn stats(&self) -> HashOpStats {
+        HashOpStats {
+            two_to_one: self.two_to_one.load(Ordering::Relaxed),
+            hash_no_pad: self.hash_no_pad.load(Ordering::Relaxed),
+            hash_or_noop: self.hash_or_noop.load(Ordering::Relaxed),
+        }
+    }
+
+    #[cfg(feature = "hash-stats")]
+    pub(crate) fn reset(&self) {
+        self.two_to_one.store(0, Ordering::Relaxed);
+        self.hash_no_pad.store(0, Ordering::Relaxed);
+        self.hash_or_noop.store(0, Ordering::Relaxed);
+    }
+}
+
+/// Untagged binary nodes use `two_to_one` so that proofs stay compatible with plonky2's Merkle
+/// proofs; otherwise the optional tag and the children's elements are hashed together. Used by
+/// the static verifiers, which belong to no tree and aren't counted.
+fn hash_children<F: RichField, H: Hasher<F>, const A: usize>(
+    children: &[H::Hash; A],
+    tag: Option<usize>,
+) -> H::Hash {
+    if A == 2 && tag.is_none() {
+        return H::two_to_one(children[0], children[1]);
+    }
+    H::hash_no_pad(&children_inputs::<F, H, A>(children, tag))
+}
+
+fn children_inputs<F: RichField, H: Hasher<F>, const A: usize>(
+    children: &[H::Hash; A],
+    tag: Option<usize>,
+) -> Vec<F> {
+    let mut inputs: Vec<F> = tag.map(F::from_canonical_usize).into_iter().collect();
+    inputs.extend(children.iter().flat_map(|h| h.to_vec()));
+    inputs
+}
+
+/// Interprets `bits` as a big-endian child index.
+fn bits_to_index(bits: &[bool]) -> usize {
+    bits.iter().fold(0, |acc, &b| (acc << 1) | b as usize)
+}
+
+/// Big-endian bit decomposition of a child index, the inverse of `bits_to_index`.
+fn index_to_bits(index: usize, len: usize) -> impl Iterator<Item = bool> {
+    (0..len).rev().map(move |i| (index >> i) & 1 == 1)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use plonky2::{
+        field::types::{Field, Sample},
+        hash::{
+            merkle_proofs::{verify_merkle_proof, verify_merkle_proof_to_cap},
+            poseidon::PoseidonHash,
+        },
+        plonk::config::{GenericConfig, PoseidonGoldilocksConfig},
+    };
+    use rand::Rng;
+    use std::collections::BTreeMap;
+
+    const D: usize = 2;
+    type C = PoseidonGoldilocksConfig;
+    type F = <C as GenericConfig<D>>::F;
+    type H = PoseidonHash;
+
+    pub(crate) fn usize_to_vec(x: usize, length: usize) -> Vec<bool> {
+        let mut x = x;
+        let mut v = vec![];
+        for _ in 0..length {
+            v.push((x & 1) == 1);
+            x >>= 1;
+        }
+        v.reverse();
+        v
+    }
+
+    #[test]
+    fn tree_test() {
+        use crate::{keyed_smt::SmtKey, verifier::verify_smt_proof};
+
+        let mut rng = rand::thread_rng();
+        let height = 100;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+
+        for _ in 0..100 {
+            let index = rng.gen::<u128>() >> (128 - height);
+            let path = index.to_path(height).unwrap();
+            let new_leaf = F::rand_vec(4);
+            tree.update(&path, new_leaf.clone());
+            let proof = tree.prove(&path);
+            assert_eq!(tree.get_leaf(&path), new_leaf.clone());
+            assert!(verify_smt_proof::<F, H>(
+                &new_leaf,
+                &path,
+                &proof.siblings,
+                tree.get_root()
+            ));
+        }
+
+        // plonky2's verifier agrees on the indices it can represent.
+        for _ in 0..100 {
+            let index = rng.gen::<usize>();
+            let path = usize_to_vec(index, height);
+            let leaf = tree.get_leaf(&path);
+            let proof = tree.prove(&path);
+            verify_merkle_proof(leaf.clone(), index, tree.get_root(), &proof).unwrap();
+            assert!(verify_smt_proof::<F, H>(
+                &leaf,
+                &path,
+                &proof.siblings,
+                tree.get_root()
+            ));
+        }
+    }
+
+    #[test]
+    fn arity_test() {
+        let mut rng = rand::thread_rng();
+        let height = 20;
+        let mut binary = SparseMerkleTree::<F, H, 2>::new(height);
+        let mut quaternary = SparseMerkleTree::<F, H, 4>::new(height);
+        assert_eq!(quaternary.zero_hashes.len(), height / 2 + 1);
+
+        for _ in 0..50 {
+            let index = rng.gen_range(0..1 << height);
+            let path = usize_to_vec(index, height);
+            let new_leaf = F::rand_vec(4);
+            binary.update(&path, new_leaf.clone());
+            quaternary.update(&path, new_leaf.clone());
+
+            let proof = binary.prove(&path);
+            assert!(binary
+                .verify(&path, &new_leaf, binary.get_root(), &proof)
+                .is_valid());
+            verify_merkle_proof(new_leaf.clone(), index, binary.get_root(), &proof).unwrap();
+
+            let proof = quaternary.prove(&path);
+            assert_eq!(proof.siblings.len(), height / 2 * 3);
+            assert_eq!(quaternary.get_leaf(&path), new_leaf);
+            assert!(quaternary
+                .verify(&path, &new_leaf, quaternary.get_root(), &proof)
+                .is_valid());
+            assert!(!quaternary
+                .verify(&path, &F::rand_vec(4), quaternary.get_root(), &proof)
+                .is_valid());
+        }
+
+        // The root of a 4-ary tree folds its four children with a single hash.
+        let children: Vec<F> = [[false, false], [false, true], [true, false], [true, true]]
+            .iter()
+            .flat_map(|bits| quaternary.get_node_hash(bits.to_vec()).to_vec())
+            .collect();
+        assert_eq!(H::hash_no_pad(&children), quaternary.get_root());
+    }
+
+    #[test]
+    fn dirty_test() {
+        let height = 4;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let a = usize_to_vec(0b0011, height);
+        let b = usize_to_vec(0b0110, height);
+        tree.update(&a, F::rand_vec(4));
+        tree.update(&b, F::rand_vec(4));
+
+        let mut expected: Vec<Path> = (0..=height)
+            .flat_map(|len| [Path::from(&a[..len]), Path::from(&b[..len])])
+            .collect();
+        expected.sort();
+        expected.dedup();
+        assert_eq!(tree.take_dirty(), expected);
+        assert!(tree.take_dirty().is_empty());
+
+        tree.update(&a, F::rand_vec(4));
+        tree.mark_clean();
+        assert!(tree.take_dirty().is_empty());
+    }
+
+    #[test]
+    fn get_leaves_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let values = [F::rand_vec(4), F::rand_vec(4)];
+        tree.update(usize_to_vec(3, height), values[0].clone());
+        tree.update(usize_to_vec(200, height), values[1].clone());
+
+        let paths: Vec<Vec<bool>> = [7, 200, 3, 0]
+            .iter()
+            .map(|&i| usize_to_vec(i, height))
+            .collect();
+        assert_eq!(
+            tree.get_leaves(&paths),
+            vec![None, Some(values[1].clone()), Some(values[0].clone()), None]
+        );
+    }
+
+    #[test]
+    fn level_nodes_test() {
+        let mut rng = rand::thread_rng();
+        let height = 10;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..5 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+
+        assert_eq!(
+            tree.level_nodes(0).unwrap(),
+            vec![(Path::new(), tree.get_root())]
+        );
+        assert_eq!(tree.level_nodes(height).unwrap().len(), 5);
+        assert_eq!(
+            tree.level_nodes(height + 1),
+            Err(SmtError::InvalidLevel {
+                level: height + 1,
+                height
+            })
+        );
+
+        // Folding the non-empty subtrees at a level, with zero hashes elsewhere, gives the root.
+        let level = 4;
+        let shards: HashMap<Path, _> = tree.level_nodes(level).unwrap().into_iter().collect();
+        fn fold(
+            prefix: Path,
+            level: usize,
+            shards: &HashMap<Path, <H as Hasher<F>>::Hash>,
+            zero: <H as Hasher<F>>::Hash,
+        ) -> <H as Hasher<F>>::Hash {
+            if prefix.len() == level {
+                return *shards.get(&prefix).unwrap_or(&zero);
+            }
+            let mut left = prefix;
+            left.push(false);
+            let mut right = prefix;
+            right.push(true);
+            H::two_to_one(
+                fold(left, level, shards, zero),
+                fold(right, level, shards, zero),
+            )
+        }
+        let zero = tree.zero_hashes[level];
+        assert_eq!(fold(Path::new(), level, &shards, zero), tree.get_root());
+    }
+
+    #[test]
+    fn node_map_test() {
+        type BTreeTree = SparseMerkleTree<F, H, 2, Vec<F>, BTreeMap<Path, Node<F, H>>>;
+        let mut rng = rand::thread_rng();
+        let height = 32;
+        let mut hash_map = SparseMerkleTree::<F, H>::new(height);
+        let mut btree_map = BTreeTree::new(height);
+
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            let value = F::rand_vec(4);
+            hash_map.update(&path, value.clone());
+            btree_map.update(&path, value);
+            assert_eq!(hash_map.get_root(), btree_map.get_root());
+        }
+        assert_eq!(hash_map.nodes.len(), btree_map.nodes.len());
+
+        // BTreeMap iterates in path order.
+        let paths: Vec<&Path> = btree_map.nodes.keys().collect();
+        assert!(paths.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[test]
+    fn verify_borrowed_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let mut leaves = vec![];
+        for _ in 0..10 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            let value = F::rand_vec(4);
+            tree.update(&path, value.clone());
+            leaves.push((path, value));
+        }
+
+        // All siblings live in one shared arena and are verified in place.
+        let arena: Vec<_> = leaves
+            .iter()
+            .flat_map(|(path, _)| tree.prove(path).siblings)
+            .collect();
+        let root = tree.get_root();
+        for (i, (path, _)) in leaves.iter().enumerate() {
+            let siblings = &arena[i * height..(i + 1) * height];
+            let value = tree.get_leaf(path);
+            assert!(SparseMerkleTree::<F, H>::verify_borrowed(
+                path, &value, siblings, root
+            ));
+            assert!(!SparseMerkleTree::<F, H>::verify_borrowed(
+                path,
+                &F::rand_vec(4),
+                siblings,
+                root
+            ));
+        }
+    }
+
+    #[test]
+    fn prove_at_version_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H, 4>::new(height);
+        tree.set_history_limit(4);
+        let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+        let old_value = F::rand_vec(4);
+        tree.update(&path, old_value.clone());
+        let version = tree.version();
+        let old_root = tree.get_root();
+
+        for _ in 0..3 {
+            let other = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&other, F::rand_vec(4));
+        }
+        tree.update(&path, F::rand_vec(4));
+
+        assert_eq!(tree.get_root_at_version(version), Some(old_root));
+        let proof = tree.prove_at_version(&path, version).unwrap();
+        assert!(tree.verify(&path, &old_value, old_root, &proof).is_valid());
+        assert!(!tree
+            .verify(&path, &old_value, tree.get_root(), &proof)
+            .is_valid());
+        assert_eq!(
+            tree.prove_at_version(&path, tree.version()),
+            Some(tree.prove(&path))
+        );
+        assert_eq!(tree.prove_at_version(&path, tree.version() + 1), None);
+
+        // Versions older than the retained history can no longer be proven.
+        tree.update(&path, F::rand_vec(4));
+        assert_eq!(tree.get_root_at_version(version), None);
+        assert_eq!(tree.prove_at_version(&path, version), None);
+    }
+
+    #[test]
+    fn prove_at_snapshot_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        tree.set_history_limit(8);
+        let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+        let snapshot = tree.snapshot();
+        assert_eq!(snapshot.root, tree.get_root());
+
+        for _ in 0..5 {
+            let other = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&other, F::rand_vec(4));
+        }
+        let proof = tree.prove_at_snapshot(&snapshot, &path).unwrap();
+        assert!(SparseMerkleTree::<F, H>::verify_borrowed(
+            &path,
+            &value,
+            &proof.siblings,
+            snapshot.root
+        ));
+
+        // A tree that reached the same version along different updates can't prove it.
+        let mut other = SparseMerkleTree::<F, H>::new(height);
+        other.set_history_limit(8);
+        for _ in 0..tree.version() {
+            other.update(&path, F::rand_vec(4));
+        }
+        assert_eq!(other.prove_at_snapshot(&snapshot, &path), None);
+    }
+
+    #[test]
+    fn update_slice_test() {
+        let height = 8;
+        let mut by_vec = SparseMerkleTree::<F, H>::new(height);
+        let mut by_slice = SparseMerkleTree::<F, H>::new(height);
+        let mut by_array = SparseMerkleTree::<F, H>::new(height);
+        let mut fixed = FixedLeafSparseMerkleTree::<F, H, 4>::new(height);
+        for i in 0..10 {
+            let path = usize_to_vec(i * 7, height);
+            let value: [F; 4] = F::rand_array();
+            by_vec.update(&path, value.to_vec());
+            by_slice.update_slice(&path, &value);
+            by_array.update(&path, value);
+            fixed.update_slice(&path, &value[..]);
+            assert_eq!(by_slice.get_leaf(&path), value.to_vec());
+        }
+        assert_eq!(by_vec.get_root(), by_slice.get_root());
+        assert_eq!(by_vec.get_root(), by_array.get_root());
+        assert_eq!(by_vec.get_root(), fixed.get_root());
+    }
+
+    #[test]
+    fn verify_result_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let path = usize_to_vec(5, height);
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+        let root = tree.get_root();
+        let proof = tree.prove(&path);
+
+        assert_eq!(
+            tree.verify(&path, &value, root, &proof),
+            VerifyResult::Valid
+        );
+        assert_eq!(
+            tree.verify(&path, &F::rand_vec(4), root, &proof),
+            VerifyResult::WrongValue
+        );
+        let taller = SparseMerkleTree::<F, H>::new(height + 1);
+        assert_eq!(
+            taller.verify(&path, &value, root, &proof),
+            VerifyResult::WrongLength
+        );
+        let mut short = proof;
+        short.siblings.pop();
+        assert_eq!(
+            tree.verify(&path, &value, root, &short),
+            VerifyResult::WrongLength
+        );
+    }
+
+    #[test]
+    fn clone_on_write_test() {
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for i in 0..10 {
+            tree.update(&usize_to_vec(i, height), F::rand_vec(4));
+        }
+        let root = tree.get_root();
+
+        let mut snapshot = tree.clone();
+        assert!(Arc::ptr_eq(&tree.nodes, &snapshot.nodes));
+
+        let path = usize_to_vec(100, height);
+        let value = F::rand_vec(4);
+        snapshot.update(&path, value.clone());
+        assert!(!Arc::ptr_eq(&tree.nodes, &snapshot.nodes));
+        assert_eq!(tree.get_root(), root);
+        assert_eq!(tree.get_leaf(&path), vec![F::ZERO; 4]);
+        assert_eq!(snapshot.get_leaf(&path), value);
+
+        tree.update(&path, F::rand_vec(4));
+        assert_ne!(tree.get_root(), snapshot.get_root());
+        assert_eq!(snapshot.get_leaf(&path), value);
+    }
+
+    #[test]
+    fn children_test() {
+        let height = 6;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let a = usize_to_vec(0b010110, height);
+        let b = usize_to_vec(0b011000, height);
+        tree.update(&a, F::rand_vec(4));
+        tree.update(&b, F::rand_vec(4));
+
+        let untouched = usize_to_vec(0b111111, height);
+        for path in [&a, &b, &untouched] {
+            for len in 0..height {
+                let prefix = path[..len].to_vec();
+                let (left, right) = tree.children(&prefix);
+                assert_eq!(H::two_to_one(left, right), tree.get_node_hash(&prefix));
+
+                let mut child = prefix.clone();
+                child.push(false);
+                assert_eq!(left, tree.get_node_hash(&child));
+            }
+        }
+    }
+
+    #[test]
+    fn set_test() {
+        let height = 12;
+        let mut by_set = SparseMerkleTree::<F, H>::new(height);
+        let mut separate = SparseMerkleTree::<F, H>::new(height);
+        let paths: Vec<Vec<bool>> = (0..8).map(|i| usize_to_vec(i * 37, height)).collect();
+
+        for (i, path) in paths.iter().enumerate() {
+            let value = F::rand_vec(4);
+            by_set.set(path, Some(value.clone()));
+            separate.update(path, value);
+            if i % 3 == 2 {
+                by_set.set(&paths[i - 1], None);
+                separate.delete(&paths[i - 1]);
+            }
+        }
+        assert_eq!(by_set.get_root(), separate.get_root());
+
+        // Deleting every leaf prunes the tree back to an empty one.
+        for path in &paths {
+            by_set.set(path, None);
+        }
+        assert_eq!(
+            by_set.get_root(),
+            SparseMerkleTree::<F, H>::new(height).get_root()
+        );
+        assert!(by_set.nodes.is_empty());
+    }
+
+    #[test]
+    fn proof_with_height_test() {
+        let height = 10;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let path = usize_to_vec(77, height);
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+        let root = tree.get_root();
+        let proof = tree.prove_with_height(&path);
+        assert_eq!(proof.height, height);
+        assert_eq!(proof.verify(&path, &value, root), VerifyResult::Valid);
+
+        // A verifier that believes the tree is one level taller uses longer paths.
+        let mut taller_path = vec![false];
+        taller_path.extend(path.iter().copied());
+        assert_eq!(
+            proof.verify(&taller_path, &value, root),
+            VerifyResult::WrongLength
+        );
+
+        // Siblings that don't match the embedded height are rejected too.
+        let mut truncated = proof;
+        truncated.proof.siblings.pop();
+        assert_eq!(
+            truncated.verify(&path, &value, root),
+            VerifyResult::WrongLength
+        );
+    }
+
+    #[test]
+    fn prove_all_arena_test() {
+        let mut rng = rand::thread_rng();
+        let height = 20;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..64 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+
+        let (arena, proofs) = tree.prove_all_arena();
+        assert_eq!(proofs.len(), tree.leaf_paths().len());
+        let root = tree.get_root();
+        for proof_ref in &proofs {
+            let proof = proof_ref.to_proof::<F, H>(&arena);
+            assert_eq!(proof, tree.prove(&proof_ref.path));
+            let value = tree.get_leaf(&proof_ref.path);
+            assert!(tree
+                .verify(&proof_ref.path, &value, root, &proof)
+                .is_valid());
+        }
+        let independent: usize = proofs.iter().map(|p| p.siblings.len()).sum();
+        assert!(arena.len() * 4 < independent);
+    }
+
+    #[test]
+    fn from_proofs_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let root = tree.get_root();
+        let items: Vec<_> = tree.leaf_paths()[..5]
+            .iter()
+            .map(|path| (path.to_bits(), tree.get_leaf(path), tree.prove(path)))
+            .collect();
+
+        let mut partial = SparseMerkleTree::<F, H>::from_proofs(height, root, &items).unwrap();
+        assert_eq!(partial.get_root(), root);
+        for (path, value, proof) in &items {
+            assert_eq!(&partial.get_leaf(path), value);
+            assert_eq!(&partial.prove(path), proof);
+        }
+
+        // Updates to the proven leaves track the full tree.
+        let value = F::rand_vec(4);
+        partial.update(&items[2].0, value.clone());
+        tree.update(&items[2].0, value);
+        assert_eq!(partial.get_root(), tree.get_root());
+
+        let mut bad = items;
+        bad[3].1 = F::rand_vec(4);
+        assert_eq!(
+            SparseMerkleTree::<F, H>::from_proofs(height, root, &bad).unwrap_err(),
+            SmtError::InvalidProof {
+                path: bad[3].0.clone()
+            }
+        );
+    }
+
+    #[test]
+    fn update_delta_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut sender = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..30 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            sender.update(&path, F::rand_vec(4));
+        }
+        let old_root = sender.get_root();
+
+        let mut updates: Vec<_> = (0..5)
+            .map(|_| {
+                let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+                (path, F::rand_vec(4))
+            })
+            .collect();
+        updates.push((sender.leaf_paths()[0].to_bits(), F::rand_vec(4)));
+        let delta = sender.update_delta(&updates).unwrap();
+        for (path, value) in &updates {
+            sender.update(path, value.clone());
+        }
+
+        let new_root = SparseMerkleTree::<F, H>::apply_delta(old_root, &delta).unwrap();
+        assert_eq!(new_root, sender.get_root());
+        assert!(delta.siblings.len() < updates.len() * height);
+
+        let mut forged = delta;
+        forged.leaves[0].1 = F::rand_vec(4);
+        assert_eq!(
+            SparseMerkleTree::<F, H>::apply_delta(old_root, &forged),
+            Err(SmtError::RootMismatch)
+        );
+    }
+
+    #[test]
+    fn first_empty_test() {
+        let height = 4;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        assert_eq!(tree.first_empty(), Some(0));
+
+        for i in [0, 1, 2, 3, 5, 9] {
+            tree.update(&usize_to_vec(i, height), F::rand_vec(4));
+        }
+        assert_eq!(tree.first_empty(), Some(4));
+        tree.update(&usize_to_vec(4, height), F::rand_vec(4));
+        assert_eq!(tree.first_empty(), Some(6));
+
+        // Setting a leaf to the empty value still occupies it.
+        tree.update(&usize_to_vec(6, height), vec![F::ZERO; 4]);
+        assert_eq!(tree.first_empty(), Some(7));
+
+        for i in 0..1 << height {
+            tree.update(&usize_to_vec(i, height), F::rand_vec(4));
+        }
+        assert_eq!(tree.first_empty(), None);
+        tree.delete(usize_to_vec(13, height));
+        assert_eq!(tree.first_empty(), Some(13));
+    }
+
+    #[test]
+    fn level_tags_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut plain = SparseMerkleTree::<F, H>::new(height);
+        let mut tagged = SparseMerkleTree::<F, H>::new_level_tagged(height);
+        let mut tagged_wide = SparseMerkleTree::<F, H, 4>::new_level_tagged(height);
+        assert_ne!(plain.get_root(), tagged.get_root());
+
+        for _ in 0..10 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            let value = F::rand_vec(4);
+            plain.update(&path, value.clone());
+            tagged.update(&path, value.clone());
+            tagged_wide.update(&path, value.clone());
+
+            // Leaves are hashed the same way, all inner nodes differ.
+            assert_eq!(plain.get_node_hash(&path), tagged.get_node_hash(&path));
+            for len in 0..height {
+                let prefix = path[..len].to_vec();
+                assert_ne!(plain.get_node_hash(&prefix), tagged.get_node_hash(&prefix));
+            }
+
+            let proof = tagged.prove(&path);
+            let root = tagged.get_root();
+            assert!(tagged.verify(&path, &value, root, &proof).is_valid());
+            assert!(!plain.verify(&path, &value, root, &proof).is_valid());
+            assert!(!SparseMerkleTree::<F, H>::verify_borrowed(
+                &path,
+                &value,
+                &proof.siblings,
+                root
+            ));
+
+            let proof = tagged_wide.prove(&path);
+            let root = tagged_wide.get_root();
+            assert!(tagged_wide.verify(&path, &value, root, &proof).is_valid());
+        }
+    }
+
+    #[test]
+    fn stored_node_hash_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        assert_eq!(tree.stored_node_hash(&vec![]), None);
+
+        let path = usize_to_vec(0b00101100, height);
+        tree.update(&path, F::rand_vec(4));
+        for len in 0..=height {
+            let prefix = path[..len].to_vec();
+            assert_eq!(
+                tree.stored_node_hash(&prefix),
+                Some(tree.get_node_hash(&prefix))
+            );
+        }
+        let untouched = vec![true, true];
+        assert_eq!(tree.stored_node_hash(&untouched), None);
+        assert_eq!(tree.get_node_hash(&untouched), tree.zero_hashes[2]);
+    }
+
+    #[test]
+    fn proof_folder_test() {
+        let mut rng = rand::thread_rng();
+        let height = 24;
+        let mut binary = SparseMerkleTree::<F, H>::new(height);
+        let mut wide = SparseMerkleTree::<F, H, 8>::new(height);
+        for _ in 0..10 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            let value = F::rand_vec(4);
+            binary.update(&path, value.clone());
+            wide.update(&path, value.clone());
+
+            let mut folder = ProofFolder::<F, H>::new(&path, &value);
+            for sibling in binary.prove(&path).siblings {
+                folder.push_sibling(sibling);
+            }
+            assert_eq!(folder.finish(), binary.get_root());
+
+            let mut folder = ProofFolder::<F, H, 8>::new(&path, &value);
+            for sibling in wide.prove(&path).siblings {
+                folder.push_sibling(sibling);
+            }
+            assert_eq!(folder.finish(), wide.get_root());
+        }
+    }
+
+    #[test]
+    fn prove_annotated_test() {
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let a = usize_to_vec(0x1234, height);
+        tree.update(&a, F::rand_vec(4));
+
+        // With a single leaf every sibling is an empty subtree.
+        let annotated = tree.prove_annotated(&a);
+        assert!(annotated.iter().all(|&(_, is_zero)| is_zero));
+
+        // A second leaf sharing the top 12 bits makes exactly one sibling of `a` stored.
+        let b = usize_to_vec(0x1239, height);
+        tree.update(b, F::rand_vec(4));
+        let annotated = tree.prove_annotated(&a);
+        let stored: Vec<usize> = (0..height).filter(|&i| !annotated[i].1).collect();
+        assert_eq!(stored, vec![3]);
+        let siblings: Vec<_> = annotated.iter().map(|&(hash, _)| hash).collect();
+        assert_eq!(siblings, tree.prove(&a).siblings);
+    }
+
+    #[test]
+    fn transaction_test() {
+        let height = 10;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        tree.update(usize_to_vec(1, height), F::rand_vec(4));
+        let root = tree.get_root();
+        let version = tree.version();
+
+        let updates: Vec<_> = (2..6)
+            .map(|i| (usize_to_vec(i, height), F::rand_vec(4)))
+            .collect();
+        #[derive(Debug, PartialEq)]
+        enum Error {
+            TooManyLeaves,
+            Update(SmtError),
+        }
+        impl From<SmtError> for Error {
+            fn from(err: SmtError) -> Self {
+                Error::Update(err)
+            }
+        }
+        let at_most = |count| {
+            move |t: &SparseMerkleTree<F, H>| {
+                if t.leaf_paths().len() > count {
+                    Err(Error::TooManyLeaves)
+                } else {
+                    Ok(())
+                }
+            }
+        };
+        tree.set_history_limit(2);
+        tree.update(usize_to_vec(9, height), F::rand_vec(4));
+        tree.delete(usize_to_vec(9, height));
+        let dirty = tree.dirty.clone();
+        let (sink, calls) = std::sync::mpsc::channel();
+        let sink = std::sync::Mutex::new(sink);
+        tree.on_root_change(move |root| sink.lock().unwrap().send(root).unwrap());
+
+        let result = tree.transaction(&updates, at_most(3));
+        assert_eq!(result, Err(Error::TooManyLeaves));
+        assert_eq!(tree.get_root(), root);
+        assert_eq!(tree.version(), version + 2);
+        assert_eq!(tree.leaf_paths().len(), 1);
+        assert_eq!(tree.dirty, dirty);
+        assert!(tree
+            .prove_at_version(usize_to_vec(9, height), version)
+            .is_some());
+        assert!(calls.try_recv().is_err());
+
+        // A rejected update rolls back the ones before it instead of panicking.
+        tree.freeze_leaf(&updates[2].0);
+        let result = tree.transaction(&updates, at_most(10));
+        let path = updates[2].0.clone();
+        assert_eq!(result, Err(Error::Update(SmtError::LeafFrozen { path })));
+        assert_eq!(tree.get_root(), root);
+
+        // So does a panic, and the observers stay registered.
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            tree.transaction(&updates[..2], |_| -> Result<(), Error> { panic!() })
+        }));
+        assert!(result.is_err());
+        assert_eq!(tree.get_root(), root);
+
+        assert_eq!(tree.transaction(&updates[..2], at_most(3)), Ok(()));
+        assert_eq!(tree.leaf_paths().len(), 3);
+        assert_eq!(tree.get_leaf(&updates[1].0), updates[1].1);
+        assert_eq!(calls.try_recv(), Ok(tree.get_root()));
+    }
+
+    #[test]
+    fn prove_in_subtree_test() {
+        let mut rng = rand::thread_rng();
+        let height = 20;
+        let cap_height = 4;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let cap = tree.get_cap(cap_height).unwrap();
+        assert_eq!(cap.0.len(), 1 << cap_height);
+        assert_eq!(
+            tree.get_cap(height + 1),
+            Err(SmtError::InvalidLevel {
+                level: height + 1,
+                height
+            })
+        );
+        let tall = SparseMerkleTree::<F, H>::new(64);
+        assert_eq!(
+            tall.get_cap(64),
+            Err(SmtError::CapTooLarge { cap_height: 64 })
+        );
+
+        // The cap folds up to the root.
+        let mut layer = cap.0.clone();
+        while layer.len() > 1 {
+            layer = layer
+                .chunks(2)
+                .map(|pair| H::two_to_one(pair[0], pair[1]))
+                .collect();
+        }
+        assert_eq!(layer[0], tree.get_root());
+
+        for index in [0, rng.gen_range(0..1 << height)] {
+            let path = usize_to_vec(index, height);
+            let (subtree, proof) = tree.prove_in_subtree(cap_height, &path);
+            assert_eq!(subtree, index >> (height - cap_height));
+            assert_eq!(proof.siblings.len(), height - cap_height);
+            verify_merkle_proof_to_cap(tree.get_leaf(&path), index, &cap, &proof).unwrap();
+        }
+    }
+
+    #[test]
+    fn hash_elements_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut inner = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..5 {
+            inner.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let inner_root = inner.get_root();
+        let elements = hash_to_elements::<F, H>(&inner_root);
+        assert_eq!(elements.len(), 4);
+        assert_eq!(elements_to_hash::<F, H>(&elements), Some(inner_root));
+        assert_eq!(elements_to_hash::<F, H>(&elements[..3]), None);
+        assert_eq!(elements_to_hash::<F, H>(&F::rand_vec(5)), None);
+
+        // Storing the inner root as a leaf gives the same sub-root as hashing it directly.
+        let mut outer = SparseMerkleTree::<F, H>::new(height);
+        let path = usize_to_vec(7, height);
+        outer.update(&path, elements.clone());
+        assert_eq!(outer.get_node_hash(&path), H::hash_or_noop(&elements));
+        let stored = outer.get_leaf(&path);
+        assert_eq!(elements_to_hash::<F, H>(&stored), Some(inner_root));
+        let proof = outer.prove(&path);
+        assert!(outer
+            .verify(&path, &stored, outer.get_root(), &proof)
+            .is_valid());
+    }
+
+    #[test]
+    fn subtree_root_test() {
+        let mut rng = rand::thread_rng();
+        let (outer_height, inner_height) = (8, 12);
+        let account = usize_to_vec(3, outer_height);
+        let slot = usize_to_vec(rng.gen_range(0..1 << inner_height), inner_height);
+        let value = F::rand_vec(4);
+
+        let mut storage = SparseMerkleTree::<F, H>::new(inner_height);
+        storage.update(&slot, value.clone());
+        let mut accounts = SparseMerkleTree::<F, H>::new(outer_height);
+        assert_eq!(accounts.get_subtree_root(&account), None);
+        accounts.update(&account, F::rand_vec(2));
+        assert_eq!(accounts.get_subtree_root(&account), None);
+        accounts.update_subtree_root(&account, storage.get_root());
+        assert_eq!(
+            accounts.get_subtree_root(&account),
+            Some(storage.get_root())
+        );
+
+        // Prove the storage slot against the outer root in two hops.
+        let inner_proof = storage.prove(&slot);
+        let sub_root = accounts.get_subtree_root(&account).unwrap();
+        assert!(storage
+            .verify(&slot, &value, sub_root, &inner_proof)
+            .is_valid());
+        let outer_proof = accounts.prove(&account);
+        let leaf = hash_to_elements::<F, H>(&sub_root);
+        assert!(accounts
+            .verify(&account, &leaf, accounts.get_root(), &outer_proof)
+            .is_valid());
+    }
+
+    #[test]
+    fn simulate_batch_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H, 4>::new(height);
+        for _ in 0..30 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        assert_eq!(tree.simulate_batch(&[]), (tree.get_root(), 0));
+
+        let updates: Vec<_> = (0..5)
+            .map(|_| {
+                (
+                    usize_to_vec(rng.gen_range(0..1 << height), height),
+                    F::rand_vec(4),
+                )
+            })
+            .collect();
+        let (root, touched) = tree.simulate_batch(&updates);
+        let old_root = tree.get_root();
+
+        let mut applied = tree.clone();
+        for (path, value) in &updates {
+            applied.update(path, value.clone());
+        }
+        assert_eq!(root, applied.get_root());
+        assert_eq!(tree.get_root(), old_root);
+        // The root is shared by every path, so at most the remaining levels are per-update.
+        let levels = height / 2;
+        assert!(touched >= levels);
+        assert!(touched <= 1 + updates.len() * (levels - 1));
+    }
+
+    #[test]
+    fn verify_const_test() {
+        let mut rng = rand::thread_rng();
+        const HEIGHT: usize = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(HEIGHT);
+        for _ in 0..10 {
+            let path = usize_to_vec(rng.gen_range(0..1 << HEIGHT), HEIGHT);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let root = tree.get_root();
+        let path = usize_to_vec(rng.gen_range(0..1 << HEIGHT), HEIGHT);
+        tree.update(&path, F::rand_vec(4));
+        let root_after = tree.get_root();
+
+        let value = tree.get_leaf(&path);
+        let siblings: [_; HEIGHT] = tree.prove(&path).siblings.try_into().unwrap();
+        let bits: [bool; HEIGHT] = path.clone().try_into().unwrap();
+        for (value, root) in [
+            (&value, root_after),
+            (&value, root),
+            (&F::rand_vec(4), root_after),
+        ] {
+            assert_eq!(
+                SparseMerkleTree::<F, H>::verify_const(&bits, value, &siblings, root),
+                SparseMerkleTree::<F, H>::verify_borrowed(&path, value, &siblings, root)
+            );
+        }
+        assert!(SparseMerkleTree::<F, H>::verify_const(
+            &bits, &value, &siblings, root_after
+        ));
+    }
+
+    #[test]
+    fn freeze_leaf_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let frozen = usize_to_vec(6, height);
+        let sibling = usize_to_vec(7, height);
+        let value = F::rand_vec(4);
+        tree.update(&frozen, value.clone());
+        let root = tree.get_root();
+        tree.freeze_leaf(&frozen);
+        assert!(tree.is_frozen(&frozen));
+        assert_eq!(tree.get_root(), root);
+
+        let err = SmtError::LeafFrozen {
+            path: frozen.clone(),
+        };
+        assert_eq!(tree.try_update(&frozen, F::rand_vec(4)), Err(err.clone()));
+        assert_eq!(tree.try_delete(&frozen), Err(err));
+        assert_eq!(tree.get_leaf(&frozen), value);
+        assert_eq!(tree.get_root(), root);
+
+        tree.try_update(&sibling, F::rand_vec(4)).unwrap();
+        assert_ne!(tree.get_root(), root);
+        tree.try_delete(&sibling).unwrap();
+        assert_eq!(tree.get_root(), root);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            tree.update(&frozen, F::rand_vec(4))
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn insert_raw_node_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut source = SparseMerkleTree::<F, H, 4>::new(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            source.update(&path, F::rand_vec(4));
+        }
+
+        let mut loaded = SparseMerkleTree::<F, H, 4>::new(height);
+        for (path, node) in source.nodes.iter() {
+            loaded.insert_raw_node(path, node.clone());
+        }
+        assert_eq!(loaded.verify_integrity(), Ok(()));
+        assert_eq!(loaded.get_root(), source.get_root());
+
+        let leaf = source.leaf_paths()[0].to_bits();
+        loaded.insert_raw_node(
+            &leaf,
+            Node::Leaf {
+                value: F::rand_vec(4),
+            },
+        );
+        let parent = leaf[..height - 2].to_vec();
+        assert_eq!(
+            loaded.verify_integrity(),
+            Err(SmtError::CorruptNode { path: parent })
+        );
+    }
+
+    #[test]
+    fn leaf_set_commitment_test() {
+        let mut rng = rand::thread_rng();
+        let height = 10;
+        let values: Vec<_> = (0..8).map(|_| F::rand_vec(4)).collect();
+        let mut a = SparseMerkleTree::<F, H>::new(height);
+        let mut b = SparseMerkleTree::<F, H>::new(height);
+        assert_eq!(a.leaf_set_commitment(), b.leaf_set_commitment());
+        for (i, value) in values.iter().enumerate() {
+            a.update(&usize_to_vec(i, height), value.clone());
+            b.update(&usize_to_vec(1000 - 3 * i, height), value.clone());
+        }
+        // Leaves explicitly reset to the empty value don't count.
+        b.update(
+            usize_to_vec(rng.gen_range(100..200), height),
+            vec![F::ZERO; 4],
+        );
+        assert_ne!(a.get_root(), b.get_root());
+        assert_eq!(a.leaf_set_commitment(), b.leaf_set_commitment());
+
+        b.update(usize_to_vec(1000, height), F::rand_vec(4));
+        assert_ne!(a.leaf_set_commitment(), b.leaf_set_commitment());
+    }
+
+    #[test]
+    fn prove_neighbors_test() {
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        assert!(matches!(tree.prove_neighbors(5).unwrap(), (None, None)));
+        for i in [3, 40, 41, 900, 2000] {
+            tree.update(&usize_to_vec(i, height), F::rand_vec(4));
+        }
+        let root = tree.get_root();
+
+        let (lower, higher) = tree.prove_neighbors(500).unwrap();
+        let (lower, higher) = (lower.unwrap(), higher.unwrap());
+        assert_eq!((lower.index, higher.index), (41, 900));
+        for leaf in [lower, higher] {
+            let path = usize_to_vec(leaf.index as usize, height);
+            assert_eq!(leaf.value, tree.get_leaf(&path));
+            assert!(tree
+                .verify(&path, &leaf.value, root, &leaf.proof)
+                .is_valid());
+        }
+
+        // The queried index itself is excluded even when set.
+        let (lower, higher) = tree.prove_neighbors(40).unwrap();
+        assert_eq!(lower.unwrap().index, 3);
+        assert_eq!(higher.unwrap().index, 41);
+        let (lower, higher) = tree.prove_neighbors(2).unwrap();
+        assert!(lower.is_none());
+        assert_eq!(higher.unwrap().index, 3);
+        assert!(tree.prove_neighbors(4000).unwrap().1.is_none());
+    }
+
+    #[cfg(feature = "log")]
+    #[test]
+    fn log_test() {
+        use std::sync::Mutex;
+
+        struct Recorder(Mutex<Vec<String>>);
+        impl log::Log for Recorder {
+            fn enabled(&self, metadata: &log::Metadata) -> bool {
+                metadata.level() <= log::Level::Warn
+            }
+            fn log(&self, record: &log::Record) {
+                self.0.lock().unwrap().push(record.args().to_string());
+            }
+            fn flush(&self) {}
+        }
+        static RECORDER: Recorder = Recorder(Mutex::new(vec![]));
+        log::set_logger(&RECORDER).unwrap();
+        log::set_max_level(log::LevelFilter::Warn);
+
+        let mut tree = SparseMerkleTree::<F, H>::new(8);
+        let err = tree.try_update(vec![true; 3], F::rand_vec(4)).unwrap_err();
+        assert_eq!(err, SmtError::InvalidPath { len: 3, height: 8 });
+        assert_eq!(*RECORDER.0.lock().unwrap(), vec![err.to_string()]);
+        assert_eq!(tree.get_root(), SparseMerkleTree::<F, H>::new(8).get_root());
+    }
+
+    #[test]
+    fn full_path_info_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..10 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let index = rng.gen_range(0..1 << height);
+        let path = usize_to_vec(index, height);
+        tree.update(&path, F::rand_vec(4));
+
+        let info = tree.full_path_info(&path);
+        assert_eq!(info.levels.len(), height);
+        assert_eq!(info.root, tree.get_root());
+        assert_eq!(info.levels[0].hash, H::hash_or_noop(&tree.get_leaf(&path)));
+        let mut h = info.levels[0].hash;
+        for (depth, level) in info.levels.iter().enumerate() {
+            assert_eq!(level.index, index >> depth);
+            assert_eq!(level.hash, h);
+            h = if level.index % 2 == 0 {
+                H::two_to_one(h, level.siblings[0])
+            } else {
+                H::two_to_one(level.siblings[0], h)
+            };
+        }
+        assert_eq!(h, info.root);
+    }
+
+    #[test]
+    fn count_under_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H, 4>::new(height);
+        let mut indices = HashSet::new();
+        while indices.len() < 50 {
+            // Leave the subtree under 111 empty.
+            indices.insert(rng.gen_range(0..7 << (height - 3)));
+        }
+        for &i in &indices {
+            tree.update(&usize_to_vec(i, height), F::rand_vec(4));
+        }
+
+        assert_eq!(tree.count_under(&vec![]), indices.len());
+        for top in 0..8 {
+            let prefix = usize_to_vec(top, 3);
+            let expected = indices
+                .iter()
+                .filter(|&&i| i >> (height - 3) == top)
+                .count();
+            assert_eq!(tree.count_under(&prefix), expected);
+        }
+        assert_eq!(tree.count_under(vec![true; 3]), 0);
+        let leaf = tree.leaf_paths()[0].to_bits();
+        assert_eq!(tree.count_under(leaf), 1);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn verify_batch_par_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let paths: Vec<_> = (0..100)
+            .map(|_| usize_to_vec(rng.gen_range(0..1 << height), height))
+            .collect();
+        for path in &paths {
+            tree.update(path, F::rand_vec(4));
+        }
+        // 10k proofs, reusing the proofs of a hundred leaves to keep setup cheap.
+        let mut items: Vec<_> = paths
+            .iter()
+            .cycle()
+            .take(10_000)
+            .map(|path| (path.clone(), tree.get_leaf(path), tree.prove(path)))
+            .collect();
+        assert!(tree.verify_batch_par(&items));
+
+        let i = rng.gen_range(0..items.len());
+        items[i].2.siblings[5] = H::hash_or_noop(&F::rand_vec(4));
+        assert!(!tree.verify_batch_par(&items));
+    }
+
+    #[test]
+    fn from_leaves_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut leaves: Vec<_> = (0..300)
+            .map(|_| {
+                let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+                (path, F::rand_vec(4))
+            })
+            .collect();
+        leaves.push((leaves[0].0.clone(), F::rand_vec(4)));
+        let mut expected = SparseMerkleTree::<F, H, 4>::new(height);
+        for (path, value) in &leaves {
+            expected.update(path, value.clone());
+        }
+
+        let tree = SparseMerkleTree::<F, H, 4>::from_leaves(height, leaves.clone());
+        assert_eq!(tree.get_root(), expected.get_root());
+        assert_eq!(tree.get_leaf(&leaves[0].0), leaves[300].1);
+        assert_eq!(tree.nodes.len(), expected.nodes.len());
+        assert_eq!(tree.verify_integrity(), Ok(()));
+        assert_eq!(tree.version(), 0);
+        #[cfg(feature = "rayon")]
+        {
+            let par = SparseMerkleTree::<F, H, 4>::from_leaves_par(height, leaves);
+            assert_eq!(par.get_root(), expected.get_root());
+            assert_eq!(par.verify_integrity(), Ok(()));
+        }
+        assert_eq!(
+            SparseMerkleTree::<F, H>::from_leaves(height, vec![]).get_root(),
+            SparseMerkleTree::<F, H>::new(height).get_root()
+        );
+    }
+
+    #[test]
+    fn leaves_bytes_test() {
+        let mut rng = rand::thread_rng();
+        let height = 20;
+        let mut tree = SparseMerkleTree::<F, H>::new_level_tagged(height);
+        for _ in 0..30 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(rng.gen_range(1..6)));
+        }
+        let bytes = tree.leaves_to_bytes();
+        let restored = SparseMerkleTree::<F, H>::from_leaves_bytes(&bytes).unwrap();
+        assert_eq!(restored.get_root(), tree.get_root());
+        assert_eq!(restored.leaf_paths(), tree.leaf_paths());
+        // Even the bare hashes of every stored node take more room than the leaves.
+        assert!(bytes.len() < tree.nodes.len() * 32);
+
+        let empty = SparseMerkleTree::<F, H>::new(height);
+        let restored = SparseMerkleTree::<F, H>::from_leaves_bytes(&empty.leaves_to_bytes());
+        assert_eq!(restored.unwrap().get_root(), empty.get_root());
+
+        let default = vec![F::ONE, F::TWO];
+        let mut defaulted = SparseMerkleTree::<F, H>::new_with_default_leaf(height, default);
+        defaulted.update(&usize_to_vec(5, height), F::rand_vec(4));
+        let restored = SparseMerkleTree::<F, H>::from_leaves_bytes(&defaulted.leaves_to_bytes());
+        let restored = restored.unwrap();
+        assert_eq!(restored.get_root(), defaulted.get_root());
+        assert_eq!(restored.default_leaf(), defaulted.default_leaf());
+
+        // A huge height, set padding bits after the 20 bits of the first path, and a leaf too
+        // wide for its type are rejected rather than allocated or panicked on.
+        let mut huge = bytes.clone();
+        huge[..8].copy_from_slice(&u64::MAX.to_le_bytes());
+        let mut padded = bytes.clone();
+        padded[8 + 1 + 4 + 8 * 4 + 8 + 2] |= 1;
+        for bad in [
+            &bytes[..bytes.len() - 1],
+            &[bytes.as_slice(), &[0]].concat(),
+            &huge,
+            &padded,
+        ] {
+            assert_eq!(
+                SparseMerkleTree::<F, H>::from_leaves_bytes(bad).unwrap_err(),
+                SmtError::InvalidEncoding
+            );
+        }
+        let mut wide = SparseMerkleTree::<F, H>::new(height);
+        wide.update(usize_to_vec(1, height), F::rand_vec(5));
+        assert_eq!(
+            SparseMerkleTree::<F, H, 2, [F; 4]>::from_leaves_bytes(&wide.leaves_to_bytes())
+                .unwrap_err(),
+            SmtError::InvalidEncoding
+        );
+    }
+
+    #[test]
+    fn strict_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H, 4>::new(height);
+        tree.set_strict(true);
+        for _ in 0..10 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        tree.delete(tree.leaf_paths()[0]);
+
+        tree.corrupt_children = true;
+        let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            tree.update(&path, F::rand_vec(4))
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn apply_update_to_root_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..10 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let old_root = tree.get_root();
+        let path = tree.leaf_paths()[3].to_bits();
+        let old_value = tree.get_leaf(&path);
+        let siblings = tree.prove(&path).siblings;
+
+        let new_value = F::rand_vec(4);
+        tree.update(&path, new_value.clone());
+        let new_root =
+            apply_update_to_root::<F, H>(old_root, &path, &old_value, &new_value, &siblings);
+        assert_eq!(new_root, Ok(tree.get_root()));
+
+        assert_eq!(
+            apply_update_to_root::<F, H>(old_root, &path, &new_value, &old_value, &siblings),
+            Err(SmtError::InvalidProof { path })
+        );
+    }
+
+    #[test]
+    fn leaf_hash_collisions_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let x = F::rand();
+        let (a, b, c) = (
+            usize_to_vec(1, height),
+            usize_to_vec(9, height),
+            usize_to_vec(20, height),
+        );
+        tree.update(&a, vec![x]);
+        tree.update(&b, vec![x, F::ZERO]);
+        tree.update(&c, vec![x]);
+        tree.update(usize_to_vec(30, height), F::rand_vec(4));
+        assert_eq!(H::hash_or_noop(&[x]), H::hash_or_noop(&[x, F::ZERO]));
+
+        // Equal values at different paths are fine.
+        assert_eq!(
+            tree.check_leaf_hash_collisions(),
+            vec![
+                (Path::from(&a), Path::from(&b)),
+                (Path::from(&b), Path::from(&c))
+            ]
+        );
+    }
+
+    #[test]
+    fn hash_only_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H, 4>::new(height);
+        for _ in 0..10 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(8),
+            );
+        }
+        let root = tree.get_root();
+        let path = tree.leaf_paths()[0].to_bits();
+        let (leaf_hash, siblings) = tree.prove_hash_only(&path);
+        assert_eq!(leaf_hash, H::hash_or_noop(&tree.get_leaf(&path)));
+        type T = SparseMerkleTree<F, H, 4>;
+        assert!(T::verify_hash_only(
+            tree.get_leaf_hash(&path),
+            &path,
+            &siblings,
+            root
+        ));
+        let other = tree.get_leaf_hash(tree.leaf_paths()[1]);
+        assert!(!T::verify_hash_only(other, &path, &siblings, root));
+        assert!(!T::verify_hash_only(leaf_hash, &path, &siblings[1..], root));
+    }
+
+    #[test]
+    fn subtree_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..40 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let prefix = usize_to_vec(5, 4);
+        let mut subtree = tree.extract_subtree(&prefix);
+        assert_eq!(subtree.height, height - 4);
+        assert_eq!(subtree.get_root(), tree.get_node_hash(&prefix));
+
+        let mut expected = tree.clone();
+        let updates: Vec<_> = (0..5)
+            .map(|_| (usize_to_vec(rng.gen_range(0..1 << 12), 12), F::rand_vec(4)))
+            .collect();
+        for (path, value) in &updates {
+            subtree.update(path, value.clone());
+            expected.update(&[prefix.clone(), path.clone()].concat(), value.clone());
+        }
+        if let Some(path) = subtree.leaf_paths().first().cloned() {
+            subtree.delete(path);
+            expected.delete([prefix.clone(), path.to_bits()].concat());
+        }
+        tree.reinsert_subtree(&prefix, &subtree);
+        assert_eq!(tree.get_node_hash(&prefix), subtree.get_root());
+        assert_eq!(tree.get_root(), expected.get_root());
+        assert_eq!(tree.leaf_paths(), expected.leaf_paths());
+        assert_eq!(tree.verify_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn get_leaf_or_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let (set, unset) = (usize_to_vec(3, height), usize_to_vec(4, height));
+        let value = F::rand_vec(4);
+        tree.update(&set, value.clone());
+        let default = vec![F::ONE];
+
+        assert_eq!(tree.get_leaf_or(&set, default.clone()), value);
+        assert_eq!(tree.get_leaf_or(&unset, default.clone()), default);
+        assert_eq!(tree.get_leaf_or_empty(&set), value);
+        assert_eq!(tree.get_leaf_or_empty(&unset), vec![F::ZERO; 4]);
+    }
+
+    #[test]
+    fn to_dot_test() {
+        let height = 2;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        tree.update(vec![false, false], F::rand_vec(4));
+        tree.update(vec![true, true], F::rand_vec(4));
+
+        let dot = tree.to_dot();
+        assert!(dot.starts_with("digraph smt {\n") && dot.ends_with("}\n"));
+        let lines: Vec<&str> = dot.lines().collect();
+        let nodes = lines.iter().filter(|l| l.contains("[label=")).count();
+        let zeros = lines.iter().filter(|l| l.contains("\"zero\"")).count();
+        let edges = lines.iter().filter(|l| l.contains(" -> ")).count();
+        // The root, both inner nodes and both leaves, plus the two empty leaves.
+        assert_eq!((nodes, zeros, edges), (7, 2, 6));
+        assert!(dot.contains("  n -> n0;\n") && dot.contains("  n0 -> z01;\n"));
+    }
+
+    #[test]
+    fn windowed_root_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        type T = SparseMerkleTree<F, H, 4>;
+        let mut tree = T::new_level_tagged(height);
+        for _ in 0..40 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        assert_eq!(tree.windowed_root(0, 1 << height), tree.get_root());
+        assert_eq!(
+            tree.windowed_root(5, 5),
+            T::new_level_tagged(height).get_root()
+        );
+
+        let (start, end) = (1000, 3001);
+        let mut window = T::new_level_tagged(height);
+        for path in tree.leaf_paths() {
+            if (start..end).contains(&path.index_at(0, height)) {
+                window.update(path, tree.get_leaf(path));
+            }
+        }
+        assert_eq!(
+            tree.windowed_root(start as u64, end as u64),
+            window.get_root()
+        );
+    }
+
+    #[test]
+    fn proof_size_test() {
+        let mut rng = rand::thread_rng();
+        let height = 32;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..16 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let path = tree.leaf_paths()[0].to_bits();
+        assert_eq!(tree.proof_size_bytes(&path), height * 32);
+        // With 16 leaves, only about the 4 top siblings are stored.
+        let compressed = tree.compressed_proof_size_bytes(&path);
+        let stored = tree.prove_annotated(&path).iter().filter(|s| !s.1).count();
+        assert_eq!(compressed, 4 + stored * 32);
+        assert!(compressed * 3 < tree.proof_size_bytes(&path));
+    }
+
+    #[test]
+    fn prove_all_absent_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        type T = SparseMerkleTree<F, H, 4>;
+        let mut tree = T::new(height);
+        for _ in 0..30 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let root = tree.get_root();
+        let set: Vec<_> = tree.leaf_paths().iter().map(Path::to_bits).collect();
+        let mut absent = vec![];
+        while absent.len() < 20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            if !set.contains(&path) {
+                absent.push(path);
+            }
+        }
+        // Neighbouring queries share their upper siblings.
+        absent.push(usize_to_vec(bits_to_index(&absent[0]) ^ 1, height));
+        absent.retain(|path| !set.contains(path));
+
+        let proof = tree.prove_all_absent(&absent).unwrap();
+        assert!(proof.siblings.len() < absent.len() * (height / 2) * 3);
+        assert!(T::verify_all_absent(&proof, root));
+        assert!(!T::verify_all_absent(&proof, T::new(height).get_root()));
+        let mut truncated = proof;
+        truncated.siblings.pop();
+        assert!(!T::verify_all_absent(&truncated, root));
+
+        absent.push(set[0].clone());
+        assert_eq!(
+            tree.prove_all_absent(&absent).unwrap_err(),
+            SmtError::LeafNotEmpty {
+                path: set[0].clone()
+            }
+        );
+    }
+
+    #[test]
+    fn prove_multi_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let mut paths = vec![];
+        for _ in 0..40 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+            paths.push(path);
+        }
+        // An unset leaf can be proven along with the set ones.
+        paths.push(usize_to_vec(bits_to_index(&paths[0]) ^ 1, height));
+        let root = tree.get_root();
+
+        let proof = tree.prove_multi(&paths);
+        let values: Vec<_> = proof.paths.iter().map(|p| tree.get_leaf(p)).collect();
+        assert!(SparseMerkleTree::<F, H>::verify_multi(
+            &proof, &values, root
+        ));
+        assert!(proof.siblings.len() < proof.paths.len() * height);
+
+        let mut wrong = values.clone();
+        wrong[3] = F::rand_vec(4);
+        assert!(!SparseMerkleTree::<F, H>::verify_multi(
+            &proof, &wrong, root
+        ));
+        assert!(!SparseMerkleTree::<F, H>::verify_multi(
+            &proof,
+            &values[1..],
+            root
+        ));
+    }
+
+    #[test]
+    fn hash_by_root_test() {
+        let height = 8;
+        let (a, b) = (usize_to_vec(1, height), usize_to_vec(2, height));
+        let value = F::rand_vec(4);
+        let mut first = SparseMerkleTree::<F, H>::new(height);
+        first.update(&a, value.clone());
+        // Same leaves, but a different history and more stored nodes.
+        let mut second = SparseMerkleTree::<F, H>::new(height);
+        second.set_history_limit(4);
+        second.update(&b, F::rand_vec(4));
+        second.update(&a, value);
+        second.update(&b, vec![F::ZERO; 4]);
+        assert_ne!(first.nodes.len(), second.nodes.len());
+        assert!(first == second);
+
+        // Trees hash by height and root only, not by their hash counters.
+        #[allow(clippy::mutable_key_type)]
+        let mut set = HashSet::new();
+        set.insert(first);
+        set.insert(second);
+        set.insert(SparseMerkleTree::<F, H>::new(height + 1));
+        assert_eq!(set.len(), 2);
+    }
+
+    #[test]
+    fn prove_to_depth_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..20 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let path = tree.leaf_paths()[0].to_bits();
+        for depth in [0, 5, height] {
+            let (hash, siblings) = tree.prove_to_depth(&path, depth);
+            assert_eq!(hash, tree.get_node_hash(&path[..depth].to_vec()));
+            assert_eq!(siblings.len(), depth);
+            assert!(SparseMerkleTree::<F, H>::verify_hash_only(
+                hash,
+                &path[..depth],
+                &siblings,
+                tree.get_root()
+            ));
+        }
+        let full = tree.prove(&path).siblings;
+        assert_eq!(tree.prove_to_depth(&path, 5).1, full[height - 5..]);
+    }
+
+    #[test]
+    fn root_if_subtree_zeroed_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H, 4>::new_level_tagged(height);
+        for _ in 0..40 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let prefix = tree.leaf_paths()[0].prefix(4);
+        let expected = tree.root_if_subtree_zeroed(prefix);
+        assert_eq!(
+            tree.root_if_subtree_zeroed(&vec![]),
+            tree.windowed_root(0, 0)
+        );
+
+        for path in tree.leaf_paths() {
+            if path.starts_with(&prefix) {
+                tree.delete(path);
+            }
+        }
+        assert_eq!(expected, tree.get_root());
+    }
+
+    #[test]
+    fn apply_log_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        tree.update(&usize_to_vec(1, height), F::rand_vec(4));
+        let base = tree.clone();
+
+        tree.start_recording();
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            if rng.gen_bool(0.2) {
+                tree.delete(&path);
+            } else {
+                tree.update(&path, F::rand_vec(4));
+            }
+        }
+        tree.delete(usize_to_vec(1, height));
+        let log = tree.take_log();
+        assert_eq!(log.len(), 21);
+        assert!(tree.take_log().is_empty());
+
+        let mut replayed = base;
+        replayed.apply_log(&log).unwrap();
+        assert_eq!(replayed.get_root(), tree.get_root());
+
+        let mut frozen = SparseMerkleTree::<F, H>::new(height);
+        frozen.freeze_leaf(usize_to_vec(1, height));
+        assert!(frozen.apply_log(&log).is_err());
+    }
+
+    #[test]
+    fn update_hash_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut full = SparseMerkleTree::<F, H>::new(height);
+        let mut hashed = SparseMerkleTree::<F, H>::new(height);
+        hashed.set_strict(true);
+        hashed.start_recording();
+        for _ in 0..10 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            let value = F::rand_vec(4);
+            full.update(&path, value.clone());
+            hashed.update_hash(&path, H::hash_or_noop(&value));
+        }
+        assert_eq!(hashed.get_root(), full.get_root());
+
+        let path = full.leaf_paths()[0].to_bits();
+        let proof = hashed.prove(&path);
+        assert_eq!(proof.siblings, full.prove(&path).siblings);
+        assert!(full
+            .verify(&path, &full.get_leaf(&path), hashed.get_root(), &proof)
+            .is_valid());
+        assert_eq!(hashed.try_get_leaf(&path), Ok(None));
+        assert_eq!(hashed.get_leaves(&[path.clone()]), vec![None]);
+        let result =
+            std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| hashed.get_leaf(&path)));
+        assert!(result.is_err());
+
+        let mut replayed = SparseMerkleTree::<F, H>::new(height);
+        replayed.apply_log(&hashed.take_log()).unwrap();
+        assert_eq!(replayed.get_root(), full.get_root());
+    }
+
+    #[test]
+    fn hash_only_leaf_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let hashed = usize_to_vec(7, height);
+        let stored = usize_to_vec(9, height);
+        tree.update_hash(&hashed, H::hash_or_noop(&F::rand_vec(4)));
+        tree.update(&stored, F::rand_vec(4));
+        let root = tree.get_root();
+        let err = SmtError::LeafHashOnly {
+            path: hashed.clone(),
+        };
+
+        let updates = vec![(hashed.clone(), F::rand_vec(4))];
+        assert_eq!(tree.update_delta(&updates).err(), Some(err.clone()));
+        assert_eq!(tree.prove_block(&updates).err(), Some(err.clone()));
+        assert_eq!(tree.get_root(), root);
+        assert_eq!(tree.prove_leaf(&hashed).err(), Some(err.clone()));
+        assert_eq!(tree.prove_neighbors(8).err(), Some(err));
+        assert_eq!(tree.prove_leaf(&stored).unwrap().root, Some(root));
+        assert!(tree
+            .update_delta(&[(stored.clone(), F::rand_vec(4))])
+            .is_ok());
+        let (lower, higher) = tree.prove_neighbors(10).unwrap();
+        assert_eq!(lower.unwrap().index, 9);
+        assert!(higher.is_none());
+
+        // Overwriting the hash-only leaf earlier in the block makes its value known.
+        let block = vec![
+            (hashed.clone(), F::rand_vec(4)),
+            (hashed.clone(), F::rand_vec(4)),
+        ];
+        assert!(tree.clone().prove_block(&block).is_err());
+        let mut overwritten = tree.clone();
+        overwritten.update(&hashed, F::rand_vec(4));
+        assert!(overwritten.prove_block(&block).is_ok());
+
+        let decoded = SparseMerkleTree::<F, H>::from_leaves_bytes(&tree.leaves_to_bytes()).unwrap();
+        assert_eq!(decoded.get_root(), root);
+        assert_eq!(decoded.try_get_leaf(&hashed), Ok(None));
+        assert_eq!(decoded.get_leaf(&stored), tree.get_leaf(&stored));
+    }
+
+    #[test]
+    fn update_with_old_proof_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..10 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+        let old_value = tree.get_leaf(&path);
+        let new_value = F::rand_vec(4);
+        let (proof, old_root, new_root) = tree.update_with_old_proof(&path, new_value.clone());
+        assert_ne!(old_root, new_root);
+        assert_eq!(new_root, tree.get_root());
+        assert!(tree.verify(&path, &old_value, old_root, &proof).is_valid());
+        assert!(tree.verify(&path, &new_value, new_root, &proof).is_valid());
+    }
+
+    #[cfg(feature = "zeroize")]
+    #[test]
+    fn zeroize_test() {
+        use std::mem::ManuallyDrop;
+
+        let value = F::rand_array::<4>();
+        let mut node = ManuallyDrop::new(Node::<F, H, 2, [F; 4]>::Leaf { value });
+        let elements = match &*node {
+            Node::Leaf { value } => value.as_ptr(),
+            _ => unreachable!(),
+        };
+        // SAFETY: the node is dropped once, and `[F; 4]` has no drop glue of its own, so its
+        // inline storage stays readable afterwards.
+        unsafe {
+            ManuallyDrop::drop(&mut node);
+            assert_eq!(std::slice::from_raw_parts(elements, 4), [F::ZERO; 4]);
+        }
+
+        // Overwriting and deleting still behave as before.
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let path = usize_to_vec(3, height);
+        tree.update(&path, F::rand_vec(4));
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+        assert_eq!(tree.get_leaf(&path), value);
+
+        // Copies handed out are the caller's to wipe, as are logged values through `Zeroize`.
+        let mut copy = tree.get_leaf(&path);
+        copy.wipe();
+        assert_eq!(copy, vec![F::ZERO; 4]);
+        let mut logged = LoggedLeaf::<F, H>::Value(value);
+        logged.zeroize();
+        assert!(matches!(&logged, LoggedLeaf::Value(v) if v.iter().all(|x| x.is_zero())));
+
+        tree.delete(&path);
+        assert_eq!(
+            tree.get_root(),
+            SparseMerkleTree::<F, H>::new(height).get_root()
+        );
+    }
+
+    #[test]
+    fn prove_wire_test() {
+        let mut rng = rand::thread_rng();
+        let height = 20;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..10 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let root = tree.get_root();
+        let path = tree.leaf_paths()[0].to_bits();
+        let value = tree.get_leaf(&path);
+        let bytes = tree.prove_wire(&path);
+        assert_eq!(bytes.len(), height * 32);
+        assert!(SparseMerkleTree::<F, H>::verify_wire(
+            &bytes, &path, &value, root
+        ));
+        assert!(!SparseMerkleTree::<F, H>::verify_wire(
+            &bytes,
+            &path,
+            &F::rand_vec(4),
+            root
+        ));
+        assert!(!SparseMerkleTree::<F, H>::verify_wire(
+            &bytes[1..],
+            &path,
+            &value,
+            root
+        ));
+        assert!(!SparseMerkleTree::<F, H>::verify_wire(
+            &bytes[32..],
+            &path,
+            &value,
+            root
+        ));
+    }
+
+    #[test]
+    fn first_divergence_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H, 4>::new(height);
+        for _ in 0..30 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let mut other = tree.clone();
+        assert!(other.matches_root(tree.get_root()));
+        assert_eq!(tree.first_divergence(&other), None);
+
+        let leaf = usize_to_vec(rng.gen_range(0..1 << height), height);
+        other.update(&leaf, F::rand_vec(4));
+        assert!(!other.matches_root(tree.get_root()));
+        assert_eq!(tree.first_divergence(&other), Some(Path::from(&leaf)));
+
+        // Two differing leaves in different halves diverge at the root.
+        let mut far = leaf;
+        far[0] = !far[0];
+        other.update(&far, F::rand_vec(4));
+        assert_eq!(tree.first_divergence(&other), Some(Path::new()));
+    }
+
+    #[test]
+    fn proofs_iter_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..10 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let mut paths: Vec<_> = tree.leaf_paths().iter().map(Path::to_bits).collect();
+        let batch = tree.prove_batch(&paths);
+        let streamed: Vec<_> = tree.proofs_iter(&paths).collect();
+        assert_eq!(batch.len(), paths.len());
+        for (a, b) in batch.iter().zip(&streamed) {
+            assert_eq!(a.siblings, b.siblings);
+        }
+
+        // Proving the malformed fourth path would panic, so only three proofs are computed.
+        paths[3] = vec![true];
+        assert_eq!(tree.proofs_iter(&paths).take(3).count(), 3);
+    }
+
+    #[test]
+    fn leaf_validator_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        tree.set_leaf_validator(|value| value.iter().any(|x| !x.is_zero()));
+        let path = usize_to_vec(9, height);
+
+        assert_eq!(
+            tree.try_update(&path, vec![F::ZERO; 4]),
+            Err(SmtError::InvalidLeaf { path: path.clone() })
+        );
+        assert_eq!(tree.get_leaves(&[path.clone()]), vec![None]);
+        let value = vec![F::ZERO, F::ONE];
+        tree.try_update(&path, value.clone()).unwrap();
+        assert_eq!(tree.get_leaf(&path), value);
+
+        // Clones share the validator.
+        let mut clone = tree.clone();
+        assert!(clone.try_update(&path, vec![F::ZERO]).is_err());
+    }
+
+    #[test]
+    fn structured_leaf_test() {
+        #[derive(Clone, Debug, PartialEq)]
+        struct Account([F; 3]);
+
+        impl Account {
+            fn balance(&self) -> F {
+                self.0[1]
+            }
+        }
+
+        impl LeafValue<F> for Account {
+            fn empty() -> Self {
+                Account([F::ZERO; 3])
+            }
+
+            fn elements(&self) -> &[F] {
+                &self.0
+            }
+
+            fn elements_mut(&mut self) -> &mut [F] {
+                &mut self.0
+            }
+
+            fn from_elements(elements: &[F]) -> Self {
+                Account(LeafValue::from_elements(elements))
+            }
+        }
+
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H, 2, Account>::new(height);
+        let path = usize_to_vec(3, height);
+        let account = Account([F::ONE, F::from_canonical_u64(100), F::rand()]);
+        tree.update(&path, account.clone());
+        assert_eq!(tree.get_leaf(&path).balance(), F::from_canonical_u64(100));
+        let proof = tree.prove(&path);
+        assert!(tree
+            .verify(&path, account.elements(), tree.get_root(), &proof)
+            .is_valid());
+
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        tree.set_leaf_validator(|value| value.iter().any(|x| !x.is_zero()));
+        tree.require_leaf_width(3);
+        assert_eq!(
+            tree.try_update(&path, F::rand_vec(4)),
+            Err(SmtError::InvalidLeaf { path: path.clone() })
+        );
+        assert!(tree.try_update(&path, vec![F::ZERO; 3]).is_err());
+        tree.try_update(&path, F::rand_vec(3)).unwrap();
+    }
+
+    #[test]
+    fn window_prover_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H, 4>::new(height);
+        for _ in 0..200 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(1000..1100), height),
+                F::rand_vec(4),
+            );
+        }
+        let mut window = WindowProver::new(&tree, 1010);
+        for index in 1010..1090 {
+            let path = usize_to_vec(index, height);
+            assert_eq!(window.index(), index as u64);
+            assert_eq!(window.path(), &Path::from(&path));
+            assert_eq!(window.proof().siblings, tree.prove(&path).siblings);
+            window.advance();
+        }
+
+        let mut last = WindowProver::new(&tree, (1 << height) - 2);
+        last.advance();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| last.advance()));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn with_zero_hashes_test() {
+        let height = 12;
+        type T = SparseMerkleTree<F, H, 4>;
+        let reference = T::new(height);
+        let table = reference.zero_hashes().to_vec();
+        let mut tree = T::with_zero_hashes(height, table.clone()).unwrap();
+        assert_eq!(tree.get_root(), reference.get_root());
+        let path = usize_to_vec(77, height);
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+        let mut reference = reference;
+        reference.update(&path, value);
+        assert_eq!(tree.get_root(), reference.get_root());
+
+        let mut bad = table.clone();
+        bad[2] = H::hash_or_noop(&[F::ONE]);
+        assert_eq!(
+            T::with_zero_hashes(height, bad).unwrap_err(),
+            SmtError::InvalidZeroHashes { level: 1 }
+        );
+        assert!(T::with_zero_hashes(height, table[1..].to_vec()).is_err());
+        let tagged = T::new_level_tagged(height).zero_hashes;
+        assert!(T::with_zero_hashes(height, tagged).is_err());
+    }
+
+    #[test]
+    fn cached_cap_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let cap_height = 4;
+        let mut tree = SparseMerkleTree::<F, H, 4>::new(height);
+        tree.update(&usize_to_vec(5, height), F::rand_vec(4));
+        tree.cache_cap(cap_height).unwrap();
+        assert_eq!(
+            tree.cache_cap(3),
+            Err(SmtError::InvalidLevel { level: 3, height })
+        );
+        for _ in 0..100 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            if rng.gen_bool(0.2) {
+                tree.delete(&path);
+            } else {
+                tree.update(&path, F::rand_vec(4));
+            }
+        }
+        let fresh: Vec<_> = (0..1 << cap_height)
+            .map(|i| tree.get_node_hash(usize_to_vec(i, cap_height)))
+            .collect();
+        assert_eq!(tree.cached_cap(), Some(fresh.as_slice()));
+
+        let mut binary = SparseMerkleTree::<F, H>::new(height);
+        binary.cache_cap(3).unwrap();
+        assert_eq!(
+            SparseMerkleTree::<F, H>::new(40).cache_cap(40),
+            Err(SmtError::CapTooLarge { cap_height: 40 })
+        );
+        for _ in 0..20 {
+            binary.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let mut layer = binary.get_cap(3).unwrap().0;
+        while layer.len() > 1 {
+            layer = layer.chunks(2).map(|p| H::two_to_one(p[0], p[1])).collect();
+        }
+        assert_eq!(layer[0], binary.get_root());
+    }
+
+    #[test]
+    fn missing_sibling_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let paths = tree.leaf_paths();
+        let items: Vec<_> = paths[..2]
+            .iter()
+            .map(|path| (path.to_bits(), tree.get_leaf(path), tree.prove(path)))
+            .collect();
+        let partial =
+            SparseMerkleTree::<F, H>::from_proofs(height, tree.get_root(), &items).unwrap();
+
+        assert_eq!(partial.try_prove(paths[0]).unwrap(), tree.prove(paths[0]));
+        // A leaf next to a proven one is fully known, as its siblings are on the proven path.
+        let mut missing = 0;
+        for path in &paths[2..] {
+            match partial.try_prove(path) {
+                Ok(proof) => assert_eq!(proof, tree.prove(path)),
+                Err(SmtError::MissingSibling { .. }) => missing += 1,
+                Err(err) => panic!("{err}"),
+            }
+        }
+        assert!(missing > 0);
+        assert_eq!(
+            partial.try_prove(vec![true; 3]).unwrap_err(),
+            SmtError::InvalidPath { len: 3, height }
+        );
+    }
+
+    #[test]
+    fn path_from_hex_test() {
+        let path = path_from_hex("0xa5f0", 12).unwrap();
+        assert_eq!(
+            path,
+            [1, 0, 1, 0, 0, 1, 0, 1, 1, 1, 1, 1]
+                .map(|b| b == 1)
+                .to_vec()
+        );
+        assert_eq!(path_from_hex("A5F0", 12).unwrap(), path);
+
+        let mut tree = SparseMerkleTree::<F, H>::new(12);
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+        assert_eq!(tree.get_leaf(usize_to_vec(0xa5f, 12)), value);
+        assert!(tree
+            .verify(&path, &value, tree.get_root(), &tree.prove(&path))
+            .is_valid());
+
+        assert_eq!(path_from_hex("a5f", 8), Err(SmtError::InvalidEncoding));
+        assert_eq!(path_from_hex("a5zz", 8), Err(SmtError::InvalidEncoding));
+        assert_eq!(
+            path_from_hex("a5", 12),
+            Err(SmtError::InvalidPath { len: 8, height: 12 })
+        );
+    }
+
+    #[cfg(feature = "hash-stats")]
+    #[test]
+    fn hash_op_stats_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..10 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        // Neighbouring leaves share all but their lowest ancestors.
+        let updates: Vec<_> = (0..32)
+            .map(|i| (usize_to_vec(i, height), F::rand_vec(4)))
+            .collect();
+
+        tree.reset_hash_op_stats();
+        let (root, _) = tree.simulate_batch(&updates);
+        let batch = tree.hash_op_stats();
+        assert_eq!(batch.hash_no_pad, 0);
+
+        tree.reset_hash_op_stats();
+        for (path, value) in updates {
+            tree.update(&path, value);
+        }
+        let sequential = tree.hash_op_stats();
+        assert_eq!(tree.get_root(), root);
+        assert!(batch.two_to_one < sequential.two_to_one);
+
+        // Counts are per tree, and include hashes computed on the rayon pool.
+        let before = tree.hash_op_stats();
+        let mut other = SparseMerkleTree::<F, H>::new(height);
+        other.update(usize_to_vec(1, height), F::rand_vec(4));
+        assert_eq!(tree.hash_op_stats(), before);
+        #[cfg(feature = "rayon")]
+        {
+            let leaves: Vec<_> = (0..64)
+                .map(|i| (usize_to_vec(i * 7, height), F::rand_vec(4)))
+                .collect();
+            let built = SparseMerkleTree::<F, H>::from_leaves(height, leaves.clone());
+            let built_par = SparseMerkleTree::<F, H>::from_leaves_par(height, leaves);
+            assert!(built.hash_op_stats().hash_or_noop >= 64);
+            assert_eq!(built_par.hash_op_stats(), built.hash_op_stats());
+        }
+
+        tree.reset_hash_op_stats();
+        assert_eq!(tree.hash_op_stats(), HashOpStats::default());
+    }
+
+    #[test]
+    fn root_digest_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let paths: Vec<_> = (0..4).map(|i| usize_to_vec(i * 37, height)).collect();
+        for path in &paths {
+            tree.update(path, F::rand_vec(4));
+        }
+        let digest = tree.root_digest();
+        assert_eq!(tree.clone().root_digest(), digest);
+        assert_eq!(digest.to_vec(), tree.get_root().to_bytes());
+
+        for path in &paths {
+            let mut changed = tree.clone();
+            changed.update(path, F::rand_vec(4));
+            assert_ne!(changed.root_digest(), digest);
+        }
+    }
+
+    #[test]
+    fn apply_and_verify_test() {
+        let height = 10;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        tree.update(&usize_to_vec(1, height), F::rand_vec(4));
+        let updates: Vec<_> = (2..6)
+            .map(|i| (usize_to_vec(i, height), F::rand_vec(4)))
+            .collect();
+        let mut expected = tree.clone();
+        for (path, value) in &updates {
+            expected.update(path, value.clone());
+        }
+
+        let root = tree.get_root();
+        let version = tree.version();
+        assert_eq!(
+            tree.apply_and_verify(&updates, root),
+            Err(SmtError::RootMismatch)
+        );
+        assert_eq!(tree.get_root(), root);
+        assert_eq!(tree.version(), version);
+        assert_eq!(tree.leaf_paths().len(), 1);
+
+        tree.apply_and_verify(&updates, expected.get_root())
+            .unwrap();
+        assert_eq!(tree.get_root(), expected.get_root());
+        assert_eq!(tree.get_leaf(&updates[3].0), updates[3].1);
+    }
+
+    #[test]
+    fn empty_slots_under_test() {
+        let height = 4;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        assert_eq!(tree.empty_slots_under(&vec![], 3), vec![0, 1, 2]);
+
+        for i in [0, 1, 2, 5, 9, 10, 11] {
+            tree.update(&usize_to_vec(i, height), F::rand_vec(4));
+        }
+        assert_eq!(tree.empty_slots_under(vec![], 5), vec![3, 4, 6, 7, 8]);
+        assert_eq!(
+            tree.empty_slots_under(vec![], 16),
+            vec![3, 4, 6, 7, 8, 12, 13, 14, 15]
+        );
+        assert_eq!(tree.empty_slots_under(vec![true], 3), vec![8, 12, 13]);
+        assert_eq!(tree.empty_slots_under(vec![true, false, true], 4), vec![]);
+        assert_eq!(tree.empty_slots_under(vec![false], 0), vec![]);
+        assert_eq!(tree.empty_slots_under(usize_to_vec(3, height), 2), vec![3]);
+    }
+
+    #[test]
+    fn verify_under_prefix_test() {
+        let mut rng = rand::thread_rng();
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..10 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let path = usize_to_vec(0b1000_0000 | rng.gen_range(0..1 << 6), height);
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+        let root = tree.get_root();
+        let proof = tree.prove(&path);
+
+        type Smt = SparseMerkleTree<F, H>;
+        assert!(Smt::verify_under_prefix(
+            &[true, false],
+            &value,
+            &[proof.clone()],
+            root
+        ));
+        assert!(!Smt::verify_under_prefix(
+            &[false, true],
+            &value,
+            &[proof.clone()],
+            root
+        ));
+        assert!(!Smt::verify_under_prefix(
+            &[true, false],
+            &F::rand_vec(4),
+            &[proof.clone()],
+            root
+        ));
+
+        // The real proof can be hidden among decoys.
+        let decoy = tree.prove(usize_to_vec(0b1011_1111 ^ (path[7] as usize), height));
+        assert!(!Smt::verify_under_prefix(
+            &[true, false],
+            &value,
+            &[decoy.clone()],
+            root
+        ));
+        assert!(Smt::verify_under_prefix(
+            &[true, false],
+            &value,
+            &[decoy, proof],
+            root
+        ));
+
+        // Too many hidden bits are rejected rather than searched through.
+        let siblings = vec![tree.zero_hashes[0]; Smt::MAX_HIDDEN_BITS + 1];
+        let long = MerkleProof { siblings };
+        assert!(!Smt::verify_under_prefix(&[], &value, &[long], root));
+        let siblings = vec![tree.zero_hashes[0]; 64];
+        let long = MerkleProof { siblings };
+        assert!(!Smt::verify_under_prefix(&[], &value, &[long], root));
+    }
+
+    #[test]
+    fn compact_empty_leaves_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let mut untouched = SparseMerkleTree::<F, H>::new(height);
+        let value = F::rand_vec(4);
+        tree.update(&usize_to_vec(3, height), value.clone());
+        untouched.update(&usize_to_vec(3, height), value);
+        for i in [2, 100, 200] {
+            tree.update(&usize_to_vec(i, height), vec![F::ZERO; 4]);
+        }
+        let root = tree.get_root();
+        assert_eq!(root, untouched.get_root());
+        assert!(tree.nodes.len() > untouched.nodes.len());
+
+        tree.compact_empty_leaves();
+        assert_eq!(tree.get_root(), root);
+        let stored = |t: &SparseMerkleTree<F, H>| {
+            let mut paths: Vec<_> = t.nodes.iter().map(|(path, _)| *path).collect();
+            paths.sort();
+            paths
+        };
+        assert_eq!(stored(&tree), stored(&untouched));
+    }
+
+    #[test]
+    fn prepared_verification_test() {
+        let height = 10;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let path = usize_to_vec(77, height);
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+        tree.update(usize_to_vec(500, height), F::rand_vec(4));
+        let old_root = tree.get_root();
+        tree.update(usize_to_vec(600, height), F::rand_vec(4));
+        let proof = tree.prove(&path);
+
+        let prepared = PreparedVerification::<F, H>::new(&path, &value, &proof.siblings);
+        assert_eq!(prepared.root(), Some(tree.get_root()));
+        assert!(prepared.matches(tree.get_root()));
+        assert!(!prepared.matches(old_root));
+
+        let truncated = PreparedVerification::<F, H>::new(&path, &value, &proof.siblings[1..]);
+        assert_eq!(truncated.root(), None);
+        assert!(!truncated.matches(tree.get_root()));
+    }
+
+    #[test]
+    fn prove_with_shared_suffix_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H, 4>::new(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        // Leaves under the prefix 1011, which spans two levels of the quaternary tree.
+        let paths: Vec<_> = [0b1011_0000_0001, 0b1011_0110_0000, 0b1011_1111_1111]
+            .into_iter()
+            .map(|i| usize_to_vec(i, height))
+            .collect();
+        for path in &paths {
+            tree.update(path, F::rand_vec(4));
+        }
+
+        let (shared, lower) = tree.prove_with_shared_suffix(&paths);
+        assert_eq!(shared.len(), 2 * 3);
+        for (path, lower) in paths.iter().zip(lower) {
+            assert_eq!(lower.len(), 4 * 3);
+            let siblings = [lower, shared.clone()].concat();
+            assert_eq!(siblings, tree.prove(path).siblings);
+            assert!(SparseMerkleTree::<F, H, 4>::verify_borrowed(
+                path,
+                &tree.get_leaf(path),
+                &siblings,
+                tree.get_root()
+            ));
+        }
+
+        let (shared, lower) = tree.prove_with_shared_suffix(&paths[..1]);
+        assert_eq!(shared, tree.prove(&paths[0]).siblings);
+        assert_eq!(lower, vec![vec![]]);
+    }
+
+    #[test]
+    fn from_node_stream_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut source = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            source.update(&path, F::rand_vec(4));
+        }
+        let stream = || {
+            source
+                .nodes
+                .iter()
+                .map(|(path, node)| (path.to_bits(), node.clone()))
+        };
+
+        let loaded = SparseMerkleTree::<F, H>::from_node_stream(height, stream()).unwrap();
+        assert_eq!(loaded.get_root(), source.get_root());
+
+        let leaf = source.leaf_paths()[0].to_bits();
+        let corrupted = stream().map(|(path, node)| {
+            if path == leaf {
+                let value = F::rand_vec(4);
+                (path, Node::Leaf { value })
+            } else {
+                (path, node)
+            }
+        });
+        assert_eq!(
+            SparseMerkleTree::<F, H>::from_node_stream(height, corrupted).unwrap_err(),
+            SmtError::CorruptNode {
+                path: leaf[..height - 1].to_vec()
+            }
+        );
+    }
+
+    #[test]
+    fn remaining_capacity_test() {
+        let height = 10;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        assert_eq!(tree.remaining_capacity(), Some(1024));
+        for i in 0..5 {
+            tree.update(&usize_to_vec(i * 3, height), F::rand_vec(4));
+        }
+        tree.update(usize_to_vec(3, height), F::rand_vec(4));
+        assert_eq!(tree.remaining_capacity(), Some(1019));
+        tree.delete(usize_to_vec(0, height));
+        assert_eq!(tree.remaining_capacity(), Some(1020));
+
+        let mut tall = SparseMerkleTree::<F, H>::new(127);
+        tall.update(vec![true; 127], F::rand_vec(4));
+        assert_eq!(tall.remaining_capacity(), Some((1 << 127) - 1));
+
+        let mut huge = SparseMerkleTree::<F, H>::new(200);
+        huge.update(vec![false; 200], F::rand_vec(4));
+        assert_eq!(huge.remaining_capacity(), None);
+    }
+
+    #[test]
+    fn packed_path_test() {
+        let mut rng = rand::thread_rng();
+        let mut paths: Vec<Vec<bool>> = (0..200)
+            .map(|_| {
+                let len = rng.gen_range(0..=Path::MAX_LEN);
+                (0..len).map(|_| rng.gen()).collect()
+            })
+            .collect();
+        paths.push(vec![]);
+        paths.push(vec![false]);
+        paths.push(vec![false, false]);
+        for path in &paths {
+            assert_eq!(Path::from(path).to_bits(), *path);
+        }
+        let mut packed: Vec<Path> = paths.iter().map(Path::from).collect();
+        packed.sort();
+        paths.sort();
+        assert_eq!(packed.iter().map(Path::to_bits).collect::<Vec<_>>(), paths);
+
+        let height = 256;
+        let mut tree = SparseMerkleTree::<F, H, 2, Vec<F>, BTreeMap<Path, Node<F, H>>>::new(height);
+        let mut reference = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..20 {
+            let path: Vec<bool> = (0..height).map(|_| rng.gen()).collect();
+            let value = F::rand_vec(4);
+            tree.update(&path, value.clone());
+            reference.update(&path, value);
+        }
+        let path = reference.leaf_paths()[7].to_bits();
+        assert_eq!(tree.get_root(), reference.get_root());
+        assert_eq!(tree.prove(&path), reference.prove(&path));
+        assert_eq!(tree.get_leaf(&path), reference.get_leaf(&path));
+        assert_eq!(tree.nodes.len(), reference.nodes.len());
+        assert_eq!(tree.verify_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn concurrent_tree_test() {
+        use crate::concurrent::ConcurrentSparseMerkleTree;
+
+        let height = 16;
+        let shared = ConcurrentSparseMerkleTree::new(SparseMerkleTree::<F, H>::new(height));
+        let paths: Vec<_> = (0..8).map(|i| usize_to_vec(i * 1000, height)).collect();
+        for path in &paths {
+            shared.update(path, F::rand_vec(4)).unwrap();
+        }
+        let snapshot = shared.snapshot();
+
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for path in &paths {
+                    shared.update(path, F::rand_vec(4)).unwrap();
+                }
+            });
+            for _ in 0..4 {
+                scope.spawn(|| {
+                    for path in &paths {
+                        let (proof, _) = shared.prove_with_root(path).unwrap();
+                        assert_eq!(proof.siblings.len(), height);
+                        // Reads under one guard see a single state.
+                        let tree = shared.read();
+                        let proof = tree.prove(path);
+                        assert!(SparseMerkleTree::<F, H>::verify_borrowed(
+                            path,
+                            &tree.get_leaf(path),
+                            &proof.siblings,
+                            tree.get_root()
+                        ));
+                    }
+                });
+            }
+        });
+        assert_ne!(shared.get_root(), snapshot.get_root());
+        let tree = shared.into_inner();
+        assert_eq!(tree.version(), snapshot.version() + paths.len() as u64);
+    }
+
+    #[test]
+    fn iter_leaves_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let mut expected = BTreeMap::new();
+        for _ in 0..30 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            let value = F::rand_vec(4);
+            tree.update(&path, value.clone());
+            expected.insert(path, value);
+        }
+        tree.update_hash(usize_to_vec(7, height), H::hash_or_noop(&F::rand_vec(4)));
+        expected.remove(&usize_to_vec(7, height));
+
+        let sorted: Vec<_> = tree
+            .iter_leaves_sorted()
+            .map(|(path, value)| (path.to_bits(), value.clone()))
+            .collect();
+        assert_eq!(sorted, expected.into_iter().collect::<Vec<_>>());
+        assert_eq!(tree.iter_leaves().count(), sorted.len());
+    }
+
+    #[test]
+    fn prove_leaf_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+        let root = tree.get_root();
+
+        let proof = tree.prove_leaf(&path).unwrap();
+        assert_eq!(proof.value, value);
+        assert_eq!(proof.root, Some(root));
+        assert!(proof.verify(root));
+        assert_eq!(proof.to_plonky2_proof(), tree.prove(&path));
+        assert!(!proof.verify(SparseMerkleTree::<F, H>::new(height).get_root()));
+
+        let mut unrooted = proof.clone();
+        unrooted.root = None;
+        assert!(unrooted.verify(root));
+        let mut wrong_root = proof.clone();
+        wrong_root.root = Some(H::hash_or_noop(&F::rand_vec(4)));
+        assert!(!wrong_root.verify(root));
+        let mut forged = proof;
+        forged.value = F::rand_vec(4);
+        assert!(!forged.verify(root));
+    }
+
+    #[test]
+    fn to_plonky2_merkle_tree_test() {
+        let mut rng = rand::thread_rng();
+        let height = 8;
+        let cap_height = 2;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let merkle_tree = tree.to_plonky2_merkle_tree(cap_height);
+        assert_eq!(merkle_tree.cap, tree.get_cap(cap_height).unwrap());
+
+        for path in tree
+            .leaf_paths()
+            .into_iter()
+            .chain([Path::from(&usize_to_vec(0, height))])
+        {
+            let index = path.index_at(0, height);
+            let proof = merkle_tree.prove(index);
+            assert_eq!(proof, tree.prove_in_subtree(cap_height, path).1);
+            verify_merkle_proof_to_cap(tree.get_leaf(path), index, &merkle_tree.cap, &proof)
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn fill_range_test() {
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let value = F::rand_vec(4);
+
+        for (start, end) in [(1024, 2048), (5, 300), (4000, 4096), (7, 7)] {
+            // Clones count their hashes from zero.
+            let mut filled = tree.clone();
+            filled.fill_range(start, end, value.clone());
+
+            let mut updated = tree.clone();
+            for i in start..end {
+                updated.update(&usize_to_vec(i as usize, height), value.clone());
+            }
+            #[cfg(feature = "hash-stats")]
+            if start == 1024 {
+                assert!(filled.hash_op_stats().two_to_one * 4 < updated.hash_op_stats().two_to_one);
+            }
+
+            assert_eq!(filled.get_root(), updated.get_root());
+            assert_eq!(filled.leaf_paths(), updated.leaf_paths());
+            assert_eq!(filled.verify_integrity(), Ok(()));
+            if end - start > 100 {
+                assert!(filled.nodes.len() * 2 < updated.nodes.len());
+            }
+        }
+
+        // A range that is a whole subtree is stored as one node below its two ancestors.
+        let mut filled = SparseMerkleTree::<F, H>::new(height);
+        let mut updated = filled.clone();
+        filled.cache_cap(4).unwrap();
+        updated.cache_cap(4).unwrap();
+        filled.fill_range(1024, 2048, value.clone());
+        for i in 1024..2048 {
+            updated.update(&usize_to_vec(i, height), value.clone());
+        }
+        assert_eq!(filled.nodes.len(), 3);
+        assert_eq!(filled.cached_cap(), updated.cached_cap());
+        let inside = usize_to_vec(1500, height);
+        assert_eq!(filled.get_leaf(&inside), value);
+        assert_eq!(filled.prove(&inside), updated.prove(&inside));
+
+        // Writes inside the subtree split it along the written path only.
+        let other = F::rand_vec(4);
+        for tree in [&mut filled, &mut updated] {
+            tree.update(&inside, other.clone());
+            tree.delete(usize_to_vec(1600, height));
+        }
+        assert_eq!(filled.get_root(), updated.get_root());
+        assert_eq!(filled.cached_cap(), updated.cached_cap());
+        assert_eq!(filled.leaf_paths(), updated.leaf_paths());
+        assert_eq!(filled.verify_integrity(), Ok(()));
+        assert!(filled.nodes.len() < 4 * height);
+        assert_eq!(filled.get_leaf(&inside), other);
+        assert_eq!(
+            filled.count_under(usize_to_vec(1024, height)[..2].to_vec()),
+            1023
+        );
+
+        // Strict mode checks the filled leaves like any other write.
+        let mut strict = tree.clone();
+        strict.set_strict(true);
+        strict.fill_range(100, 140, value);
+        assert_eq!(strict.verify_integrity(), Ok(()));
+    }
+
+    #[test]
+    fn verify_membership_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let set = usize_to_vec(9, height);
+        let unset = usize_to_vec(10, height);
+        tree.update(&set, F::rand_vec(4));
+        let root = tree.get_root();
+
+        type Smt = SparseMerkleTree<F, H>;
+        let proof = tree.prove(&set);
+        assert!(Smt::verify_membership(
+            &set,
+            &tree.get_leaf(&set),
+            &proof.siblings,
+            root
+        ));
+
+        let proof = tree.prove(&unset);
+        let empty = tree.get_leaf(&unset);
+        assert!(tree.verify(&unset, &empty, root, &proof).is_valid());
+        assert!(!Smt::verify_membership(
+            &unset,
+            &empty,
+            &proof.siblings,
+            root
+        ));
+        assert!(!Smt::verify_membership(
+            &unset,
+            &[F::ZERO],
+            &proof.siblings,
+            root
+        ));
+    }
+
+    #[test]
+    fn read_only_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let path = usize_to_vec(42, height);
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+
+        fn audit(view: ReadOnlySmt<F, H>, path: &Vec<bool>) -> bool {
+            let proof = view.prove(path);
+            view.verify(path, &view.get_leaf(path), view.get_root(), &proof)
+                .is_valid()
+        }
+        let view = tree.read_only();
+        assert!(audit(view, &path));
+        assert_eq!(view.height(), height);
+        assert_eq!(view.get_leaf(&path), value);
+        assert_eq!(view.get_root(), tree.get_root());
+        assert_eq!(view.count_under(vec![]), 1);
+        assert_eq!(view.first_empty(), Some(0));
+    }
+
+    #[test]
+    fn leaf_path_hashes_test() {
+        let mut rng = rand::thread_rng();
+        let height = 10;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..10 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let path = tree.leaf_paths()[3].to_bits();
+        let hashes = tree.leaf_path_hashes(&path);
+        assert_eq!(hashes.len(), height + 1);
+        assert_eq!(hashes[0], H::hash_or_noop(&tree.get_leaf(&path)));
+        assert_eq!(hashes[height], tree.get_root());
+
+        let siblings = tree.prove(&path).siblings;
+        for (i, pair) in hashes.windows(2).enumerate() {
+            let expected = if path[height - 1 - i] {
+                H::two_to_one(siblings[i], pair[0])
+            } else {
+                H::two_to_one(pair[0], siblings[i])
+            };
+            assert_eq!(pair[1], expected);
+        }
+    }
+
+    #[test]
+    fn test_vectors_test() {
+        type Smt = SparseMerkleTree<F, H>;
+        let vectors = Smt::test_vectors(16, 7, 20);
+        assert_eq!(vectors, Smt::test_vectors(16, 7, 20));
+        assert_ne!(vectors, Smt::test_vectors(16, 8, 20));
+        assert_eq!(vectors[..5], Smt::test_vectors(16, 7, 5));
+
+        let mut tree = Smt::new(16);
+        for (index, value, root) in vectors {
+            assert!(index < 1 << 16);
+            tree.update(&usize_to_vec(index as usize, 16), value);
+            assert_eq!(tree.get_root(), root);
+        }
+    }
+
+    #[test]
+    fn symmetric_difference_test() {
+        let mut rng = rand::thread_rng();
+        let height = 20;
+        let mut ours = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..500 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            ours.update(&path, F::rand_vec(4));
+        }
+        let mut theirs = ours.clone();
+        let paths = ours.leaf_paths();
+        let (changed, deleted) = (paths[10], paths[200]);
+        let added = (0..)
+            .map(|_| usize_to_vec(rng.gen_range(0..1 << height), height))
+            .find(|path| ours.nodes.get(&Path::from(path)).is_none())
+            .unwrap();
+        let value = F::rand_vec(4);
+        theirs.update(changed, value.clone());
+        theirs.delete(deleted);
+        theirs.update(&added, value.clone());
+
+        // Both sides come out in path order.
+        let (only_ours, only_theirs) = ours.symmetric_difference(&theirs);
+        let mut expected_ours = vec![
+            (changed, ours.get_leaf(changed)),
+            (deleted, ours.get_leaf(deleted)),
+        ];
+        expected_ours.sort_by(|a, b| a.0.cmp(&b.0));
+        let mut expected_theirs = vec![(changed, value.clone()), (Path::from(&added), value)];
+        expected_theirs.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(only_ours, expected_ours);
+        assert_eq!(only_theirs, expected_theirs);
+
+        let visited = ours.diff_subtrees(&theirs, &mut Path::new(), &mut vec![]);
+        assert!(visited * 10 < ours.nodes.len());
+        assert_eq!(ours.symmetric_difference(&ours), (vec![], vec![]));
+    }
+
+    #[test]
+    fn apply_diff_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut primary = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..100 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            primary.update(&path, F::rand_vec(4));
+        }
+        let mut replica = primary.clone();
+        let paths = primary.leaf_paths();
+        primary.update(paths[3], F::rand_vec(4));
+        primary.delete(paths[50]);
+        primary.update(usize_to_vec(1, height), F::rand_vec(4));
+
+        let diff = replica.diff(&primary);
+        assert!(diff.windows(2).all(|w| w[0].0 < w[1].0));
+        assert!(diff
+            .iter()
+            .any(|(path, _, new)| *path == paths[50] && new.is_none()));
+        replica.apply_diff(&diff).unwrap();
+        assert_eq!(replica.get_root(), primary.get_root());
+        assert!(replica.diff(&primary).is_empty());
+
+        // Replaying a diff on a replica that already moved on is rejected as a whole.
+        let root = replica.get_root();
+        assert_eq!(replica.apply_diff(&diff), Err(SmtError::RootMismatch));
+        assert_eq!(replica.get_root(), root);
+    }
+
+    #[test]
+    fn block_proof_test() {
+        let mut rng = rand::thread_rng();
+        let height = 10;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..10 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let initial_root = tree.get_root();
+        // Neighbouring leaves and a repeated path, so later updates change earlier siblings.
+        let updates: Vec<_> = [4, 5, 6, 4, 900]
+            .into_iter()
+            .map(|i| (usize_to_vec(i, height), F::rand_vec(4)))
+            .collect();
+        let block = tree.prove_block(&updates).unwrap();
+        assert_eq!(block.old_root, initial_root);
+        assert_eq!(block.new_root, tree.get_root());
+
+        type Smt = SparseMerkleTree<F, H>;
+        assert_eq!(Smt::verify_block(&block, &updates), Ok(tree.get_root()));
+
+        let mut reordered = updates.clone();
+        reordered.swap(0, 1);
+        assert_eq!(
+            Smt::verify_block(&block, &reordered),
+            Err(SmtError::InvalidProof {
+                path: reordered[0].0.clone()
+            })
+        );
+        // A different value breaks the proof of the next update to a nearby leaf, or the final
+        // root for the last update.
+        let mut altered = updates.clone();
+        altered[2].1 = F::rand_vec(4);
+        assert_eq!(
+            Smt::verify_block(&block, &altered),
+            Err(SmtError::InvalidProof {
+                path: altered[3].0.clone()
+            })
+        );
+        let mut altered = updates;
+        altered[4].1 = F::rand_vec(4);
+        assert_eq!(
+            Smt::verify_block(&block, &altered),
+            Err(SmtError::RootMismatch)
+        );
+    }
+
+    #[test]
+    fn non_membership_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let root = tree.get_root();
+        let set = tree.leaf_paths()[0].to_bits();
+        let unset = (0..)
+            .map(|i| usize_to_vec(i, height))
+            .find(|path| tree.nodes.get(&Path::from(path)).is_none())
+            .unwrap();
+
+        type Smt = SparseMerkleTree<F, H>;
+        let proof = tree.prove_non_membership(&unset).unwrap();
+        assert!(Smt::verify_non_membership(&unset, &proof.siblings, root));
+        assert!(!Smt::verify_non_membership(&set, &proof.siblings, root));
+        assert!(!Smt::verify_non_membership(
+            &set,
+            &tree.prove(&set).siblings,
+            root
+        ));
+        assert_eq!(
+            tree.prove_non_membership(&set),
+            Err(SmtError::LeafNotEmpty { path: set.clone() })
+        );
+
+        tree.delete(&set);
+        let proof = tree.prove_non_membership(&set).unwrap();
+        assert!(Smt::verify_non_membership(
+            &set,
+            &proof.siblings,
+            tree.get_root()
+        ));
+    }
+
+    #[test]
+    fn delete_prunes_empty_siblings_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        tree.update(usize_to_vec(0b1000_0000, height), F::rand_vec(4));
+        tree.update(usize_to_vec(0b1000_0001, height), vec![F::ZERO; 4]);
+        tree.update(usize_to_vec(0b1000_0110, height), vec![F::ZERO; 4]);
+        let version = tree.version();
+        tree.delete(usize_to_vec(0b1000_0000, height));
+        assert!(tree.nodes.is_empty());
+        assert_eq!(
+            tree.get_root(),
+            SparseMerkleTree::<F, H>::new(height).get_root()
+        );
+        assert_eq!(tree.version(), version + 1);
+    }
+
+    #[test]
+    fn node_store_test() {
+        use crate::node_map::NodeStore;
+
+        /// A store that counts the writes reaching it, as a database backend would see them.
+        #[derive(Clone, Default)]
+        struct CountingStore {
+            nodes: BTreeMap<Path, Node<F, H>>,
+            writes: usize,
+        }
+
+        impl MerkleNodeMap<Node<F, H>> for CountingStore {
+            fn get(&self, path: &Path) -> Option<&Node<F, H>> {
+                self.nodes.get(path)
+            }
+
+            fn insert(&mut self, path: Path, node: Node<F, H>) -> Option<Node<F, H>> {
+                self.writes += 1;
+                self.nodes.insert(path, node)
+            }
+
+            fn remove(&mut self, path: &Path) -> Option<Node<F, H>> {
+                self.writes += 1;
+                self.nodes.remove(path)
+            }
+
+            fn len(&self) -> usize {
+                self.nodes.len()
+            }
+
+            fn iter(&self) -> Box<dyn Iterator<Item = (Path, &Node<F, H>)> + '_> {
+                Box::new(self.nodes.iter().map(|(path, node)| (*path, node)))
+            }
+        }
+
+        fn stored_nodes<S: NodeStore<F, H>>(store: &S) -> usize {
+            store.len()
+        }
+
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H, 2, Vec<F>, CountingStore>::new(height);
+        let mut reference = SparseMerkleTree::<F, H>::new(height);
+        for i in 0..10 {
+            let value = F::rand_vec(4);
+            tree.update(&usize_to_vec(i * 1000, height), value.clone());
+            reference.update(&usize_to_vec(i * 1000, height), value);
+        }
+        assert_eq!(tree.get_root(), reference.get_root());
+        assert_eq!(tree.nodes.writes, 10 * (height + 1));
+        assert_eq!(stored_nodes(tree.nodes.as_ref()), reference.nodes.len());
+
+        let store = (*tree.nodes).clone();
+        let reopened =
+            SparseMerkleTree::<F, H, 2, Vec<F>, CountingStore>::with_store(height, store);
+        assert_eq!(reopened.verify_integrity(), Ok(()));
+        assert_eq!(reopened.get_root(), reference.get_root());
+    }
+
+    #[test]
+    fn update_batch_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H, 4>::new(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let mut entries: Vec<_> = (0..50)
+            .map(|_| {
+                let path = usize_to_vec(rng.gen_range(0..1 << 6), height);
+                (path, F::rand_vec(4))
+            })
+            .collect();
+        entries.push((entries[0].0.clone(), F::rand_vec(4)));
+
+        let mut sequential = tree.clone();
+        for (path, value) in &entries {
+            sequential.update(path, value.clone());
+        }
+        let version = tree.version();
+        #[cfg(feature = "hash-stats")]
+        tree.reset_hash_op_stats();
+        tree.update_batch(&entries);
+        #[cfg(feature = "hash-stats")]
+        {
+            let mut again = sequential.clone();
+            for (path, value) in &entries {
+                again.update(path, value.clone());
+            }
+            assert!(tree.hash_op_stats().hash_no_pad * 4 < again.hash_op_stats().hash_no_pad);
+        }
+        assert_eq!(tree.get_root(), sequential.get_root());
+        assert_eq!(tree.get_leaf(&entries[0].0), entries[50].1);
+        assert_eq!(tree.version(), version + 1);
+        assert_eq!(tree.verify_integrity(), Ok(()));
+
+        tree.freeze_leaf(&entries[3].0);
+        let root = tree.get_root();
+        assert_eq!(
+            tree.try_update_batch(&[
+                (entries[1].0.clone(), F::rand_vec(4)),
+                (entries[3].0.clone(), F::rand_vec(4))
+            ]),
+            Err(SmtError::LeafFrozen {
+                path: entries[3].0.clone()
+            })
+        );
+        assert_eq!(tree.get_root(), root);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde_test() {
+        use crate::serialization::{CompactPath, TreeRepr};
+
+        fn assert_serde<T: serde::Serialize + serde::de::DeserializeOwned>() {}
+        assert_serde::<SparseMerkleTree<F, H, 4>>();
+        assert_serde::<Node<F, H, 4>>();
+        assert_serde::<SparseMerkleMultiProof<F, H>>();
+        assert_serde::<MultiNonInclusionProof<F, H>>();
+        assert_serde::<TreeSnapshot<F, H>>();
+        assert_serde::<SparseMerkleProof<F, H>>();
+
+        let mut rng = rand::thread_rng();
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H, 4>::new_level_tagged(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+        }
+        let frozen = tree.leaf_paths()[0].to_bits();
+        tree.freeze_leaf(&frozen);
+        let restored = SparseMerkleTree::<F, H, 4>::try_from(TreeRepr::from(tree.clone())).unwrap();
+        assert_eq!(restored.get_root(), tree.get_root());
+        assert!(restored.is_level_tagged());
+        assert_eq!(restored.nodes.len(), tree.nodes.len());
+        assert_eq!(restored.frozen_leaves(), vec![Path::from(&frozen)]);
+
+        let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+        assert_eq!(CompactPath::new(&path).len(), height);
+        assert_eq!(CompactPath::new(&path).to_path(), path);
+        assert_eq!(
+            CompactPath::from_bytes(4, vec![0b1010_0000])
+                .unwrap()
+                .to_path(),
+            vec![true, false, true, false]
+        );
+        assert_eq!(
+            CompactPath::from_bytes(4, vec![0b1010_0001]),
+            Err(SmtError::InvalidEncoding)
+        );
+        assert_eq!(
+            CompactPath::from_bytes(9, vec![0]),
+            Err(SmtError::InvalidEncoding)
+        );
+    }
+
+    #[test]
+    fn try_get_leaf_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let path = usize_to_vec(5, height);
+        assert_eq!(tree.try_get_leaf(&path), Ok(None));
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+        assert_eq!(tree.try_get_leaf(&path), Ok(Some(value.clone())));
+        assert_eq!(tree.read_only().try_get_leaf(&path), Ok(Some(value)));
+
+        let malformed = vec![true; height + 1];
+        let err = SmtError::InvalidPath {
+            len: height + 1,
+            height,
+        };
+        assert_eq!(tree.try_get_leaf(&malformed), Err(err.clone()));
+        assert_eq!(tree.try_prove(&malformed), Err(err.clone()));
+        assert_eq!(
+            tree.try_update(&malformed, F::rand_vec(4)),
+            Err(err.clone())
+        );
+        assert_eq!(tree.try_delete(&malformed), Err(err));
+    }
+
+    #[test]
+    fn fixed_leaf_test() {
+        let mut rng = rand::thread_rng();
+        let height = 32;
+        let mut dynamic = SparseMerkleTree::<F, H>::new(height);
+        let mut fixed = FixedLeafSparseMerkleTree::<F, H, 4>::new(height);
+        assert_eq!(dynamic.get_root(), fixed.get_root());
+
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            let value: [F; 4] = F::rand_array();
+            dynamic.update(&path, value.to_vec());
+            fixed.update(&path, value);
+            assert_eq!(fixed.get_leaf(&path), value);
+            assert_eq!(dynamic.get_root(), fixed.get_root());
+        }
+
+        // Other widths get their own empty leaf.
+        let mut wide = FixedLeafSparseMerkleTree::<F, H, 8>::new(height);
+        let path = usize_to_vec(1, height);
+        assert_eq!(wide.get_leaf(&path), [F::ZERO; 8]);
+        let value: [F; 8] = F::rand_array();
+        wide.update(&path, value);
+        assert!(wide
+            .verify(&path, &value, wide.get_root(), &wide.prove(&path))
+            .is_valid());
+    }
+
+    #[test]
+    fn root_observers_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let seen = Arc::new(Mutex::new(vec![]));
+        let log = seen.clone();
+        tree.on_root_change(move |root| log.lock().unwrap().push(root));
+        let roots = tree.subscribe();
+
+        let path = usize_to_vec(3, height);
+        let result = tree.update_tracked(&path, F::rand_vec(4));
+        assert_eq!(result.old_root, tree.zero_hashes()[0]);
+        assert_eq!(result.new_root, tree.get_root());
+        assert_eq!(result.dirtied_nodes.len(), height + 1);
+        assert_eq!(result.dirtied_nodes[0], path);
+        assert!(result.dirtied_nodes[height].is_empty());
+
+        // Rewriting the same value and rolled back writes don't change the root.
+        tree.update(&path, tree.get_leaf(&path));
+        let other = usize_to_vec(4, height);
+        let rejected = tree.apply_and_verify(&[(other.clone(), F::rand_vec(4))], result.old_root);
+        assert_eq!(rejected, Err(SmtError::RootMismatch));
+        let entries = [(other, F::rand_vec(4)), (path, F::rand_vec(4))];
+        tree.update_batch(&entries);
+
+        let expected = vec![result.new_root, tree.get_root()];
+        assert_eq!(*seen.lock().unwrap(), expected);
+        assert_eq!(roots.try_iter().collect::<Vec<_>>(), expected);
+        // Clones don't notify.
+        tree.clone().update(usize_to_vec(5, height), F::rand_vec(4));
+        assert_eq!(seen.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn default_leaf_test() {
+        let height = 8;
+        let default = vec![F::ONE, F::ZERO, F::ZERO, F::TWO];
+        let mut tree = SparseMerkleTree::<F, H>::new_with_default_leaf(height, default.clone());
+        let path = usize_to_vec(7, height);
+        assert_eq!(tree.get_leaf(&path), default);
+        assert_eq!(tree.try_get_leaf(&path), Ok(None));
+
+        // The root is that of a tree with every leaf set to the default.
+        let full = SparseMerkleTree::<F, H>::from_leaves(
+            height,
+            (0..1 << height).map(|i| (usize_to_vec(i, height), default.clone())),
+        );
+        assert_eq!(tree.get_root(), full.get_root());
+        assert_ne!(
+            tree.get_root(),
+            SparseMerkleTree::<F, H>::new(height).get_root()
+        );
+
+        let value = F::rand_vec(4);
+        tree.update(&path, value.clone());
+        let mut expected = full.clone();
+        expected.update(&path, value);
+        assert_eq!(tree.get_root(), expected.get_root());
+        let proof = tree.prove(&path);
+        assert!(tree
+            .verify(&path, &tree.get_leaf(&path), tree.get_root(), &proof)
+            .is_valid());
+
+        tree.delete(&path);
+        assert_eq!(tree.get_leaf(&path), default);
+        assert_eq!(tree.get_root(), full.get_root());
+        assert_eq!(tree.extract_subtree(vec![true]).default_leaf(), &default);
+    }
+
+    #[test]
+    fn proof_bytes_test() {
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let path = usize_to_vec(0xabc, height);
+        tree.update(&path, F::rand_vec(4));
+        tree.update(usize_to_vec(1, height), F::rand_vec(4));
+        assert_eq!(path_to_bytes(&path), [0xab, 0xc0]);
+        assert_eq!(path_from_bytes(&[0xab, 0xc0], height), Ok(path.clone()));
+        assert_eq!(
+            path_from_bytes(&[0xab, 0xc1], height),
+            Err(SmtError::InvalidEncoding)
+        );
+
+        let proof = tree.prove_leaf(&path).unwrap();
+        let bytes = proof.to_bytes();
+        assert_eq!(bytes[..6], [0, 0, 0, 12, 0xab, 0xc0]);
+        assert_eq!(bytes.len(), 4 + 2 + 4 + 4 * 8 + 4 + height * 32 + 1 + 32);
+        let decoded = SparseMerkleProof::<F, H>::from_bytes(&bytes).unwrap();
+        assert_eq!(decoded, proof);
+        assert!(decoded.verify(tree.get_root()));
+
+        let unrooted = SparseMerkleProof {
+            root: None,
+            ..proof
+        };
+        assert_eq!(
+            SparseMerkleProof::from_bytes(&unrooted.to_bytes()),
+            Ok(unrooted)
+        );
+        assert!(SparseMerkleProof::<F, H>::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        let mut trailing = bytes.clone();
+        trailing.push(0);
+        assert!(SparseMerkleProof::<F, H>::from_bytes(&trailing).is_err());
+        let mut non_canonical = bytes;
+        non_canonical[10..18].copy_from_slice(&u64::MAX.to_be_bytes());
+        assert!(SparseMerkleProof::<F, H>::from_bytes(&non_canonical).is_err());
+    }
+
+    #[test]
+    fn graft_subtree_test() {
+        let mut rng = rand::thread_rng();
+        let height = 10;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..30 {
+            tree.update(
+                &usize_to_vec(rng.gen_range(0..1 << height), height),
+                F::rand_vec(4),
+            );
+        }
+        let mut expected = tree.clone();
+
+        // Two workers update their own shard each.
+        let mut shards: Vec<_> = [vec![false, false], vec![true, false]]
+            .into_iter()
+            .map(|prefix| {
+                let shard = tree.get_subtree(&prefix).unwrap();
+                (prefix, shard)
+            })
+            .collect();
+        for (prefix, shard) in &mut shards {
+            for _ in 0..10 {
+                let index = rng.gen_range(0..1 << (height - 2));
+                let value = F::rand_vec(4);
+                shard.update(&usize_to_vec(index, height - 2), value.clone());
+                let path = [prefix.clone(), usize_to_vec(index, height - 2)].concat();
+                expected.update(&path, value);
+            }
+        }
+        for (prefix, shard) in &shards {
+            tree.insert_subtree(prefix, shard).unwrap();
+        }
+        assert_eq!(tree.get_root(), expected.get_root());
+        assert!(tree.verify_integrity().is_ok());
+
+        let shard = &shards[0].1;
+        let err = SmtError::InvalidLevel { level: 1, height };
+        assert_eq!(tree.insert_subtree(&[true], shard), Err(err));
+        assert_eq!(
+            tree.get_subtree(vec![false; height + 1]).err(),
+            Some(SmtError::InvalidLevel {
+                level: height + 1,
+                height
+            })
+        );
+        let other = SparseMerkleTree::<F, H>::new_with_default_leaf(height - 2, F::rand_vec(4));
+        assert_eq!(
+            tree.insert_subtree(&[true, true], &other),
+            Err(SmtError::InvalidZeroHashes { level: 0 })
+        );
+        assert_eq!(tree.get_root(), expected.get_root());
+    }
+
+    #[test]
+    fn node_hash_cache_test() {
+        let mut rng = rand::thread_rng();
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let mut paths = vec![];
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            tree.update(&path, F::rand_vec(4));
+            paths.push(path);
+        }
+        let mut cached = tree.clone();
+        cached.cache_node_hashes();
+
+        for (i, path) in paths.iter().enumerate() {
+            if i % 3 == 0 {
+                tree.delete(path);
+                cached.delete(path);
+            } else {
+                let value = F::rand_vec(4);
+                tree.update(path, value.clone());
+                cached.update(path, value);
+            }
+            assert_eq!(cached.get_root(), tree.get_root());
+        }
+        let entries: Vec<_> = (0..8)
+            .map(|i| (usize_to_vec(i, height), F::rand_vec(4)))
+            .collect();
+        tree.update_batch(&entries);
+        cached.update_batch(&entries);
+        assert_eq!(cached.get_root(), tree.get_root());
+        assert!(cached.verify_integrity().is_ok());
+        for path in &paths {
+            assert_eq!(cached.prove(path), tree.prove(path));
+        }
+
+        #[cfg(feature = "hash-stats")]
+        {
+            cached.reset_hash_op_stats();
+            cached.prove(&paths[1]);
+            assert_eq!(cached.hash_op_stats(), HashOpStats::default());
+        }
+        cached.clear_node_hash_cache();
+        assert_eq!(cached.get_root(), tree.get_root());
+    }
+
+    #[test]
+    fn byte_hasher_test() {
+        use crate::byte_hasher::{ByteHasher, Bytes32Hasher};
+        use plonky2::hash::keccak::KeccakHash;
+
+        #[derive(Clone, Debug, PartialEq, Eq)]
+        struct Keccak256;
+
+        impl ByteHasher for Keccak256 {
+            fn hash(input: &[u8]) -> [u8; 32] {
+                keccak_hash::keccak(input).0
+            }
+        }
+
+        // Matches plonky2's own Keccak hasher, which hashes the same bytes.
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, Bytes32Hasher<Keccak256>>::new(height);
+        let mut reference = SparseMerkleTree::<F, KeccakHash<32>>::new(height);
+        for i in [3, 100, 4096] {
+            let path = usize_to_vec(i, height);
+            let value = F::rand_vec(6);
+            tree.update(&path, value.clone());
+            reference.update(&path, value);
+        }
+        assert_eq!(tree.get_root().0, reference.get_root().0);
+
+        let path = usize_to_vec(100, height);
+        let proof = tree.prove_leaf(&path).unwrap();
+        assert!(proof.verify(tree.get_root()));
+        let decoded = SparseMerkleProof::from_bytes(&proof.to_bytes()).unwrap();
+        assert_eq!(decoded, proof);
+        assert!(!tree
+            .verify(&path, &F::rand_vec(6), tree.get_root(), &tree.prove(&path))
+            .is_valid());
+    }
+
+    #[test]
+    fn stateless_verifier_test() {
+        use crate::verifier::{compute_root_from_proof, verify_smt_proof};
+
+        let mut rng = rand::thread_rng();
+        for height in [8, 100] {
+            let mut tree = SparseMerkleTree::<F, H>::new(height);
+            let path: Vec<bool> = (0..height).map(|_| rng.gen()).collect();
+            let value = F::rand_vec(4);
+            tree.update(&path, value.clone());
+            let siblings = tree.prove(&path).siblings;
+            assert_eq!(
+                compute_root_from_proof::<F, H>(&value, &path, &siblings),
+                Some(tree.get_root())
+            );
+            assert!(verify_smt_proof::<F, H>(
+                &value,
+                &path,
+                &siblings,
+                tree.get_root()
+            ));
+            let absent: Vec<bool> = path.iter().map(|b| !b).collect();
+            let empty = tree.prove(&absent).siblings;
+            assert!(verify_smt_proof::<F, H>(
+                &[],
+                &absent,
+                &empty,
+                tree.get_root()
+            ));
+            assert!(!verify_smt_proof::<F, H>(
+                &value,
+                &absent,
+                &siblings,
+                tree.get_root()
+            ));
+            assert_eq!(
+                compute_root_from_proof::<F, H>(&value, &path[1..], &siblings),
+                None
+            );
+        }
+    }
+
+    #[test]
+    fn height_256_test() {
+        use crate::{keyed_smt::SmtKV, verifier::verify_smt_proof};
+
+        let height = 256;
+        let mut kv = SmtKV::<F, H>::new(height);
+        let keys: Vec<[u8; 32]> = (0u8..20).map(|i| keccak_hash::keccak([i]).0).collect();
+        for key in &keys {
+            kv.insert(key, F::rand_vec(4)).unwrap();
+        }
+        let root = kv.get_root();
+        for key in &keys {
+            let path = kv.path_of(key).unwrap();
+            assert_eq!(path_to_bytes(&path), key);
+            let value = kv.get(key).unwrap().unwrap();
+            let proof = kv.prove(key).unwrap();
+            assert!(kv.verify(key, &value, root, &proof));
+            assert!(verify_smt_proof::<F, H>(
+                &value,
+                &path,
+                &proof.siblings,
+                root
+            ));
+
+            let proof = kv.tree().prove_leaf(&path).unwrap();
+            let decoded = SparseMerkleProof::<F, H>::from_bytes(&proof.to_bytes()).unwrap();
+            assert!(decoded.verify(root));
+        }
+        let absent = keccak_hash::keccak([0xff]).0;
+        let path = kv.path_of(&absent).unwrap();
+        let proof = kv.prove(&absent).unwrap();
+        assert!(verify_smt_proof::<F, H>(&[], &path, &proof.siblings, root));
+    }
+
+    #[test]
+    fn fork_test() {
+        let height = 16;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        for i in 0..50 {
+            tree.update(&usize_to_vec(i * 97, height), F::rand_vec(4));
+        }
+        let root = tree.get_root();
+        let mut expected = tree.clone();
+
+        // A discarded fork leaves the tree as it was.
+        let mut fork = tree.fork();
+        fork.update(usize_to_vec(1, height), F::rand_vec(4));
+        assert_ne!(fork.get_root(), root);
+        drop(fork);
+        assert_eq!(tree.get_root(), root);
+
+        let mut fork = tree.fork();
+        let value = F::rand_vec(4);
+        fork.update(usize_to_vec(2, height), value.clone());
+        fork.delete(usize_to_vec(97, height));
+        expected.update(usize_to_vec(2, height), value.clone());
+        expected.delete(usize_to_vec(97, height));
+        assert_eq!(fork.get_root(), expected.get_root());
+        assert_eq!(fork.nodes.len(), expected.nodes.len());
+        assert!(fork.nodes.num_changes() < 2 * (height + 1));
+        assert!(fork.verify_integrity().is_ok());
+        // Reads of untouched leaves go to the shared nodes.
+        assert_eq!(
+            fork.get_leaf(usize_to_vec(194, height)),
+            tree.get_leaf(usize_to_vec(194, height))
+        );
+
+        let version = tree.version();
+        tree.commit_fork(fork).unwrap();
+        assert_eq!(tree.get_root(), expected.get_root());
+        assert_eq!(tree.get_leaf(usize_to_vec(2, height)), value);
+        assert_eq!(tree.version(), version + 1);
+        assert!(tree.verify_integrity().is_ok());
+
+        // Leaves frozen on the fork stay frozen once it is committed.
+        let mut fork = tree.fork();
+        fork.freeze_leaf(usize_to_vec(2, height));
+        tree.commit_fork(fork).unwrap();
+        assert!(tree.is_frozen(usize_to_vec(2, height)));
+
+        // A fork of an older state can't be committed.
+        let stale = tree.fork();
+        tree.update(usize_to_vec(3, height), F::rand_vec(4));
+        assert_eq!(tree.commit_fork(stale), Err(SmtError::RootMismatch));
+    }
+
+    #[test]
+    fn leaves_with_prefix_test() {
+        let height = 12;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        // Four shards of 2 bits, each holding a few accounts.
+        let path = |shard: usize, account: usize| usize_to_vec(shard << 10 | account, height);
+        let mut expected = vec![];
+        for account in [3, 17, 900] {
+            let value = F::rand_vec(4);
+            tree.update(&path(2, account), value.clone());
+            expected.push((path(2, account), value));
+        }
+        tree.update(path(1, 5), F::rand_vec(4));
+        tree.update(path(3, 5), F::rand_vec(4));
+        // Explicitly empty leaves don't count.
+        tree.update(path(2, 4), vec![F::ZERO; 4]);
+
+        let shard = tree.leaves_with_prefix(&[true, false]);
+        let shard: Vec<_> = shard
+            .into_iter()
+            .map(|(path, value)| (path.to_bits(), value.clone()))
+            .collect();
+        assert_eq!(shard, expected);
+        assert_eq!(tree.count_nonzero_under(&[true, false]), 3);
+        assert_eq!(tree.count_under(vec![true, false]), 4);
+        assert_eq!(tree.count_nonzero_under(&[]), 5);
+        assert_eq!(tree.count_nonzero_under(&[false, false]), 0);
+        assert_eq!(tree.leaves_with_prefix(path(3, 5)).len(), 1);
+        assert!(SparseMerkleTree::<F, H>::new(height)
+            .leaves_with_prefix(&[])
+            .is_empty());
+    }
+
+    #[test]
+    fn rebuild_internal_nodes_test() {
+        let mut rng = rand::thread_rng();
+        let height = 8;
+        let mut source = SparseMerkleTree::<F, H>::new(height);
+        for _ in 0..20 {
+            let path = usize_to_vec(rng.gen_range(0..1 << height), height);
+            source.update(&path, F::rand_vec(4));
+        }
+        let root = source.get_root();
+
+        // Keep only the leaves, then corrupt two inner nodes on the way to them.
+        let mut damaged = SparseMerkleTree::<F, H>::new(height);
+        for (path, node) in source.nodes.iter() {
+            if matches!(node, Node::Leaf { .. }) {
+                damaged.insert_raw_node(path, node.clone());
+            }
+        }
+        let leaf = source.leaf_paths()[0].to_bits();
+        let stale = Node::InnerNode {
+            children: [H::hash_or_noop(&[F::ONE]); 2],
+        };
+        damaged.insert_raw_node(vec![], stale.clone());
+        damaged.insert_raw_node(leaf[..4].to_vec(), stale);
+        let errors = damaged.check_integrity().unwrap_err();
+        assert!(errors.contains(&IntegrityError::ChildHashMismatch {
+            path: vec![],
+            child: 0,
+        }));
+        assert!(errors.iter().any(|e| e.path() == &leaf[..4]));
+        assert_eq!(
+            damaged.verify_integrity(),
+            Err(SmtError::CorruptNode { path: vec![] })
+        );
+
+        damaged.rebuild_internal_nodes();
+        assert_eq!(damaged.check_integrity(), Ok(()));
+        assert_eq!(damaged.get_root(), root);
+        assert_eq!(damaged.prove(&leaf), source.prove(&leaf));
+    }
+
+    #[test]
+    fn update_log_test() {
+        let height = 8;
+        let mut tree = SparseMerkleTree::<F, H>::new(height);
+        let first = F::rand_vec(4);
+        tree.update(usize_to_vec(1, height), first.clone());
+        tree.start_update_log();
+        let start = tree.get_root();
+
+        let mut roots = vec![];
+        tree.update(usize_to_vec(2, height), F::rand_vec(4));
+        roots.push(tree.get_root());
+        tree.update(usize_to_vec(1, height), F::rand_vec(4));
+        roots.push(tree.get_root());
+        tree.update_batch(&[
+            (usize_to_vec(3, height), F::rand_vec(4)),
+            (usize_to_vec(2, height), F::rand_vec(4)),
+            (usize_to_vec(3, height), F::rand_vec(4)),
+        ]);
+        roots.push(tree.get_root());
+        tree.update_hash(usize_to_vec(5, height), H::hash_or_noop(&[F::ONE]));
+        roots.push(tree.get_root());
+        tree.fill_range(8, 12, F::rand_vec(4));
+        roots.push(tree.get_root());
+        tree.delete(usize_to_vec(1, height));
+        roots.push(tree.get_root());
+        assert_eq!(tree.update_log().unwrap().len(), 11);
+
+        // Grafted subtrees and committed forks log the leaves they change.
+        let prefix = usize_to_vec(0, 4);
+        let mut subtree = tree.extract_subtree(&prefix);
+        subtree.update(usize_to_vec(2, 4), F::rand_vec(4));
+        subtree.delete(usize_to_vec(3, 4));
+        tree.insert_subtree(&prefix, &subtree).unwrap();
+        roots.push(tree.get_root());
+        let mut fork = tree.fork();
+        fork.update(usize_to_vec(200, height), F::rand_vec(4));
+        fork.fill_range(16, 20, F::rand_vec(4));
+        tree.commit_fork(fork).unwrap();
+        roots.push(tree.get_root());
+        assert_eq!(tree.update_log().unwrap().len(), 18);
+
+        // Replaying rebuilds the tree from where the log started, and checks that it did.
+        let log = tree.update_log().unwrap().clone();
+        assert_eq!(log.start_root(), start);
+        let mut replayed = SparseMerkleTree::<F, H>::new(height);
+        assert_eq!(replayed.replay(&log), Err(SmtError::RootMismatch));
+        replayed.update(usize_to_vec(1, height), first);
+        replayed.replay(&log).unwrap();
+        assert_eq!(replayed.get_root(), tree.get_root());
+
+        // Reverting undoes hash-only and range writes as well.
+        assert_eq!(tree.revert_to(roots[5]), Ok(7));
+        assert_eq!(tree.revert_to(roots[2]), Ok(6));
+        assert_eq!(tree.get_root(), roots[2]);
+        assert_eq!(tree.update_log().unwrap().len(), 5);
+        assert_eq!(
+            tree.revert_to(H::hash_or_noop(&[F::TWO])),
+            Err(SmtError::RootMismatch)
+        );
+
+        // A revert that can't be completed is rolled back.
+        tree.freeze_leaf(usize_to_vec(1, height));
+        assert!(matches!(
+            tree.revert_to(start),
+            Err(SmtError::LeafFrozen { .. })
+        ));
+        assert_eq!(tree.get_root(), roots[2]);
+        assert_eq!(tree.update_log().unwrap().len(), 5);
+        assert_eq!(tree.revert_to(roots[1]), Ok(3));
+        assert_eq!(tree.get_root(), roots[1]);
     }
 }
@@ -0,0 +1,39 @@
+use plonky2::{hash::hash_types::RichField, plonk::config::Hasher};
+
+/// Recomputes the root of a binary tree without level tags from the leaf `leaf` at `path` and
+/// its `siblings`, bottom-up as returned by `SparseMerkleTree::prove`. The path is taken as bits,
+/// so unlike plonky2's `verify_merkle_proof`, whose leaf index is a `usize`, any height works.
+/// Returns `None` if there isn't exactly one sibling per path bit.
+pub fn compute_root_from_proof<F: RichField, H: Hasher<F>>(
+    leaf: &[F],
+    path: &[bool],
+    siblings: &[H::Hash],
+) -> Option<H::Hash> {
+    if siblings.len() != path.len() {
+        return None;
+    }
+    let root =
+        path.iter()
+            .rev()
+            .zip(siblings)
+            .fold(H::hash_or_noop(leaf), |h, (&bit, &sibling)| {
+                if bit {
+                    H::two_to_one(sibling, h)
+                } else {
+                    H::two_to_one(h, sibling)
+                }
+            });
+    Some(root)
+}
+
+/// Checks that `siblings` show `leaf` at `path` under `expected_root`, see
+/// [`compute_root_from_proof`]. An empty leaf verifies like any other value, so this also
+/// checks proofs of absence.
+pub fn verify_smt_proof<F: RichField, H: Hasher<F>>(
+    leaf: &[F],
+    path: &[bool],
+    siblings: &[H::Hash],
+    expected_root: H::Hash,
+) -> bool {
+    compute_root_from_proof::<F, H>(leaf, path, siblings) == Some(expected_root)
+}